@@ -0,0 +1,173 @@
+// Admin api
+//
+// Privileged operations live under /admin, mounted separately from the todo routes.
+// Every route takes the auth::Admin guard, and the auth fairing already requires
+// the admin scope for anything under /admin, so a read or write token can not
+// reach them. Token management (tokens.rs) is mounted here as well.
+
+use std::fs;
+use std::path::Path;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Admin;
+use crate::config::AppConfig;
+use crate::error::{ApiError, ErrorCode};
+use crate::housekeeping::{self, Checkpoint};
+use crate::limits::LimitedJson;
+use crate::maintenance::MaintenanceMode;
+use crate::{clock, db};
+
+#[derive(Serialize)]
+pub struct Stats {
+    items: i64,
+    open_items: i64,
+    archived_items: i64,
+    lists: i64,
+    tags: i64,
+    attachments: i64,
+    comments: i64,
+    tokens: i64,
+    // size of the database file in bytes
+    database_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct Backup {
+    path: String,
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct PurgeResults {
+    items_deleted: usize,
+    notifications_deleted: usize,
+}
+
+// GET /admin/maintenance, and the body of PUT /admin/maintenance (retry_after is
+// ignored there, it comes from the config)
+#[derive(Serialize, Deserialize)]
+pub struct MaintenanceStatus {
+    read_only: bool,
+    #[serde(default)]
+    retry_after: u64,
+}
+
+#[get("/stats")]
+pub fn fetch_stats(_admin: Admin) -> Result<Json<Stats>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let results = db_connection.query_row(
+        "select
+             (select count(*) from todo_list),
+             (select count(*) from todo_list where done = 0 and archived = 0),
+             (select count(*) from todo_list where archived = 1),
+             (select count(*) from lists),
+             (select count(*) from tags),
+             (select count(*) from attachments),
+             (select count(*) from comments),
+             (select count(*) from api_tokens)",
+        rusqlite::NO_PARAMS,
+        |row| Ok(Stats {
+            items: row.get(0)?,
+            open_items: row.get(1)?,
+            archived_items: row.get(2)?,
+            lists: row.get(3)?,
+            tags: row.get(4)?,
+            attachments: row.get(5)?,
+            comments: row.get(6)?,
+            tokens: row.get(7)?,
+            database_size: fs::metadata(db::database_file()).map(|metadata| metadata.len()).unwrap_or(0),
+        }));
+
+    match results {
+        Ok(stats) => Ok(Json(stats)),
+        Err(_) => Err("Failed to fetch stats".into()),
+    }
+}
+
+// Writes a consistent copy of the database to backup_dir with VACUUM INTO, which
+// is safe to run while the server keeps handling requests
+#[post("/backup")]
+pub fn create_backup(_admin: Admin, app_config: State<AppConfig>) -> Result<Json<Backup>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    if fs::create_dir_all(&app_config.backup_dir).is_err() {
+        return Err("Failed to create the backup directory".into());
+    }
+
+    let created_at = clock::now();
+    let path = Path::new(&app_config.backup_dir).join(format!("todo-{}.sqlite", created_at));
+    let path = path.to_string_lossy().into_owned();
+
+    match db_connection.execute("vacuum into $1", params![path]) {
+        Ok(_) => Ok(Json(Backup { path, created_at })),
+        Err(_) => Err("Failed to write the backup".into()),
+    }
+}
+
+// Permanently deletes archived items (with their attachments, tags and comments)
+// and notifications that were already read
+#[post("/purge")]
+pub fn purge(_admin: Admin) -> Result<Json<PurgeResults>, ApiError> {
+
+    let mut db_connection = db::connect()?;
+
+    let transaction = match db_connection.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    let items_deleted = match transaction.execute("delete from todo_list where archived = 1", rusqlite::NO_PARAMS) {
+        Ok(rows_deleted) => rows_deleted,
+        Err(_) => return Err("Failed to purge archived ToDo Items".into()),
+    };
+    let notifications_deleted = match transaction.execute("delete from notifications where read = 1", rusqlite::NO_PARAMS) {
+        Ok(rows_deleted) => rows_deleted,
+        Err(_) => return Err("Failed to purge notifications".into()),
+    };
+
+    match transaction.commit() {
+        Ok(_) => Ok(Json(PurgeResults { items_deleted, notifications_deleted })),
+        Err(_) => Err("Failed to purge".into()),
+    }
+}
+
+#[get("/maintenance")]
+pub fn fetch_maintenance(_admin: Admin, mode: State<MaintenanceMode>) -> Json<MaintenanceStatus> {
+    Json(MaintenanceStatus {
+        read_only: mode.is_read_only(),
+        retry_after: mode.retry_after(),
+    })
+}
+
+// Switches read-only mode on or off, e.g. {"read_only": true} before a backup
+#[put("/maintenance", format = "json", data = "<status>")]
+pub fn set_maintenance(_admin: Admin, mode: State<MaintenanceMode>, status: LimitedJson<MaintenanceStatus>) -> Json<MaintenanceStatus> {
+    mode.set_read_only(status.0.read_only);
+
+    Json(MaintenanceStatus {
+        read_only: mode.is_read_only(),
+        retry_after: mode.retry_after(),
+    })
+}
+
+// Copies the -wal file back into the database and truncates it, e.g. before copying
+// the database file by hand
+#[post("/checkpoint")]
+pub fn checkpoint(_admin: Admin) -> Result<Json<Checkpoint>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    Ok(Json(housekeeping::checkpoint(&db_connection)?))
+}
+
+#[catch(403)]
+pub fn forbidden() -> ApiError {
+    ApiError::new(ErrorCode::Forbidden, "Admin access required")
+}
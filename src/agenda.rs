@@ -0,0 +1,207 @@
+// Date based views of the items
+//
+// GET /todo/overdue: the open items whose due date has passed, the most overdue
+// first. Each carries days_overdue, counted in calendar days of the user's time
+// zone (see timezone.rs): 0 for an item due earlier today, 1 for one due
+// yesterday, however late yesterday.
+//
+// GET /views/today and GET /views/week: what a client shows as its agenda, so the
+// date math is done once, here:
+//
+//     {"timezone": "+02:00",
+//      "overdue": [...],                  open items due before today, as above
+//      "days": [{"date": "2024-05-01", "weekday": "wednesday", "items": [...]}, ...],
+//      "pinned": [...]}                   open pinned items not due in those days
+//
+// days is today for /views/today and today plus the six days after it for
+// /views/week, each with the open items due that day (local time) by due time.
+// Pinned items already in overdue or days are not repeated in pinned.
+//
+// GET /calendar/<year>/<month>: every day of the month (local time) with the
+// items due that day and the items completed that day, for a calendar page:
+//
+//     {"year": 2024, "month": 5, "timezone": "+02:00",
+//      "days": [{"date": "2024-05-01", "weekday": "wednesday", "due": [...], "completed": [...]}, ...]}
+//
+// due leaves out archived items, completed does not (archiving does not undo the work).
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, ToSql};
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::timezone::{TimeZone, UserTimeZone};
+use crate::{clock, db, ToDoItem, ITEM_COLUMNS, ITEM_ORDER};
+
+#[derive(Serialize)]
+pub struct OverdueItem {
+    #[serde(flatten)]
+    item: ToDoItem,
+    days_overdue: i64,
+}
+
+#[derive(Serialize)]
+pub struct OverdueItems {
+    items: Vec<OverdueItem>,
+    // the zone days_overdue was counted in, e.g. "+02:00"
+    timezone: String,
+}
+
+#[derive(Serialize)]
+pub struct AgendaDay {
+    date: String,
+    weekday: &'static str,
+    items: Vec<ToDoItem>,
+}
+
+#[derive(Serialize)]
+pub struct Agenda {
+    timezone: String,
+    overdue: Vec<OverdueItem>,
+    days: Vec<AgendaDay>,
+    pinned: Vec<ToDoItem>,
+}
+
+// items matching condition, in the given order
+fn select_items(condition: &str, order: &str, params: &[&dyn ToSql]) -> Result<Vec<ToDoItem>, ApiError> {
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(&format!(
+        "select {} from todo_list where {} order by {}", ITEM_COLUMNS, condition, order))
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let items = statement
+        .query_map(params, ToDoItem::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<ToDoItem>>>());
+
+    match items {
+        Ok(items) => Ok(items),
+        Err(_) => Err("Failed to fetch ToDo Items".into()),
+    }
+}
+
+// open (not done, not archived) items matching condition, in the given order
+fn open_items(condition: &str, order: &str, params: &[&dyn ToSql]) -> Result<Vec<ToDoItem>, ApiError> {
+    select_items(&format!("done = 0 and archived = 0 and {}", condition), order, params)
+}
+
+// open items due before the timestamp, most overdue first
+fn overdue_items(timezone: TimeZone, before: i64, now: i64) -> Result<Vec<OverdueItem>, ApiError> {
+    let today = timezone.day(now);
+    let items = open_items("due_at < $1", "due_at, id", params![before])?;
+
+    Ok(items.into_iter()
+        .map(|item| {
+            // due_at is never null here, the condition leaves those out
+            let due_day = timezone.day(item.due_at.unwrap_or(now));
+            OverdueItem { days_overdue: today - due_day, item }
+        })
+        .collect())
+}
+
+#[get("/todo/overdue")]
+pub fn overdue(timezone: UserTimeZone) -> Result<Json<OverdueItems>, ApiError> {
+    let timezone = timezone.resolve()?;
+    let now = clock::now();
+
+    let items = overdue_items(timezone, now, now)?;
+    Ok(Json(OverdueItems { items, timezone: timezone.name() }))
+}
+
+// The agenda for days local days from today on
+fn agenda(timezone: TimeZone, days: i64) -> Result<Agenda, ApiError> {
+    let now = clock::now();
+    let today = timezone.day(now);
+    let start = timezone.start_of_day(today);
+    let end = timezone.start_of_day(today + days);
+
+    let overdue = overdue_items(timezone, start, now)?;
+
+    let mut due = open_items("due_at >= $1 and due_at < $2", "due_at, id", params![start, end])?.into_iter().peekable();
+    let days = (today..today + days)
+        .map(|day| {
+            let day_end = timezone.start_of_day(day + 1);
+            let mut items = Vec::new();
+            // due is ordered by due_at, so each day takes the items from the front
+            while let Some(item) = due.next_if(|item| item.due_at.map_or(false, |due_at| due_at < day_end)) {
+                items.push(item);
+            }
+            AgendaDay { date: clock::format_date(day), weekday: clock::weekday_name(day), items }
+        })
+        .collect();
+
+    let pinned = open_items("pinned = 1 and (due_at is null or due_at >= $1)", ITEM_ORDER, params![end])?;
+
+    Ok(Agenda { timezone: timezone.name(), overdue, days, pinned })
+}
+
+#[get("/views/today")]
+pub fn today(timezone: UserTimeZone) -> Result<Json<Agenda>, ApiError> {
+    Ok(Json(agenda(timezone.resolve()?, 1)?))
+}
+
+#[get("/views/week")]
+pub fn week(timezone: UserTimeZone) -> Result<Json<Agenda>, ApiError> {
+    Ok(Json(agenda(timezone.resolve()?, 7)?))
+}
+
+#[derive(Serialize)]
+pub struct CalendarDay {
+    date: String,
+    weekday: &'static str,
+    due: Vec<ToDoItem>,
+    completed: Vec<ToDoItem>,
+}
+
+#[derive(Serialize)]
+pub struct CalendarMonth {
+    year: i64,
+    month: u32,
+    timezone: String,
+    days: Vec<CalendarDay>,
+}
+
+#[get("/calendar/<year>/<month>")]
+pub fn calendar(year: i64, month: u32, timezone: UserTimeZone) -> Result<Json<CalendarMonth>, ApiError> {
+    let timezone = timezone.resolve()?;
+    if !(1..=12).contains(&month) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "The month has to be between 1 and 12"));
+    }
+    if !(1..=9999).contains(&year) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "The year has to be between 1 and 9999"));
+    }
+
+    let first_day = clock::days_from_civil(year, month, 1);
+    let length = clock::days_in_month(year, month) as i64;
+    let start = timezone.start_of_day(first_day);
+    let end = timezone.start_of_day(first_day + length);
+
+    let mut days: Vec<CalendarDay> = (first_day..first_day + length)
+        .map(|day| CalendarDay {
+            date: clock::format_date(day),
+            weekday: clock::weekday_name(day),
+            due: Vec::new(),
+            completed: Vec::new(),
+        })
+        .collect();
+
+    // both queries only return timestamps within the month, so every item has a day
+    let due = select_items("archived = 0 and due_at >= $1 and due_at < $2", "due_at, id", params![start, end])?;
+    for item in due {
+        if let Some(day) = item.due_at.and_then(|due_at| days.get_mut((timezone.day(due_at) - first_day) as usize)) {
+            day.due.push(item);
+        }
+    }
+
+    let completed = select_items("completed_at >= $1 and completed_at < $2", "completed_at, id", params![start, end])?;
+    for item in completed {
+        if let Some(day) = item.completed_at.and_then(|completed_at| days.get_mut((timezone.day(completed_at) - first_day) as usize)) {
+            day.completed.push(item);
+        }
+    }
+
+    Ok(Json(CalendarMonth { year, month, timezone: timezone.name(), days }))
+}
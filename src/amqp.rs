@@ -0,0 +1,138 @@
+// AMQP (RabbitMQ) publishing of item changes
+//
+// With amqp_url set, every change event (see events.rs) is published as its json to
+// the amqp_exchange topic exchange (declared durable when it is missing), with the
+// event name as the routing key: item.created, item.updated, item.completed or
+// item.deleted. Consumers bind a queue with e.g. item.* or item.completed.
+// Messages are persistent, and every publish waits for the broker's confirm (the
+// channel is in confirm mode), so a publish only counts as done once the broker has
+// taken the message; a nack or no confirm within CONFIRM_TIMEOUT is an error.
+//
+// When the connection breaks it is opened again on the next event, and the event is
+// tried once more. While the broker can not be reached, the attempts to connect are
+// spaced out (doubling from 1 second to a minute) and events in between fail right
+// away instead of each waiting for a connect timeout.
+//
+// Only plain amqp:// urls are supported, put TLS in front of the broker with a
+// local proxy if needed.
+
+use std::time::{Duration, Instant};
+
+use amiquip::{AmqpProperties, Channel, Confirm, Connection, ExchangeDeclareOptions, ExchangeType, Publish};
+use crossbeam_channel::Receiver;
+
+use crate::events::{ChangeEvent, Sink};
+
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+// an open connection in confirm mode. The channel goes (is dropped) before the
+// connection it is on
+struct Session {
+    channel: Channel,
+    confirms: Receiver<Confirm>,
+    _connection: Connection,
+}
+
+pub struct AmqpSink {
+    url: String,
+    exchange: String,
+    session: Option<Session>,
+    // while the broker is unreachable: when to try again and how long to wait after
+    retry_at: Option<Instant>,
+    retry_delay: Duration,
+}
+
+impl AmqpSink {
+    pub fn new(url: &str, exchange: &str) -> Result<AmqpSink, String> {
+        if !url.starts_with("amqp://") {
+            return Err(format!("Invalid amqp_url {}, expected amqp://[user:password@]host[:port][/vhost]", url));
+        }
+        if exchange.trim().is_empty() {
+            return Err("amqp_exchange can not be empty".into());
+        }
+        Ok(AmqpSink {
+            url: url.to_string(),
+            exchange: exchange.trim().to_string(),
+            session: None,
+            retry_at: None,
+            retry_delay: FIRST_RETRY,
+        })
+    }
+
+    fn open(&self) -> Result<Session, String> {
+        let mut connection = Connection::insecure_open(&self.url).map_err(|e| e.to_string())?;
+        let channel = connection.open_channel(None).map_err(|e| e.to_string())?;
+        channel.exchange_declare(ExchangeType::Topic, self.exchange.as_str(), ExchangeDeclareOptions {
+            durable: true,
+            ..ExchangeDeclareOptions::default()
+        }).map_err(|e| e.to_string())?;
+        let confirms = channel.listen_for_publisher_confirms().map_err(|e| e.to_string())?;
+        channel.enable_publisher_confirms().map_err(|e| e.to_string())?;
+        Ok(Session { channel, confirms, _connection: connection })
+    }
+
+    fn connect(&mut self) -> Result<(), String> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                return Err("the broker is unreachable, waiting before connecting again".into());
+            }
+        }
+        match self.open() {
+            Ok(session) => {
+                self.session = Some(session);
+                self.retry_at = None;
+                self.retry_delay = FIRST_RETRY;
+                Ok(())
+            }
+            Err(e) => {
+                self.retry_at = Some(Instant::now() + self.retry_delay);
+                self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY);
+                Err(e)
+            }
+        }
+    }
+
+    fn send(&mut self, routing_key: &str, body: &[u8]) -> Result<(), String> {
+        self.connect()?;
+        let session = match self.session.as_ref() {
+            Some(session) => session,
+            None => return Err("not connected".into()),
+        };
+
+        // persistent, so the message survives a broker restart in a durable queue
+        let properties = AmqpProperties::default()
+            .with_content_type("application/json".into())
+            .with_delivery_mode(2);
+        let published = session.channel
+            .basic_publish(self.exchange.as_str(), Publish::with_properties(body, routing_key, properties))
+            .map_err(|e| e.to_string())
+            .and_then(|_| match session.confirms.recv_timeout(CONFIRM_TIMEOUT) {
+                Ok(Confirm::Ack(_)) => Ok(()),
+                Ok(Confirm::Nack(_)) => Err("the broker did not take the message (nack)".into()),
+                Err(_) => Err("no confirm from the broker".into()),
+            });
+
+        // anything going wrong leaves the channel in an unknown state, start over
+        if published.is_err() {
+            self.session = None;
+        }
+        published
+    }
+}
+
+impl Sink for AmqpSink {
+    fn name(&self) -> &'static str {
+        "AMQP"
+    }
+
+    fn publish(&mut self, event: &ChangeEvent) -> Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let routing_key = event.kind.as_str();
+        self.send(routing_key, &body).or_else(|_| self.send(routing_key, &body))
+    }
+}
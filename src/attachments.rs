@@ -0,0 +1,238 @@
+// File attachments on todo items, with thumbnails for images
+//
+// Uploads are stored as blobs in the attachments table. When the upload is an image
+// a background thread decodes it and caches a small and a medium thumbnail in the
+// thumbnails table, so list UIs can show previews without downloading the original.
+//
+// A small file can claim to be a huge image, which would take gigabytes to decode, so
+// the dimensions are read from its header first: images of more than
+// max_image_pixels (width x height) get no thumbnails.
+
+use std::io::Cursor;
+use std::sync::OnceLock;
+use std::thread;
+
+use image::io::Reader;
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket_contrib::json::Json;
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+use crate::{db, quotas};
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::Upload;
+
+#[derive(Serialize)]
+pub struct Attachment {
+    id: i64,
+    todo_id: i64,
+    content_type: String,
+    size: usize,
+}
+
+// name used in ?size= and the bounding box (in pixels) of that thumbnail
+const THUMBNAIL_SIZES: [(&str, u32); 2] = [("small", 64), ("medium", 256)];
+
+const DEFAULT_MAX_IMAGE_PIXELS: u64 = 25_000_000;
+
+static MAX_IMAGE_PIXELS: OnceLock<u64> = OnceLock::new();
+
+// Called once at startup by the config fairing
+pub fn set_max_image_pixels(pixels: u64) {
+    let _ = MAX_IMAGE_PIXELS.set(pixels);
+}
+
+fn thumbnail_dimension(size: &str) -> Option<u32> {
+    THUMBNAIL_SIZES.iter()
+        .find(|(name, _)| *name == size)
+        .map(|(_, dimension)| *dimension)
+}
+
+// Scales the image down so it fits in a dimension x dimension box (keeping the aspect
+// ratio) and encodes it as png
+fn render_thumbnail(image: &image::DynamicImage, dimension: u32) -> Option<Vec<u8>> {
+    let mut png = Vec::new();
+    match image.thumbnail(dimension, dimension).write_to(&mut png, image::ImageOutputFormat::Png) {
+        Ok(_) => Some(png),
+        Err(_) => None,
+    }
+}
+
+// Decodes an uploaded image, unless its header says it is larger than
+// max_image_pixels
+fn decode(bytes: &[u8]) -> Result<image::DynamicImage, String> {
+    let (width, height) = Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .into_dimensions()
+        .map_err(|e| e.to_string())?;
+
+    let max_pixels = *MAX_IMAGE_PIXELS.get().unwrap_or(&DEFAULT_MAX_IMAGE_PIXELS);
+    if u64::from(width) * u64::from(height) > max_pixels {
+        return Err(format!("{}x{} pixels is more than max_image_pixels ({})", width, height, max_pixels));
+    }
+
+    image::load_from_memory(bytes).map_err(|e| e.to_string())
+}
+
+fn store_thumbnail(db_connection: &rusqlite::Connection, attachment_id: i64, size: &str, png: &[u8]) -> rusqlite::Result<usize> {
+    db_connection.execute(
+        "insert or replace into thumbnails (attachment_id, size, data) values ($1, $2, $3)",
+        params![attachment_id, size, png])
+}
+
+// Runs on its own thread so the upload request does not wait for image decoding.
+// Failures are only logged - the thumb route can still generate a thumbnail on demand.
+// The thread does not know the request's tenant (see tenants.rs), so it is given the
+// database the attachment went to
+fn generate_thumbnails(attachment_id: i64, bytes: Vec<u8>) {
+    let database = db::database_file();
+    thread::spawn(move || {
+        let image = match decode(&bytes) {
+            Ok(image) => image,
+            Err(e) => {
+                println!("Could not decode attachment {} for thumbnails: {}", attachment_id, e);
+                return;
+            }
+        };
+
+        let db_connection = match db::connect_to(&database) {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+
+        for (size, dimension) in THUMBNAIL_SIZES.iter() {
+            if let Some(png) = render_thumbnail(&image, *dimension) {
+                if store_thumbnail(&db_connection, attachment_id, size, &png).is_err() {
+                    println!("Could not store {} thumbnail for attachment {}", size, attachment_id);
+                }
+            }
+        }
+    });
+}
+
+// The body is the raw file, the Content-Type header tells us what it is.
+// Returns None (404) when the todo item does not exist
+#[post("/todo/<todo_id>/attachments", data = "<upload>")]
+pub fn add_attachment(todo_id: i64, upload: Upload) -> Result<Option<Json<Attachment>>, ApiError> {
+
+    let mut db_connection = db::connect()?;
+
+    // immediate, so no other upload can be stored between the quota check and the insert
+    let transaction = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    let todo_exists = transaction.query_row(
+        "select 1 from todo_list where id = $1", params![todo_id], |_| Ok(()))
+        .optional();
+    match todo_exists {
+        Ok(Some(_)) => (),
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch ToDo Item".into()),
+    }
+
+    quotas::check_attachment(&transaction, upload.bytes.len())?;
+
+    let content_type = upload.content_type.unwrap_or(ContentType::Binary);
+    let results = transaction.execute(
+        "insert into attachments (id, todo_id, content_type, data) values (null, $1, $2, $3)",
+        params![todo_id, content_type.to_string(), upload.bytes]);
+
+    if results.is_err() {
+        return Err("Failed to insert attachment".into());
+    }
+
+    let id = transaction.last_insert_rowid();
+    if transaction.commit().is_err() {
+        return Err("Failed to insert attachment".into());
+    }
+    let size = upload.bytes.len();
+
+    if content_type.top() == "image" {
+        generate_thumbnails(id, upload.bytes);
+    }
+
+    Ok(Some(Json(Attachment {
+        id,
+        todo_id,
+        content_type: content_type.to_string(),
+        size,
+    })))
+}
+
+#[get("/attachments/<id>")]
+pub fn fetch_attachment(id: i64) -> Result<Option<Content<Vec<u8>>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let attachment = db_connection.query_row(
+        "select content_type, data from attachments where id = $1",
+        params![id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))
+        .optional();
+
+    match attachment {
+        Ok(Some((content_type, data))) => {
+            let content_type = ContentType::parse_flexible(&content_type).unwrap_or(ContentType::Binary);
+            Ok(Some(Content(content_type, data)))
+        }
+        Ok(None) => Ok(None),
+        Err(_) => Err("Failed to fetch attachment".into()),
+    }
+}
+
+// size is "small" or "medium" (the default). Thumbnails are normally ready from the
+// background task, but if it has not finished (or failed) one is rendered and cached
+// here instead. Attachments that are not images have no thumbnail (404)
+#[get("/attachments/<id>/thumb?<size>")]
+pub fn fetch_thumbnail(id: i64, size: Option<String>) -> Result<Option<Content<Vec<u8>>>, ApiError> {
+
+    let size = size.unwrap_or_else(|| "medium".into());
+    let dimension = match thumbnail_dimension(&size) {
+        Some(dimension) => dimension,
+        None => return Err(ApiError::new(ErrorCode::BadRequest, format!("Unknown thumbnail size {}, use small or medium", size))),
+    };
+
+    let db_connection = db::connect()?;
+
+    let cached = db_connection.query_row(
+        "select data from thumbnails where attachment_id = $1 and size = $2",
+        params![id, size],
+        |row| row.get::<_, Vec<u8>>(0))
+        .optional();
+
+    match cached {
+        Ok(Some(png)) => return Ok(Some(Content(ContentType::PNG, png))),
+        Ok(None) => (),
+        Err(_) => return Err("Failed to fetch thumbnail".into()),
+    }
+
+    let original = db_connection.query_row(
+        "select content_type, data from attachments where id = $1",
+        params![id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))
+        .optional();
+
+    let bytes = match original {
+        Ok(Some((content_type, data))) if content_type.starts_with("image/") => data,
+        Ok(_) => return Ok(None),
+        Err(_) => return Err("Failed to fetch attachment".into()),
+    };
+
+    let png = match decode(&bytes).ok().and_then(|image| render_thumbnail(&image, dimension)) {
+        Some(png) => png,
+        None => return Ok(None),
+    };
+
+    // caching is best effort, the thumbnail is returned either way
+    let _ = store_thumbnail(&db_connection, id, &size, &png);
+
+    Ok(Some(Content(ContentType::PNG, png)))
+}
@@ -0,0 +1,368 @@
+// Authentication and scope checks for every route
+//
+// A request can authenticate with
+//   - Basic auth, when basic_auth_username/password are configured (the owner of the
+//     server, may do everything) or a directory server (ldap.rs, may do what the
+//     user's groups allow). See basic_auth::AuthProvider
+//   - a bearer token from POST /admin/tokens, which may only do what its scopes allow
+// Without either the request is let through as before, unless Basic auth is
+// configured or require_token = true in Rocket.toml.
+//
+// Like the IP filter this runs in a fairing before routing, so it covers every
+// route without each handler needing a guard. Rejected requests are rewritten to
+// GET /__unauthorized (401, with a WWW-Authenticate header so browsers show their
+// login prompt) or GET /__forbidden (403, token lacks the scope). When the
+// credentials can not be checked at all (the database or the directory server is
+// down) the request gets GET /__auth_unavailable (503) instead.
+//
+// The scopes of an accepted request are kept in the request cache for guards like
+// Admin that need to know who is asking.
+//
+// Rejected credentials count as failed logins of the client address, which is
+// locked out after too many of them (see lockout.rs): GET /__locked_out (429).
+// Credentials that could not be checked do not count.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Outcome, Request, Rocket, State};
+
+use crate::basic_auth::{self, AuthProvider, BasicCredentials};
+use crate::ldap::LdapProvider;
+use crate::tokens::{self, Grant, Scope};
+use crate::error::{ApiError, ErrorCode};
+use crate::lockout::{self, LockoutPolicy};
+use crate::{db, ip_filter, tenants};
+
+const UNAUTHORIZED_PATH: &str = "/__unauthorized";
+const FORBIDDEN_PATH: &str = "/__forbidden";
+const UNAVAILABLE_PATH: &str = "/__auth_unavailable";
+
+// paths that never need credentials: health checks, internal rejection routes,
+// public share links (which are meant for people without an account) and the
+// files of the bundled frontend (spa.rs)
+const PUBLIC_PREFIXES: [&str; 4] = ["/health", "/__", "/shared/", "/app"];
+
+// paths that need the admin scope whatever the method
+const ADMIN_PREFIXES: [&str; 1] = ["/admin"];
+
+pub struct AuthSettings {
+    // what Basic auth credentials are checked with, in order. Empty = no Basic auth
+    providers: Vec<Box<dyn AuthProvider>>,
+    require_token: bool,
+    // admin_allow_loopback, see Admin
+    allow_loopback_admin: bool,
+    lockout: LockoutPolicy,
+}
+
+pub fn is_public(path: &str) -> bool {
+    PUBLIC_PREFIXES.iter().any(|prefix| path.starts_with(prefix))
+}
+
+// The one place that decides which scope a request needs
+pub fn required_scope(method: Method, path: &str) -> Scope {
+    if ADMIN_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+        return Scope::Admin;
+    }
+
+    match method {
+        Method::Get | Method::Head | Method::Options => Scope::Read,
+        _ => Scope::Write,
+    }
+}
+
+// What the auth fairing found out about the request, None when it came without
+// credentials (only possible when nothing is configured)
+struct Authenticated(Option<Grant>);
+
+enum Decision {
+    Allow(Option<Grant>),
+    Unauthorized,
+    Forbidden,
+    LockedOut,
+    // the credentials could not be checked
+    Unavailable,
+}
+
+// The grant of the first provider that accepts the credentials. Err when none does
+// but one of them could not check them, they may be right
+fn basic_grant(header: &str, settings: &AuthSettings) -> Result<Option<Grant>, String> {
+    let (username, password) = match basic_auth::parse_header(header) {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+    let mut failed = None;
+    for provider in &settings.providers {
+        match provider.authenticate(&username, &password) {
+            Ok(Some(grant)) => return Ok(Some(grant)),
+            Ok(None) => (),
+            Err(e) => failed = Some(format!("{} could not check the credentials of {}: {}", provider.name(), username, e)),
+        }
+    }
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+// The grant of a bearer token. In multi-tenant mode a tenant's tokens are in its own
+// database and only reach its items: a token from there never gets the admin scope,
+// and the admin api (with the tenant registry) only takes tokens of the main database
+// Err when the database could not be asked
+fn token_grant(token: &str, scope: Scope) -> Result<Option<Grant>, String> {
+    let admin = scope == Scope::Admin;
+    let db_connection = if admin { db::connect_main() } else { db::connect() }?;
+    let mut grant = match db::retry(|| tokens::authenticate(&db_connection, token)) {
+        Ok(Some(grant)) => grant,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(format!("Failed to look up an api token: {}", e)),
+    };
+
+    if !admin && tenants::current_database().is_some() && grant.scopes.contains(&Scope::Admin) {
+        grant.scopes = vec![Scope::Read, Scope::Write];
+    }
+    Ok(Some(grant))
+}
+
+fn decide(request: &Request, settings: &AuthSettings) -> Decision {
+    let path = request.uri().path();
+    if is_public(path) {
+        return Decision::Allow(None);
+    }
+
+    let scope = required_scope(request.method(), path);
+    let header = request.headers().get_one("Authorization");
+
+    let grant = match header {
+        Some(header) if header.starts_with("Bearer ") => {
+            let token = header.trim_start_matches("Bearer ").trim();
+            match token_grant(token, scope) {
+                Ok(Some(grant)) => grant,
+                Ok(None) => return Decision::Unauthorized,
+                Err(e) => {
+                    println!("{}", e);
+                    return Decision::Unavailable;
+                }
+            }
+        }
+        Some(header) if header.starts_with("Basic ") => match basic_grant(header, settings) {
+            Ok(Some(grant)) => grant,
+            Ok(None) => return Decision::Unauthorized,
+            Err(e) => {
+                println!("{}", e);
+                return Decision::Unavailable;
+            }
+        },
+        _ if !settings.providers.is_empty() || settings.require_token => return Decision::Unauthorized,
+        // nothing configured, the api stays open
+        _ => return Decision::Allow(None),
+    };
+
+    if grant.allows(scope) {
+        Decision::Allow(Some(grant))
+    } else {
+        Decision::Forbidden
+    }
+}
+
+// decide(), with locked out accounts turned away and failed and successful logins
+// counted for the account: the address, and the username with Basic auth
+fn decide_with_lockout(request: &Request, settings: &AuthSettings) -> Decision {
+    let address = match ip_filter::client_ip(request) {
+        Some(address) if !is_public(request.uri().path()) => address,
+        _ => return decide(request, settings),
+    };
+    let authorization = request.headers().get_one("Authorization");
+    let username = authorization.and_then(basic_auth::parse_header).map(|(username, _)| username).unwrap_or_default();
+    let account = lockout::Account { address, username: &username };
+    if lockout::check(request, &settings.lockout, &account).is_some() {
+        return Decision::LockedOut;
+    }
+
+    let decision = decide(request, settings);
+    match decision {
+        // a request without credentials is not a failed login, browsers try that first
+        Decision::Unauthorized if authorization.is_some() => lockout::record_failure(&settings.lockout, &account),
+        Decision::Allow(Some(_)) => lockout::record_success(&settings.lockout, &account),
+        _ => (),
+    }
+    decision
+}
+
+// Returns what the request was authenticated with, None without credentials
+pub fn current_grant(request: &Request) -> Option<Grant> {
+    request.local_cache(|| Authenticated(None)).0.clone()
+}
+
+// Request guard for the /admin routes. Needs Basic auth or a token with the admin
+// scope. With admin_allow_loopback = true requests without credentials from the
+// machine itself are let in too, for setups without any authentication configured.
+// It is off by default: behind a reverse proxy on the same machine every client
+// looks like it comes from there (unless the proxy is in trusted_proxies)
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        match current_grant(request) {
+            Some(grant) if grant.allows(Scope::Admin) => Outcome::Success(Admin),
+            Some(_) => Outcome::Failure((Status::Forbidden, ())),
+            None => {
+                let allow_loopback = match request.guard::<State<AuthSettings>>() {
+                    Outcome::Success(settings) => settings.allow_loopback_admin,
+                    _ => false,
+                };
+                match ip_filter::client_ip(request) {
+                    Some(ip) if allow_loopback && ip.is_loopback() => Outcome::Success(Admin),
+                    _ => Outcome::Failure((Status::Forbidden, ())),
+                }
+            }
+        }
+    }
+}
+
+pub struct Auth;
+
+impl Fairing for Auth {
+    fn info(&self) -> Info {
+        Info {
+            name: "Authentication",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        match BasicCredentials::from_rocket(&rocket) {
+            Ok(Some(basic)) => providers.push(Box::new(basic)),
+            Ok(None) => (),
+            Err(e) => {
+                println!("{}", e);
+                return Err(rocket);
+            }
+        }
+        match LdapProvider::from_rocket(&rocket) {
+            Ok(Some(ldap)) => providers.push(Box::new(ldap)),
+            Ok(None) => (),
+            Err(e) => {
+                println!("{}", e);
+                return Err(rocket);
+            }
+        }
+        let require_token = rocket.config().get_bool("require_token").unwrap_or(false);
+        let allow_loopback_admin = rocket.config().get_bool("admin_allow_loopback").unwrap_or(false);
+        let lockout = match LockoutPolicy::from_rocket(&rocket) {
+            Ok(lockout) => lockout,
+            Err(e) => {
+                println!("{}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket
+            .manage(AuthSettings { providers, require_token, allow_loopback_admin, lockout })
+            .mount("/", routes![unauthorized, forbidden, auth_unavailable, lockout::locked_out]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let decision = match request.guard::<State<AuthSettings>>() {
+            rocket::Outcome::Success(settings) => decide_with_lockout(request, &settings),
+            _ => Decision::Allow(None),
+        };
+
+        let rejected_path = match decision {
+            Decision::Allow(grant) => {
+                request.local_cache(|| Authenticated(grant));
+                return;
+            }
+            Decision::Unauthorized => UNAUTHORIZED_PATH,
+            Decision::Forbidden => FORBIDDEN_PATH,
+            Decision::LockedOut => lockout::LOCKED_OUT_PATH,
+            Decision::Unavailable => UNAVAILABLE_PATH,
+        };
+
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(rejected_path).expect("valid rejection path"));
+    }
+}
+
+// carries the WWW-Authenticate challenge: Basic when Basic auth is configured (so
+// browsers prompt for it), Bearer otherwise
+pub struct Unauthorized(&'static str);
+
+impl<'r> Responder<'r> for Unauthorized {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let error = ApiError::new(ErrorCode::Unauthorized, "Authentication required");
+
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("WWW-Authenticate", self.0)
+            .ok()
+    }
+}
+
+#[get("/__unauthorized")]
+pub fn unauthorized(settings: State<AuthSettings>) -> Unauthorized {
+    if settings.providers.is_empty() {
+        Unauthorized("Bearer realm=\"todo\"")
+    } else {
+        Unauthorized("Basic realm=\"todo\", charset=\"UTF-8\"")
+    }
+}
+
+#[get("/__forbidden")]
+pub fn forbidden() -> ApiError {
+    ApiError::new(ErrorCode::Forbidden, "This token does not have the scope for this request")
+}
+
+#[get("/__auth_unavailable")]
+pub fn auth_unavailable() -> ApiError {
+    ApiError::new(ErrorCode::DatabaseUnavailable, "The credentials could not be checked, please try again later")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_need_the_read_scope() {
+        assert_eq!(required_scope(Method::Get, "/todo"), Scope::Read);
+        assert_eq!(required_scope(Method::Head, "/todo/3"), Scope::Read);
+        assert_eq!(required_scope(Method::Options, "/todo"), Scope::Read);
+    }
+
+    #[test]
+    fn everything_else_needs_the_write_scope() {
+        assert_eq!(required_scope(Method::Post, "/todo"), Scope::Write);
+        assert_eq!(required_scope(Method::Put, "/todo/3/done"), Scope::Write);
+        assert_eq!(required_scope(Method::Patch, "/todo/3"), Scope::Write);
+        assert_eq!(required_scope(Method::Delete, "/todo/3"), Scope::Write);
+    }
+
+    #[test]
+    fn the_admin_api_needs_the_admin_scope_whatever_the_method() {
+        assert_eq!(required_scope(Method::Get, "/admin/tokens"), Scope::Admin);
+        assert_eq!(required_scope(Method::Post, "/admin/query"), Scope::Admin);
+        assert_eq!(required_scope(Method::Delete, "/admin/tokens/1"), Scope::Admin);
+        assert_eq!(required_scope(Method::Get, "/admin"), Scope::Admin);
+    }
+
+    #[test]
+    fn admin_only_counts_at_the_start_of_the_path() {
+        assert_eq!(required_scope(Method::Get, "/todo/admin"), Scope::Read);
+        assert_eq!(required_scope(Method::Post, "/lists/admin"), Scope::Write);
+    }
+
+    #[test]
+    fn public_paths_need_no_credentials() {
+        assert!(is_public("/health"));
+        assert!(is_public("/__unauthorized"));
+        assert!(is_public("/shared/abc"));
+        assert!(is_public("/app/index.html"));
+        assert!(!is_public("/todo"));
+        assert!(!is_public("/admin/tokens"));
+        assert!(!is_public("/shared"));
+    }
+}
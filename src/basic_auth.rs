@@ -0,0 +1,129 @@
+// Optional HTTP Basic authentication
+//
+// For self hosters who just want to keep strangers out without running a user
+// system: set a username and password in Rocket.toml (or ROCKET_BASIC_AUTH_USERNAME /
+// ROCKET_BASIC_AUTH_PASSWORD) and every route except the public ones (see auth.rs) needs
+// them. Without both settings nothing changes.
+//
+// The check itself happens in the auth fairing (auth.rs), together with the
+// bearer tokens. Basic auth credentials go to every configured AuthProvider in turn:
+// the username and password from the config (owner, may do everything) and / or a
+// directory server (see ldap.rs).
+
+use rocket::Rocket;
+
+use crate::tokens::Grant;
+
+// Something that can check a username and password
+pub trait AuthProvider: Send + Sync {
+    // for the log
+    fn name(&self) -> &'static str;
+    // What the user may do, None for wrong credentials. Err when the provider
+    // could not check them, e.g. a directory server that is down
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<Grant>, String>;
+}
+
+pub struct BasicCredentials {
+    username: String,
+    password: String,
+}
+
+// Compares in time that only depends on the length, so the password can not be
+// guessed one character at a time by measuring response times
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+impl BasicCredentials {
+    pub fn from_rocket(rocket: &Rocket) -> Result<Option<BasicCredentials>, String> {
+        let config = rocket.config();
+        match (config.get_string("basic_auth_username").ok(), config.get_string("basic_auth_password").ok()) {
+            (Some(username), Some(password)) => Ok(Some(BasicCredentials { username, password })),
+            (None, None) => Ok(None),
+            // half a configuration would otherwise leave the server open without any warning
+            _ => Err("basic_auth_username and basic_auth_password have to be set together".into()),
+        }
+    }
+}
+
+// The username and password of an "Authorization: Basic base64(username:password)"
+// header value
+pub fn parse_header(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?.trim();
+    let decoded = base64::decode(encoded).ok().and_then(|bytes| String::from_utf8(bytes).ok())?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+impl AuthProvider for BasicCredentials {
+    fn name(&self) -> &'static str {
+        "Basic auth"
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<Grant>, String> {
+        // both are always compared so a wrong username takes as long as a wrong password
+        let username_ok = constant_time_eq(username.as_bytes(), self.username.as_bytes());
+        let password_ok = constant_time_eq(password.as_bytes(), self.password.as_bytes());
+        if username_ok & password_ok {
+            Ok(Some(Grant::full()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(credentials: &str) -> String {
+        format!("Basic {}", base64::encode(credentials))
+    }
+
+    fn pair(username: &str, password: &str) -> Option<(String, String)> {
+        Some((username.to_string(), password.to_string()))
+    }
+
+    #[test]
+    fn parses_username_and_password() {
+        assert_eq!(parse_header(&header("alice:secret")), pair("alice", "secret"));
+        assert_eq!(parse_header(&format!("{}  ", header("alice:secret"))), pair("alice", "secret"));
+    }
+
+    #[test]
+    fn only_splits_at_the_first_colon() {
+        assert_eq!(parse_header(&header("alice:se:cr:et")), pair("alice", "se:cr:et"));
+    }
+
+    #[test]
+    fn allows_empty_parts() {
+        assert_eq!(parse_header(&header("alice:")), pair("alice", ""));
+        assert_eq!(parse_header(&header(":secret")), pair("", "secret"));
+    }
+
+    #[test]
+    fn keeps_utf8() {
+        assert_eq!(parse_header(&header("jürgen:pässwörd")), pair("jürgen", "pässwörd"));
+    }
+
+    #[test]
+    fn rejects_other_headers() {
+        assert_eq!(parse_header(&format!("Bearer {}", base64::encode("alice:secret"))), None);
+        assert_eq!(parse_header(&format!("basic {}", base64::encode("alice:secret"))), None);
+        assert_eq!(parse_header(&header("no colon")), None);
+        assert_eq!(parse_header("Basic not base64!"), None);
+        assert_eq!(parse_header(&format!("Basic {}", base64::encode([0xff, b':', 0xfe]))), None);
+        assert_eq!(parse_header(""), None);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_bytes() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+    }
+}
@@ -0,0 +1,238 @@
+// Circuit breaker around the database
+//
+// sqlite reports every error it hits to a global log callback. Errors that point at
+// a broken database rather than a bad request (disk i/o errors, a full disk,
+// corruption, a file that can not be opened) are counted here. When
+// breaker_failure_threshold of them happen within breaker_window seconds the
+// circuit opens: for breaker_cooldown seconds every request except /health and the
+// admin api gets 503 with Retry-After right away, instead of each one tying up a
+// worker thread on a disk that is not answering.
+//
+// After the cool-down the circuit is half-open: requests go through again, one more
+// failure opens it straight away, and a full window without failures closes it.
+//
+// There is a circuit per database file, so in multi-tenant mode (see tenants.rs) a
+// tenant whose file is failing does not take the others down with it. sqlite does not
+// say which connection an error came from; it is counted for the database the thread
+// is working on (db::database_file), which is why the fairing runs after the tenant
+// is known.
+
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Request, Rocket};
+use rusqlite::ffi;
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::{db, reporting};
+
+const UNAVAILABLE_PATH: &str = "/__unavailable";
+
+// requests that still go through while the circuit is open
+const ALWAYS_ALLOWED_PREFIXES: [&str; 3] = ["/health", "/admin", "/__"];
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Clone, Copy)]
+struct Settings {
+    failure_threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+}
+
+#[derive(Default)]
+struct Breaker {
+    // times of the failures within the window
+    failures: Vec<Instant>,
+    opened_at: Option<Instant>,
+    // when the last cool-down ended, while half-open
+    half_open_since: Option<Instant>,
+}
+
+struct Breakers {
+    settings: Settings,
+    // by database file
+    circuits: Option<HashMap<String, Breaker>>,
+}
+
+static BREAKERS: Mutex<Breakers> = Mutex::new(Breakers {
+    settings: Settings {
+        failure_threshold: 5,
+        window: Duration::from_secs(30),
+        cooldown: Duration::from_secs(30),
+    },
+    circuits: None,
+});
+
+// What /health reports
+#[derive(Serialize)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub recent_failures: usize,
+    // seconds until the circuit half-opens, while open
+    pub retry_after: Option<u64>,
+}
+
+impl Breaker {
+    // moves on from open / half-open when their time is up
+    fn state(&mut self, settings: Settings, now: Instant) -> CircuitState {
+        if let Some(opened_at) = self.opened_at {
+            if now.duration_since(opened_at) < settings.cooldown {
+                return CircuitState::Open;
+            }
+            self.opened_at = None;
+            self.failures.clear();
+            self.half_open_since = Some(now);
+        }
+
+        if let Some(since) = self.half_open_since {
+            if now.duration_since(since) < settings.window {
+                return CircuitState::HalfOpen;
+            }
+            self.half_open_since = None;
+        }
+        CircuitState::Closed
+    }
+
+    fn record_failure(&mut self, settings: Settings, now: Instant) {
+        self.failures.retain(|failure| now.duration_since(*failure) < settings.window);
+        self.failures.push(now);
+
+        let state = self.state(settings, now);
+        let trip = match state {
+            CircuitState::Closed => self.failures.len() >= settings.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+
+        if trip {
+            self.opened_at = Some(now);
+            self.half_open_since = None;
+        }
+    }
+
+    fn status(&mut self, settings: Settings, now: Instant) -> CircuitStatus {
+        let state = self.state(settings, now);
+        let recent_failures = self.failures.iter().filter(|failure| now.duration_since(**failure) < settings.window).count();
+        let retry_after = match (state, self.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => {
+                Some((settings.cooldown - now.duration_since(opened_at)).as_secs().max(1))
+            }
+            _ => None,
+        };
+
+        CircuitStatus { state, recent_failures, retry_after }
+    }
+}
+
+fn breakers() -> std::sync::MutexGuard<'static, Breakers> {
+    // a panic while the lock was held does not leave the breakers in a bad state
+    BREAKERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Runs f on the circuit of the database this thread is working on
+fn with_breaker<T>(f: impl FnOnce(&mut Breaker, Settings) -> T) -> T {
+    let database = db::database_file();
+    let mut breakers = breakers();
+    let settings = breakers.settings;
+    let breaker = breakers.circuits.get_or_insert_with(HashMap::new).entry(database).or_default();
+    f(breaker, settings)
+}
+
+// The circuit of the database this thread is working on, the request's
+pub fn status() -> CircuitStatus {
+    with_breaker(|breaker, settings| breaker.status(settings, Instant::now()))
+}
+
+// the sqlite error codes that mean the database itself is in trouble
+fn is_storage_failure(code: c_int) -> bool {
+    matches!(code & 0xff,
+        ffi::SQLITE_IOERR | ffi::SQLITE_CORRUPT | ffi::SQLITE_FULL | ffi::SQLITE_CANTOPEN | ffi::SQLITE_NOTADB)
+}
+
+fn sqlite_log(code: c_int, message: &str) {
+    if is_storage_failure(code) {
+        with_breaker(|breaker, settings| breaker.record_failure(settings, Instant::now()));
+        reporting::capture("database", format!("sqlite error {}: {}", code, message));
+    }
+}
+
+// Registers the sqlite log callback. sqlite only accepts this before the first
+// connection is opened, so it is the first thing main does
+pub fn install() {
+    // safe here: nothing else is using sqlite yet
+    if unsafe { rusqlite::trace::config_log(Some(sqlite_log)) }.is_err() {
+        println!("Could not register the sqlite error log, the database circuit breaker is off");
+    }
+}
+
+pub struct CircuitBreaker;
+
+impl Fairing for CircuitBreaker {
+    fn info(&self) -> Info {
+        Info {
+            name: "Database circuit breaker",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let config = rocket.config();
+        {
+            let settings = &mut breakers().settings;
+            if let Ok(threshold) = config.get_int("breaker_failure_threshold") {
+                settings.failure_threshold = threshold.max(1) as usize;
+            }
+            if let Ok(window) = config.get_int("breaker_window") {
+                settings.window = Duration::from_secs(window.max(1) as u64);
+            }
+            if let Ok(cooldown) = config.get_int("breaker_cooldown") {
+                settings.cooldown = Duration::from_secs(cooldown.max(1) as u64);
+            }
+        }
+
+        Ok(rocket.mount("/", routes![unavailable]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = request.uri().path();
+        if ALWAYS_ALLOWED_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+            return;
+        }
+
+        if status().state == CircuitState::Open {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(UNAVAILABLE_PATH).expect("valid unavailable path"));
+        }
+    }
+}
+
+pub struct Unavailable(u64);
+
+impl<'r> Responder<'r> for Unavailable {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let error = ApiError::new(ErrorCode::DatabaseUnavailable, "The database is unavailable, please try again later");
+
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", self.0.to_string())
+            .ok()
+    }
+}
+
+#[get("/__unavailable")]
+pub fn unavailable() -> Unavailable {
+    Unavailable(status().retry_after.unwrap_or(1))
+}
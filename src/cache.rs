@@ -0,0 +1,85 @@
+// In-memory cache of GET /todo
+//
+// Clients that poll the main list get the json from memory instead of the database.
+// One entry per combination of filters and sort order (see ItemQuery).
+//
+// Any request that can change data (anything but GET, HEAD and OPTIONS) empties the
+// cache once it has been handled, so the next GET reads the database again. Every
+// invalidation also bumps a generation number: a GET only stores its result if no
+// invalidation happened while it was reading, otherwise a write that finished in
+// the meantime could be hidden behind an old list.
+//
+// In multi-tenant mode (see tenants.rs) entries are kept per tenant database, and a
+// write by any tenant empties the cache of all of them.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Request, Response, Rocket, State};
+
+use crate::tenants;
+
+// the filters and order of GET /todo
+pub(crate) type ListKey = crate::ItemQuery;
+
+pub struct ItemCache {
+    generation: AtomicU64,
+    // by the tenant database (None for the main one) and the query
+    entries: Mutex<HashMap<(Option<String>, ListKey), String>>,
+}
+
+impl ItemCache {
+    // Returns the cached json, or the current generation to pass to store()
+    pub(crate) fn lookup(&self, key: &ListKey) -> Result<String, u64> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match entries.get(&(tenants::current_database(), key.clone())) {
+            Some(json) => Ok(json.clone()),
+            None => Err(generation),
+        }
+    }
+
+    pub(crate) fn store(&self, key: ListKey, generation: u64, json: String) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // checked while holding the lock, invalidate() bumps it under the same lock
+        if self.generation.load(Ordering::SeqCst) == generation {
+            entries.insert((tenants::current_database(), key), json);
+        }
+    }
+
+    pub fn invalidate(&self) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.clear();
+    }
+}
+
+pub struct CacheInvalidation;
+
+impl Fairing for CacheInvalidation {
+    fn info(&self) -> Info {
+        Info {
+            name: "Item cache invalidation",
+            kind: Kind::Attach | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.manage(ItemCache {
+            generation: AtomicU64::new(0),
+            entries: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn on_response(&self, request: &Request, _: &mut Response) {
+        if matches!(request.method(), Method::Get | Method::Head | Method::Options) {
+            return;
+        }
+        if let rocket::Outcome::Success(cache) = request.guard::<State<ItemCache>>() {
+            cache.invalidate();
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// Command line
+//
+//     todo [serve] [--seed <file.json>]   runs the http server (the default)
+//     todo migrate                        creates / updates the database schema
+//     todo export [--output <file>]       writes all lists and items as seed json
+//     todo seed <file.json>               loads seed json into an empty database
+//
+// Every command reads the same Rocket.toml / ROCKET_* settings as the server, so
+// e.g. the database key is picked up the same way.
+
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Parser)]
+#[clap(name = "todo", about = "ToDo list REST api", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    // the serve options also work without the subcommand
+    #[clap(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Seed data (json) to load when the database is empty
+    #[clap(long, value_name = "FILE")]
+    pub seed: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the http server
+    Serve(ServeArgs),
+    /// Create the database schema or bring it up to date, then exit
+    Migrate,
+    /// Write all lists, items and tags in the seed format
+    Export {
+        /// File to write to instead of stdout
+        #[clap(long, short, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// Load seed data (json) into an empty database
+    Seed {
+        #[clap(value_name = "FILE")]
+        file: String,
+    },
+}
+
+impl Cli {
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Serve(self.serve))
+    }
+}
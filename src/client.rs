@@ -0,0 +1,183 @@
+// Typed client for the api (the "client" feature)
+//
+// For Rust programs talking to a running server, with the same ToDoItem and error
+// codes the server uses instead of copies of them:
+//
+//     let client = TodoClient::new("http://localhost:8000").with_token("...");
+//     client.create("Buy milk")?;
+//     for item in client.list()? {
+//         println!("{} {}", item.id, item.item);
+//     }
+//
+// Requests are blocking (reqwest's blocking api). Every request asks for responses
+// without the {data, meta, errors} envelope (see envelope.rs), whatever the server's
+// default. An error response becomes ClientError::Api with the server's code and
+// message.
+//
+// The client is compiled into the same crate as the server, so it also builds Rocket;
+// build with `--features client`.
+
+use std::fmt;
+
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::ErrorCode;
+use crate::{StatusMessage, ToDoItem, ToDoList};
+
+// the error body of the api, see error.rs
+#[derive(Deserialize, Debug)]
+pub struct ApiFailure {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Value>,
+    pub request_id: String,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    // the server could not be reached, or the response could not be read
+    Http(reqwest::Error),
+    // the server answered with an error
+    Api { status: u16, failure: ApiFailure },
+    // an error status without the api's error body, e.g. from a proxy in between
+    Status(u16),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "{}", e),
+            ClientError::Api { status, failure } => write!(f, "{} ({:?}, status {})", failure.message, failure.code, status),
+            ClientError::Status(status) => write!(f, "the server answered with status {}", status),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> ClientError {
+        ClientError::Http(e)
+    }
+}
+
+enum Credentials {
+    Token(String),
+    Basic(String, String),
+}
+
+pub struct TodoClient {
+    base_url: String,
+    http: Client,
+    credentials: Option<Credentials>,
+    // sent as X-Tenant, see tenants.rs
+    tenant: Option<String>,
+}
+
+impl TodoClient {
+    // base_url is where the server runs, e.g. http://localhost:8000
+    pub fn new(base_url: &str) -> TodoClient {
+        TodoClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: Client::new(),
+            credentials: None,
+            tenant: None,
+        }
+    }
+
+    // an api token from POST /admin/tokens
+    pub fn with_token(mut self, token: &str) -> TodoClient {
+        self.credentials = Some(Credentials::Token(token.to_string()));
+        self
+    }
+
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> TodoClient {
+        self.credentials = Some(Credentials::Basic(username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: &str) -> TodoClient {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut request = self.http.request(method, format!("{}{}", self.base_url, path))
+            .query(&[("envelope", "false")]);
+        request = match &self.credentials {
+            Some(Credentials::Token(token)) => request.bearer_auth(token),
+            Some(Credentials::Basic(username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        };
+        if let Some(tenant) = &self.tenant {
+            request = request.header("X-Tenant", tenant.as_str());
+        }
+        request
+    }
+
+    // the response, or the error it carries
+    fn send(request: RequestBuilder) -> Result<Response, ClientError> {
+        let response = request.send()?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        match response.json::<ApiFailure>() {
+            Ok(failure) => Err(ClientError::Api { status: status.as_u16(), failure }),
+            Err(_) => Err(ClientError::Status(status.as_u16())),
+        }
+    }
+
+    fn json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, ClientError> {
+        Ok(TodoClient::send(request)?.json()?)
+    }
+
+    // GET /todo, all items in the server's order
+    pub fn list(&self) -> Result<Vec<ToDoItem>, ClientError> {
+        let list: ToDoList = TodoClient::json(self.request(Method::GET, "/todo"))?;
+        Ok(list.items)
+    }
+
+    // POST /todo. The server does not send the new item back, use put_by_uuid to get it
+    pub fn create(&self, text: &str) -> Result<StatusMessage, ClientError> {
+        TodoClient::json(self.request(Method::POST, "/todo").json(text))
+    }
+
+    // PUT /todo/uuid/<uuid>, creates the item or changes its text
+    pub fn put_by_uuid(&self, uuid: &str, text: &str) -> Result<ToDoItem, ClientError> {
+        TodoClient::json(self.request(Method::PUT, &format!("/todo/uuid/{}", uuid)).json(text))
+    }
+
+    // GET /todo/uuid/<uuid>, None when there is no such item
+    pub fn get_by_uuid(&self, uuid: &str) -> Result<Option<ToDoItem>, ClientError> {
+        match TodoClient::json(self.request(Method::GET, &format!("/todo/uuid/{}", uuid))) {
+            Ok(item) => Ok(Some(item)),
+            Err(ClientError::Api { failure: ApiFailure { code: ErrorCode::NotFound, .. }, .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // PUT /todo/<id>/done
+    pub fn complete(&self, id: i64) -> Result<StatusMessage, ClientError> {
+        TodoClient::json(self.request(Method::PUT, &format!("/todo/{}/done", id)))
+    }
+
+    // DELETE /todo/<id>/done
+    pub fn reopen(&self, id: i64) -> Result<StatusMessage, ClientError> {
+        TodoClient::json(self.request(Method::DELETE, &format!("/todo/{}/done", id)))
+    }
+
+    // DELETE /todo/<id>
+    pub fn delete(&self, id: i64) -> Result<StatusMessage, ClientError> {
+        TodoClient::json(self.request(Method::DELETE, &format!("/todo/{}", id)))
+    }
+
+    // DELETE /todo/uuid/<uuid>
+    pub fn delete_by_uuid(&self, uuid: &str) -> Result<StatusMessage, ClientError> {
+        TodoClient::json(self.request(Method::DELETE, &format!("/todo/uuid/{}", uuid)))
+    }
+}
@@ -0,0 +1,163 @@
+// Timestamps are stored as unix time in seconds (sqlite integers)
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs() as i64).unwrap_or(0)
+}
+
+// A unix timestamp broken down into its UTC calendar parts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UtcTime {
+    // 1 - 12
+    pub month: u32,
+    // 1 - 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    // 0 = sunday
+    pub weekday: u32,
+}
+
+// Days since 1970-01-01 to (year, month, day), the "civil from days" algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = (if month_index < 10 { month_index + 3 } else { month_index - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn utc(timestamp: i64) -> UtcTime {
+    let days = timestamp.div_euclid(86_400);
+    let seconds_of_day = timestamp.rem_euclid(86_400);
+    let (_, month, day) = civil_from_days(days);
+
+    UtcTime {
+        month,
+        day,
+        hour: (seconds_of_day / 3600) as u32,
+        minute: (seconds_of_day % 3600 / 60) as u32,
+        // 1970-01-01 was a thursday
+        weekday: (days + 4).rem_euclid(7) as u32,
+    }
+}
+
+// (year, month, day) to days since 1970-01-01, the inverse of civil_from_days
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// fixed width decimal field, None when it is not all digits
+fn digits(text: &str, range: std::ops::Range<usize>) -> Option<i64> {
+    let field = text.get(range)?;
+    if field.is_empty() || !field.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+// An RFC 3339 date-time, e.g. 2024-05-01T12:00:00Z or 2024-05-01T14:00:00.5+02:00,
+// as a unix timestamp. Fractions of a second are dropped
+pub fn parse_rfc3339(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if text.len() < 20 || !text.is_char_boundary(19) {
+        return None;
+    }
+    let separators = text.as_bytes();
+    if separators[4] != b'-' || separators[7] != b'-' || !matches!(separators[10], b'T' | b't' | b' ')
+        || separators[13] != b':' || separators[16] != b':' {
+        return None;
+    }
+
+    let year = digits(text, 0..4)?;
+    let month = digits(text, 5..7)? as u32;
+    let day = digits(text, 8..10)? as u32;
+    let hour = digits(text, 11..13)?;
+    let minute = digits(text, 14..16)?;
+    // 60 is a leap second, counted as the next one
+    let second = digits(text, 17..19)?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month)
+        || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &text[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let length = fraction.bytes().take_while(|byte| byte.is_ascii_digit()).count();
+        if length == 0 {
+            return None;
+        }
+        rest = &fraction[length..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return None;
+            }
+            let hours = digits(rest, 1..3)?;
+            let minutes = digits(rest, 4..6)?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    let days = days_from_civil(year, month, day);
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+// A day counted from 1970-01-01 as yyyy-mm-dd
+pub fn format_date(day: i64) -> String {
+    let (year, month, day) = civil_from_days(day);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+const WEEKDAYS: [&str; 7] = ["sunday", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday"];
+
+// the name of the weekday of a day counted from 1970-01-01
+pub fn weekday_name(day: i64) -> &'static str {
+    // 1970-01-01 was a thursday
+    WEEKDAYS[(day + 4).rem_euclid(7) as usize]
+}
+
+// A yyyy-mm-dd date as days since 1970-01-01
+pub fn parse_date(text: &str) -> Option<i64> {
+    if text.len() != 10 || text.as_bytes()[4] != b'-' || text.as_bytes()[7] != b'-' {
+        return None;
+    }
+    let year = digits(text, 0..4)?;
+    let month = digits(text, 5..7)? as u32;
+    let day = digits(text, 8..10)? as u32;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
@@ -0,0 +1,36 @@
+// Color labels for items and lists
+//
+// A color is either one of the names in the palette below or a hex color
+// (#rgb or #rrggbb). Colors are stored normalized (lower case, hex expanded to 6
+// digits) so filtering by color is a plain equality check.
+
+pub const PALETTE: [&str; 8] = ["red", "orange", "yellow", "green", "blue", "purple", "pink", "gray"];
+
+// Returns the normalized color or None when the text is not a valid color
+pub fn normalize_color(color: &str) -> Option<String> {
+    let color = color.trim().to_lowercase();
+
+    if PALETTE.contains(&color.as_str()) {
+        return Some(color);
+    }
+
+    let hex = color.strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    match hex.len() {
+        6 => Some(color),
+        // #abc is short for #aabbcc
+        3 => Some(hex.chars().fold(String::from("#"), |mut expanded, c| {
+            expanded.push(c);
+            expanded.push(c);
+            expanded
+        })),
+        _ => None,
+    }
+}
+
+pub fn invalid_color_message(color: &str) -> String {
+    format!("Invalid color {}, use #rrggbb or one of {}", color, PALETTE.join(", "))
+}
@@ -0,0 +1,118 @@
+// Comments on todo items
+//
+// Comments belong to an item and are deleted with it. Items report how many comments
+// they have (see the comments column in ITEM_COLUMNS).
+// There are no user accounts, so comments are not attributed to an author yet.
+// Like item text, comments are stored encrypted when item_encryption_key is set (see
+// crypto.rs).
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::limits::LimitedJson;
+use crate::{clock, crypto, db, notifications, validation, StatusMessage};
+
+#[derive(Serialize)]
+pub struct Comment {
+    id: i64,
+    item_id: i64,
+    body: String,
+    // unix timestamp (seconds)
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct Comments {
+    comments: Vec<Comment>,
+}
+
+impl Comment {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Comment> {
+        Ok(Comment {
+            id: row.get(0)?,
+            item_id: row.get(1)?,
+            body: crypto::decrypt_column(row.get(2)?, 2)?,
+            created_at: row.get(3)?,
+        })
+    }
+}
+
+// The body is the comment text as a json string. 404 when the item does not exist
+#[post("/todo/<id>/comments", format = "json", data = "<body>")]
+pub fn add_comment(id: i64, body: LimitedJson<String>) -> Result<Option<Json<Comment>>, ApiError> {
+
+    let body = validation::comment_body(&body.0)?;
+    let stored = crypto::encrypt_text(&body)?;
+
+    let db_connection = db::connect()?;
+
+    let item_exists = db_connection.query_row(
+        "select 1 from todo_list where id = $1", params![id], |_| Ok(()))
+        .optional();
+    match item_exists {
+        Ok(Some(_)) => (),
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch ToDo Item".into()),
+    }
+
+    let created_at = clock::now();
+    let results = db_connection.execute(
+        "insert into comments (id, item_id, body, created_at) values (null, $1, $2, $3)",
+        params![id, stored, created_at]);
+
+    if results.is_err() {
+        return Err("Failed to insert comment".into());
+    }
+    let comment_id = db_connection.last_insert_rowid();
+
+    // the inbox is informational, the comment is saved even if this fails
+    let _ = notifications::notify(&db_connection, "comment", "New comment on an item", Some(id), None);
+
+    Ok(Some(Json(Comment {
+        id: comment_id,
+        item_id: id,
+        body,
+        created_at,
+    })))
+}
+
+// oldest comment first
+#[get("/todo/<id>/comments")]
+pub fn fetch_comments(id: i64) -> Result<Json<Comments>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(
+        "select id, item_id, body, created_at from comments where item_id = $1 order by created_at, id")
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(params![id], Comment::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Comment>>>());
+
+    match results {
+        Ok(comments) => Ok(Json(Comments { comments })),
+        Err(_) => Err("Failed to fetch comments".into()),
+    }
+}
+
+#[delete("/todo/<id>/comments/<comment_id>")]
+pub fn remove_comment(id: i64, comment_id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let results = db_connection.execute(
+        "delete from comments where id = $1 and item_id = $2", params![comment_id, id]);
+
+    match results {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to delete comment".into()),
+    }
+}
@@ -0,0 +1,96 @@
+// Application settings
+//
+// Read once at startup from the extra keys in Rocket.toml (or the matching ROCKET_*
+// environment variables, e.g. ROCKET_DEDUPE_DEFAULT=true) and put into managed
+// state, so handlers can take a `State<AppConfig>` guard instead of parsing the
+// config on every request.
+
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::Rocket;
+
+use crate::timezone::{self, TimeZone};
+use crate::{attachments, crypto, db, quotas};
+
+pub struct AppConfig {
+    // whether POST /todo rejects duplicates when the request does not say ?dedupe=
+    pub dedupe_default: bool,
+    // uuid mode: give every new item a uuid, even when the client did not send one
+    pub generate_uuids: bool,
+    // where POST /admin/backup writes the database copies
+    pub backup_dir: String,
+    // whether POST /admin/query is available at all
+    pub admin_query: bool,
+    // how long one of those queries may run
+    pub admin_query_timeout: Duration,
+    // wrap json responses in {data, meta, errors} unless ?envelope=false
+    pub envelope: bool,
+    // the time zone of requests without a Time-Zone header
+    pub timezone: TimeZone,
+}
+
+impl AppConfig {
+    fn from_rocket(rocket: &Rocket, timezone: TimeZone) -> AppConfig {
+        let config = rocket.config();
+
+        AppConfig {
+            dedupe_default: config.get_bool("dedupe_default").unwrap_or(false),
+            generate_uuids: config.get_bool("generate_uuids").unwrap_or(false),
+            backup_dir: config.get_string("backup_dir").unwrap_or_else(|_| "backups".into()),
+            admin_query: config.get_bool("admin_query").unwrap_or(false),
+            admin_query_timeout: Duration::from_millis(config.get_int("admin_query_timeout_ms").unwrap_or(5000).max(1) as u64),
+            envelope: config.get_bool("envelope").unwrap_or(false),
+            timezone,
+        }
+    }
+}
+
+// Attach this to the rocket to make AppConfig available as managed state.
+// Has to be attached before the database is first opened, since it also hands the
+// SQLCipher key (database_key) to the db module
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Application config", |rocket| {
+        let database_key = rocket.config().get_string("database_key").ok();
+
+        // without the sqlcipher feature the key would be silently ignored and the
+        // data written in plain text, so refuse to start instead
+        if database_key.is_some() && !cfg!(feature = "sqlcipher") {
+            println!("database_key is set but this build does not have the sqlcipher feature");
+            return Err(rocket);
+        }
+        db::set_database_key(database_key);
+
+        if let Ok(budget) = rocket.config().get_int("db_retry_budget_ms") {
+            db::set_retry_budget(budget.max(0) as u64);
+        }
+        if let Ok(threshold) = rocket.config().get_int("slow_query_ms") {
+            db::set_slow_query_threshold(threshold.max(0) as u64);
+        }
+
+        let item_encryption_key = rocket.config().get_string("item_encryption_key").ok();
+        if let Err(e) = crypto::set_key(item_encryption_key.as_deref()) {
+            println!("{}", e);
+            return Err(rocket);
+        }
+
+        if let Ok(pixels) = rocket.config().get_int("max_image_pixels") {
+            attachments::set_max_image_pixels(pixels.max(0) as u64);
+        }
+
+        quotas::set_limits(
+            rocket.config().get_int("max_items").unwrap_or(0),
+            rocket.config().get_int("max_attachment_bytes").unwrap_or(0));
+
+        let timezone = match timezone::from_rocket(&rocket) {
+            Ok(timezone) => timezone,
+            Err(e) => {
+                println!("{}", e);
+                return Err(rocket);
+            }
+        };
+
+        let app_config = AppConfig::from_rocket(&rocket, timezone);
+        Ok(rocket.manage(app_config))
+    })
+}
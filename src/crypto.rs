@@ -0,0 +1,172 @@
+// Field level encryption of item text and comments
+//
+// When item_encryption_key is configured (32 random bytes, base64 encoded), item text
+// and comments (the notes of an item) are encrypted with AES-256-GCM before they are
+// written and decrypted when they are read, so a copy of data.sqlite (e.g. a backup)
+// does not show what the items say.
+//
+// Encrypted values are stored as "enc:v1:" + base64(nonce + ciphertext). Values
+// without that prefix are read as plain text, so a database that was started
+// without a key keeps working after one is configured (old rows stay plain text
+// until they are written again). Text starting with the prefix itself would be read
+// as an encrypted value, so validation.rs does not let it in.
+
+use std::sync::OnceLock;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use rand::RngCore;
+
+pub const PREFIX: &str = "enc:v1:";
+const NONCE_LENGTH: usize = 12;
+
+static CIPHER: OnceLock<Option<Aes256Gcm>> = OnceLock::new();
+
+// Called once at startup by the config fairing with the configured key, if any
+pub fn set_key(key: Option<&str>) -> Result<(), String> {
+    let cipher = match key {
+        Some(key) => {
+            let key = base64::decode(key).map_err(|_| String::from("item_encryption_key is not valid base64"))?;
+            if key.len() != 32 {
+                return Err("item_encryption_key must be 32 bytes".into());
+            }
+            Some(Aes256Gcm::new(GenericArray::from_slice(&key)))
+        }
+        None => None,
+    };
+
+    let _ = CIPHER.set(cipher);
+    Ok(())
+}
+
+fn cipher() -> Option<&'static Aes256Gcm> {
+    CIPHER.get().and_then(Option::as_ref)
+}
+
+// true when item text is stored encrypted, so sqlite can not look inside it
+pub fn enabled() -> bool {
+    cipher().is_some()
+}
+
+// Returns the value to store for the text - encrypted when a key is configured,
+// otherwise the text unchanged
+pub fn encrypt_text(text: &str) -> Result<String, String> {
+    let cipher = match cipher() {
+        Some(cipher) => cipher,
+        None => return Ok(text.to_string()),
+    };
+
+    // a fresh random nonce for every value, it is stored in front of the ciphertext
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = cipher
+        .encrypt(GenericArray::from_slice(&nonce), text.as_bytes())
+        .map_err(|_| String::from("Failed to encrypt ToDo Item"))?;
+
+    let mut stored = nonce.to_vec();
+    stored.extend_from_slice(&ciphertext);
+    Ok(format!("{}{}", PREFIX, base64::encode(&stored)))
+}
+
+// Turns a stored value back into the text
+pub fn decrypt_text(stored: String) -> Result<String, String> {
+    let encoded = match stored.strip_prefix(PREFIX) {
+        Some(encoded) => encoded,
+        None => return Ok(stored),
+    };
+
+    let cipher = match cipher() {
+        Some(cipher) => cipher,
+        None => return Err("ToDo Item is encrypted but no item_encryption_key is configured".into()),
+    };
+
+    let bytes = base64::decode(encoded).map_err(|_| String::from("Encrypted ToDo Item is corrupt"))?;
+    if bytes.len() < NONCE_LENGTH {
+        return Err("Encrypted ToDo Item is corrupt".into());
+    }
+
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+    let text = cipher
+        .decrypt(GenericArray::from_slice(nonce), ciphertext)
+        .map_err(|_| String::from("Failed to decrypt ToDo Item, wrong item_encryption_key?"))?;
+
+    String::from_utf8(text).map_err(|_| String::from("Encrypted ToDo Item is corrupt"))
+}
+
+// decrypt_text for use inside rusqlite row mapping closures
+pub fn decrypt_column(stored: String, column: usize) -> rusqlite::Result<String> {
+    decrypt_text(stored).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::validation;
+
+    // the key is process wide, every test sets the same one
+    fn with_key() {
+        set_key(Some(&base64::encode([7u8; 32]))).unwrap();
+        assert!(enabled());
+    }
+
+    #[test]
+    fn round_trips_text() {
+        with_key();
+        for text in ["Buy milk", "", "Grüße 👋 from the café", "enc:v0:not ours"] {
+            let stored = encrypt_text(text).unwrap();
+            assert!(stored.starts_with(PREFIX));
+            assert!(!stored.contains(text) || text.is_empty());
+            assert_eq!(decrypt_text(stored).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn uses_a_fresh_nonce_every_time() {
+        with_key();
+        assert_ne!(encrypt_text("Buy milk").unwrap(), encrypt_text("Buy milk").unwrap());
+    }
+
+    #[test]
+    fn reads_values_without_the_prefix_as_plain_text() {
+        with_key();
+        assert_eq!(decrypt_text("Buy milk".into()).unwrap(), "Buy milk");
+        assert_eq!(decrypt_text(" enc:v1:abc".into()).unwrap(), " enc:v1:abc");
+    }
+
+    #[test]
+    fn rejects_corrupt_values() {
+        with_key();
+        assert!(decrypt_text(format!("{}not base64!", PREFIX)).is_err());
+        assert!(decrypt_text(format!("{}{}", PREFIX, base64::encode([1u8; 4]))).is_err());
+
+        let mut stored = base64::decode(encrypt_text("Buy milk").unwrap().strip_prefix(PREFIX).unwrap()).unwrap();
+        let last = stored.len() - 1;
+        stored[last] ^= 1;
+        assert!(decrypt_text(format!("{}{}", PREFIX, base64::encode(&stored))).is_err());
+    }
+
+    #[test]
+    fn decrypt_column_reports_the_column() {
+        with_key();
+        match decrypt_column(format!("{}!", PREFIX), 3) {
+            Err(rusqlite::Error::FromSqlConversionFailure(3, _, _)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn text_starting_with_the_prefix_is_not_let_in() {
+        let item = validation::item_text("enc:v1:Buy milk").unwrap_err();
+        assert_eq!(item.code, ErrorCode::ValidationFailed);
+        let comment = validation::comment_body("  enc:v1:Buy milk").unwrap_err();
+        assert_eq!(comment.code, ErrorCode::ValidationFailed);
+
+        assert!(validation::item_text("Buy enc:v1: milk").is_ok());
+        assert!(validation::comment_body("Buy enc:v1: milk").is_ok());
+    }
+}
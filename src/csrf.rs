@@ -0,0 +1,130 @@
+// CSRF protection for browser requests
+//
+// A browser sends its basic auth credentials and cookies along with any request,
+// also one a page on another site makes it send (a hidden form that posts to
+// /ui/items/3/delete). Requests that change data and come from a browser therefore
+// have to prove they come from our own pages, with the double-submit pattern:
+//   - pages that contain forms take the CsrfToken guard, which sets a random token
+//     in the csrf_token cookie (SameSite=Strict) and puts it in the form
+//   - a POST/PUT/PATCH/DELETE then has to repeat the cookie's token, either as the
+//     first field of a form body (csrf_token=...) or in an X-CSRF-Token header for
+//     requests made from javascript
+// Another site can make the browser send the cookie but can not read it, so it can
+// not repeat it. Failing requests get 403.
+//
+// Bearer token requests are not checked, the token is never sent on its own. Nor
+// are requests without any sign of a browser (no cookies, no Origin or
+// Sec-Fetch-Site header), so curl and other api clients keep working unchanged.
+
+use rand::Rng;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Cookie, Method, SameSite};
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Outcome, Request, Rocket};
+
+use crate::basic_auth::constant_time_eq;
+use crate::error::{ApiError, ErrorCode};
+
+const REJECTED_PATH: &str = "/__csrf";
+
+pub const COOKIE_NAME: &str = "csrf_token";
+const HEADER_NAME: &str = "X-CSRF-Token";
+const FORM_FIELD: &str = "csrf_token=";
+
+// The token of this browser, for the hidden field of the forms on a page
+pub struct CsrfToken(pub String);
+
+fn new_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<CsrfToken, ()> {
+        let mut cookies = request.cookies();
+        if let Some(cookie) = cookies.get(COOKIE_NAME) {
+            return Outcome::Success(CsrfToken(cookie.value().to_string()));
+        }
+
+        let token = new_token();
+        // not http only, scripts of the bundled frontend read it for the header
+        cookies.add(Cookie::build(COOKIE_NAME, token.clone())
+            .path("/")
+            .same_site(SameSite::Strict)
+            .finish());
+        Outcome::Success(CsrfToken(token))
+    }
+}
+
+fn is_safe(method: Method) -> bool {
+    matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+fn from_browser(request: &Request) -> bool {
+    let headers = request.headers();
+    request.cookies().iter().next().is_some() || headers.contains("Origin") || headers.contains("Sec-Fetch-Site")
+}
+
+fn uses_bearer_token(request: &Request) -> bool {
+    request.headers().get_one("Authorization").map_or(false, |value| value.starts_with("Bearer "))
+}
+
+// The token the request repeats: the header, or the first field of a form body.
+// Only the start of the body can be looked at before it is read, which is why the
+// field has to come first
+fn submitted_token(request: &Request, data: &Data) -> Option<String> {
+    if let Some(token) = request.headers().get_one(HEADER_NAME) {
+        return Some(token.to_string());
+    }
+
+    if !request.content_type().map_or(false, |content_type| content_type.is_form()) {
+        return None;
+    }
+    let body = String::from_utf8_lossy(data.peek());
+    let value = body.strip_prefix(FORM_FIELD)?;
+    Some(value.split('&').next().unwrap_or("").to_string())
+}
+
+pub struct Csrf;
+
+impl Fairing for Csrf {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF protection",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.mount("/", routes![rejected]))
+    }
+
+    fn on_request(&self, request: &mut Request, data: &Data) {
+        if is_safe(request.method())
+            || request.uri().path().starts_with("/__")
+            || uses_bearer_token(request)
+            || !from_browser(request)
+        {
+            return;
+        }
+
+        let expected = request.cookies().get(COOKIE_NAME).map(|cookie| cookie.value().to_string());
+        let valid = match (expected, submitted_token(request, data)) {
+            (Some(expected), Some(submitted)) => constant_time_eq(expected.as_bytes(), submitted.as_bytes()),
+            _ => false,
+        };
+
+        if !valid {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(REJECTED_PATH).expect("valid csrf path"));
+        }
+    }
+}
+
+#[get("/__csrf")]
+pub fn rejected() -> ApiError {
+    ApiError::new(ErrorCode::CsrfFailed, "Missing or invalid CSRF token, reload the page and try again")
+}
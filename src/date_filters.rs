@@ -0,0 +1,86 @@
+// Date range filters of item lists
+//
+// GET /todo and GET /todo/search take
+//
+//     ?created_after=2024-05-01T00:00:00Z&created_before=...&due_after=...&due_before=...
+//
+// as RFC 3339 date-times (with Z or an offset like +02:00). Each one that is given
+// narrows the list: after and before are exclusive, and an item without a due date
+// never matches a due_ filter. The parameters are parsed here for every endpoint, so
+// they are rejected the same way everywhere: 422 validation_failed naming the
+// parameter, also when an after is not before its before.
+
+use serde_json::json;
+
+use crate::clock;
+use crate::error::{ApiError, ErrorCode};
+
+// the bounds as unix timestamps, None when not filtered on
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct DateFilters {
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    pub due_after: Option<i64>,
+    pub due_before: Option<i64>,
+}
+
+fn parse(name: &str, value: Option<String>) -> Result<Option<i64>, ApiError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match clock::parse_rfc3339(&value) {
+        Some(timestamp) => Ok(Some(timestamp)),
+        None => Err(ApiError::new(ErrorCode::ValidationFailed,
+            format!("{} has to be an RFC 3339 date-time like 2024-05-01T12:00:00Z", name))
+            .with_details(json!({ "parameter": name, "value": value }))),
+    }
+}
+
+fn check_order(after_name: &str, after: Option<i64>, before_name: &str, before: Option<i64>) -> Result<(), ApiError> {
+    match (after, before) {
+        (Some(after), Some(before)) if after >= before => Err(ApiError::new(ErrorCode::ValidationFailed,
+            format!("{} has to be earlier than {}", after_name, before_name))
+            .with_details(json!({ "parameter": after_name }))),
+        _ => Ok(()),
+    }
+}
+
+impl DateFilters {
+    pub fn from_params(
+        created_after: Option<String>,
+        created_before: Option<String>,
+        due_after: Option<String>,
+        due_before: Option<String>,
+    ) -> Result<DateFilters, ApiError> {
+        let filters = DateFilters {
+            created_after: parse("created_after", created_after)?,
+            created_before: parse("created_before", created_before)?,
+            due_after: parse("due_after", due_after)?,
+            due_before: parse("due_before", due_before)?,
+        };
+        check_order("created_after", filters.created_after, "created_before", filters.created_before)?;
+        check_order("due_after", filters.due_after, "due_before", filters.due_before)?;
+        Ok(filters)
+    }
+
+    // The where clause for the filters, numbering its parameters from first on.
+    // The values go with params(), in the same order. sqlite numbers $n parameters
+    // in the order they first appear, so first has to follow the parameters used
+    // earlier in the statement
+    pub fn sql(first: usize) -> String {
+        let bounds = [("created_at", ">"), ("created_at", "<"), ("due_at", ">"), ("due_at", "<")];
+        bounds.iter()
+            .enumerate()
+            .map(|(i, (column, operator))| {
+                let parameter = first + i;
+                format!("(${} is null or {} {} ${})", parameter, column, operator, parameter)
+            })
+            .collect::<Vec<String>>()
+            .join(" and ")
+    }
+
+    pub fn params(&self) -> [Option<i64>; 4] {
+        [self.created_after, self.created_before, self.due_after, self.due_before]
+    }
+}
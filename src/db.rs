@@ -0,0 +1,676 @@
+// Database helpers shared by all the route modules
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+use rusqlite::{params, Connection, ErrorCode, OpenFlags};
+
+use crate::{metrics, tenants};
+
+pub const DATABASE_FILE: &str = "data.sqlite";
+
+// SQLCipher key for the database file (database_key in the config). Set once at
+// startup by the config fairing, before any connection is opened
+static DATABASE_KEY: OnceLock<Option<String>> = OnceLock::new();
+
+pub fn set_database_key(key: Option<String>) {
+    // only the first call counts, the key can not change while running
+    let _ = DATABASE_KEY.set(key);
+}
+
+// With the sqlcipher feature the file is encrypted, and "pragma key" has to be the
+// very first statement on every new connection
+#[cfg(feature = "sqlcipher")]
+fn apply_key(db_connection: &Connection) -> rusqlite::Result<()> {
+    if let Some(Some(key)) = DATABASE_KEY.get() {
+        db_connection.pragma_update(None, "key", key)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn apply_key(_db_connection: &Connection) -> rusqlite::Result<()> {
+    Ok(())
+}
+
+// How long (in total) an operation may wait and retry when the database is busy or
+// locked by another connection, db_retry_budget_ms in the config
+static RETRY_BUDGET_MS: OnceLock<u64> = OnceLock::new();
+const DEFAULT_RETRY_BUDGET_MS: u64 = 2000;
+
+const FIRST_BACKOFF_MS: u64 = 5;
+const MAX_BACKOFF_MS: u64 = 200;
+
+pub fn set_retry_budget(milliseconds: u64) {
+    let _ = RETRY_BUDGET_MS.set(milliseconds);
+}
+
+fn retry_budget_ms() -> u64 {
+    *RETRY_BUDGET_MS.get().unwrap_or(&DEFAULT_RETRY_BUDGET_MS)
+}
+
+// 5ms, 10ms, 20ms, ... up to 200ms
+fn backoff_ms(attempt: u32) -> u64 {
+    (FIRST_BACKOFF_MS << attempt.min(16)).min(MAX_BACKOFF_MS)
+}
+
+// whether one more retry after `attempt` earlier ones still fits in the budget
+fn within_budget(attempt: u32) -> bool {
+    (0..=attempt).map(backoff_ms).sum::<u64>() <= retry_budget_ms()
+}
+
+// Sleeps between half and all of the backoff, so connections that hit the same lock
+// do not all come back at the same moment
+fn sleep_backoff(attempt: u32) {
+    let backoff = backoff_ms(attempt);
+    let jittered = rand::thread_rng().gen_range(backoff / 2, backoff + 1);
+    thread::sleep(Duration::from_millis(jittered));
+}
+
+// Called by sqlite whenever a statement finds the database locked by another
+// connection. Returning true makes sqlite try again
+fn busy_handler(attempts: i32) -> bool {
+    let attempt = attempts.max(0) as u32;
+    if !within_budget(attempt) {
+        return false;
+    }
+    sleep_backoff(attempt);
+    true
+}
+
+pub fn is_transient(e: &rusqlite::Error) -> bool {
+    match e {
+        rusqlite::Error::SqliteFailure(failure, _) => {
+            failure.code == ErrorCode::DatabaseBusy || failure.code == ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    }
+}
+
+// Runs the operation again while it fails with busy/locked, within the retry budget.
+// The busy handler already covers single statements; this is for the cases sqlite
+// gives up on without asking it (e.g. two transactions that both want to write)
+// and for SQLITE_LOCKED. The whole operation is repeated, so it has to be safe to
+// run twice, e.g. a complete transaction
+pub fn retry<T, F>(mut operation: F) -> rusqlite::Result<T>
+where
+    F: FnMut() -> rusqlite::Result<T>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if is_transient(&e) && within_budget(attempt) => {
+                sleep_backoff(attempt);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Statements that take longer than this are logged, slow_query_ms in the config.
+// 0 turns the log off
+static SLOW_QUERY_MS: OnceLock<u64> = OnceLock::new();
+const DEFAULT_SLOW_QUERY_MS: u64 = 100;
+
+// longer statements are cut off in the log
+const LOGGED_STATEMENT_LENGTH: usize = 120;
+
+pub fn set_slow_query_threshold(milliseconds: u64) {
+    let _ = SLOW_QUERY_MS.set(milliseconds);
+}
+
+// The statement as one line (the queries in this app span several), used as its
+// name in the log
+fn statement_name(sql: &str) -> String {
+    let name = sql.split_whitespace().collect::<Vec<&str>>().join(" ");
+    match name.char_indices().nth(LOGGED_STATEMENT_LENGTH) {
+        Some((end, _)) => format!("{}...", &name[..end]),
+        None => name,
+    }
+}
+
+// sqlite calls this with the run time of every statement on the connection. The
+// time counts towards the request's database time (metrics.rs), and slow statements
+// are logged
+fn profile_statement(sql: &str, duration: Duration) {
+    metrics::record_db_time(duration);
+
+    let threshold = *SLOW_QUERY_MS.get().unwrap_or(&DEFAULT_SLOW_QUERY_MS);
+    if threshold > 0 && duration >= Duration::from_millis(threshold) {
+        println!("Slow query ({} ms): {}", duration.as_millis(), statement_name(sql));
+    }
+}
+
+// The database of the tenant this thread is handling a request for (see tenants.rs),
+// the main database otherwise
+pub fn database_file() -> String {
+    tenants::current_database().unwrap_or_else(|| DATABASE_FILE.to_string())
+}
+
+// Where connections come from. The default opens the sqlite file as named; a program
+// embedding the app can hand in its own (AppBuilder::with_repository), e.g. to keep
+// the databases somewhere else, open them through its own VFS or set pragmas of its
+// own. The queries are written for sqlite, so it still has to hand out sqlite
+// connections. The key, profiling, the busy handler and foreign keys are applied on
+// top of what it returns. file is the name the app gives the database (data.sqlite,
+// a tenant's file); what looks at the files themselves (tenant database sizes,
+// backups) still does so by that name
+pub trait Repository: Send + Sync {
+    fn open(&self, file: &str) -> rusqlite::Result<Connection>;
+
+    // for POST /admin/query, query_only is switched on as well
+    fn open_read_only(&self, file: &str) -> rusqlite::Result<Connection> {
+        Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    }
+}
+
+pub struct FileRepository;
+
+impl Repository for FileRepository {
+    fn open(&self, file: &str) -> rusqlite::Result<Connection> {
+        Connection::open(file)
+    }
+}
+
+// Set once at startup, before any connection is opened. FileRepository without it
+static REPOSITORY: OnceLock<Box<dyn Repository>> = OnceLock::new();
+
+pub fn set_repository(repository: Box<dyn Repository>) -> Result<(), String> {
+    REPOSITORY.set(repository).map_err(|_| String::from("The repository is already set"))
+}
+
+fn repository() -> &'static dyn Repository {
+    match REPOSITORY.get() {
+        Some(repository) => repository.as_ref(),
+        None => &FileRepository,
+    }
+}
+
+fn open_file(file: &str) -> rusqlite::Result<Connection> {
+    let mut db_connection = repository().open(file)?;
+    apply_key(&db_connection)?;
+    db_connection.profile(Some(profile_statement));
+    Ok(db_connection)
+}
+
+fn open() -> rusqlite::Result<Connection> {
+    open_file(&database_file())
+}
+
+// Opens a connection to the database for a single request. The error is a String so
+// it can be returned straight from the handlers, same as the rest of their errors
+pub fn connect() -> Result<Connection, String> {
+    connect_to(&database_file())
+}
+
+// The main database whatever the tenant, for the tenant registry
+pub fn connect_main() -> Result<Connection, String> {
+    connect_to(DATABASE_FILE)
+}
+
+// A connection to the database in file, e.g. a tenant's
+pub fn connect_to(file: &str) -> Result<Connection, String> {
+    let db_connection = match open_file(file) {
+        Ok(connection) => connection,
+        Err(_) => return Err(String::from("Failed to connect to database")),
+    };
+
+    // wait and retry with backoff instead of failing right away when another
+    // connection is writing
+    if db_connection.busy_handler(Some(busy_handler)).is_err() {
+        return Err(String::from("Failed to connect to database"));
+    }
+
+    // sqlite does not enforce foreign keys (and so "on delete cascade") unless it is
+    // switched on for every connection
+    match db_connection.execute_batch("pragma foreign_keys = on;") {
+        Ok(_) => Ok(db_connection),
+        Err(_) => Err(String::from("Failed to connect to database")),
+    }
+}
+
+// A connection that can not change anything, for POST /admin/query. The file is
+// opened read-only and query_only makes sqlite refuse writes on top of that
+pub fn connect_read_only() -> Result<Connection, String> {
+    let opened = repository().open_read_only(&database_file())
+        .and_then(|mut db_connection| {
+            apply_key(&db_connection)?;
+            db_connection.profile(Some(profile_statement));
+            db_connection.execute_batch("pragma query_only = on;")?;
+            Ok(db_connection)
+        });
+
+    opened.map_err(|_| String::from("Failed to connect to database"))
+}
+
+// "create table if not exists" does nothing for a table created by an older version
+// of the app, so columns added later are added here when they are missing.
+// Returns true when the column was added, for columns whose value has to be filled
+// in for the existing rows
+fn add_column_if_missing(db_connection: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<bool> {
+    let columns = table_columns(db_connection, table)?;
+
+    if columns.iter().any(|name| name == column) {
+        return Ok(false);
+    }
+
+    db_connection.execute_batch(&format!("alter table {} add column {} {};", table, column, definition))?;
+    Ok(true)
+}
+
+// What the code expects to find in the database: every table with the columns the
+// queries use, and the indexes. Has to be kept in step with init_schema
+const EXPECTED_COLUMNS: [(&str, &[&str]); 21] = [
+    ("todo_list", &["id", "item", "done", "client_key", "uuid", "list_id", "pinned", "archived", "color", "position", "status", "created_at", "due_at", "completed_at", "estimate"]),
+    ("lists", &["id", "name", "slug", "color", "template"]),
+    ("attachments", &["id", "todo_id", "content_type", "data"]),
+    ("thumbnails", &["attachment_id", "size", "data"]),
+    ("list_shares", &["token", "list_id", "expires_at", "revoked"]),
+    ("tags", &["id", "name"]),
+    ("item_tags", &["item_id", "tag_id"]),
+    ("comments", &["id", "item_id", "body", "created_at"]),
+    ("notifications", &["id", "kind", "message", "item_id", "list_id", "created_at", "read"]),
+    ("smartlists", &["id", "name", "filter"]),
+    ("api_tokens", &["id", "name", "token_hash", "scopes", "created_at", "last_used_at", "rate_limit"]),
+    ("feature_flags", &["name", "enabled", "environments", "token_ids"]),
+    ("rate_limit_counters", &["key", "window_start", "count"]),
+    ("completions", &["id", "item_id", "completed_at"]),
+    ("time_entries", &["id", "item_id", "started_at", "stopped_at"]),
+    ("item_dependencies", &["item_id", "depends_on_id"]),
+    ("outbox", &["id", "event", "item_id", "created_at", "delivered_at", "attempts"]),
+    ("tenants", &["name", "created_at", "suspended"]),
+    ("usage_calls", &["day", "tenant", "token_id", "calls"]),
+    ("usage_storage", &["day", "tenant", "bytes", "max_bytes"]),
+    ("login_failures", &["address", "username", "failures", "last_failure_at", "locked_until"]),
+];
+
+const EXPECTED_INDEXES: [&str; 7] = [
+    "todo_list_client_key", "todo_list_uuid", "todo_list_item_nocase", "todo_list_due_at",
+    "time_entries_item", "time_entries_running", "outbox_pending",
+];
+
+fn table_columns(db_connection: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = db_connection.prepare(&format!("pragma table_info({})", table))?;
+    let rows = statement.query_map(rusqlite::NO_PARAMS, |row| row.get(1))?;
+    rows.collect()
+}
+
+fn schema_names(db_connection: &Connection, kind: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = db_connection.prepare("select name from sqlite_master where type = $1")?;
+    let rows = statement.query_map(params![kind], |row| row.get(0))?;
+    rows.collect()
+}
+
+// Everything EXPECTED_COLUMNS and EXPECTED_INDEXES list that the database is
+// missing, e.g. "todo_list.status" or "index todo_list_uuid"
+fn schema_drift(db_connection: &Connection) -> rusqlite::Result<Vec<String>> {
+    let tables = schema_names(db_connection, "table")?;
+    let indexes = schema_names(db_connection, "index")?;
+    let mut missing = Vec::new();
+
+    for (table, columns) in EXPECTED_COLUMNS.iter() {
+        if !tables.iter().any(|name| name == table) {
+            missing.push(format!("table {}", table));
+            continue;
+        }
+        let existing = table_columns(db_connection, table)?;
+        for column in columns.iter() {
+            if !existing.iter().any(|name| name == column) {
+                missing.push(format!("{}.{}", table, column));
+            }
+        }
+    }
+
+    for index in EXPECTED_INDEXES.iter() {
+        if !indexes.iter().any(|name| name == index) {
+            missing.push(format!("index {}", index));
+        }
+    }
+
+    Ok(missing)
+}
+
+// Makes sure the database has the schema the code expects before the app starts,
+// instead of a handler failing on a missing column later. A new (empty) database
+// is always set up. For an existing one auto_migrate decides: true brings it up to
+// date with init_schema, false leaves it alone and refuses to start when anything
+// is missing, for when migrations are run by hand
+pub fn prepare_schema(auto_migrate: bool) -> Result<(), String> {
+    let db_connection = open().map_err(|_| String::from("Failed to connect to database"))?;
+    let tables = schema_names(&db_connection, "table")
+        .map_err(|_| String::from("Failed to read the database schema"))?;
+
+    if auto_migrate || tables.is_empty() {
+        init_schema().map_err(|e| format!("Failed to set up the database schema: {}", e))?;
+    }
+
+    let missing = schema_drift(&db_connection)
+        .map_err(|_| String::from("Failed to read the database schema"))?;
+    if !missing.is_empty() {
+        return Err(format!("The database schema is out of date, missing: {}", missing.join(", ")));
+    }
+    Ok(())
+}
+
+// Creates all the tables if they do not exist yet
+fn init_schema() -> rusqlite::Result<()> {
+    let db_connection = open()?;
+
+    // lets the housekeeping job hand the pages of deleted rows back to the file
+    // system. Only takes effect for a new database file, older ones are switched
+    // over by the first full vacuum (see housekeeping.rs)
+    db_connection.execute_batch("pragma auto_vacuum = incremental;")?;
+
+    // readers no longer wait for a writer and the other way round. The mode is kept
+    // in the database file, so setting it once here covers every connection. The
+    // -wal file is truncated by the housekeeping job
+    db_connection.execute_batch("pragma journal_mode = wal;")?;
+
+    // login_failures was keyed by the address alone before failures were counted per
+    // account. sqlite can not change a primary key, so the old table is put aside
+    // here and its rows are carried over (with no username) once the new one exists
+    let rekey_login_failures = schema_names(&db_connection, "table")?.iter().any(|name| name == "login_failures")
+        && !table_columns(&db_connection, "login_failures")?.iter().any(|name| name == "username");
+    if rekey_login_failures {
+        db_connection.execute_batch("alter table login_failures rename to login_failures_by_address;")?;
+    }
+
+    db_connection.execute_batch("
+        create table if not exists todo_list
+        (
+            id integer primary key,
+            item varchar(64) not null
+        );
+
+        create table if not exists lists
+        (
+            id integer primary key,
+            name text not null,
+            slug text not null unique
+        );
+
+        create table if not exists attachments
+        (
+            id integer primary key,
+            todo_id integer not null references todo_list(id) on delete cascade,
+            content_type text not null,
+            data blob not null
+        );
+
+        -- thumbnails are generated in the background after an image is uploaded,
+        -- one row per size
+        create table if not exists thumbnails
+        (
+            attachment_id integer not null references attachments(id) on delete cascade,
+            size text not null,
+            data blob not null,
+            primary key (attachment_id, size)
+        );
+
+        -- public read-only links to a list, see shares.rs
+        create table if not exists list_shares
+        (
+            token text primary key,
+            list_id integer not null references lists(id) on delete cascade
+        );
+
+        -- names are unique ignoring case, so Groceries and groceries are one tag
+        create table if not exists tags
+        (
+            id integer primary key,
+            name text not null unique collate nocase
+        );
+
+        create table if not exists item_tags
+        (
+            item_id integer not null references todo_list(id) on delete cascade,
+            tag_id integer not null references tags(id) on delete cascade,
+            primary key (item_id, tag_id)
+        );
+
+        -- item_id can only start once depends_on_id is done, see dependencies.rs
+        create table if not exists item_dependencies
+        (
+            item_id integer not null references todo_list(id) on delete cascade,
+            depends_on_id integer not null references todo_list(id) on delete cascade,
+            primary key (item_id, depends_on_id)
+        );
+
+        create table if not exists comments
+        (
+            id integer primary key,
+            item_id integer not null references todo_list(id) on delete cascade,
+            body text not null,
+            created_at integer not null
+        );
+
+        -- item_id and list_id are kept as plain numbers (no foreign key) so the
+        -- notification stays readable after the item or list is deleted
+        create table if not exists notifications
+        (
+            id integer primary key,
+            kind text not null,
+            message text not null,
+            item_id integer,
+            list_id integer,
+            created_at integer not null,
+            read integer not null default 0
+        );
+
+        -- one row every time an item is marked done, for the streaks (see streaks.rs).
+        -- Filled in by the todo_list_completed_at triggers. item_id is a plain number
+        -- so the completion still counts after the item is deleted
+        create table if not exists completions
+        (
+            id integer primary key,
+            item_id integer not null,
+            completed_at integer not null
+        );
+
+        -- work on an item, from POST /todo/<id>/timer/start to /stop (see timers.rs).
+        -- stopped_at is null while the timer runs
+        create table if not exists time_entries
+        (
+            id integer primary key,
+            item_id integer not null references todo_list(id) on delete cascade,
+            started_at integer not null,
+            stopped_at integer
+        );
+
+        -- item change events waiting to be sent to the message brokers (see events.rs),
+        -- written by the outbox triggers below. delivered_at is null until they are
+        create table if not exists outbox
+        (
+            id integer primary key,
+            event text not null,
+            item_id integer not null,
+            created_at integer not null,
+            delivered_at integer,
+            attempts integer not null default 0
+        );
+
+        -- the tenant registry of the multi-tenant mode (see tenants.rs), only used in
+        -- the main database. Every tenant has its own database file
+        create table if not exists tenants
+        (
+            name text primary key,
+            created_at integer not null,
+            suspended integer not null default 0
+        );
+
+        -- usage metering (see metering.rs), only used in the main database. day counts
+        -- from 1970-01-01 (UTC), tenant is '' for the main database and token_id 0
+        -- without an api token
+        create table if not exists usage_calls
+        (
+            day integer not null,
+            tenant text not null,
+            token_id integer not null,
+            calls integer not null,
+            primary key (day, tenant, token_id)
+        );
+        create table if not exists usage_storage
+        (
+            day integer not null,
+            tenant text not null,
+            bytes integer not null,
+            max_bytes integer not null,
+            primary key (day, tenant)
+        );
+
+        -- failed logins per client address and Basic auth username (see lockout.rs),
+        -- only used in the main database. username is empty for bearer tokens.
+        -- locked_until is null while under login_max_failures
+        create table if not exists login_failures
+        (
+            address text not null,
+            username text not null default '',
+            failures integer not null,
+            last_failure_at integer not null,
+            locked_until integer,
+            primary key (address, username)
+        );
+
+        -- saved searches, filter is the json of smartlists::ItemFilter
+        create table if not exists smartlists
+        (
+            id integer primary key,
+            name text not null,
+            filter text not null
+        );
+
+        -- bearer tokens, see tokens.rs. Only the sha256 of the token is kept and
+        -- scopes is a comma separated list like 'read,write'
+        create table if not exists api_tokens
+        (
+            id integer primary key,
+            name text not null,
+            token_hash text not null unique,
+            scopes text not null,
+            created_at integer not null,
+            last_used_at integer
+        );
+
+        -- see features.rs. environments and token_ids are comma separated lists,
+        -- empty environments means every environment
+        create table if not exists feature_flags
+        (
+            name text primary key,
+            enabled integer not null default 0,
+            environments text not null default '',
+            token_ids text not null default ''
+        );
+
+        -- requests per key ('token:<id>' or 'ip:<address>') and one minute window,
+        -- see rate_limit.rs. Old windows are deleted as new ones start
+        create table if not exists rate_limit_counters
+        (
+            key text not null,
+            window_start integer not null,
+            count integer not null,
+            primary key (key, window_start)
+        );
+    ")?;
+
+    add_column_if_missing(&db_connection, "todo_list", "done", "integer not null default 0")?;
+    add_column_if_missing(&db_connection, "todo_list", "client_key", "text")?;
+    add_column_if_missing(&db_connection, "todo_list", "uuid", "text")?;
+    add_column_if_missing(&db_connection, "todo_list", "list_id", "integer references lists(id) on delete cascade")?;
+    add_column_if_missing(&db_connection, "todo_list", "pinned", "integer not null default 0")?;
+    add_column_if_missing(&db_connection, "todo_list", "archived", "integer not null default 0")?;
+    add_column_if_missing(&db_connection, "todo_list", "color", "text")?;
+    add_column_if_missing(&db_connection, "todo_list", "position", "integer")?;
+    add_column_if_missing(&db_connection, "todo_list", "created_at", "integer")?;
+    add_column_if_missing(&db_connection, "todo_list", "due_at", "integer")?;
+    add_column_if_missing(&db_connection, "todo_list", "completed_at", "integer")?;
+    add_column_if_missing(&db_connection, "todo_list", "estimate", "real")?;
+    add_column_if_missing(&db_connection, "lists", "color", "text")?;
+    add_column_if_missing(&db_connection, "lists", "template", "integer not null default 0")?;
+
+    // items finished before the kanban workflow existed start out as done
+    if add_column_if_missing(&db_connection, "todo_list", "status", "text not null default 'todo'")? {
+        db_connection.execute_batch("update todo_list set status = 'done' where done = 1;")?;
+    }
+    add_column_if_missing(&db_connection, "list_shares", "expires_at", "integer")?;
+    add_column_if_missing(&db_connection, "list_shares", "revoked", "integer not null default 0")?;
+    add_column_if_missing(&db_connection, "api_tokens", "rate_limit", "integer")?;
+
+    if rekey_login_failures {
+        db_connection.execute_batch("
+            insert into login_failures (address, username, failures, last_failure_at, locked_until)
+                select address, '', failures, last_failure_at, locked_until from login_failures_by_address;
+            drop table login_failures_by_address;")?;
+    }
+
+    // sqlite can not add a column with a unique constraint, so uniqueness is an index.
+    // Rows without a client key (null) do not conflict with each other
+    db_connection.execute_batch("
+        create unique index if not exists todo_list_client_key on todo_list(client_key);
+        create unique index if not exists todo_list_uuid on todo_list(uuid);
+
+        -- lets sqlite answer the case insensitive prefix matches (like 'abc%') of
+        -- /todo/suggest from the index instead of scanning the table
+        create index if not exists todo_list_item_nocase on todo_list(item collate nocase);
+
+        -- for the ?due_after= / ?due_before= filters, see date_filters.rs
+        create index if not exists todo_list_due_at on todo_list(due_at);
+
+        -- the tracked time of every listed item is summed from time_entries, and
+        -- an item can have only one running timer
+        create index if not exists time_entries_item on time_entries(item_id);
+        create unique index if not exists time_entries_running on time_entries(item_id) where stopped_at is null;
+        create index if not exists outbox_pending on outbox(id) where delivered_at is null;
+
+        -- items are added by many queries (routes, lists, seed data, the batch writer),
+        -- so created_at is filled in here rather than by each of them. Items from
+        -- before the column existed have none
+        create trigger if not exists todo_list_created_at after insert on todo_list
+        when new.created_at is null
+        begin
+            update todo_list set created_at = cast(strftime('%s', 'now') as integer) where id = new.id;
+        end;
+
+        -- the same for completed_at, whichever way done changes (the done routes, the
+        -- kanban status, seed data). Reopening clears it
+        create trigger if not exists todo_list_completed_at_insert after insert on todo_list
+        when new.done = 1 and new.completed_at is null
+        begin
+            update todo_list set completed_at = cast(strftime('%s', 'now') as integer) where id = new.id;
+            insert into completions (item_id, completed_at) values (new.id, cast(strftime('%s', 'now') as integer));
+        end;
+        create trigger if not exists todo_list_completed_at_update after update of done on todo_list
+        when new.done is not old.done
+        begin
+            update todo_list set completed_at = case when new.done = 1 then cast(strftime('%s', 'now') as integer) end
+            where id = new.id;
+            insert into completions (item_id, completed_at)
+            select new.id, cast(strftime('%s', 'now') as integer) where new.done = 1;
+        end;
+
+        -- the outbox: every change to an item is recorded in the transaction making
+        -- it, so events are neither lost (after a crash) nor sent for changes that were
+        -- rolled back. The update trigger leaves out created_at and completed_at, which
+        -- only the triggers above set, and has to list columns added to todo_list later
+        create trigger if not exists outbox_item_created after insert on todo_list
+        begin
+            insert into outbox (event, item_id, created_at) values ('item.created', new.id, cast(strftime('%s', 'now') as integer));
+        end;
+        create trigger if not exists outbox_item_updated
+        after update of item, done, client_key, uuid, list_id, pinned, archived, color, position, status, due_at, estimate on todo_list
+        begin
+            insert into outbox (event, item_id, created_at) values ('item.updated', new.id, cast(strftime('%s', 'now') as integer));
+        end;
+        create trigger if not exists outbox_item_completed after insert on completions
+        begin
+            insert into outbox (event, item_id, created_at) values ('item.completed', new.item_id, new.completed_at);
+        end;
+        create trigger if not exists outbox_item_deleted after delete on todo_list
+        begin
+            insert into outbox (event, item_id, created_at) values ('item.deleted', old.id, cast(strftime('%s', 'now') as integer));
+        end;
+    ")?;
+
+    Ok(())
+}
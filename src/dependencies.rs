@@ -0,0 +1,133 @@
+// Dependencies between items
+//
+// PUT /todo/<id>/dependencies/<other> says the item can only start once <other> is
+// done, DELETE takes that back and GET /todo/<id>/dependencies lists what the item
+// waits for. Dependencies are deleted with either item. A dependency that would close
+// a cycle (a waits for b waits for a) is refused with 409, so the graph stays
+// acyclic and the schedule (see planning.rs) can always be computed.
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::{db, StatusMessage};
+
+#[derive(Serialize)]
+pub struct Dependencies {
+    item_id: i64,
+    depends_on: Vec<i64>,
+}
+
+fn item_exists(db_connection: &Connection, id: i64) -> Result<bool, ApiError> {
+    let exists = db_connection.query_row(
+        "select 1 from todo_list where id = $1", params![id], |_| Ok(()))
+        .optional();
+    match exists {
+        Ok(exists) => Ok(exists.is_some()),
+        Err(_) => Err("Failed to fetch ToDo Item".into()),
+    }
+}
+
+// whether to can be reached from from by following dependencies
+fn depends_on(db_connection: &Connection, from: i64, to: i64) -> rusqlite::Result<bool> {
+    db_connection.query_row(
+        "with recursive reachable(id) as (
+             select $1
+             union
+             select item_dependencies.depends_on_id from item_dependencies
+             join reachable on item_dependencies.item_id = reachable.id
+         )
+         select exists(select 1 from reachable where id = $2)",
+        params![from, to],
+        |row| row.get(0))
+}
+
+// The dependencies of every item in the list, as (item, depends on) pairs
+pub fn list_dependencies(db_connection: &Connection, list_id: i64) -> rusqlite::Result<Vec<(i64, i64)>> {
+    let mut statement = db_connection.prepare(
+        "select item_dependencies.item_id, item_dependencies.depends_on_id from item_dependencies
+         join todo_list on todo_list.id = item_dependencies.item_id
+         where todo_list.list_id = $1")?;
+    let rows = statement.query_map(params![list_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+// 404 when either item does not exist
+#[put("/todo/<id>/dependencies/<other>")]
+pub fn add_dependency(id: i64, other: i64) -> Result<Option<Json<StatusMessage>>, ApiError> {
+
+    if id == other {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "An item can not depend on itself"));
+    }
+
+    let mut db_connection = db::connect()?;
+    if !item_exists(&db_connection, id)? || !item_exists(&db_connection, other)? {
+        return Ok(None);
+    }
+
+    // the cycle check and the insert in one transaction, so two requests can not
+    // each add half of a cycle
+    let transaction = match db_connection.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    match depends_on(&transaction, other, id) {
+        Ok(false) => (),
+        Ok(true) => return Err(ApiError::new(ErrorCode::Conflict,
+            format!("Item {} already depends on item {}, this would be a cycle", other, id))),
+        Err(_) => return Err("Failed to fetch dependencies".into()),
+    }
+
+    let results = transaction.execute(
+        "insert or ignore into item_dependencies (item_id, depends_on_id) values ($1, $2)", params![id, other]);
+
+    match results.and_then(|rows_inserted| transaction.commit().map(|_| rows_inserted)) {
+        Ok(rows_inserted) => Ok(Some(Json(StatusMessage {
+            message: format!("{} rows inserted!", rows_inserted),
+        }))),
+        Err(_) => Err("Failed to insert dependency".into()),
+    }
+}
+
+#[delete("/todo/<id>/dependencies/<other>")]
+pub fn remove_dependency(id: i64, other: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let results = db_connection.execute(
+        "delete from item_dependencies where item_id = $1 and depends_on_id = $2", params![id, other]);
+
+    match results {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to delete dependency".into()),
+    }
+}
+
+#[get("/todo/<id>/dependencies")]
+pub fn fetch_dependencies(id: i64) -> Result<Option<Json<Dependencies>>, ApiError> {
+
+    let db_connection = db::connect()?;
+    if !item_exists(&db_connection, id)? {
+        return Ok(None);
+    }
+
+    let mut statement = match db_connection.prepare(
+        "select depends_on_id from item_dependencies where item_id = $1 order by depends_on_id")
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(params![id], |row| row.get(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<i64>>>());
+
+    match results {
+        Ok(depends_on) => Ok(Some(Json(Dependencies { item_id: id, depends_on }))),
+        Err(_) => Err("Failed to fetch dependencies".into()),
+    }
+}
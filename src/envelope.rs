@@ -0,0 +1,113 @@
+// Enveloped responses
+//
+// Some clients want every response in one shape, whatever the endpoint. With
+// envelope = true in the config, or ?envelope=true on a request (?envelope=false
+// turns it off again for one request), json bodies are wrapped:
+//
+//     {"data": <the usual body>, "meta": {...}, "errors": []}
+//
+// and errors (see error.rs) come as
+//
+//     {"data": null, "meta": {...}, "errors": [{"code": ..., "message": ..., ...}]}
+//
+// meta has the request_id, `count` when data is a list, and `page` for a page of a
+// longer list (see pagination.rs). The status code does not change. Bodies that are not json (attachments, /metrics, the /ui pages) are
+// sent as they are.
+
+use std::io::Cursor;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{Request, Response, State};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::config::AppConfig;
+use crate::error;
+
+const QUERY_PARAM: &str = "envelope";
+
+// meta.page, left in the request-local cache by the responder of a paged response
+#[derive(Serialize, Clone, Copy)]
+pub struct PageMeta {
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+struct RecordedPage(Option<PageMeta>);
+
+impl PageMeta {
+    pub fn record(self, request: &Request) {
+        request.local_cache(|| RecordedPage(Some(self)));
+    }
+}
+
+// ?envelope=true / false, None when the request does not say
+fn requested(request: &Request) -> Option<bool> {
+    request.uri().query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == QUERY_PARAM)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+fn enabled(request: &Request) -> bool {
+    requested(request).unwrap_or_else(|| match request.guard::<State<AppConfig>>() {
+        rocket::Outcome::Success(app_config) => app_config.envelope,
+        _ => false,
+    })
+}
+
+fn wrap(request: &Request, body: Value, failed: bool) -> Value {
+    let mut meta = Map::new();
+    meta.insert("request_id".into(), json!(error::request_id(request)));
+    if let Value::Array(items) = &body {
+        meta.insert("count".into(), json!(items.len()));
+    }
+    if let Some(page) = request.local_cache(|| RecordedPage(None)).0 {
+        meta.insert("page".into(), json!(page));
+    }
+
+    if failed {
+        json!({ "data": null, "meta": meta, "errors": [body] })
+    } else {
+        json!({ "data": body, "meta": meta, "errors": [] })
+    }
+}
+
+pub struct Envelope;
+
+impl Fairing for Envelope {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response envelopes",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let is_json = response.content_type().map_or(false, |content_type| content_type.is_json());
+        if !is_json || !enabled(request) {
+            return;
+        }
+
+        let body = match response.body_string() {
+            Some(body) => body,
+            None => return,
+        };
+        // not expected for a json content type, but then it is sent unchanged
+        let value: Value = match serde_json::from_str(&body) {
+            Ok(value) => value,
+            Err(_) => {
+                response.set_sized_body(Cursor::new(body));
+                return;
+            }
+        };
+
+        let failed = response.status().code >= 400;
+        let wrapped = wrap(request, value, failed);
+        response.set_sized_body(Cursor::new(wrapped.to_string()));
+        response.set_header(ContentType::JSON);
+    }
+}
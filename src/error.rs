@@ -0,0 +1,222 @@
+// Error responses
+//
+// Every error the api sends, from a handler, a catcher or one of the fairings that
+// turn requests away, has the same json body:
+//
+//     {
+//         "code": "not_found",
+//         "message": "Not Found",
+//         "details": null,
+//         "request_id": "5f0c..."
+//     }
+//
+// `code` is one of ErrorCode below and does not change between versions, so
+// clients can branch on it; `message` is for people and may change (and be
+// translated, see i18n.rs). `details` carries extra data for some codes, e.g. the
+// limit for payload_too_large. `request_id` is also sent as X-Request-Id on every
+// response and can be quoted when reporting a problem.
+//
+// Handlers return Result<T, ApiError>. The helpers around them still fail with a
+// plain String, which ? turns into an internal_error.
+
+use std::fmt;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Request};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::reporting;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// longest request id taken over from a client
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidJson,
+    ValidationFailed,
+    Unauthorized,
+    Forbidden,
+    AddressBlocked,
+    CsrfFailed,
+    NotFound,
+    Conflict,
+    Gone,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    RateLimited,
+    ReadOnly,
+    Overloaded,
+    DatabaseUnavailable,
+    QueryTimeout,
+    ServiceUnavailable,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> Status {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::InvalidJson => Status::BadRequest,
+            ErrorCode::ValidationFailed => Status::UnprocessableEntity,
+            ErrorCode::Unauthorized => Status::Unauthorized,
+            ErrorCode::Forbidden | ErrorCode::AddressBlocked | ErrorCode::CsrfFailed => Status::Forbidden,
+            ErrorCode::NotFound => Status::NotFound,
+            ErrorCode::Conflict => Status::Conflict,
+            ErrorCode::Gone => Status::Gone,
+            ErrorCode::PayloadTooLarge => Status::PayloadTooLarge,
+            ErrorCode::RangeNotSatisfiable => Status::RangeNotSatisfiable,
+            ErrorCode::RateLimited => Status::TooManyRequests,
+            ErrorCode::ReadOnly
+            | ErrorCode::Overloaded
+            | ErrorCode::DatabaseUnavailable
+            | ErrorCode::QueryTimeout
+            | ErrorCode::ServiceUnavailable => Status::ServiceUnavailable,
+            ErrorCode::InternalError => Status::InternalServerError,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> ApiError {
+        ApiError { code, message: message.into(), details: None }
+    }
+
+    pub fn with_details(mut self, details: Value) -> ApiError {
+        self.details = Some(details);
+        self
+    }
+}
+
+// the helpers' String errors are failures on our side
+impl From<String> for ApiError {
+    fn from(message: String) -> ApiError {
+        ApiError::new(ErrorCode::InternalError, message)
+    }
+}
+
+impl From<&str> for ApiError {
+    fn from(message: &str) -> ApiError {
+        ApiError::new(ErrorCode::InternalError, message)
+    }
+}
+
+// for the places that only show the message, e.g. flash messages and the CLI
+impl From<ApiError> for String {
+    fn from(error: ApiError) -> String {
+        error.message
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+    details: Option<Value>,
+    request_id: String,
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        if self.code == ErrorCode::InternalError {
+            reporting::capture("internal_error", self.message.clone());
+        }
+
+        let status = self.code.status();
+        let body = Json(ErrorBody {
+            code: self.code,
+            message: self.message,
+            details: self.details,
+            request_id: request_id(request),
+        });
+
+        Response::build_from(body.respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+struct RequestId(String);
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// The id of the request: the client's X-Request-Id when it sent a sensible one
+// (e.g. from a proxy that already logs it), a new uuid otherwise
+pub fn request_id(request: &Request) -> String {
+    request.local_cache(|| {
+        let id = request.headers().get_one(REQUEST_ID_HEADER)
+            .filter(|id| valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        RequestId(id)
+    }).0.clone()
+}
+
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request_id(request);
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        response.set_raw_header(REQUEST_ID_HEADER, request_id(request));
+    }
+}
+
+// Catchers for the statuses Rocket answers itself (no route, a failing guard ...),
+// which would otherwise be html pages
+
+fn caught(code: ErrorCode) -> ApiError {
+    ApiError::new(code, code.status().reason)
+}
+
+#[catch(401)]
+pub fn unauthorized() -> ApiError {
+    caught(ErrorCode::Unauthorized)
+}
+
+#[catch(404)]
+pub fn not_found() -> ApiError {
+    caught(ErrorCode::NotFound)
+}
+
+#[catch(500)]
+pub fn internal_error() -> ApiError {
+    caught(ErrorCode::InternalError)
+}
+
+#[catch(503)]
+pub fn unavailable() -> ApiError {
+    caught(ErrorCode::ServiceUnavailable)
+}
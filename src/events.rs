@@ -0,0 +1,282 @@
+// Item change events
+//
+// Every change to an item (added, changed, completed, deleted) becomes an event that
+// is handed to the configured sinks: an MQTT broker (see mqtt.rs), an AMQP exchange
+// (see amqp.rs) and / or a Kafka topic (see kafka.rs):
+//
+//     {"id": 981, "event": "item.updated", "item_id": 12, "timestamp": 1714557600}
+//
+// Events go through a transactional outbox. Triggers on todo_list (see
+// db::init_schema) write them to the outbox table in the same transaction as the
+// change itself, whichever query makes it, so an event exists exactly when its change
+// was committed: none go missing when the app stops or crashes before sending them,
+// and none are sent for changes that were rolled back.
+//
+// A background thread delivers them: every POLL_INTERVAL it reads up to BATCH_SIZE
+// undelivered events in the order they happened, hands them to every sink, flushes
+// the sinks that batch (Kafka) and marks them delivered. When a sink fails the whole
+// batch is tried again later (backing off from POLL_INTERVAL to MAX_BACKOFF), so
+// delivery is at least once: a consumer can get an event twice, and can use its id
+// to tell. Delivered events are deleted after RETENTION_SECONDS.
+//
+// On Ctrl-C or SIGTERM the events committed so far are delivered once more and the
+// sinks flushed (for at most SHUTDOWN_TIMEOUT) before the app exits; whatever could
+// not be sent stays in the outbox for the next start.
+
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::Rocket;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::amqp::AmqpSink;
+use crate::kafka::KafkaSink;
+use crate::mqtt::MqttSink;
+use crate::{clock, db};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const BATCH_SIZE: usize = 100;
+const RETENTION_SECONDS: i64 = 7 * 24 * 3600;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum ChangeKind {
+    #[serde(rename = "item.created")]
+    Created,
+    #[serde(rename = "item.updated")]
+    Updated,
+    #[serde(rename = "item.completed")]
+    Completed,
+    #[serde(rename = "item.deleted")]
+    Deleted,
+}
+
+impl ChangeKind {
+    // the text in the outbox's event column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Created => "item.created",
+            ChangeKind::Updated => "item.updated",
+            ChangeKind::Completed => "item.completed",
+            ChangeKind::Deleted => "item.deleted",
+        }
+    }
+
+    fn parse(event: &str) -> Option<ChangeKind> {
+        [ChangeKind::Created, ChangeKind::Updated, ChangeKind::Completed, ChangeKind::Deleted]
+            .iter()
+            .copied()
+            .find(|kind| kind.as_str() == event)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChangeEvent {
+    // the outbox id, the same when an event is delivered again
+    pub id: i64,
+    #[serde(rename = "event")]
+    pub kind: ChangeKind,
+    pub item_id: i64,
+    // unix timestamp (seconds) of the change
+    pub timestamp: i64,
+}
+
+// Somewhere events can be sent to
+pub trait Sink: Send {
+    // for the log
+    fn name(&self) -> &'static str;
+    fn publish(&mut self, event: &ChangeEvent) -> Result<(), String>;
+    // sends what publish has held back, for sinks that batch
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// asks the delivering thread to finish, it answers when it has
+static SHUTDOWN: OnceLock<SyncSender<SyncSender<()>>> = OnceLock::new();
+
+fn pending_events(db_connection: &rusqlite::Connection) -> rusqlite::Result<Vec<ChangeEvent>> {
+    let mut statement = db_connection.prepare(
+        "select id, event, item_id, created_at from outbox where delivered_at is null order by id limit $1")?;
+    let rows = statement.query_map(params![BATCH_SIZE as i64], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?, row.get(3)?))
+    })?;
+
+    let mut events = Vec::new();
+    for row in rows {
+        let (id, event, item_id, timestamp) = row?;
+        match ChangeKind::parse(&event) {
+            Some(kind) => events.push(ChangeEvent { id, kind, item_id, timestamp }),
+            None => println!("Unknown event {} in the outbox (id {}), skipped", event, id),
+        }
+    }
+    Ok(events)
+}
+
+fn publish_all(sinks: &mut [Box<dyn Sink>], events: &[ChangeEvent]) -> Result<(), String> {
+    for event in events {
+        for sink in sinks.iter_mut() {
+            sink.publish(event).map_err(|e| {
+                format!("Failed to publish {} of item {} to {}: {}", event.kind.as_str(), event.item_id, sink.name(), e)
+            })?;
+        }
+    }
+    for sink in sinks.iter_mut() {
+        sink.flush().map_err(|e| format!("Failed to flush the change events of {}: {}", sink.name(), e))?;
+    }
+    Ok(())
+}
+
+// Delivers the oldest undelivered events, returns how many
+fn deliver(sinks: &mut [Box<dyn Sink>]) -> Result<usize, String> {
+    let db_connection = db::connect()?;
+    let events = pending_events(&db_connection).map_err(|e| format!("Failed to read the outbox: {}", e))?;
+    let (first, last) = match (events.first(), events.last()) {
+        (Some(first), Some(last)) => (first.id, last.id),
+        _ => return Ok(0),
+    };
+
+    if let Err(e) = publish_all(sinks, &events) {
+        let _ = db_connection.execute(
+            "update outbox set attempts = attempts + 1 where id between $1 and $2 and delivered_at is null",
+            params![first, last]);
+        return Err(e);
+    }
+
+    let now = clock::now();
+    db_connection.execute(
+        "update outbox set delivered_at = $1 where id between $2 and $3 and delivered_at is null",
+        params![now, first, last])
+        .and_then(|_| db_connection.execute(
+            "delete from outbox where delivered_at < $1", params![now - RETENTION_SECONDS]))
+        .map_err(|e| format!("Failed to mark change events delivered: {}", e))?;
+    Ok(events.len())
+}
+
+fn run(mut sinks: Vec<Box<dyn Sink>>, shutdown: Receiver<SyncSender<()>>) {
+    let mut backoff = POLL_INTERVAL;
+    loop {
+        let wait = match deliver(&mut sinks) {
+            // a full batch, there may be more waiting
+            Ok(delivered) if delivered == BATCH_SIZE => Duration::from_millis(0),
+            Ok(_) => {
+                backoff = POLL_INTERVAL;
+                POLL_INTERVAL
+            }
+            Err(e) => {
+                println!("{}, trying again in {} seconds", e, backoff.as_secs_f64());
+                let wait = backoff;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                wait
+            }
+        };
+
+        match shutdown.recv_timeout(wait) {
+            Ok(done) => {
+                if let Err(e) = deliver(&mut sinks) {
+                    println!("{}, left in the outbox", e);
+                }
+                let _ = done.send(());
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// Delivers what is in the outbox and flushes the sinks, waiting for at most
+// SHUTDOWN_TIMEOUT
+pub fn shutdown() {
+    let shutdown = match SHUTDOWN.get() {
+        Some(shutdown) => shutdown,
+        None => return,
+    };
+    let (done, finished) = mpsc::sync_channel(1);
+    if shutdown.send(done).is_ok() && finished.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+        println!("Gave up delivering the change events after {} seconds", SHUTDOWN_TIMEOUT.as_secs());
+    }
+}
+
+pub fn start(sinks: Vec<Box<dyn Sink>>) -> Result<(), String> {
+    let (sender, receiver) = mpsc::sync_channel::<SyncSender<()>>(1);
+    if SHUTDOWN.set(sender).is_err() {
+        return Err("Change events are already running".into());
+    }
+    thread::spawn(move || run(sinks, receiver));
+
+    ctrlc::set_handler(|| {
+        println!("Stopping, delivering the change events");
+        shutdown();
+        process::exit(0);
+    }).map_err(|e| format!("Failed to watch for Ctrl-C / SIGTERM: {}", e))
+}
+
+// Starts the sinks from the config: mqtt_url, amqp_url, kafka_brokers
+pub struct ChangeEvents;
+
+impl Fairing for ChangeEvents {
+    fn info(&self) -> Info {
+        Info {
+            name: "Change events",
+            kind: Kind::Attach,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+
+        let mqtt_url = rocket.config().get_str("mqtt_url").unwrap_or("").to_string();
+        if !mqtt_url.is_empty() {
+            let topic_prefix = rocket.config().get_str("mqtt_topic_prefix").unwrap_or("todo").to_string();
+            match MqttSink::from_url(&mqtt_url, &topic_prefix) {
+                Ok(sink) => sinks.push(Box::new(sink)),
+                Err(e) => {
+                    println!("{}", e);
+                    return Err(rocket);
+                }
+            }
+        }
+
+        let amqp_url = rocket.config().get_str("amqp_url").unwrap_or("").to_string();
+        if !amqp_url.is_empty() {
+            let exchange = rocket.config().get_str("amqp_exchange").unwrap_or("todo").to_string();
+            match AmqpSink::new(&amqp_url, &exchange) {
+                Ok(sink) => sinks.push(Box::new(sink)),
+                Err(e) => {
+                    println!("{}", e);
+                    return Err(rocket);
+                }
+            }
+        }
+
+        let kafka_brokers = rocket.config().get_str("kafka_brokers").unwrap_or("").to_string();
+        if !kafka_brokers.is_empty() {
+            let topic = rocket.config().get_str("kafka_topic").unwrap_or("todo-events").to_string();
+            let batch_size = rocket.config().get_int("kafka_batch_size").unwrap_or(100);
+            match KafkaSink::new(&kafka_brokers, &topic, batch_size) {
+                Ok(sink) => sinks.push(Box::new(sink)),
+                Err(e) => {
+                    println!("{}", e);
+                    return Err(rocket);
+                }
+            }
+        }
+
+        // without sinks the events are only marked delivered, so the outbox does
+        // not grow
+        match start(sinks) {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                println!("{}", e);
+                Err(rocket)
+            }
+        }
+    }
+}
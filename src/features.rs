@@ -0,0 +1,201 @@
+// Feature flags
+//
+// Experimental behavior is switched on and off with rows in feature_flags instead
+// of a redeploy. A flag is on when
+//   - enabled is set and the current Rocket environment (development, staging,
+//     production) is in its environments list, or the list is empty, or
+//   - the request was made with one of the tokens in its token_ids list, so a
+//     feature can be tried by one client before everyone gets it
+// Flags that do not exist are off.
+//
+// All flags are kept in memory (managed state) and reloaded after every change
+// through /admin/flags, so checking a flag does not touch the database.
+//
+// Flags in use:
+//   search-tags - GET /todo/search also matches tag names
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use rocket::fairing::AdHoc;
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, State};
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Admin};
+use crate::error::ApiError;
+use crate::limits::LimitedJson;
+use crate::{db, StatusMessage};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FeatureFlag {
+    #[serde(default)]
+    name: String,
+    enabled: bool,
+    // environments the flag is enabled in, empty = all of them
+    #[serde(default)]
+    environments: Vec<String>,
+    // tokens that always get the feature, even when it is not enabled
+    #[serde(default)]
+    token_ids: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct FeatureFlagList {
+    flags: Vec<FeatureFlag>,
+}
+
+// the managed cache of all flags
+pub struct FeatureFlags {
+    environment: String,
+    flags: RwLock<HashMap<String, FeatureFlag>>,
+}
+
+// Lists are stored as comma separated text
+fn split_list(text: &str) -> Vec<String> {
+    text.split(',').map(|part| part.trim().to_string()).filter(|part| !part.is_empty()).collect()
+}
+
+impl FeatureFlag {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<FeatureFlag> {
+        let environments: String = row.get(2)?;
+        let token_ids: String = row.get(3)?;
+        Ok(FeatureFlag {
+            name: row.get(0)?,
+            enabled: row.get(1)?,
+            environments: split_list(&environments),
+            token_ids: split_list(&token_ids).iter().filter_map(|id| id.parse().ok()).collect(),
+        })
+    }
+
+    fn is_on(&self, environment: &str, token_id: Option<i64>) -> bool {
+        let for_environment = self.enabled
+            && (self.environments.is_empty() || self.environments.iter().any(|name| name == environment));
+        let for_token = token_id.map_or(false, |id| self.token_ids.contains(&id));
+        for_environment || for_token
+    }
+}
+
+fn load_flags(db_connection: &Connection) -> rusqlite::Result<HashMap<String, FeatureFlag>> {
+    let mut statement = db_connection.prepare(
+        "select name, enabled, environments, token_ids from feature_flags")?;
+
+    let flags = statement
+        .query_map(rusqlite::NO_PARAMS, FeatureFlag::from_row)?
+        .collect::<rusqlite::Result<Vec<FeatureFlag>>>()?;
+
+    Ok(flags.into_iter().map(|flag| (flag.name.clone(), flag)).collect())
+}
+
+impl FeatureFlags {
+    // Re-reads the flags after they were changed
+    fn reload(&self, db_connection: &Connection) -> rusqlite::Result<()> {
+        let flags = load_flags(db_connection)?;
+        *self.flags.write().expect("feature flag lock poisoned") = flags;
+        Ok(())
+    }
+
+    // The evaluation helper: whether the flag is on for this environment and token
+    pub fn is_enabled(&self, name: &str, token_id: Option<i64>) -> bool {
+        self.flags.read().expect("feature flag lock poisoned")
+            .get(name)
+            .map_or(false, |flag| flag.is_on(&self.environment, token_id))
+    }
+}
+
+// Request guard for handlers that behave differently behind a flag:
+// `features.enabled("search-tags")` answers for the token the request was made with
+pub struct Features<'r> {
+    flags: State<'r, FeatureFlags>,
+    token_id: Option<i64>,
+}
+
+impl<'r> Features<'r> {
+    pub fn enabled(&self, name: &str) -> bool {
+        self.flags.is_enabled(name, self.token_id)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Features<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Features<'r>, ()> {
+        let flags = request.guard::<State<FeatureFlags>>()?;
+        let token_id = auth::current_grant(request).and_then(|grant| grant.token_id);
+        Outcome::Success(Features { flags, token_id })
+    }
+}
+
+// Loads the flags into managed state. Attach after the schema is set up
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Feature flags", |rocket| {
+        let flags = db::connect().and_then(|db_connection| {
+            load_flags(&db_connection).map_err(|_| String::from("Failed to load feature flags"))
+        });
+
+        match flags {
+            Ok(flags) => {
+                let environment = rocket.config().environment.to_string();
+                Ok(rocket.manage(FeatureFlags { environment, flags: RwLock::new(flags) }))
+            }
+            Err(e) => {
+                println!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[get("/flags")]
+pub fn fetch_all_flags(_admin: Admin, feature_flags: State<FeatureFlags>) -> Json<FeatureFlagList> {
+    let mut flags: Vec<FeatureFlag> = feature_flags.flags.read().expect("feature flag lock poisoned")
+        .values().cloned().collect();
+    flags.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Json(FeatureFlagList { flags })
+}
+
+// Creates or replaces a flag, e.g. {"enabled": true, "environments": ["staging"]}
+#[put("/flags/<name>", format = "json", data = "<flag>")]
+pub fn put_flag(_admin: Admin, feature_flags: State<FeatureFlags>, name: String, flag: LimitedJson<FeatureFlag>) -> Result<Json<FeatureFlag>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut flag = flag.0;
+    flag.name = name;
+
+    let token_ids: Vec<String> = flag.token_ids.iter().map(|id| id.to_string()).collect();
+    let results = db_connection.execute(
+        "insert into feature_flags (name, enabled, environments, token_ids) values ($1, $2, $3, $4)
+         on conflict(name) do update set enabled = $2, environments = $3, token_ids = $4",
+        params![flag.name, flag.enabled, flag.environments.join(","), token_ids.join(",")]);
+
+    if results.is_err() {
+        return Err("Failed to save feature flag".into());
+    }
+
+    match feature_flags.reload(&db_connection) {
+        Ok(_) => Ok(Json(flag)),
+        Err(_) => Err("Failed to reload feature flags".into()),
+    }
+}
+
+#[delete("/flags/<name>")]
+pub fn remove_flag(_admin: Admin, feature_flags: State<FeatureFlags>, name: String) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let rows_deleted = match db_connection.execute("delete from feature_flags where name = $1", params![name]) {
+        Ok(rows_deleted) => rows_deleted,
+        Err(_) => return Err("Failed to delete feature flag".into()),
+    };
+
+    match feature_flags.reload(&db_connection) {
+        Ok(_) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to reload feature flags".into()),
+    }
+}
@@ -0,0 +1,100 @@
+// Form-encoded bodies
+//
+// Next to json, the create / update routes accept application/x-www-form-urlencoded
+// bodies (item=Milk, name=Groceries), so a plain html form can post to them. Each
+// has a `format = "form"` twin of its json route that ends up in the same code.
+// The forms are read leniently, so the csrf_token field browsers send along (see
+// csrf.rs) does not make them fail.
+//
+// When the request comes from a browser page (it asks for html first) the answer is
+// a redirect back to the page with a flash message (post/redirect/get) instead of
+// json, so the browser does not show raw json and a reload does not post again.
+// Only /ui pages are redirected back to, anything else goes to /ui.
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Flash, Redirect, Responder};
+use rocket::{Outcome, Request};
+
+use crate::error::ApiError;
+
+const UI_PATH: &str = "/ui";
+
+// body of the item routes
+#[derive(FromForm)]
+pub struct ItemForm {
+    pub item: String,
+}
+
+// Request guard for requests from a browser page, forwards for api clients
+pub struct FromBrowser {
+    return_to: String,
+}
+
+// the path of a Referer header (which is a full url), when it is a /ui page
+fn referring_ui_page(referer: &str) -> Option<String> {
+    let after_scheme = referer.split("://").nth(1)?;
+    let path = &after_scheme[after_scheme.find('/')?..];
+    if path == UI_PATH || path.starts_with("/ui/") || path.starts_with("/ui?") {
+        Some(path.to_string())
+    } else {
+        None
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for FromBrowser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<FromBrowser, ()> {
+        let wants_html = request.accept().map_or(false, |accept| accept.preferred().is_html());
+        if !wants_html {
+            return Outcome::Forward(());
+        }
+
+        let return_to = request.headers().get_one("Referer")
+            .and_then(referring_ui_page)
+            .unwrap_or_else(|| UI_PATH.to_string());
+        Outcome::Success(FromBrowser { return_to })
+    }
+}
+
+pub enum FormResponse<R> {
+    Redirect(Box<Flash<Redirect>>),
+    Api(R),
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for FormResponse<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            FormResponse::Redirect(redirect) => (*redirect).respond_to(request),
+            FormResponse::Api(response) => response.respond_to(request),
+        }
+    }
+}
+
+// The api response for api clients. Browsers are sent back with a flash message:
+// `flash` tells from the result what to say, Err for an error message
+pub fn respond<R, F>(browser: Option<FromBrowser>, result: Result<R, ApiError>, flash: F) -> Result<FormResponse<R>, ApiError>
+where
+    F: FnOnce(&R) -> Result<String, String>,
+{
+    let browser = match browser {
+        Some(browser) => browser,
+        None => return result.map(FormResponse::Api),
+    };
+
+    let message = result.map_err(String::from).and_then(|response| flash(&response));
+    let redirect = Redirect::to(browser.return_to);
+    Ok(FormResponse::Redirect(Box::new(match message {
+        Ok(message) => Flash::success(redirect, message),
+        Err(e) => Flash::error(redirect, e),
+    })))
+}
+
+// for form routes that can not find what they should change
+pub fn not_found<R>(response: &Option<R>, found: &str) -> Result<String, String> {
+    match response {
+        Some(_) => Ok(found.to_string()),
+        None => Err(Status::NotFound.reason.to_string()),
+    }
+}
@@ -0,0 +1,31 @@
+// Health check for load balancers and monitoring
+//
+// GET /health needs no credentials and is never rate limited or shed. It answers
+// 200 while the database circuit (see breaker.rs) is closed or half-open and 503
+// while it is open. That is the main database's circuit, or with X-Tenant the
+// tenant's.
+
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::Json;
+use serde::Serialize;
+
+use crate::breaker::{self, CircuitState, CircuitStatus};
+
+#[derive(Serialize)]
+pub struct Health {
+    // "ok" or "unavailable"
+    status: &'static str,
+    database: CircuitStatus,
+}
+
+#[get("/health")]
+pub fn health() -> status::Custom<Json<Health>> {
+    let database = breaker::status();
+
+    if database.state == CircuitState::Open {
+        status::Custom(Status::ServiceUnavailable, Json(Health { status: "unavailable", database }))
+    } else {
+        status::Custom(Status::Ok, Json(Health { status: "ok", database }))
+    }
+}
@@ -0,0 +1,142 @@
+// Scheduled database housekeeping
+//
+// At vacuum_schedule (cron-like, see scheduler.rs, default every night at
+// 03:30 UTC) the database
+//   - gives the pages of deleted rows back to the file system (incremental vacuum),
+//     so the file does not keep growing after items are deleted
+//   - refreshes the statistics the query planner uses (ANALYZE)
+// An empty vacuum_schedule turns this off.
+//
+// The database runs in WAL mode (see db::init_schema), where writes go to a
+// separate -wal file first. sqlite copies them back into the database now and then,
+// but under steady traffic the -wal file never gets a quiet moment to be reset and
+// keeps its largest size. At checkpoint_schedule (default every five minutes) it is
+// copied back and truncated to zero; POST /admin/checkpoint does the same on demand.
+//
+// In multi-tenant mode (see tenants.rs) both jobs go through every tenant's database
+// after the main one. A database that fails is reported, the others are still done.
+
+use std::time::Instant;
+
+use rocket::fairing::AdHoc;
+use rocket::Rocket;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::scheduler::{self, Schedule};
+use crate::{db, metrics, tenants};
+
+const DEFAULT_VACUUM_SCHEDULE: &str = "30 3 * * *";
+const DEFAULT_CHECKPOINT_SCHEDULE: &str = "*/5 * * * *";
+
+// pragma auto_vacuum values
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+fn vacuum_and_analyze(db_connection: &Connection) -> Result<String, String> {
+    let auto_vacuum: i64 = db_connection.query_row("pragma auto_vacuum", rusqlite::NO_PARAMS, |row| row.get(0))
+        .map_err(|_| String::from("Failed to read the auto_vacuum mode"))?;
+
+    let vacuumed = if auto_vacuum == AUTO_VACUUM_INCREMENTAL {
+        db_connection.execute_batch("pragma incremental_vacuum;")
+    } else {
+        // databases created before incremental vacuum was switched on need one full
+        // vacuum to change over, after that only the incremental one runs
+        db_connection.execute_batch("pragma auto_vacuum = incremental; vacuum;")
+    };
+    vacuumed.map_err(|_| String::from("Failed to vacuum the database"))?;
+
+    db_connection.execute_batch("analyze;").map_err(|_| String::from("Failed to analyze the database"))?;
+
+    Ok("vacuumed and analyzed the database".into())
+}
+
+// The result of pragma wal_checkpoint, in pages (frames) of the -wal file
+#[derive(Serialize)]
+pub struct Checkpoint {
+    // another connection was in the way, the -wal file could not be fully copied
+    // back and was not truncated
+    pub busy: bool,
+    pub log_frames: i64,
+    pub checkpointed_frames: i64,
+}
+
+pub fn checkpoint(db_connection: &Connection) -> Result<Checkpoint, String> {
+    let started = Instant::now();
+    let checkpoint = db_connection
+        .query_row("pragma wal_checkpoint(truncate)", rusqlite::NO_PARAMS, |row| {
+            Ok(Checkpoint {
+                busy: row.get::<_, i64>(0)? != 0,
+                log_frames: row.get(1)?,
+                checkpointed_frames: row.get(2)?,
+            })
+        })
+        .map_err(|_| String::from("Failed to checkpoint the database"))?;
+
+    metrics::record_checkpoint(&checkpoint, started.elapsed());
+    Ok(checkpoint)
+}
+
+fn checkpoint_and_truncate(db_connection: &Connection) -> Result<String, String> {
+    let checkpoint = checkpoint(db_connection)?;
+    if checkpoint.busy {
+        return Err("the database was busy, will try again next time".into());
+    }
+    Ok(format!("copied {} pages back into the database", checkpoint.checkpointed_frames))
+}
+
+// Runs job on the main database and then on each tenant's
+fn on_every_database(job: fn(&Connection) -> Result<String, String>) -> Result<String, String> {
+    let mut databases = vec![db::DATABASE_FILE.to_string()];
+    databases.extend(tenants::databases()?);
+
+    let mut done = Vec::new();
+    let mut failed = Vec::new();
+    for database in databases {
+        match db::connect_to(&database).and_then(|db_connection| job(&db_connection)) {
+            Ok(message) => done.push(format!("{}: {}", database, message)),
+            Err(e) => failed.push(format!("{}: {}", database, e)),
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(done.join("; "))
+    } else {
+        Err(failed.join("; "))
+    }
+}
+
+fn scheduled_vacuum() -> Result<String, String> {
+    on_every_database(vacuum_and_analyze)
+}
+
+fn scheduled_checkpoint() -> Result<String, String> {
+    on_every_database(checkpoint_and_truncate)
+}
+
+// Starts job on the schedule in the config key. Err when the schedule is not valid
+fn schedule(rocket: &Rocket, key: &str, default: &str, name: &'static str, job: fn() -> Result<String, String>) -> Result<(), String> {
+    let schedule = rocket.config().get_string(key).unwrap_or_else(|_| default.into());
+    if schedule.trim().is_empty() {
+        return Ok(());
+    }
+
+    let schedule = schedule.parse::<Schedule>().map_err(|e| format!("{}: {}", key, e))?;
+    scheduler::spawn(name, schedule, job);
+    Ok(())
+}
+
+// Refuses to start the app when a schedule is not valid
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Database housekeeping", |rocket| {
+        let scheduled = schedule(&rocket, "vacuum_schedule", DEFAULT_VACUUM_SCHEDULE, "Database vacuum", scheduled_vacuum)
+            .and_then(|_| schedule(&rocket, "checkpoint_schedule", DEFAULT_CHECKPOINT_SCHEDULE, "WAL checkpoint", scheduled_checkpoint));
+
+        match scheduled {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                println!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,263 @@
+// Localized messages
+//
+// The messages of the api are written in English in the code. The catalog below
+// holds their translations per language; a message with a number or name in it is
+// listed with {} in its place ("{} rows inserted!") and the translation puts the
+// same values back in the same order.
+//
+// The language is picked from the request's Accept-Language header among the
+// catalog's languages, English when none of them fits. The Localize fairing then
+// translates the "message" of json responses and plain text error bodies, and
+// tells the client the language in Content-Language. Messages that are not in the
+// catalog stay English.
+
+use std::io::Cursor;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest};
+use rocket::response::Body;
+use rocket::{Outcome, Request, Response};
+use serde_json::Value;
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+// bodies larger than this are never a status message, so they are left alone
+const MAX_TRANSLATED_BODY: usize = 4096;
+
+type Catalog = &'static [(&'static str, &'static str)];
+
+const GERMAN: Catalog = &[
+    ("{} rows inserted!", "{} Zeilen eingefügt!"),
+    ("{} rows deleted", "{} Zeilen gelöscht"),
+    ("{} rows updated", "{} Zeilen aktualisiert"),
+    ("Item added", "Eintrag hinzugefügt"),
+    ("Item saved", "Eintrag gespeichert"),
+    ("Item completed", "Eintrag erledigt"),
+    ("Item reopened", "Eintrag wieder geöffnet"),
+    ("Item deleted", "Eintrag gelöscht"),
+    ("List added", "Liste hinzugefügt"),
+    ("List renamed", "Liste umbenannt"),
+    ("The item can not be empty", "Der Eintrag darf nicht leer sein"),
+    ("The item contains a blocked word", "Der Eintrag enthält ein gesperrtes Wort"),
+    ("The item can be at most {} characters long", "Der Eintrag darf höchstens {} Zeichen lang sein"),
+    ("Tag name can not be empty", "Der Name des Tags darf nicht leer sein"),
+    ("An open item with this text already exists", "Es gibt schon einen offenen Eintrag mit diesem Text"),
+    ("Failed to connect to database", "Keine Verbindung zur Datenbank"),
+    ("Failed to prepare a query", "Die Abfrage konnte nicht vorbereitet werden"),
+    ("Failed to start a transaction", "Die Transaktion konnte nicht gestartet werden"),
+    ("Failed to fetch ToDo Items", "Die Einträge konnten nicht geladen werden"),
+    ("Failed to fetch ToDo Item", "Der Eintrag konnte nicht geladen werden"),
+    ("Failed to insert ToDo Item", "Der Eintrag konnte nicht gespeichert werden"),
+    ("Failed to update ToDo Item", "Der Eintrag konnte nicht geändert werden"),
+    ("Failed to delete ToDo Item", "Der Eintrag konnte nicht gelöscht werden"),
+    ("Failed to fetch list", "Die Liste konnte nicht geladen werden"),
+    ("Failed to insert list", "Die Liste konnte nicht gespeichert werden"),
+    ("Failed to update list", "Die Liste konnte nicht geändert werden"),
+    ("Failed to delete list", "Die Liste konnte nicht gelöscht werden"),
+    ("Encrypted ToDo Item is corrupt", "Der verschlüsselte Eintrag ist beschädigt"),
+    ("Admin access required", "Nur für Administratoren"),
+    ("Too many requests, please slow down", "Zu viele Anfragen, bitte etwas langsamer"),
+    ("The server is busy, please try again in a moment", "Der Server ist ausgelastet, bitte gleich noch einmal versuchen"),
+    ("The database is unavailable, please try again later", "Die Datenbank ist nicht erreichbar, bitte später noch einmal versuchen"),
+    ("Requests from your address are not allowed", "Anfragen von Ihrer Adresse sind nicht erlaubt"),
+    ("This share link has expired or was revoked", "Dieser Link ist abgelaufen oder wurde zurückgezogen"),
+    ("Request body is too large", "Die Anfrage ist zu groß"),
+    ("Missing or invalid CSRF token, reload the page and try again",
+        "CSRF-Token fehlt oder ist ungültig, bitte die Seite neu laden und noch einmal versuchen"),
+];
+
+const SPANISH: Catalog = &[
+    ("{} rows inserted!", "¡{} filas insertadas!"),
+    ("{} rows deleted", "{} filas eliminadas"),
+    ("{} rows updated", "{} filas actualizadas"),
+    ("Item added", "Tarea añadida"),
+    ("Item saved", "Tarea guardada"),
+    ("Item completed", "Tarea completada"),
+    ("Item reopened", "Tarea reabierta"),
+    ("Item deleted", "Tarea eliminada"),
+    ("List added", "Lista añadida"),
+    ("List renamed", "Lista renombrada"),
+    ("The item can not be empty", "La tarea no puede estar vacía"),
+    ("The item contains a blocked word", "La tarea contiene una palabra bloqueada"),
+    ("The item can be at most {} characters long", "La tarea puede tener como máximo {} caracteres"),
+    ("Tag name can not be empty", "El nombre de la etiqueta no puede estar vacío"),
+    ("An open item with this text already exists", "Ya existe una tarea abierta con este texto"),
+    ("Failed to connect to database", "No se pudo conectar con la base de datos"),
+    ("Failed to prepare a query", "No se pudo preparar la consulta"),
+    ("Failed to start a transaction", "No se pudo iniciar la transacción"),
+    ("Failed to fetch ToDo Items", "No se pudieron cargar las tareas"),
+    ("Failed to fetch ToDo Item", "No se pudo cargar la tarea"),
+    ("Failed to insert ToDo Item", "No se pudo guardar la tarea"),
+    ("Failed to update ToDo Item", "No se pudo modificar la tarea"),
+    ("Failed to delete ToDo Item", "No se pudo eliminar la tarea"),
+    ("Failed to fetch list", "No se pudo cargar la lista"),
+    ("Failed to insert list", "No se pudo guardar la lista"),
+    ("Failed to update list", "No se pudo modificar la lista"),
+    ("Failed to delete list", "No se pudo eliminar la lista"),
+    ("Encrypted ToDo Item is corrupt", "La tarea cifrada está dañada"),
+    ("Admin access required", "Se requiere acceso de administrador"),
+    ("Too many requests, please slow down", "Demasiadas solicitudes, más despacio por favor"),
+    ("The server is busy, please try again in a moment", "El servidor está ocupado, inténtelo de nuevo en un momento"),
+    ("The database is unavailable, please try again later", "La base de datos no está disponible, inténtelo más tarde"),
+    ("Requests from your address are not allowed", "No se permiten solicitudes desde su dirección"),
+    ("This share link has expired or was revoked", "Este enlace ha caducado o fue revocado"),
+    ("Request body is too large", "El cuerpo de la solicitud es demasiado grande"),
+    ("Missing or invalid CSRF token, reload the page and try again",
+        "Falta el token CSRF o no es válido, recargue la página e inténtelo de nuevo"),
+];
+
+const CATALOGS: [(&str, Catalog); 2] = [("de", GERMAN), ("es", SPANISH)];
+
+// The values in place of the {}s when message has the shape of template
+fn match_template<'m>(template: &str, message: &'m str) -> Option<Vec<&'m str>> {
+    let mut parts = template.split("{}");
+    let first = parts.next().unwrap_or("");
+    let mut rest = message.strip_prefix(first)?;
+    let mut values = Vec::new();
+
+    let parts: Vec<&str> = parts.collect();
+    for (index, part) in parts.iter().enumerate() {
+        let last = index == parts.len() - 1;
+        let end = if last {
+            if !rest.ends_with(part) {
+                return None;
+            }
+            rest.len() - part.len()
+        } else if part.is_empty() {
+            return None;
+        } else {
+            rest.find(part)?
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + part.len()..];
+    }
+
+    if parts.is_empty() && !rest.is_empty() {
+        return None;
+    }
+    Some(values)
+}
+
+fn fill_in(translation: &str, values: &[&str]) -> String {
+    let mut values = values.iter();
+    let mut parts = translation.split("{}");
+    let mut text = parts.next().unwrap_or("").to_string();
+    for part in parts {
+        text.push_str(values.next().copied().unwrap_or(""));
+        text.push_str(part);
+    }
+    text
+}
+
+// The message in the language, the message itself when it is not in the catalog
+pub fn translate(language: &str, message: &str) -> String {
+    let catalog = match CATALOGS.iter().find(|(code, _)| *code == language) {
+        Some((_, catalog)) => catalog,
+        None => return message.to_string(),
+    };
+
+    for (template, translation) in catalog.iter() {
+        if let Some(values) = match_template(template, message) {
+            return fill_in(translation, &values);
+        }
+    }
+    message.to_string()
+}
+
+fn is_supported(language: &str) -> bool {
+    language == DEFAULT_LANGUAGE || CATALOGS.iter().any(|(code, _)| *code == language)
+}
+
+// "de-CH, fr;q=0.8, en;q=0.5" -> the supported language with the highest weight.
+// Only the primary tag is compared, so de-CH counts as de
+fn negotiate(accept_language: &str) -> &'static str {
+    let mut best: Option<(&str, f32)> = None;
+
+    for range in accept_language.split(',') {
+        let mut fields = range.split(';');
+        let tag = fields.next().unwrap_or("").trim();
+        let primary = tag.split('-').next().unwrap_or("").to_lowercase();
+        let weight = fields
+            .filter_map(|field| field.trim().strip_prefix("q="))
+            .filter_map(|q| q.parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if weight <= 0.0 || !is_supported(&primary) {
+            continue;
+        }
+        if best.map_or(true, |(_, best_weight)| weight > best_weight) {
+            let code = CATALOGS.iter().map(|(code, _)| *code).find(|code| *code == primary).unwrap_or(DEFAULT_LANGUAGE);
+            best = Some((code, weight));
+        }
+    }
+
+    best.map_or(DEFAULT_LANGUAGE, |(code, _)| code)
+}
+
+// Request guard with the language of the response, e.g. for the /ui pages
+pub struct Language(pub &'static str);
+
+fn request_language(request: &Request) -> &'static str {
+    request.headers().get_one("Accept-Language").map_or(DEFAULT_LANGUAGE, negotiate)
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Language {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Language, ()> {
+        Outcome::Success(Language(request_language(request)))
+    }
+}
+
+// {"message": "..."} with the message translated, None for any other json
+fn translate_json(language: &str, body: &str) -> Option<String> {
+    let mut json: Value = serde_json::from_str(body).ok()?;
+    let message = json.get("message")?.as_str()?;
+    let translated = translate(language, message);
+    json["message"] = Value::String(translated);
+    serde_json::to_string(&json).ok()
+}
+
+pub struct Localize;
+
+impl Fairing for Localize {
+    fn info(&self) -> Info {
+        Info {
+            name: "Localized messages",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let language = request_language(request);
+        response.set_raw_header("Content-Language", language);
+        if language == DEFAULT_LANGUAGE {
+            return;
+        }
+
+        let content_type = response.content_type();
+        let is_json = content_type.as_ref().map_or(false, |content_type| content_type.is_json());
+        let is_text = content_type.as_ref().map_or(false, |content_type| content_type.is_plain());
+        if !is_json && !is_text {
+            return;
+        }
+
+        // the body has to be read to be translated, so only small bodies are
+        let small = matches!(response.body(), Some(Body::Sized(_, size)) if size <= MAX_TRANSLATED_BODY as u64);
+        if !small {
+            return;
+        }
+        let body = match response.body_string() {
+            Some(body) => body,
+            None => return,
+        };
+
+        let translated = if is_json {
+            translate_json(language, &body).unwrap_or(body)
+        } else {
+            translate(language, &body)
+        };
+        response.set_sized_body(Cursor::new(translated));
+    }
+}
@@ -0,0 +1,517 @@
+// Imports from other todo apps
+//
+// POST /import/todoist takes a Todoist export and adds its contents:
+//   - a backup or sync api dump (json): {"projects": [...], "items": [...], "labels": [...]}.
+//     Projects become lists (found by name, created when missing), items their items,
+//     labels (names, or label ids of the older format) tags, checked items are done
+//   - a project template (csv, TYPE,CONTENT,DESCRIPTION,PRIORITY,INDENT,...,DATE,...).
+//     The file is one project, its list is ?list= (Todoist when left out). Only the
+//     task rows are items; @label words in CONTENT become tags
+// json is recognized by the content type or by the body starting with {. Priority 1
+// (the highest, 4 in the json) pins the item. Due dates are read when they are a
+// date or date-time; the recurring / natural language ones of the csv ("every
+// monday") are dropped with a warning. Sub tasks become items of the same list.
+//
+// Everything is added in one transaction. The response says what was added and what
+// was skipped and why:
+//
+//     {"lists": 2, "items": 41, "tags": 5,
+//      "skipped": [{"entry": "section Groceries", "reason": "sections are not imported"}],
+//      "warnings": ["Due date \"every monday\" of Water the plants is not a date, left out"]}
+//
+// POST /import/trello takes the json export of a Trello board (Menu > Print, export
+// and share > Export as JSON), in the same way and with the same response:
+//   - lists (the board's columns) become lists, found by name or created
+//   - cards become items of their list, in the board's order. Their labels are tags
+//     (the label color when the label has no name), the due date is due_at and a
+//     card marked complete is done. The description and the comments of a card
+//     become comments of the item
+//   - checklist items become items of the same list, right after their card, and
+//     the card depends on them (see dependencies.rs)
+// Archived lists and cards are skipped.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use rocket::http::ContentType;
+use rocket_contrib::json::Json;
+use rusqlite::{params, Transaction};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedText;
+use crate::timezone::{TimeZone, UserTimeZone};
+use crate::{clock, crypto, lists, quotas, tags, validation, writer};
+
+const DEFAULT_TODOIST_LIST: &str = "Todoist";
+
+#[derive(Serialize)]
+pub struct Skipped {
+    entry: String,
+    reason: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    lists: usize,
+    items: usize,
+    tags: usize,
+    skipped: Vec<Skipped>,
+    warnings: Vec<String>,
+}
+
+impl ImportReport {
+    fn skip<E: Into<String>, R: Into<String>>(&mut self, entry: E, reason: R) {
+        self.skipped.push(Skipped { entry: entry.into(), reason: reason.into() });
+    }
+}
+
+// An item to insert. text has been through validation::item_text
+pub struct NewItem<'a> {
+    pub text: &'a str,
+    pub done: bool,
+    pub pinned: bool,
+    pub list_id: Option<i64>,
+    pub created_at: Option<i64>,
+    pub completed_at: Option<i64>,
+    pub due_at: Option<i64>,
+    pub tags: &'a [String],
+}
+
+// Inserts an item at the end of its list, with its tags. Returns its id
+pub fn insert_item(transaction: &Transaction, item: &NewItem) -> Result<i64, ApiError> {
+    let text = crypto::encrypt_text(item.text)?;
+    let status = if item.done { "done" } else { "todo" };
+
+    transaction.execute(
+        "insert into todo_list (id, item, done, status, pinned, list_id, position, created_at, completed_at, due_at)
+         values (null, $1, $2, $3, $4, $5,
+             case when $5 is null then null else (select coalesce(max(position), 0) + 1 from todo_list where list_id = $5) end,
+             $6, $7, $8)",
+        params![text, item.done, status, item.pinned, item.list_id, item.created_at, item.completed_at, item.due_at])
+        .map_err(|_| ApiError::from("Failed to insert ToDo Item"))?;
+
+    let item_id = transaction.last_insert_rowid();
+    for name in item.tags.iter().map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        tags::tag_item(transaction, item_id, name).map_err(|_| ApiError::from("Failed to tag item"))?;
+    }
+    Ok(item_id)
+}
+
+fn tag_count(transaction: &Transaction) -> Result<usize, ApiError> {
+    transaction.query_row("select count(*) from tags", rusqlite::NO_PARAMS, |row| row.get::<_, i64>(0))
+        .map(|count| count as usize)
+        .map_err(|_| ApiError::from("Failed to count tags"))
+}
+
+fn list_id(transaction: &Transaction, name: &str, report: &mut ImportReport) -> Result<i64, ApiError> {
+    let (list, created) = lists::find_or_insert_list(transaction, name)
+        .map_err(|_| ApiError::from("Failed to insert list"))?;
+    if created {
+        report.lists += 1;
+    }
+    Ok(list.id())
+}
+
+// A due date as Todoist writes it: a date (due at the end of that day), a date-time
+// with an offset, or a "floating" date-time in the user's time zone
+fn due_date(text: &str, timezone: TimeZone) -> Option<i64> {
+    if let Some(day) = clock::parse_date(text) {
+        return Some(timezone.start_of_day(day + 1) - 1);
+    }
+    clock::parse_rfc3339(text).or_else(|| clock::parse_rfc3339(&format!("{}{}", text, timezone.name())))
+}
+
+fn entries<'a>(value: &'a Value, name: &str) -> Vec<&'a Value> {
+    value.get(name).and_then(Value::as_array).map(|entries| entries.iter().collect()).unwrap_or_default()
+}
+
+// Splits csv (RFC 4180: quoted fields with "" for a quote, line breaks allowed inside
+// quotes) into rows of fields
+fn csv_rows(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => row.push(std::mem::take(&mut field)),
+            ('\r', false) => (),
+            ('\n', false) => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+fn import_todoist_csv(transaction: &Transaction, body: &str, list: &str, timezone: TimeZone, report: &mut ImportReport) -> Result<(), ApiError> {
+    let mut rows = csv_rows(body).into_iter();
+    let header: Vec<String> = match rows.next() {
+        Some(header) => header.iter().map(|name| name.trim().to_uppercase()).collect(),
+        None => return Err(ApiError::new(ErrorCode::ValidationFailed, "The csv file is empty")),
+    };
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let (type_column, content_column) = match (column("TYPE"), column("CONTENT")) {
+        (Some(type_column), Some(content_column)) => (type_column, content_column),
+        _ => return Err(ApiError::new(ErrorCode::ValidationFailed,
+            "This is not a Todoist csv export, it needs TYPE and CONTENT columns")),
+    };
+    let priority_column = column("PRIORITY");
+    let date_column = column("DATE");
+
+    let list_id = list_id(transaction, list, report)?;
+
+    for row in rows.filter(|row| row.iter().any(|field| !field.trim().is_empty())) {
+        let field = |index: Option<usize>| index.and_then(|index| row.get(index)).map(|field| field.trim()).unwrap_or("");
+        let kind = field(Some(type_column));
+        let content = field(Some(content_column));
+
+        if kind != "task" {
+            let kind = if kind.is_empty() { "row" } else { kind };
+            report.skip(format!("{} {}", kind, content), format!("{}s are not imported", kind));
+            continue;
+        }
+
+        // @label words are tags, the rest is the text
+        let (labels, words): (Vec<&str>, Vec<&str>) = content.split_whitespace()
+            .partition(|word| word.len() > 1 && word.starts_with('@'));
+        let text = words.join(" ");
+        let labels: Vec<String> = labels.iter().map(|label| label[1..].to_string()).collect();
+        let text = match validation::item_text(&text) {
+            Ok(text) => text,
+            Err(e) => {
+                report.skip(format!("task {}", content), e.message);
+                continue;
+            }
+        };
+
+        let date = field(date_column);
+        let due_at = if date.is_empty() { None } else { due_date(date, timezone) };
+        if !date.is_empty() && due_at.is_none() {
+            report.warnings.push(format!("Due date \"{}\" of {} is not a date, left out", date, text));
+        }
+
+        let item = NewItem {
+            text: &text,
+            done: false,
+            pinned: field(priority_column) == "1",
+            list_id: Some(list_id),
+            created_at: None,
+            completed_at: None,
+            due_at,
+            tags: &labels,
+        };
+        insert_item(transaction, &item)?;
+        report.items += 1;
+    }
+    Ok(())
+}
+
+fn import_todoist_json(transaction: &Transaction, body: &str, timezone: TimeZone, report: &mut ImportReport) -> Result<(), ApiError> {
+    let export: Value = match serde_json::from_str(body) {
+        Ok(export) => export,
+        Err(e) => return Err(ApiError::new(ErrorCode::InvalidJson, e.to_string())),
+    };
+
+    // ids are numbers in older exports and strings in newer ones
+    let id = |value: &Value| value.get("id").map(|id| id.to_string().trim_matches('"').to_string());
+
+    let labels: HashMap<String, String> = entries(&export, "labels").into_iter()
+        .filter_map(|label| Some((id(label)?, label.get("name")?.as_str()?.to_string())))
+        .collect();
+
+    let mut projects: HashMap<String, i64> = HashMap::new();
+    for project in entries(&export, "projects") {
+        match (id(project), project.get("name").and_then(Value::as_str)) {
+            (Some(project_id), Some(name)) => {
+                projects.insert(project_id, list_id(transaction, name, report)?);
+            }
+            _ => report.skip("project", "it has no id or name"),
+        }
+    }
+
+    for item in entries(&export, "items") {
+        let content = item.get("content").and_then(Value::as_str).unwrap_or("");
+        let text = match validation::item_text(content) {
+            Ok(text) => text,
+            Err(e) => {
+                report.skip(format!("item {}", content), e.message);
+                continue;
+            }
+        };
+        let project = item.get("project_id").map(|id| id.to_string().trim_matches('"').to_string());
+        let list_id = match project.and_then(|project| projects.get(&project).copied()) {
+            Some(list_id) => list_id,
+            None => {
+                report.skip(format!("item {}", content), "its project is not in the export");
+                continue;
+            }
+        };
+
+        let tags: Vec<String> = item.get("labels").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(|label| match label {
+                Value::String(name) => Some(name.clone()),
+                other => labels.get(&other.to_string()).cloned(),
+            })
+            .collect();
+
+        let due = item.get("due").and_then(|due| due.get("date")).and_then(Value::as_str);
+        let due_at = due.and_then(|due| due_date(due, timezone));
+        if let (Some(due), None) = (due, due_at) {
+            report.warnings.push(format!("Due date \"{}\" of {} is not a date, left out", due, content));
+        }
+
+        // checked is a bool in newer exports and 0 / 1 in older ones
+        let done = match item.get("checked") {
+            Some(Value::Bool(checked)) => *checked,
+            Some(Value::Number(checked)) => checked.as_i64() == Some(1),
+            _ => false,
+        };
+        let completed_at = item.get("completed_at").and_then(Value::as_str).and_then(clock::parse_rfc3339);
+        let created_at = item.get("added_at").or_else(|| item.get("date_added"))
+            .and_then(Value::as_str).and_then(clock::parse_rfc3339);
+
+        let new_item = NewItem {
+            text: &text,
+            done,
+            pinned: item.get("priority").and_then(Value::as_i64) == Some(4),
+            list_id: Some(list_id),
+            created_at,
+            completed_at: if done { completed_at } else { None },
+            due_at,
+            tags: &tags,
+        };
+        insert_item(transaction, &new_item)?;
+        report.items += 1;
+    }
+    Ok(())
+}
+
+// Runs an import in one transaction and counts the tags it created
+fn run_import<F>(import: F) -> Result<Json<ImportReport>, ApiError>
+    where F: FnOnce(&Transaction, &mut ImportReport) -> Result<(), ApiError> + Send + 'static
+{
+    writer::write(move |db_connection| {
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        let mut report = ImportReport::default();
+        let tags_before = tag_count(&transaction)?;
+        import(&transaction, &mut report)?;
+        report.tags = tag_count(&transaction)? - tags_before;
+        quotas::check_items(&transaction, 0)?;
+
+        match transaction.commit() {
+            Ok(_) => Ok(Json(report)),
+            Err(_) => Err("Failed to import the items".into()),
+        }
+    })?
+}
+
+#[post("/import/todoist?<list>", data = "<body>")]
+pub fn import_todoist(list: Option<String>, body: LimitedText, content_type: Option<&ContentType>, timezone: UserTimeZone) -> Result<Json<ImportReport>, ApiError> {
+
+    let timezone = timezone.resolve()?;
+    let is_json = content_type.map_or(false, |content_type| content_type.is_json())
+        || body.0.trim_start().starts_with('{');
+
+    run_import(move |transaction, report| {
+        if is_json {
+            import_todoist_json(transaction, &body.0, timezone, report)
+        } else {
+            let list = list.as_deref().map(str::trim).filter(|list| !list.is_empty()).unwrap_or(DEFAULT_TODOIST_LIST);
+            import_todoist_csv(transaction, &body.0, list, timezone, report)
+        }
+    })
+}
+
+fn add_comment(transaction: &Transaction, item_id: i64, body: &str, created_at: i64) -> Result<(), ApiError> {
+    let body = crypto::encrypt_text(&validation::comment_body(body)?)?;
+    transaction.execute(
+        "insert into comments (id, item_id, body, created_at) values (null, $1, $2, $3)",
+        params![item_id, body, created_at])
+        .map(|_| ())
+        .map_err(|_| ApiError::from("Failed to insert comment"))
+}
+
+// Trello ids start with the unix time they were made at, in hex
+fn trello_created_at(id: &str) -> Option<i64> {
+    if id.len() != 24 {
+        return None;
+    }
+    id.get(..8).and_then(|seconds| i64::from_str_radix(seconds, 16).ok())
+}
+
+// entries of the export in the board's order
+fn by_position(entries: &mut [&Value]) {
+    let position = |entry: &Value| entry.get("pos").and_then(Value::as_f64).unwrap_or(0.0);
+    entries.sort_by(|a, b| position(a).partial_cmp(&position(b)).unwrap_or(Ordering::Equal));
+}
+
+fn import_trello_json(transaction: &Transaction, body: &str, report: &mut ImportReport) -> Result<(), ApiError> {
+    let board: Value = match serde_json::from_str(body) {
+        Ok(board) => board,
+        Err(e) => return Err(ApiError::new(ErrorCode::InvalidJson, e.to_string())),
+    };
+    if board.get("lists").and_then(Value::as_array).is_none() || board.get("cards").and_then(Value::as_array).is_none() {
+        return Err(ApiError::new(ErrorCode::ValidationFailed,
+            "This is not a Trello board export, it needs lists and cards"));
+    }
+    let text = |value: &Value, name: &str| value.get(name).and_then(Value::as_str).unwrap_or("").to_string();
+    let closed = |value: &Value| value.get("closed").and_then(Value::as_bool).unwrap_or(false);
+
+    let labels: HashMap<String, String> = entries(&board, "labels").into_iter()
+        .map(|label| {
+            let name = text(label, "name");
+            (text(label, "id"), if name.trim().is_empty() { text(label, "color") } else { name })
+        })
+        .collect();
+
+    // None for the archived lists, whose cards are skipped
+    let mut lists: HashMap<String, Option<i64>> = HashMap::new();
+    let mut columns = entries(&board, "lists");
+    by_position(&mut columns);
+    for column in columns {
+        let name = text(column, "name");
+        if closed(column) {
+            report.skip(format!("list {}", name), "it is archived in Trello");
+            lists.insert(text(column, "id"), None);
+        } else if name.trim().is_empty() {
+            report.skip("list", "it has no name");
+            lists.insert(text(column, "id"), None);
+        } else {
+            lists.insert(text(column, "id"), Some(list_id(transaction, name.trim(), report)?));
+        }
+    }
+
+    let mut checklists: HashMap<String, Vec<&Value>> = HashMap::new();
+    for checklist in entries(&board, "checklists") {
+        checklists.entry(text(checklist, "idCard")).or_default().push(checklist);
+    }
+    // comments are actions, newest first
+    let mut comments: HashMap<String, Vec<(String, i64)>> = HashMap::new();
+    for action in entries(&board, "actions").into_iter().rev().filter(|action| text(action, "type") == "commentCard") {
+        let data = action.get("data").cloned().unwrap_or(Value::Null);
+        let card = data.get("card").map(|card| text(card, "id")).unwrap_or_default();
+        let created_at = clock::parse_rfc3339(&text(action, "date")).unwrap_or_else(clock::now);
+        comments.entry(card).or_default().push((text(&data, "text"), created_at));
+    }
+
+    let mut cards = entries(&board, "cards");
+    by_position(&mut cards);
+    for card in cards {
+        let name = text(card, "name");
+        let list_id = match lists.get(&text(card, "idList")) {
+            Some(Some(list_id)) => *list_id,
+            Some(None) => {
+                report.skip(format!("card {}", name), "its list is not imported");
+                continue;
+            }
+            None => {
+                report.skip(format!("card {}", name), "its list is not in the export");
+                continue;
+            }
+        };
+        if closed(card) {
+            report.skip(format!("card {}", name), "it is archived in Trello");
+            continue;
+        }
+        let item_text = match validation::item_text(&name) {
+            Ok(item_text) => item_text,
+            Err(e) => {
+                report.skip(format!("card {}", name), e.message);
+                continue;
+            }
+        };
+
+        let tags: Vec<String> = card.get("idLabels").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(|id| id.as_str().and_then(|id| labels.get(id)).cloned())
+            .collect();
+        let due = text(card, "due");
+        let due_at = clock::parse_rfc3339(&due);
+        if !due.is_empty() && due_at.is_none() {
+            report.warnings.push(format!("Due date \"{}\" of {} is not a date, left out", due, name));
+        }
+        let done = card.get("dueComplete").and_then(Value::as_bool).unwrap_or(false);
+        let created_at = trello_created_at(&text(card, "id"));
+
+        let card_id = insert_item(transaction, &NewItem {
+            text: &item_text,
+            done,
+            pinned: false,
+            list_id: Some(list_id),
+            created_at,
+            completed_at: None,
+            due_at,
+            tags: &tags,
+        })?;
+        report.items += 1;
+
+        let description = text(card, "desc");
+        if !description.trim().is_empty() {
+            add_comment(transaction, card_id, description.trim(), created_at.unwrap_or_else(clock::now))?;
+        }
+        for (comment, created_at) in comments.remove(&text(card, "id")).unwrap_or_default() {
+            if !comment.trim().is_empty() {
+                add_comment(transaction, card_id, comment.trim(), created_at)?;
+            }
+        }
+
+        let mut card_checklists = checklists.remove(&text(card, "id")).unwrap_or_default();
+        by_position(&mut card_checklists);
+        for checklist in card_checklists {
+            let mut check_items = entries(checklist, "checkItems");
+            by_position(&mut check_items);
+            for check_item in check_items {
+                let name = text(check_item, "name");
+                let check_text = match validation::item_text(&name) {
+                    Ok(check_text) => check_text,
+                    Err(e) => {
+                        report.skip(format!("checklist item {}", name), e.message);
+                        continue;
+                    }
+                };
+                let check_id = insert_item(transaction, &NewItem {
+                    text: &check_text,
+                    done: text(check_item, "state") == "complete",
+                    pinned: false,
+                    list_id: Some(list_id),
+                    created_at: trello_created_at(&text(check_item, "id")),
+                    completed_at: None,
+                    due_at: clock::parse_rfc3339(&text(check_item, "due")),
+                    tags: &[],
+                })?;
+                report.items += 1;
+
+                transaction.execute(
+                    "insert or ignore into item_dependencies (item_id, depends_on_id) values ($1, $2)",
+                    params![card_id, check_id])
+                    .map_err(|_| ApiError::from("Failed to insert dependency"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[post("/import/trello", data = "<body>")]
+pub fn import_trello(body: LimitedText) -> Result<Json<ImportReport>, ApiError> {
+    run_import(move |transaction, report| import_trello_json(transaction, &body.0, report))
+}
@@ -0,0 +1,143 @@
+// Database integrity check
+//
+// POST /admin/integrity-check runs "pragma integrity_check" and
+// "pragma foreign_key_check" on a background thread, both read the whole database
+// and take a while on a big file. The request answers 202 right away with the check
+// marked running; GET /admin/integrity-check returns the latest report once it is
+// done. Only one check runs at a time, starting another while one is running just
+// returns the running one.
+
+use std::sync::Mutex;
+use std::thread;
+
+use rocket::response::status;
+use rocket_contrib::json::Json;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::auth::Admin;
+use crate::{clock, db};
+
+// sqlite stops listing problems after this many
+const MAX_PROBLEMS: i64 = 100;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckStatus {
+    Running,
+    // no problems found
+    Ok,
+    // the database has problems, see the lists
+    Failed,
+    // the check itself could not run, see error
+    Error,
+}
+
+// A row that points at a missing parent row
+#[derive(Serialize, Clone)]
+pub struct ForeignKeyViolation {
+    table: String,
+    // null for tables without a rowid
+    rowid: Option<i64>,
+    parent: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct IntegrityReport {
+    status: CheckStatus,
+    started_at: i64,
+    finished_at: Option<i64>,
+    // what integrity_check reported, e.g. "row 12 missing from index ..."
+    problems: Vec<String>,
+    foreign_key_violations: Vec<ForeignKeyViolation>,
+    error: Option<String>,
+}
+
+static LATEST: Mutex<Option<IntegrityReport>> = Mutex::new(None);
+
+fn latest() -> std::sync::MutexGuard<'static, Option<IntegrityReport>> {
+    LATEST.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn integrity_problems(db_connection: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut statement = db_connection.prepare(&format!("pragma integrity_check({})", MAX_PROBLEMS))?;
+    let rows = statement.query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?;
+    let messages = rows.collect::<rusqlite::Result<Vec<String>>>()?;
+
+    // a healthy database gives a single row "ok"
+    Ok(messages.into_iter().filter(|message| message != "ok").collect())
+}
+
+fn foreign_key_violations(db_connection: &Connection) -> rusqlite::Result<Vec<ForeignKeyViolation>> {
+    let mut statement = db_connection.prepare("pragma foreign_key_check")?;
+    let rows = statement.query_map(rusqlite::NO_PARAMS, |row| {
+        Ok(ForeignKeyViolation {
+            table: row.get(0)?,
+            rowid: row.get(1)?,
+            parent: row.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn run_check(report: &mut IntegrityReport, database: &str) -> Result<(), String> {
+    let db_connection = db::connect_to(database)?;
+    report.problems = integrity_problems(&db_connection)
+        .map_err(|_| String::from("Failed to run the integrity check"))?;
+    report.foreign_key_violations = foreign_key_violations(&db_connection)
+        .map_err(|_| String::from("Failed to run the foreign key check"))?;
+    Ok(())
+}
+
+fn check(mut report: IntegrityReport, database: String) {
+    report.status = match run_check(&mut report, &database) {
+        Ok(_) if report.problems.is_empty() && report.foreign_key_violations.is_empty() => CheckStatus::Ok,
+        Ok(_) => CheckStatus::Failed,
+        Err(e) => {
+            report.error = Some(e);
+            CheckStatus::Error
+        }
+    };
+    report.finished_at = Some(clock::now());
+
+    if report.status != CheckStatus::Ok {
+        println!("Database integrity check: {} problems, {} foreign key violations{}",
+            report.problems.len(),
+            report.foreign_key_violations.len(),
+            report.error.as_ref().map(|e| format!(", {}", e)).unwrap_or_default());
+    }
+
+    *latest() = Some(report);
+}
+
+#[post("/integrity-check")]
+pub fn start_check(_admin: Admin) -> status::Accepted<Json<IntegrityReport>> {
+    let mut latest = latest();
+    if let Some(report) = latest.as_ref().filter(|report| report.status == CheckStatus::Running) {
+        return status::Accepted(Some(Json(report.clone())));
+    }
+
+    let report = IntegrityReport {
+        status: CheckStatus::Running,
+        started_at: clock::now(),
+        finished_at: None,
+        problems: Vec::new(),
+        foreign_key_violations: Vec::new(),
+        error: None,
+    };
+    *latest = Some(report.clone());
+    drop(latest);
+
+    // the check runs on its own thread, which does not know the request's tenant
+    let running = report.clone();
+    let database = db::database_file();
+    thread::spawn(move || check(running, database));
+
+    status::Accepted(Some(Json(report)))
+}
+
+// The running or last finished check, 404 before the first one
+#[get("/integrity-check")]
+pub fn fetch_check(_admin: Admin) -> Option<Json<IntegrityReport>> {
+    latest().clone().map(Json)
+}
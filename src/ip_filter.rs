@@ -0,0 +1,307 @@
+// IP allowlist / denylist
+//
+// Configured with CIDR ranges in Rocket.toml:
+//
+//     [global]
+//     ip_allowlist = ["10.0.0.0/8", "192.168.1.0/24"]   # empty or missing = everyone
+//     ip_denylist = ["10.0.0.13/32"]
+//     trusted_proxies = ["127.0.0.1/32"]
+//
+// The denylist wins over the allowlist. When the request comes from a trusted proxy
+// the client address is taken from X-Forwarded-For instead of the socket.
+//
+// The check runs in a fairing, before routing, so a blocked request never reaches a
+// handler. Rocket 0.4 fairings can not answer a request themselves, so a blocked
+// request is rewritten to GET /__blocked which answers 403.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::{Data, Request, Rocket, State};
+use crate::error::{ApiError, ErrorCode};
+
+const BLOCKED_PATH: &str = "/__blocked";
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+// v4 addresses as they appear in a v6 socket (::ffff:1.2.3.4) are treated as v4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u128::MAX << (128 - self.prefix) };
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// "10.0.0.0/8", or a single address which is the same as /32 (/128 for v6)
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid CIDR range {}", text);
+
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+
+        let network = canonical(address.trim().parse::<IpAddr>().map_err(|_| invalid())?);
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+pub struct IpRules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    trusted_proxies: Vec<Cidr>,
+}
+
+// The address of the client that made the request, as determined by client_ip().
+// Cached per request so later guards and fairings see the same answer
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+fn in_any(ranges: &[Cidr], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+impl IpRules {
+    fn from_rocket(rocket: &Rocket) -> Result<IpRules, String> {
+        Ok(IpRules {
+            allow: cidr_list(rocket, "ip_allowlist")?,
+            deny: cidr_list(rocket, "ip_denylist")?,
+            trusted_proxies: cidr_list(rocket, "trusted_proxies")?,
+        })
+    }
+
+    pub fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        let remote = request.remote()?.ip();
+        if !in_any(&self.trusted_proxies, remote) {
+            return Some(remote);
+        }
+
+        Some(self.forwarded_client(request.headers().get("X-Forwarded-For")).unwrap_or(remote))
+    }
+
+    // X-Forwarded-For is "client, proxy1, proxy2" with every proxy appending the
+    // address it got the request from. Walking from the right, the first address
+    // that is not one of our proxies is the client. Anything further left could
+    // have been made up by the client
+    fn forwarded_client<'h>(&self, headers: impl Iterator<Item = &'h str>) -> Option<IpAddr> {
+        let forwarded: Vec<IpAddr> = headers
+            .flat_map(|header| header.split(','))
+            .filter_map(|address| address.trim().parse().ok())
+            .collect();
+
+        forwarded.iter().rev()
+            .find(|address| !in_any(&self.trusted_proxies, **address))
+            .copied()
+            .or_else(|| forwarded.first().copied())
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if in_any(&self.deny, ip) {
+            return false;
+        }
+        self.allow.is_empty() || in_any(&self.allow, ip)
+    }
+}
+
+fn cidr_list(rocket: &Rocket, key: &str) -> Result<Vec<Cidr>, String> {
+    let values = match rocket.config().get_slice(key) {
+        Ok(values) => values,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    values.iter()
+        .map(|value| match value.as_str() {
+            Some(text) => text.parse::<Cidr>(),
+            None => Err(format!("{} must be a list of strings", key)),
+        })
+        .collect()
+}
+
+// Returns the client address for this request, resolved once and then cached
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    request.local_cache(|| {
+        let address = match request.guard::<State<IpRules>>() {
+            rocket::Outcome::Success(rules) => rules.client_ip(request),
+            _ => request.remote().map(|remote| remote.ip()),
+        };
+        ClientIp(address)
+    }).0
+}
+
+pub struct IpFilter;
+
+impl Fairing for IpFilter {
+    fn info(&self) -> Info {
+        Info {
+            name: "IP allowlist/denylist",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        match IpRules::from_rocket(&rocket) {
+            Ok(rules) => Ok(rocket.manage(rules).mount("/", routes![blocked])),
+            Err(e) => {
+                println!("{}", e);
+                Err(rocket)
+            }
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let allowed = match request.guard::<State<IpRules>>() {
+            rocket::Outcome::Success(rules) => match client_ip(request) {
+                Some(ip) => rules.is_allowed(ip),
+                // no address (e.g. local testing client) - only allowed if no allowlist
+                None => rules.allow.is_empty(),
+            },
+            _ => true,
+        };
+
+        if !allowed {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(BLOCKED_PATH).expect("valid blocked path"));
+        }
+    }
+}
+
+#[get("/__blocked")]
+pub fn blocked() -> ApiError {
+    ApiError::new(ErrorCode::AddressBlocked, "Requests from your address are not allowed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(text: &str) -> Cidr {
+        text.parse().unwrap()
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn rules(trusted_proxies: &[&str]) -> IpRules {
+        IpRules {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            trusted_proxies: trusted_proxies.iter().map(|range| cidr(range)).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_ranges_and_single_addresses() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.200.3.4")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr(" 192.168.1.0 / 24 ").contains(ip("192.168.1.255")));
+        assert!(cidr("10.0.0.13").contains(ip("10.0.0.13")));
+        assert!(!cidr("10.0.0.13").contains(ip("10.0.0.14")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("::/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn v4_mapped_addresses_match_v4_ranges() {
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+        assert!(cidr("::ffff:10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!cidr("127.0.0.0/8").contains(ip("::1")));
+    }
+
+    #[test]
+    fn the_denylist_wins_over_the_allowlist() {
+        let rules = IpRules {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.0.0.13")],
+            trusted_proxies: Vec::new(),
+        };
+        assert!(rules.is_allowed(ip("10.0.0.12")));
+        assert!(!rules.is_allowed(ip("10.0.0.13")));
+        assert!(!rules.is_allowed(ip("192.168.0.1")));
+        assert!(IpRules { allow: Vec::new(), ..rules }.is_allowed(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn forwarded_client_is_the_last_address_that_is_not_a_proxy() {
+        let rules = rules(&["127.0.0.1", "10.0.0.0/8"]);
+        let client = |header: &str| rules.forwarded_client(vec![header].into_iter());
+
+        assert_eq!(client("203.0.113.7"), Some(ip("203.0.113.7")));
+        assert_eq!(client("203.0.113.7, 10.0.0.2"), Some(ip("203.0.113.7")));
+        // anything left of the client could have been made up by it
+        assert_eq!(client("1.1.1.1, 203.0.113.7, 10.0.0.2"), Some(ip("203.0.113.7")));
+        assert_eq!(client(" 2001:db8::1 ,10.0.0.2"), Some(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn forwarded_client_reads_every_header() {
+        let rules = rules(&["10.0.0.0/8"]);
+        assert_eq!(rules.forwarded_client(vec!["203.0.113.7", "10.0.0.2"].into_iter()), Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn forwarded_client_skips_garbage() {
+        let rules = rules(&["10.0.0.0/8"]);
+        let client = |header: &str| rules.forwarded_client(vec![header].into_iter());
+
+        assert_eq!(client("203.0.113.7, unknown, 10.0.0.2"), Some(ip("203.0.113.7")));
+        assert_eq!(client("unknown"), None);
+        assert_eq!(client(""), None);
+        assert_eq!(rules.forwarded_client(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn forwarded_client_falls_back_to_the_first_address_when_all_are_proxies() {
+        let rules = rules(&["10.0.0.0/8"]);
+        assert_eq!(rules.forwarded_client(vec!["10.0.0.3, 10.0.0.2"].into_iter()), Some(ip("10.0.0.3")));
+    }
+}
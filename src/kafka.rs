@@ -0,0 +1,128 @@
+// Kafka event stream of item changes
+//
+// With kafka_brokers set, every change event (see events.rs) is appended to
+// kafka_topic, for analytics pipelines. The record value is the event's json with the
+// version of its schema, so consumers can tell old records from new ones when fields
+// are added:
+//
+//     {"schema_version": 2, "id": 981, "event": "item.completed", "item_id": 12, "timestamp": 1714557600}
+//
+// The key is the item id, so the events of one item land in one partition and keep
+// their order. Events are held back and sent together: when kafka_batch_size of them
+// are waiting, and otherwise when the outbox has handed over all it had (see
+// events.rs). Every batch waits for the leader's acknowledgement. When sending fails
+// the batch is dropped and the connection made anew: the events are still in the
+// outbox, which hands them over again.
+//
+// Schema versions: 1 had no id, 2 added it.
+
+use std::time::Duration;
+
+use kafka::producer::{Producer, Record, RequiredAcks};
+use serde::Serialize;
+
+use crate::events::{ChangeEvent, Sink};
+
+// version of the record value, to be raised when its fields change
+pub const SCHEMA_VERSION: u32 = 2;
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct VersionedEvent<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    event: &'a ChangeEvent,
+}
+
+pub struct KafkaSink {
+    brokers: Vec<String>,
+    topic: String,
+    batch_size: usize,
+    producer: Option<Producer>,
+    // (key, value) of the records not sent yet
+    buffered: Vec<(String, Vec<u8>)>,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str, batch_size: i64) -> Result<KafkaSink, String> {
+        let brokers: Vec<String> = brokers.split(',')
+            .map(|broker| broker.trim().to_string())
+            .filter(|broker| !broker.is_empty())
+            .collect();
+        if brokers.is_empty() {
+            return Err("kafka_brokers has to list at least one host:port".into());
+        }
+        if topic.trim().is_empty() {
+            return Err("kafka_topic can not be empty".into());
+        }
+        if batch_size < 1 {
+            return Err("kafka_batch_size has to be at least 1".into());
+        }
+        Ok(KafkaSink {
+            brokers,
+            topic: topic.trim().to_string(),
+            batch_size: batch_size as usize,
+            producer: None,
+            buffered: Vec::new(),
+        })
+    }
+
+    fn send_buffered(&mut self) -> Result<(), String> {
+        if self.producer.is_none() {
+            let producer = Producer::from_hosts(self.brokers.clone())
+                .with_ack_timeout(ACK_TIMEOUT)
+                .with_required_acks(RequiredAcks::One)
+                .create()
+                .map_err(|e| e.to_string())?;
+            self.producer = Some(producer);
+        }
+        let producer = match self.producer.as_mut() {
+            Some(producer) => producer,
+            None => return Ok(()),
+        };
+
+        let records: Vec<Record<&str, &[u8]>> = self.buffered.iter()
+            .map(|(key, value)| Record::from_key_value(self.topic.as_str(), key.as_str(), value.as_slice()))
+            .collect();
+        let sent = producer.send_all(&records).map_err(|e| e.to_string()).and_then(|confirms| {
+            let failed = confirms.iter()
+                .flat_map(|confirm| confirm.partition_confirms.iter())
+                .filter_map(|partition| partition.offset.as_ref().err())
+                .next();
+            match failed {
+                Some(code) => Err(format!("the broker refused the records: {:?}", code)),
+                None => Ok(()),
+            }
+        });
+
+        self.buffered.clear();
+        if sent.is_err() {
+            self.producer = None;
+        }
+        sent
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "Kafka"
+    }
+
+    fn publish(&mut self, event: &ChangeEvent) -> Result<(), String> {
+        let value = serde_json::to_vec(&VersionedEvent { schema_version: SCHEMA_VERSION, event })
+            .map_err(|e| e.to_string())?;
+        self.buffered.push((event.item_id.to_string(), value));
+
+        if self.buffered.len() >= self.batch_size {
+            return self.send_buffered();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.buffered.is_empty() {
+            return Ok(());
+        }
+        self.send_buffered()
+    }
+}
@@ -0,0 +1,165 @@
+// LDAP / Active Directory logins
+//
+// With ldap_url set, Basic auth credentials are also checked against a directory
+// server, so a company can give its people access with the accounts they already
+// have. The user is bound to directly, with ldap_user_dn as the DN and {username}
+// replaced by the (escaped) username:
+//
+//     ldap_user_dn = "uid={username},ou=people,dc=example,dc=com"
+//     ldap_user_dn = "cn={username},cn=Users,dc=corp,dc=example,dc=com"   (Active Directory)
+//
+// When the bind works, the user's groups are looked up under ldap_base_dn with
+// ldap_group_filter ({dn} is the user's DN) while still bound as the user, and each
+// group found in [global.ldap_group_scopes] gives its scopes (see tokens.rs):
+//
+//     [global.ldap_group_scopes]
+//     "cn=todo-admins,ou=groups,dc=example,dc=com" = "admin"
+//     "cn=staff,ou=groups,dc=example,dc=com" = "read,write"
+//
+// A user in none of the mapped groups is turned away like a wrong password.
+//
+// Clients send Basic auth with every request, so an accepted login is remembered for
+// CACHE_SECONDS (by a hash of the username and password) instead of asking the
+// server each time; a password changed or a user removed in the directory can keep
+// working for that long. ldaps:// urls are supported.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ldap3::{dn_escape, ldap_escape, LdapConn, LdapConnSettings, Scope as SearchScope, SearchEntry};
+use rocket::Rocket;
+use sha2::{Digest, Sha256};
+
+use crate::basic_auth::AuthProvider;
+use crate::tokens::{Grant, Scope};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const CACHE_SECONDS: u64 = 60;
+const DEFAULT_GROUP_FILTER: &str = "(|(member={dn})(uniqueMember={dn}))";
+// the code of a bind with wrong credentials
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapProvider {
+    url: String,
+    user_dn: String,
+    base_dn: String,
+    group_filter: String,
+    // group DN (lowercase) to its scopes
+    group_scopes: Vec<(String, Vec<Scope>)>,
+    // hash of username and password to the grant and when it was checked
+    accepted: Mutex<HashMap<String, (Grant, Instant)>>,
+}
+
+impl LdapProvider {
+    // None when ldap_url is not set
+    pub fn from_rocket(rocket: &Rocket) -> Result<Option<LdapProvider>, String> {
+        let config = rocket.config();
+        let url = config.get_str("ldap_url").unwrap_or("").trim().to_string();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        if !url.starts_with("ldap://") && !url.starts_with("ldaps://") {
+            return Err(format!("Invalid ldap_url {}, expected ldap://host[:port] or ldaps://host[:port]", url));
+        }
+
+        let user_dn = config.get_str("ldap_user_dn").unwrap_or("").to_string();
+        if !user_dn.contains("{username}") {
+            return Err("ldap_user_dn has to contain {username}".into());
+        }
+
+        let mut group_scopes = Vec::new();
+        if let Ok(table) = config.get_table("ldap_group_scopes") {
+            for (group, scopes) in table {
+                let scopes = scopes.as_str()
+                    .map(|scopes| scopes.split(',').map(|scope| Scope::parse(scope.trim())).collect::<Option<Vec<Scope>>>())
+                    .unwrap_or(None)
+                    .filter(|scopes| !scopes.is_empty())
+                    .ok_or_else(|| format!("The scopes of LDAP group {} have to be read, write and / or admin", group))?;
+                group_scopes.push((group.to_lowercase(), scopes));
+            }
+        }
+        if group_scopes.is_empty() {
+            return Err("ldap_url is set but [global.ldap_group_scopes] gives no group any scopes".into());
+        }
+
+        Ok(Some(LdapProvider {
+            url,
+            user_dn,
+            base_dn: config.get_str("ldap_base_dn").unwrap_or("").to_string(),
+            group_filter: config.get_str("ldap_group_filter").unwrap_or(DEFAULT_GROUP_FILTER).to_string(),
+            group_scopes,
+            accepted: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // Binds as the user and collects the scopes of their groups
+    fn check(&self, username: &str, password: &str) -> Result<Option<Grant>, String> {
+        let settings = LdapConnSettings::new().set_conn_timeout(TIMEOUT);
+        let mut ldap = LdapConn::with_settings(settings, &self.url).map_err(|e| e.to_string())?;
+        ldap.with_timeout(TIMEOUT);
+
+        let dn = self.user_dn.replace("{username}", &dn_escape(username));
+        let bound = ldap.simple_bind(&dn, password).map_err(|e| e.to_string())?;
+        if bound.rc == INVALID_CREDENTIALS {
+            let _ = ldap.unbind();
+            return Ok(None);
+        }
+        bound.success().map_err(|e| e.to_string())?;
+
+        let filter = self.group_filter.replace("{dn}", &ldap_escape(&dn));
+        let searched = ldap.search(&self.base_dn, SearchScope::Subtree, &filter, vec!["1.1"])
+            .and_then(|result| result.success());
+        let _ = ldap.unbind();
+        let (entries, _) = searched.map_err(|e| e.to_string())?;
+
+        let groups: Vec<String> = entries.into_iter()
+            .map(|entry| SearchEntry::construct(entry).dn.to_lowercase())
+            .collect();
+        let mut scopes: Vec<Scope> = Vec::new();
+        for (group, group_scopes) in &self.group_scopes {
+            if !groups.contains(group) {
+                continue;
+            }
+            for scope in group_scopes {
+                if !scopes.contains(scope) {
+                    scopes.push(*scope);
+                }
+            }
+        }
+
+        if scopes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Grant { scopes, token_id: None, rate_limit: None }))
+    }
+}
+
+impl AuthProvider for LdapProvider {
+    fn name(&self) -> &'static str {
+        "LDAP"
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<Grant>, String> {
+        // an empty password is an anonymous bind to most servers, which always works
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        let key = format!("{:x}", Sha256::digest(format!("{}:{}", username, password).as_bytes()));
+        {
+            let mut accepted = self.accepted.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            accepted.retain(|_, (_, checked_at)| checked_at.elapsed() < Duration::from_secs(CACHE_SECONDS));
+            if let Some((grant, _)) = accepted.get(&key) {
+                return Ok(Some(grant.clone()));
+            }
+        }
+
+        let grant = self.check(username, password)?;
+        if let Some(grant) = &grant {
+            let mut accepted = self.accepted.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            accepted.insert(key, (grant.clone(), Instant::now()));
+        }
+        Ok(grant)
+    }
+}
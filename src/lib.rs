@@ -0,0 +1,1200 @@
+// The todo api as a library
+//
+// main.rs only runs AppBuilder::new().run(). A program embedding the crate can do
+// the same with its own additions, without forking main.rs:
+//
+//     rest_api_rocket::AppBuilder::new()
+//         .with_fairing(MyAuditLog)
+//         .with_validator(|text: String| Ok(text.replace("TODO", "")))
+//         .run();
+//
+// with_fairing attaches any Rocket fairing after the app's own ones, with_validator
+// adds a step to the item text validator chain (see validation.rs), with_repository
+// replaces where the database connections come from (see db::Repository).
+
+// Tools for using decorator
+// procedureal macros are being used
+#![feature(proc_macro_hygiene, decl_macro)]
+
+// All the macros and decorators from rocket shall be imported into this project
+// imports the rocket macros globally and can be used anywhere in our application
+#[macro_use] extern crate rocket;
+
+use std::fs;
+use std::process;
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use rocket::http::ContentType;
+use rocket::request::LenientForm;
+use rocket::response::content::Content;
+use rocket::fairing::Fairing;
+use rocket::{Rocket, State};
+use rocket_contrib::json::Json;
+use rocket_contrib::templates::Template;
+use rocket_contrib::uuid::Uuid;
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+
+mod admin;
+mod agenda;
+mod amqp;
+mod attachments;
+mod auth;
+mod basic_auth;
+mod breaker;
+mod cache;
+mod cli;
+#[cfg(feature = "client")]
+pub mod client;
+mod clock;
+mod colors;
+mod comments;
+mod config;
+mod crypto;
+mod csrf;
+mod date_filters;
+mod db;
+mod dependencies;
+mod envelope;
+pub mod error;
+mod events;
+mod features;
+mod forms;
+mod health;
+mod housekeeping;
+mod i18n;
+mod imports;
+mod integrity;
+mod ip_filter;
+mod kafka;
+mod ldap;
+mod limits;
+mod lists;
+mod load_shed;
+mod lockout;
+mod maintenance;
+mod markdown;
+mod metering;
+mod metrics;
+mod mqtt;
+mod notifications;
+mod pagination;
+mod panics;
+mod planning;
+mod query;
+mod quotas;
+mod rate_limit;
+mod reporting;
+mod reports;
+mod scheduler;
+mod search;
+mod seed;
+mod shares;
+mod smartlists;
+mod sorting;
+mod spa;
+mod streaks;
+mod tags;
+mod tenants;
+mod timers;
+mod timezone;
+mod tls;
+mod todotxt;
+mod tokens;
+mod ui;
+pub mod validation;
+mod workflow;
+mod writer;
+use cache::ItemCache;
+use cli::{Cli, Command, ServeArgs};
+use config::AppConfig;
+use date_filters::DateFilters;
+use error::{ApiError, ErrorCode};
+use forms::{FormResponse, FromBrowser, ItemForm};
+use limits::LimitedJson;
+use pagination::{PageRequest, Paged, RangeHeader, Slice};
+pub use workflow::ItemStatus;
+pub use db::{FileRepository, Repository};
+
+
+// serialize by serde library will allow you to convert a struct to a json
+// deserialize will allow you to convert a json back to this struct
+// derive macro gives the struct on which it acts implementation functions on this 
+// struct which are pre-generated for us. So it eliminates our writing of these 
+// implementation functions ourselves
+// also what the typed client (client.rs) reads
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToDoItem {
+    pub id: i64, // i64 compatible with sqlite integers
+    pub item: String,
+    pub done: bool,
+    // key chosen by the client for PUT /todo/by-key/<client_key>, if any
+    pub client_key: Option<String>,
+    // client generated (or in uuid mode server generated) uuid, if any
+    pub uuid: Option<String>,
+    // the list the item belongs to, None for items that are not in a list
+    pub list_id: Option<i64>,
+    pub pinned: bool,
+    // archived items are kept but no longer shown on the main list
+    pub archived: bool,
+    // color label, a palette name or #rrggbb (see colors.rs)
+    pub color: Option<String>,
+    // kanban status, see workflow.rs
+    pub status: ItemStatus,
+    // place of the item within its list
+    pub position: Option<i64>,
+    // when the item was added and when it is due, unix time (see clock.rs)
+    pub created_at: Option<i64>,
+    pub due_at: Option<i64>,
+    // when the item was last marked done, None while it is open
+    pub completed_at: Option<i64>,
+    // estimated work in hours, see planning.rs
+    pub estimate: Option<f64>,
+    // names of the item's tags
+    pub tags: Vec<String>,
+    // number of comments on the item
+    pub comments: i64,
+    // seconds of work tracked on the item, see timers.rs
+    pub tracked_seconds: i64
+}
+
+// the columns every query returning ToDoItems selects, in the order from_row reads them.
+// The subqueries collect the tag names of the item into one text (see tags::split_tags),
+// count its comments and add up its tracked time
+const ITEM_COLUMNS: &str = "id, item, done, client_key, uuid, list_id, pinned, archived, color, status, position,
+    created_at, due_at, completed_at, estimate,
+    (select group_concat(tags.name, char(31)) from item_tags join tags on tags.id = item_tags.tag_id
+     where item_tags.item_id = todo_list.id),
+    (select count(*) from comments where comments.item_id = todo_list.id),
+    (select coalesce(sum(coalesce(stopped_at, cast(strftime('%s', 'now') as integer)) - started_at), 0)
+     from time_entries where time_entries.item_id = todo_list.id)";
+
+// the order items are listed in. Pinned items always come first, whatever else
+// the list is sorted by
+const ITEM_ORDER: &str = "pinned desc, position, id";
+
+impl ToDoItem {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<ToDoItem> {
+        Ok(ToDoItem {
+            // the ? will return an error to propagate if there was an issue with the reading of database
+            // also ? will return an error if the types do not match that is Rust know id is an integer but
+            // if sql returns a string an error is propagated back.
+            id: row.get(0)?,
+            // item text may be stored encrypted, see crypto.rs
+            item: crypto::decrypt_column(row.get(1)?, 1)?,
+            done: row.get(2)?,
+            client_key: row.get(3)?,
+            uuid: row.get(4)?,
+            list_id: row.get(5)?,
+            pinned: row.get(6)?,
+            archived: row.get(7)?,
+            color: row.get(8)?,
+            status: row.get(9)?,
+            position: row.get(10)?,
+            created_at: row.get(11)?,
+            due_at: row.get(12)?,
+            completed_at: row.get(13)?,
+            estimate: row.get(14)?,
+            tags: tags::split_tags(row.get(15)?),
+            comments: row.get(16)?,
+            tracked_seconds: row.get(17)?
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToDoList {
+    pub items: Vec<ToDoItem>
+}
+
+// used for sending messages to user
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusMessage {
+    pub message: String
+}
+
+
+// we are using the get() function provided by rocket with the argument "/"
+// the function index
+#[get("/")]
+fn index() -> &'static str {
+    "Hello, world!"
+}
+
+// The filters and order of GET /todo, also the key of its cache
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct ItemQuery {
+    color: Option<String>,
+    // ?created_after= and friends, see date_filters.rs
+    dates: DateFilters,
+    // an order by clause, ITEM_ORDER or built by sorting.rs
+    order: String,
+}
+
+impl ItemQuery {
+    fn all() -> ItemQuery {
+        ItemQuery { color: None, dates: DateFilters::default(), order: ITEM_ORDER.to_string() }
+    }
+}
+
+// ?color= only returns items with that color label.
+// ?created_after= / ?created_before= / ?due_after= / ?due_before= (RFC 3339) only
+// return items in those date ranges, see date_filters.rs.
+// ?sort=done:asc,status:desc sorts by those fields, see sorting.rs.
+// The json is cached in memory until the next change, see cache.rs.
+// ?page= / ?per_page= return one page with Link headers, a Range: items=0-49
+// header a 206 with those items, see pagination.rs
+#[get("/todo?<color>&<created_after>&<created_before>&<due_after>&<due_before>&<sort>&<page>&<per_page>")]
+#[allow(clippy::too_many_arguments)]
+fn fetch_all_todo_items(color: Option<String>, created_after: Option<String>, created_before: Option<String>, due_after: Option<String>, due_before: Option<String>,
+    sort: Option<String>, page: Option<i64>, per_page: Option<i64>, range: RangeHeader, cache: State<ItemCache>) -> Result<Paged<Content<String>>, ApiError> {
+
+    let color = match color {
+        Some(color) => match colors::normalize_color(&color) {
+            Some(color) => Some(color),
+            None => return Err(ApiError::new(ErrorCode::ValidationFailed, colors::invalid_color_message(&color)))
+        },
+        None => None
+    };
+    let order = match sort {
+        Some(sort) => sorting::order_by(&sort)?,
+        None => ITEM_ORDER.to_string()
+    };
+    let dates = DateFilters::from_params(created_after, created_before, due_after, due_before)?;
+    let query = ItemQuery { color, dates, order };
+
+    // pages and ranges are read from the database, only whole lists are cached
+    if let Some(page) = PageRequest::from_params(page, per_page)? {
+        let (todo_list, total) = fetch_todo_page(&query, page.per_page, page.offset())?;
+        return list_slice(&todo_list, Slice::Page(page, total));
+    }
+    if let Some(range) = range.0 {
+        let (todo_list, total) = fetch_todo_page(&query, range.limit(), range.offset())?;
+        let count = todo_list.items.len() as i64;
+        return list_slice(&todo_list, Slice::Range(range, count, total));
+    }
+
+    let generation = match cache.lookup(&query) {
+        Ok(json) => return Ok(Paged { body: Content(ContentType::JSON, json), slice: None }),
+        Err(generation) => generation
+    };
+
+    let todo_list = fetch_todo_list(&query)?;
+    let json = match serde_json::to_string(&todo_list) {
+        Ok(json) => json,
+        Err(_) => return Err("Failed to serialize ToDo Items".into())
+    };
+
+    cache.store(query, generation, json.clone());
+    Ok(Paged { body: Content(ContentType::JSON, json), slice: None })
+}
+
+fn list_slice(todo_list: &ToDoList, slice: Slice) -> Result<Paged<Content<String>>, ApiError> {
+    match serde_json::to_string(todo_list) {
+        Ok(json) => Ok(Paged { body: Content(ContentType::JSON, json), slice: Some(slice) }),
+        Err(_) => Err("Failed to serialize ToDo Items".into())
+    }
+}
+
+// limit items from offset on, and how many items there are in total
+fn fetch_todo_page(query: &ItemQuery, limit: i64, offset: i64) -> Result<(ToDoList, i64), ApiError> {
+    let todo_list = fetch_items(query, limit, offset)?;
+
+    let db_connection = db::connect()?;
+    let [created_after, created_before, due_after, due_before] = query.dates.params();
+    let total = db_connection.query_row(
+        &format!("select count(*) from todo_list where archived = 0 and ($1 is null or color = $1) and {}",
+            DateFilters::sql(2)),
+        params![query.color, created_after, created_before, due_after, due_before],
+        |row| row.get(0));
+
+    match total {
+        Ok(total) => Ok((todo_list, total)),
+        Err(_) => Err("Failed to count ToDo Items".into())
+    }
+}
+
+fn fetch_todo_list(query: &ItemQuery) -> Result<ToDoList, ApiError> {
+    // a limit of -1 is no limit for sqlite
+    fetch_items(query, -1, 0)
+}
+
+// In this function we will take care of error handling instead of just using unwrap and panic
+// For this function, we are going to return error as a String as implied by the 
+// second argument in the Result. 
+// First one is the ToDoList in Result OK()
+fn fetch_items(query: &ItemQuery, limit: i64, offset: i64) -> Result<ToDoList, ApiError> {
+
+    // Rocket is multi threaded and will not panic if panic occurs on one thread. Only 
+    // that particular thread will crash if panic occurs 
+    // so ok to use unwrap. But we want to handle errors so we can handle the response
+    // to user
+    let db_connection = db::connect()?;
+
+    // Once we get a database connection, we can use it to query the database
+    let mut statement = match db_connection.prepare(
+        &format!("select {} from todo_list where archived = 0 and ($1 is null or color = $1) and {} order by {} limit $6 offset $7",
+            ITEM_COLUMNS, DateFilters::sql(2), query.order))
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into())
+    };
+
+    // every row is mapped to a ToDoItem, see ToDoItem::from_row
+    let [created_after, created_before, due_after, due_before] = query.dates.params();
+    let results = statement.query_map(
+        params![query.color, created_after, created_before, due_after, due_before, limit, offset], ToDoItem::from_row);
+
+    // results will be an iterator per rusqlite documentation
+    // for result in results {
+    //     println!("id and item in rows are {} and {}", result.get(0)?, result.get(1)?);
+    // }
+
+    // Since match is the last block here and without a semicolon so we are 
+    // returning here.
+    match results {
+        Ok(rows) => {
+            // Vec<ToDoItem> because in the above we said the rows returned are mapped to the ToDoItem struct
+            // Take all the rows collected and put it into a vector of ToDoItems using the collect() function
+            // Since results are Result<> type, the collect() function can return a Result<Collection<T>>. T in this case we are saying is 
+            // ToDoItem struct
+            let collection: rusqlite::Result<Vec<ToDoItem>> = rows.collect();
+
+            // vector of ToDoItem is the ToDoList we defined. So we are take the items which in this case will be a vector
+            // of ToDoItems and obtain the ToDoList, which the route above serializes to json. 
+            match collection {
+                Ok(items) => Ok(ToDoList {items}),
+                Err(_) => Err("Could not collect items".into()) 
+            }
+        }
+        Err(_) => Err("Failed to fetch ToDo Items".into())
+    }
+
+
+    // into() function if implemented on the type will return the Type required per the 
+    // Return type set on this function. Which in this case if Error occurs shall be
+    // a String
+    // Err("Unknown Error".into())
+}
+
+// What POST /todo responds with. The derived Responder sets the status of each variant
+#[derive(Responder)]
+enum AddItemResponse {
+    #[response(status = 200)]
+    Added(Json<StatusMessage>),
+    // when deduplicating, the open item that already has this text is sent back instead
+    #[response(status = 409)]
+    Duplicate(Json<Box<ToDoItem>>),
+}
+
+// Items are considered the same when they only differ in case or whitespace
+fn normalize_item_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+fn find_open_duplicate(db_connection: &rusqlite::Connection, text: &str) -> rusqlite::Result<Option<ToDoItem>> {
+    let normalized = normalize_item_text(text);
+
+    let mut statement = db_connection.prepare(
+        &format!("select {} from todo_list where done = 0 and archived = 0", ITEM_COLUMNS))?;
+    let rows = statement.query_map(rusqlite::NO_PARAMS, ToDoItem::from_row)?;
+
+    for row in rows {
+        let existing = row?;
+        if normalize_item_text(&existing.item) == normalized {
+            return Ok(Some(existing));
+        }
+    }
+    Ok(None)
+}
+
+// format says in what format we are expecting the Post request made in
+// data field specifies the variable name we want to use to receive the data sent
+// LimitedJson works like Json but returns a 413 if the body is over the "json" limit
+// ?dedupe=true returns a 409 with the existing item if an open item with the same
+// text already exists. Without the parameter the dedupe_default setting applies
+#[post("/todo?<dedupe>", format = "json", data = "<item>")]
+// Rocket will automatically respond with the return type to the client
+fn add_todo_item(item: LimitedJson<String>, dedupe: Option<bool>, app_config: State<AppConfig>) -> Result<AddItemResponse, ApiError> {
+    add_item(item.0, dedupe.unwrap_or(app_config.dedupe_default), &app_config)
+}
+
+// the same from a form (item=...), see forms.rs
+#[post("/todo?<dedupe>", format = "form", data = "<form>")]
+fn add_todo_item_form(form: LenientForm<ItemForm>, dedupe: Option<bool>, app_config: State<AppConfig>, browser: Option<FromBrowser>) -> Result<FormResponse<AddItemResponse>, ApiError> {
+    let result = add_item(form.into_inner().item, dedupe.unwrap_or(app_config.dedupe_default), &app_config);
+
+    forms::respond(browser, result, |response| match response {
+        AddItemResponse::Added(_) => Ok("Item added".into()),
+        AddItemResponse::Duplicate(_) => Err("An open item with this text already exists".into()),
+    })
+}
+
+// Inserts an item without the duplicate check. Without max_items that is a plain
+// insert, which the writer can batch with the inserts of other requests
+// (write_batch_ms). With it the quota check and the insert are one job on the writer,
+// in an immediate transaction so that not even a tenant's writes (which do not go
+// through the queue) can add items in between
+fn insert_new_item(text: String, uuid: Option<String>) -> Result<usize, ApiError> {
+    if !quotas::limits_items() {
+        return Ok(writer::insert_item(text, uuid)?);
+    }
+
+    writer::write(move |db_connection| {
+
+        let transaction = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into())
+        };
+        quotas::check_items(&transaction, 1)?;
+
+        let rows_added = match transaction.execute(
+            "insert into todo_list (id, item, uuid) values (null, $1, $2)", params![text, uuid])
+        {
+            Ok(rows_added) => rows_added,
+            Err(_) => return Err("Failed to insert ToDo Item".into())
+        };
+
+        match transaction.commit() {
+            Ok(_) => Ok(rows_added),
+            Err(_) => Err("Failed to insert ToDo Item".into())
+        }
+    })?
+}
+
+fn add_item(item: String, dedupe: bool, app_config: &AppConfig) -> Result<AddItemResponse, ApiError> {
+
+    let item = validation::item_text(&item)?;
+    let uuid = generated_uuid(app_config);
+    // the item text is encrypted here, if configured, so the writer only stores it
+    let text = crypto::encrypt_text(&item)?;
+
+    if !dedupe {
+        let rows_added = insert_new_item(text, uuid)?;
+        return Ok(AddItemResponse::Added(Json(StatusMessage {
+            message: format!("{} rows inserted!", rows_added),
+        })));
+    }
+
+    // the duplicate check and the insert run together on the writer thread (see
+    // writer.rs), so two requests adding the same text can not both pass the check.
+    // The transaction is immediate for the tenants' writes, which skip the writer
+    writer::write(move |db_connection| {
+
+        let transaction = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into())
+        };
+
+        match find_open_duplicate(&transaction, &item) {
+            Ok(Some(existing)) => return Ok(AddItemResponse::Duplicate(Json(Box::new(existing)))),
+            Ok(None) => (),
+            Err(_) => return Err("Failed to check for duplicate ToDo Items".into())
+        }
+        quotas::check_items(&transaction, 1)?;
+
+        let mut statement = match transaction.prepare(
+            "insert into todo_list (id, item, uuid) values (null, $1, $2)") 
+        {
+            Ok(statement) => statement,
+            Err(_) => return Err("Failed to prepare a query".into())
+        };
+
+        // add item to the database table
+        // params! borrows the values, the item text (encrypted if configured) and the
+        // uuid (null unless uuid mode is on) fill in $1 and $2
+        let results = statement.execute(params![text, uuid]);
+        drop(statement);
+
+        match results.and_then(|rows_added| transaction.commit().map(|_| rows_added)) {
+            // the variable rows_added can be named with any name. It just represents the value in Ok(T).
+            // That is it represents T which the Result got when the result was successfull and there 
+            // were no errors
+            Ok(rows_added) => Ok(AddItemResponse::Added(Json(StatusMessage {
+                message: format!("{} rows inserted!", rows_added),
+            }))),
+            Err(_) => Err("Failed to insert ToDo Item".into())
+        }
+    })?
+
+}
+
+#[delete("/todo/<id>")]
+// Rocket will automatically respond with the return type to the client
+fn remove_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    // the writer's connection has foreign keys switched on (db::connect) so related
+    // rows (e.g. attachments) are cleaned up along with the item
+    writer::write(move |db_connection| {
+
+        let mut statement = match db_connection.prepare(
+            "delete from todo_list where id = $1;") 
+        {
+            Ok(statement) => statement,
+            Err(_) => return Err("Failed to prepare a query".into())
+        };
+
+        let results = statement.execute(&[&id]);
+
+        match results {
+            // the variable rows_added can be named with any name. It just represents the value in Ok(T).
+            // That is it represents T which the Result got when the result was successfull and there 
+            // were no errors
+            Ok(rows_deleted) => Ok(Json(StatusMessage {
+                message: format!("{} rows deleted", rows_deleted),
+            })),
+            Err(_) => Err("Failed to delete ToDo Item".into())
+        }
+    })?
+
+}
+
+// Sets one of the boolean columns (pinned, archived) of an item. The column name comes
+// from the routes below, never from the request
+fn set_todo_item_flag(id: i64, column: &'static str, value: bool) -> Result<Json<StatusMessage>, ApiError> {
+
+    writer::write(move |db_connection| {
+
+        let results = db_connection.execute(
+            &format!("update todo_list set {} = $1 where id = $2;", column), params![value, id]);
+
+        match results {
+            Ok(rows_updated) => Ok(Json(StatusMessage {
+                message: format!("{} rows updated", rows_updated),
+            })),
+            Err(_) => Err("Failed to update ToDo Item".into())
+        }
+    })?
+}
+
+// Completing and reopening set the workflow status directly (done / todo) without
+// the transition checks of PUT /todo/<id>/status
+fn force_todo_item_status(id: i64, status: ItemStatus) -> Result<Json<StatusMessage>, ApiError> {
+
+    writer::write(move |db_connection| {
+
+        match workflow::write_status(db_connection, id, status) {
+            Ok(rows_updated) => Ok(Json(StatusMessage {
+                message: format!("{} rows updated", rows_updated),
+            })),
+            Err(_) => Err("Failed to update ToDo Item".into())
+        }
+    })?
+}
+
+// marks an item as completed
+#[put("/todo/<id>/done")]
+fn complete_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    force_todo_item_status(id, ItemStatus::Done)
+}
+
+// opens a completed item again
+#[delete("/todo/<id>/done")]
+fn reopen_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    force_todo_item_status(id, ItemStatus::Todo)
+}
+
+// pinned items are always listed first
+#[put("/todo/<id>/pin")]
+fn pin_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_todo_item_flag(id, "pinned", true)
+}
+
+#[delete("/todo/<id>/pin")]
+fn unpin_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_todo_item_flag(id, "pinned", false)
+}
+
+// Sets or clears (None) the color label of an item or list. table only ever comes
+// from the routes, never from the request
+fn set_color(table: &'static str, id: i64, color: Option<&str>) -> Result<Json<StatusMessage>, ApiError> {
+
+    let color = match color {
+        Some(color) => match colors::normalize_color(color) {
+            Some(color) => Some(color),
+            None => return Err(ApiError::new(ErrorCode::ValidationFailed, colors::invalid_color_message(color)))
+        },
+        None => None
+    };
+
+    writer::write(move |db_connection| {
+
+        let results = db_connection.execute(
+            &format!("update {} set color = $1 where id = $2;", table), params![color, id]);
+
+        match results {
+            Ok(rows_updated) => Ok(Json(StatusMessage {
+                message: format!("{} rows updated", rows_updated),
+            })),
+            Err(_) => Err("Failed to update color".into())
+        }
+    })?
+}
+
+// the body is the color as a json string, e.g. "green" or "#00ff7f"
+#[put("/todo/<id>/color", format = "json", data = "<color>")]
+fn set_todo_item_color(id: i64, color: LimitedJson<String>) -> Result<Json<StatusMessage>, ApiError> {
+    set_color("todo_list", id, Some(&color.0))
+}
+
+#[delete("/todo/<id>/color")]
+fn clear_todo_item_color(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_color("todo_list", id, None)
+}
+
+// Sets or clears (None) the due date of an item
+fn set_due(id: i64, due: Option<&str>) -> Result<Json<StatusMessage>, ApiError> {
+
+    let due_at = match due {
+        Some(due) => match clock::parse_rfc3339(due) {
+            Some(due_at) => Some(due_at),
+            None => return Err(ApiError::new(ErrorCode::ValidationFailed,
+                "The due date has to be an RFC 3339 date-time like 2024-05-01T12:00:00Z"))
+        },
+        None => None
+    };
+
+    writer::write(move |db_connection| {
+
+        let results = db_connection.execute("update todo_list set due_at = $1 where id = $2;", params![due_at, id]);
+
+        match results {
+            Ok(rows_updated) => Ok(Json(StatusMessage {
+                message: format!("{} rows updated", rows_updated),
+            })),
+            Err(_) => Err("Failed to update due date".into())
+        }
+    })?
+}
+
+// the body is the due date as a json string, e.g. "2024-05-01T17:00:00+02:00"
+#[put("/todo/<id>/due", format = "json", data = "<due>")]
+fn set_todo_item_due(id: i64, due: LimitedJson<String>) -> Result<Json<StatusMessage>, ApiError> {
+    set_due(id, Some(&due.0))
+}
+
+#[delete("/todo/<id>/due")]
+fn clear_todo_item_due(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_due(id, None)
+}
+
+#[put("/lists/<id>/color", format = "json", data = "<color>")]
+fn set_list_color(id: i64, color: LimitedJson<String>) -> Result<Json<StatusMessage>, ApiError> {
+    set_color("lists", id, Some(&color.0))
+}
+
+#[delete("/lists/<id>/color")]
+fn clear_list_color(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_color("lists", id, None)
+}
+
+// Archiving takes an item off the main list without deleting it. The item is
+// still available from GET /todo/archive
+#[post("/todo/<id>/archive")]
+fn archive_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_todo_item_flag(id, "archived", true)
+}
+
+// puts an archived item back on the main list
+#[delete("/todo/<id>/archive")]
+fn unarchive_todo_item(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_todo_item_flag(id, "archived", false)
+}
+
+// Archives every completed item in one go
+#[post("/todo/archive")]
+fn archive_completed_todo_items() -> Result<Json<StatusMessage>, ApiError> {
+
+    writer::write(|db_connection| {
+
+        let results = db_connection.execute(
+            "update todo_list set archived = 1 where done = 1 and archived = 0;", rusqlite::NO_PARAMS);
+
+        match results {
+            Ok(rows_archived) => Ok(Json(StatusMessage {
+                message: format!("{} rows archived", rows_archived),
+            })),
+            Err(_) => Err("Failed to archive ToDo Items".into())
+        }
+    })?
+}
+
+#[get("/todo/archive")]
+fn fetch_archived_todo_items() -> Result<Json<ToDoList>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(
+        &format!("select {} from todo_list where archived = 1 order by {}", ITEM_COLUMNS, ITEM_ORDER))
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into())
+    };
+
+    let results = statement
+        .query_map(rusqlite::NO_PARAMS, ToDoItem::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<ToDoItem>>>());
+
+    match results {
+        Ok(items) => Ok(Json(ToDoList {items})),
+        Err(_) => Err("Failed to fetch ToDo Items".into())
+    }
+}
+
+// What the upsert routes respond with - 201 the first time a key is seen, 200 after that
+#[derive(Responder)]
+enum UpsertResponse {
+    #[response(status = 201)]
+    Created(Json<ToDoItem>),
+    #[response(status = 200)]
+    Updated(Json<ToDoItem>),
+}
+
+// In uuid mode (generate_uuids in Rocket.toml) every item gets a uuid, also the ones
+// created without the client supplying one
+fn generated_uuid(app_config: &AppConfig) -> Option<String> {
+    if app_config.generate_uuids {
+        Some(uuid::Uuid::new_v4().to_string())
+    } else {
+        None
+    }
+}
+
+// Inserts or updates the item whose key_column (client_key or uuid) is key.
+// key_column only ever comes from the code, never from the request
+fn upsert_item(key_column: &'static str, key: String, text: &str, uuid: Option<String>) -> Result<UpsertResponse, ApiError> {
+
+    let text = crypto::encrypt_text(&validation::item_text(text)?)?;
+
+    writer::write(move |db_connection| {
+
+        // look up and write in one transaction so two scripts syncing the same key at the
+        // same time can not both decide to insert
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into())
+        };
+
+        let existing = transaction.query_row(
+            &format!("select id from todo_list where {} = $1", key_column), params![key], |row| row.get::<_, i64>(0))
+            .optional();
+
+        let (id, created) = match existing {
+            Ok(Some(id)) => {
+                if transaction.execute("update todo_list set item = $1 where id = $2", params![text, id]).is_err() {
+                    return Err("Failed to update ToDo Item".into());
+                }
+                (id, false)
+            }
+            Ok(None) => {
+                quotas::check_items(&transaction, 1)?;
+                let inserted = transaction.execute(
+                    &format!("insert into todo_list (id, item, {}) values (null, $1, $2)", key_column), params![text, key]);
+                if inserted.is_err() {
+                    return Err("Failed to insert ToDo Item".into());
+                }
+                let id = transaction.last_insert_rowid();
+
+                if let Some(uuid) = uuid {
+                    let updated = transaction.execute(
+                        "update todo_list set uuid = $1 where id = $2 and uuid is null", params![uuid, id]);
+                    if updated.is_err() {
+                        return Err("Failed to insert ToDo Item".into());
+                    }
+                }
+                (id, true)
+            }
+            Err(_) => return Err("Failed to fetch ToDo Item".into())
+        };
+
+        let todo_item = transaction.query_row(
+            &format!("select {} from todo_list where id = $1", ITEM_COLUMNS), params![id], ToDoItem::from_row);
+
+        let todo_item = match todo_item {
+            Ok(todo_item) => todo_item,
+            Err(_) => return Err("Failed to fetch ToDo Item".into())
+        };
+
+        if transaction.commit().is_err() {
+            return Err("Failed to save ToDo Item".into());
+        }
+
+        if created {
+            Ok(UpsertResponse::Created(Json(todo_item)))
+        } else {
+            Ok(UpsertResponse::Updated(Json(todo_item)))
+        }
+    })?
+}
+
+// Inserts or updates the item with the client supplied key, so import and sync scripts
+// can send the same data again without creating duplicates.
+// The rank keeps this route apart from PUT /todo/<id>/done which has the same shape
+#[put("/todo/by-key/<client_key>", format = "json", data = "<item>", rank = 1)]
+fn upsert_todo_item(client_key: String, item: LimitedJson<String>, app_config: State<AppConfig>) -> Result<UpsertResponse, ApiError> {
+    upsert_item("client_key", client_key, &item.0, generated_uuid(&app_config))
+}
+
+#[put("/todo/by-key/<client_key>", format = "form", data = "<form>", rank = 1)]
+fn upsert_todo_item_form(client_key: String, form: LenientForm<ItemForm>, app_config: State<AppConfig>, browser: Option<FromBrowser>) -> Result<FormResponse<UpsertResponse>, ApiError> {
+    let result = upsert_item("client_key", client_key, &form.item, generated_uuid(&app_config));
+    forms::respond(browser, result, |_| Ok("Item saved".into()))
+}
+
+// Items identified by a uuid the client generated itself. This lets clients create
+// items while offline and merge them later from several devices without id clashes.
+// The Uuid guard rejects anything that is not a valid uuid
+#[put("/todo/uuid/<uuid>", format = "json", data = "<item>", rank = 1)]
+fn put_todo_item_by_uuid(uuid: Uuid, item: LimitedJson<String>) -> Result<UpsertResponse, ApiError> {
+    upsert_item("uuid", uuid.to_string(), &item.0, None)
+}
+
+#[put("/todo/uuid/<uuid>", format = "form", data = "<form>", rank = 1)]
+fn put_todo_item_by_uuid_form(uuid: Uuid, form: LenientForm<ItemForm>, browser: Option<FromBrowser>) -> Result<FormResponse<UpsertResponse>, ApiError> {
+    let result = upsert_item("uuid", uuid.to_string(), &form.item, None);
+    forms::respond(browser, result, |_| Ok("Item saved".into()))
+}
+
+#[get("/todo/uuid/<uuid>")]
+fn fetch_todo_item_by_uuid(uuid: Uuid) -> Result<Option<Json<ToDoItem>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let todo_item = db_connection.query_row(
+        &format!("select {} from todo_list where uuid = $1", ITEM_COLUMNS),
+        params![uuid.to_string()],
+        ToDoItem::from_row)
+        .optional();
+
+    match todo_item {
+        Ok(todo_item) => Ok(todo_item.map(Json)),
+        Err(_) => Err("Failed to fetch ToDo Item".into())
+    }
+}
+
+#[delete("/todo/uuid/<uuid>", rank = 1)]
+fn remove_todo_item_by_uuid(uuid: Uuid) -> Result<Json<StatusMessage>, ApiError> {
+
+    let uuid = uuid.to_string();
+
+    writer::write(move |db_connection| {
+        let results = db_connection.execute("delete from todo_list where uuid = $1;", params![uuid]);
+
+        match results {
+            Ok(rows_deleted) => Ok(Json(StatusMessage {
+                message: format!("{} rows deleted", rows_deleted),
+            })),
+            Err(_) => Err("Failed to delete ToDo Item".into())
+        }
+    })?
+}
+
+// Runs the http server. Returns only when it could not start
+fn serve(args: ServeArgs, app: AppBuilder) -> Result<(), String> {
+
+    // the config fairing runs as soon as it is attached, so the database settings
+    // (e.g. the SQLCipher key) are known before the schema is set up below.
+    // TLS has to be set up first because it replaces the rocket
+    let rocket = tls::configure(rocket::ignite()).attach(config::fairing()).attach(writer::fairing());
+
+    // sqlite database initialization - creates the tables if they are missing and
+    // checks that nothing the code needs is missing from an existing database
+    let auto_migrate = rocket.config().get_bool("auto_migrate").unwrap_or(true);
+    db::prepare_schema(auto_migrate)?;
+
+    // --seed <file.json> loads demo / test data into an empty database, see seed.rs
+    if let Some(path) = args.seed {
+        seed_from(&path)?;
+    }
+
+    // add the function names in the routes! macro to let Rocket open the endpoints
+    let rocket = rocket
+        // gives every request an id, sent back as X-Request-Id and in error bodies
+        .attach(error::RequestIds)
+        // panics and internal errors go to the error tracker (error_reporting_dsn)
+        .attach(reporting::ErrorReporting)
+        // first, so the time of everything below is measured
+        .attach(metrics::RequestMetrics)
+        // counts the request per tenant and api token
+        .attach(metering::Metering)
+        // translates the messages of every response, also the rejections below
+        .attach(i18n::Localize)
+        // after Localize, which only looks at top level messages
+        .attach(envelope::Envelope)
+        // when overloaded, requests are turned away before anything else is done
+        .attach(load_shed::LoadShed)
+        // blocked addresses are turned away before routing
+        .attach(ip_filter::IpFilter)
+        // picks the tenant's database, before anything reads one
+        .attach(tenants::Tenancy)
+        // while the request's database is failing, it gets 503 without trying it
+        .attach(breaker::CircuitBreaker)
+        // then anyone without valid credentials, or a token without the needed scope
+        .attach(auth::Auth)
+        // counts the request against the token's (or address') quota
+        .attach(rate_limit::RateLimit)
+        // changes from a browser have to carry the page's CSRF token
+        .attach(csrf::Csrf)
+        // and changes while the service is read-only
+        .attach(maintenance::Maintenance)
+        // empties the GET /todo cache after every change
+        .attach(cache::CacheInvalidation)
+        // needs the schema, loads the feature flags into memory
+        .attach(features::fairing())
+        // checks (and may change) the text of new and updated items
+        .attach(app.validators)
+        // scheduled vacuum / analyze
+        .attach(housekeeping::fairing())
+        // delivers the item change events of the outbox to the message brokers
+        .attach(events::ChangeEvents)
+        // renders the pages of /ui from templates/
+        .attach(Template::fairing())
+        // serves the bundled frontend under /app when spa_dir is set
+        .attach(spa::fairing())
+        // a panicking handler answers with a json 500 and an incident id, see panics.rs
+        .mount("/", panics::catch_panics(routes![
+        index, 
+        health::health,
+        metrics::metrics,
+        fetch_all_todo_items, 
+        add_todo_item,
+        add_todo_item_form,
+        remove_todo_item,
+        complete_todo_item,
+        reopen_todo_item,
+        workflow::set_todo_item_status,
+        workflow::fetch_board,
+        pin_todo_item,
+        unpin_todo_item,
+        set_todo_item_color,
+        clear_todo_item_color,
+        set_todo_item_due,
+        clear_todo_item_due,
+        agenda::overdue,
+        agenda::today,
+        agenda::week,
+        agenda::calendar,
+        streaks::streaks,
+        timers::start_timer,
+        timers::stop_timer,
+        reports::time_report,
+        planning::set_todo_item_estimate,
+        planning::clear_todo_item_estimate,
+        planning::burndown,
+        planning::schedule,
+        dependencies::add_dependency,
+        dependencies::remove_dependency,
+        dependencies::fetch_dependencies,
+        todotxt::export,
+        todotxt::import,
+        imports::import_todoist,
+        imports::import_trello,
+        markdown::export,
+        set_list_color,
+        clear_list_color,
+        archive_todo_item,
+        unarchive_todo_item,
+        archive_completed_todo_items,
+        fetch_archived_todo_items,
+        upsert_todo_item,
+        upsert_todo_item_form,
+        put_todo_item_by_uuid,
+        put_todo_item_by_uuid_form,
+        fetch_todo_item_by_uuid,
+        remove_todo_item_by_uuid,
+        attachments::add_attachment,
+        attachments::fetch_attachment,
+        attachments::fetch_thumbnail,
+        lists::fetch_all_lists,
+        lists::add_list,
+        lists::add_list_form,
+        lists::fetch_list_by_id,
+        lists::fetch_list_by_slug,
+        lists::rename_list,
+        lists::rename_list_form,
+        lists::remove_list,
+        lists::add_list_item,
+        lists::move_items,
+        lists::merge_lists,
+        lists::duplicate_list,
+        lists::mark_template,
+        lists::unmark_template,
+        lists::instantiate_template,
+        shares::share_list,
+        shares::revoke_share,
+        shares::fetch_shared_list,
+        search::suggest,
+        search::search,
+        comments::add_comment,
+        comments::fetch_comments,
+        comments::remove_comment,
+        notifications::fetch_notifications,
+        notifications::fetch_unread_count,
+        notifications::mark_read,
+        notifications::mark_all_read,
+        tags::fetch_all_tags,
+        tags::add_item_tag,
+        tags::remove_item_tag,
+        tags::rename_tag,
+        tags::merge_tags,
+        smartlists::fetch_all_smartlists,
+        smartlists::add_smartlist,
+        smartlists::fetch_smartlist_by_id,
+        smartlists::update_smartlist,
+        smartlists::remove_smartlist,
+        smartlists::fetch_smartlist_items,
+        ui::index,
+        ui::add_item,
+        ui::complete_item,
+        ui::reopen_item,
+        ui::remove_item,
+        ui::print_list,
+        quotas::fetch_usage
+        ]))
+        // privileged operations, all behind the auth::Admin guard
+        .mount("/admin", panics::catch_panics(routes![
+        admin::fetch_stats,
+        admin::create_backup,
+        admin::purge,
+        admin::fetch_maintenance,
+        admin::set_maintenance,
+        admin::checkpoint,
+        integrity::start_check,
+        integrity::fetch_check,
+        seed::seed,
+        query::run_query,
+        features::fetch_all_flags,
+        features::put_flag,
+        features::remove_flag,
+        tokens::fetch_all_tokens,
+        tokens::add_token,
+        tokens::set_token_rate_limit,
+        tokens::remove_token,
+        tenants::fetch_all_tenants,
+        tenants::add_tenant,
+        tenants::set_suspended,
+        tenants::remove_tenant,
+        metering::fetch_usage,
+        lockout::fetch_lockouts,
+        lockout::remove_lockout
+        ]))
+        // catchers replace Rocket's default html error pages
+        .register(catchers![
+            limits::bad_request,
+            error::unauthorized,
+            admin::forbidden,
+            error::not_found,
+            shares::gone,
+            limits::payload_too_large,
+            limits::unprocessable,
+            error::internal_error,
+            error::unavailable
+        ]);
+
+    // the embedding program's fairings, see AppBuilder
+    let rocket = app.fairings.into_iter().fold(rocket, |rocket, attach| attach(rocket));
+
+    let error = rocket.launch();
+    Err(error.to_string())
+}
+
+fn seed_from(path: &str) -> Result<(), String> {
+    match seed::load_file(path)? {
+        Some(results) => println!("Seeded {} from {}", results.summary(), path),
+        None => println!("The database already has data, {} was not loaded", path),
+    }
+    Ok(())
+}
+
+fn export(output: Option<String>) -> Result<(), String> {
+    let data = seed::export()?;
+    let json = serde_json::to_string_pretty(&data).map_err(|_| String::from("Failed to serialize the data"))?;
+
+    match output {
+        Some(path) => fs::write(&path, json).map_err(|e| format!("Failed to write {}: {}", path, e)),
+        None => {
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+// The commands other than serve only need the database settings (e.g. the
+// SQLCipher key), which the config fairing hands to the db module
+fn load_config() {
+    let _ = rocket::ignite().attach(config::fairing());
+}
+
+type Attach = Box<dyn FnOnce(Rocket) -> Rocket + Send>;
+
+// The app with what the embedding program adds to it
+pub struct AppBuilder {
+    fairings: Vec<Attach>,
+    validators: validation::Validators,
+    repository: Option<Box<dyn db::Repository>>,
+}
+
+impl Default for AppBuilder {
+    fn default() -> AppBuilder {
+        AppBuilder::new()
+    }
+}
+
+impl AppBuilder {
+    pub fn new() -> AppBuilder {
+        AppBuilder { fairings: Vec::new(), validators: validation::Validators::new(), repository: None }
+    }
+
+    // attached after the app's own fairings, in the order they are added
+    pub fn with_fairing<F: Fairing>(mut self, fairing: F) -> AppBuilder {
+        self.fairings.push(Box::new(move |rocket: Rocket| rocket.attach(fairing)));
+        self
+    }
+
+    // runs after the built-in validators (blocked_words), in the order they are added
+    pub fn with_validator<V: validation::Validator + 'static>(mut self, validator: V) -> AppBuilder {
+        self.validators = self.validators.with(validator);
+        self
+    }
+
+    // used for every database, the main one and the tenants', by every command
+    pub fn with_repository<R: db::Repository + 'static>(mut self, repository: R) -> AppBuilder {
+        self.repository = Some(Box::new(repository));
+        self
+    }
+
+    // Parses the command line and runs the command, `serve` (the default) with the
+    // fairings, validators and repository added here. Exits the process on errors
+    pub fn run(mut self) {
+
+        // has to happen before sqlite is used for anything
+        breaker::install();
+        if let Some(repository) = self.repository.take() {
+            if let Err(e) = db::set_repository(repository) {
+                println!("{}", e);
+                process::exit(1);
+            }
+        }
+
+        let result = match Cli::parse().command() {
+            Command::Serve(args) => serve(args, self),
+            Command::Migrate => {
+                load_config();
+                db::prepare_schema(true).map(|_| println!("The database schema is up to date"))
+            }
+            Command::Export { output } => {
+                load_config();
+                db::prepare_schema(false).and_then(|_| export(output))
+            }
+            Command::Seed { file } => {
+                load_config();
+                db::prepare_schema(true).and_then(|_| seed_from(&file))
+            }
+        };
+
+        if let Err(e) = result {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// Request body size limits
+//
+// Rocket already supports named body limits through the `limits` table of
+// Rocket.toml (or the ROCKET_LIMITS environment variable), e.g.
+//
+//     [global.limits]
+//     json = 1048576
+//     uploads = 10485760
+//     text = 1048576
+//
+// The problem is what happens when a client goes over the limit: rocket_contrib's
+// Json guard only reads up to the limit, so an oversized body just looks like a
+// truncated (malformed) document and the client gets a confusing parse error.
+// The guards in this module read one byte past the configured limit so they can
+// tell "too big" apart from "broken" and fail with a proper 413 instead.
+//
+// A body that is not valid json (400) or does not fit the expected shape (422) is
+// answered with where the problem is, so the client does not have to guess:
+//
+//     {"code": "invalid_json", "message": "...",
+//      "details": {"line": 3, "column": 14, "field": "lists[0].name"}}
+//
+// field is the path to the value that could not be read, null when the document
+// is broken before any field.
+
+use std::io::{self, Read};
+
+use rocket::data::{self, FromDataSimple};
+use rocket::http::{ContentType, Status};
+use rocket::{Data, Outcome, Request};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::error::{ApiError, ErrorCode};
+
+// names of the entries in the `limits` config table
+pub const JSON_LIMIT: &str = "json";
+pub const UPLOADS_LIMIT: &str = "uploads";
+pub const TEXT_LIMIT: &str = "text";
+
+// used when the limit is not configured at all. 1 MiB matches what rocket_contrib
+// uses for Json so the behavior does not change for existing deployments
+const DEFAULT_JSON_LIMIT: u64 = 1024 * 1024;
+const DEFAULT_UPLOADS_LIMIT: u64 = 10 * 1024 * 1024;
+const DEFAULT_TEXT_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum BodyError {
+    Io(io::Error),
+    TooLarge(u64),
+    Parse(serde_json::Error),
+    NotUtf8,
+}
+
+// The 413 catcher only gets the Request, not the error from the data guard, so the
+// guard leaves a note in the request-local cache saying which limit was exceeded.
+struct ExceededLimit(Option<(&'static str, u64)>);
+
+// The same for the 400 / 422 catchers: what was wrong with the json body
+#[derive(Serialize, Clone)]
+struct JsonProblem {
+    line: usize,
+    column: usize,
+    field: Option<String>,
+    #[serde(skip)]
+    message: String,
+}
+
+struct InvalidBody(Option<JsonProblem>);
+
+pub fn limit_for(request: &Request, name: &str) -> u64 {
+    let default = match name {
+        UPLOADS_LIMIT => DEFAULT_UPLOADS_LIMIT,
+        TEXT_LIMIT => DEFAULT_TEXT_LIMIT,
+        _ => DEFAULT_JSON_LIMIT,
+    };
+    request.limits().get(name).unwrap_or(default)
+}
+
+// Reads the whole body into memory as long as it fits in the named limit
+fn read_limited(request: &Request, data: Data, name: &'static str) -> data::Outcome<Vec<u8>, BodyError> {
+    let limit = limit_for(request, name);
+
+    // take one byte more than the limit - if we manage to read it the body is too big
+    let mut body = Vec::new();
+    if let Err(e) = data.open().take(limit + 1).read_to_end(&mut body) {
+        return Outcome::Failure((Status::BadRequest, BodyError::Io(e)));
+    }
+
+    if body.len() as u64 > limit {
+        request.local_cache(|| ExceededLimit(Some((name, limit))));
+        return Outcome::Failure((Status::PayloadTooLarge, BodyError::TooLarge(limit)));
+    }
+
+    Outcome::Success(body)
+}
+
+// Drop-in replacement for rocket_contrib's Json<T> data guard that honors the
+// `json` limit and answers with 413 when it is exceeded. Like Json the value is
+// available as `.0`
+pub struct LimitedJson<T>(pub T);
+
+impl<T: DeserializeOwned> FromDataSimple for LimitedJson<T> {
+    type Error = BodyError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, BodyError> {
+        let body = match read_limited(request, data, JSON_LIMIT) {
+            Outcome::Success(body) => body,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(data) => return Outcome::Forward(data),
+        };
+
+        let (e, field) = match parse(&body) {
+            Ok(value) => return Outcome::Success(LimitedJson(value)),
+            Err(failure) => failure,
+        };
+
+        request.local_cache(|| InvalidBody(Some(JsonProblem {
+            line: e.line(),
+            column: e.column(),
+            field,
+            message: e.to_string(),
+        })));
+
+        // same split as rocket_contrib: broken syntax is a 400, valid json of the
+        // wrong shape is a 422
+        let status = if e.is_data() { Status::UnprocessableEntity } else { Status::BadRequest };
+        Outcome::Failure((status, BodyError::Parse(e)))
+    }
+}
+
+// Deserializes the whole body, on failure with the path of the field that failed
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, (serde_json::Error, Option<String>)> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value: T = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        // "." is the document itself
+        let field = if path == "." { None } else { Some(path) };
+        (e.into_inner(), field)
+    })?;
+
+    // nothing but whitespace may follow the value
+    deserializer.end().map_err(|e| (e, None))?;
+    Ok(value)
+}
+
+// Raw binary body for file uploads, limited by the `uploads` limit. The content type
+// the client sent is kept so it can be stored next to the bytes
+pub struct Upload {
+    pub content_type: Option<ContentType>,
+    pub bytes: Vec<u8>,
+}
+
+impl FromDataSimple for Upload {
+    type Error = BodyError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, BodyError> {
+        let content_type = request.content_type().cloned();
+        match read_limited(request, data, UPLOADS_LIMIT) {
+            Outcome::Success(bytes) => Outcome::Success(Upload { content_type, bytes }),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(data) => Outcome::Forward(data),
+        }
+    }
+}
+
+// Plain text body (imports of text formats), limited by the `text` limit. Text that
+// is not utf-8 is a 400
+pub struct LimitedText(pub String);
+
+impl FromDataSimple for LimitedText {
+    type Error = BodyError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, BodyError> {
+        match read_limited(request, data, TEXT_LIMIT) {
+            Outcome::Success(bytes) => match String::from_utf8(bytes) {
+                Ok(text) => Outcome::Success(LimitedText(text)),
+                Err(_) => Outcome::Failure((Status::BadRequest, BodyError::NotUtf8)),
+            },
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(data) => Outcome::Forward(data),
+        }
+    }
+}
+
+// Rocket's default 413 is an HTML page. Replace it with the json error that also
+// tells the client what the limit is (details.limit) so it can split/shrink the
+// request
+#[catch(413)]
+pub fn payload_too_large(request: &Request) -> ApiError {
+    let exceeded = request.local_cache(|| ExceededLimit(None));
+
+    match exceeded.0 {
+        Some((name, limit)) => ApiError::new(ErrorCode::PayloadTooLarge,
+            format!("Request body exceeds the {} limit of {} bytes", name, limit))
+            .with_details(json!({ "limit": limit })),
+        None => ApiError::new(ErrorCode::PayloadTooLarge, "Request body is too large"),
+    }
+}
+
+// json_code when the json body was the problem, with where it is, code for any
+// other failure with this status
+fn body_problem(request: &Request, json_code: ErrorCode, code: ErrorCode) -> ApiError {
+    match &request.local_cache(|| InvalidBody(None)).0 {
+        Some(problem) => ApiError::new(json_code, problem.message.clone())
+            .with_details(json!(problem)),
+        None => ApiError::new(code, code.status().reason),
+    }
+}
+
+// Rocket's default 400 and 422 are HTML pages. When the json body was the problem
+// the error says where it is (details.line / column / field)
+#[catch(400)]
+pub fn bad_request(request: &Request) -> ApiError {
+    body_problem(request, ErrorCode::InvalidJson, ErrorCode::BadRequest)
+}
+
+#[catch(422)]
+pub fn unprocessable(request: &Request) -> ApiError {
+    body_problem(request, ErrorCode::ValidationFailed, ErrorCode::ValidationFailed)
+}
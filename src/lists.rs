@@ -0,0 +1,621 @@
+// Named lists that group todo items, e.g. "Groceries" or "Packing"
+//
+// Every list gets a human readable slug made from its name (groceries, groceries-2 ...)
+// so it can be addressed as /lists/by-slug/<slug> as well as by id. The slug is
+// regenerated when the list is renamed.
+// Items that are not in any list (list_id is null) still show up on GET /todo.
+
+use rocket::request::LenientForm;
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiError, ErrorCode};
+use crate::forms::{self, FormResponse, FromBrowser};
+use crate::limits::LimitedJson;
+use crate::{colors, crypto, db, quotas, validation, writer, StatusMessage, ToDoItem, ITEM_COLUMNS, ITEM_ORDER};
+
+#[derive(Serialize)]
+pub struct List {
+    id: i64,
+    name: String,
+    slug: String,
+    color: Option<String>,
+    // templates are lists meant to be instantiated with POST /lists/from-template/<id>
+    template: bool,
+}
+
+#[derive(Serialize)]
+pub struct ListWithItems {
+    // flatten puts the list fields next to "items" instead of nesting them
+    #[serde(flatten)]
+    list: List,
+    items: Vec<ToDoItem>,
+}
+
+#[derive(Serialize)]
+pub struct Lists {
+    lists: Vec<List>,
+}
+
+// body of POST /lists and PUT /lists/<id>, as json or as a form
+#[derive(Deserialize, FromForm)]
+pub struct ListName {
+    name: String,
+}
+
+const LIST_COLUMNS: &str = "id, name, slug, color, template";
+
+impl List {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<List> {
+        Ok(List {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            slug: row.get(2)?,
+            color: row.get(3)?,
+            template: row.get(4)?,
+        })
+    }
+}
+
+// "Groceries & Snacks!" -> "groceries-snacks"
+fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        String::from("list")
+    } else {
+        slug.to_string()
+    }
+}
+
+// Finds a slug for the name that no other list uses yet by appending -2, -3 ... to it.
+// Has to run inside the transaction that writes the slug so two lists created at the
+// same time can not end up with the same one (the unique index would reject it anyway)
+fn unique_slug(transaction: &Transaction, name: &str, list_id: Option<i64>) -> rusqlite::Result<String> {
+    let base = slugify(name);
+    let mut candidate = base.clone();
+    let mut counter = 1;
+
+    loop {
+        // the list being renamed may keep its own slug
+        let taken = transaction.query_row(
+            "select 1 from lists where slug = $1 and id is not $2",
+            params![candidate, list_id],
+            |_| Ok(()))
+            .optional()?;
+
+        if taken.is_none() {
+            return Ok(candidate);
+        }
+
+        counter += 1;
+        candidate = format!("{}-{}", base, counter);
+    }
+}
+
+pub fn fetch_list(db_connection: &Connection, id: i64) -> rusqlite::Result<Option<List>> {
+    db_connection.query_row(
+        &format!("select {} from lists where id = $1", LIST_COLUMNS), params![id], List::from_row)
+        .optional()
+}
+
+pub fn fetch_list_items(db_connection: &Connection, list_id: i64) -> rusqlite::Result<Vec<ToDoItem>> {
+    let mut statement = db_connection.prepare(
+        &format!("select {} from todo_list where list_id = $1 and archived = 0 order by {}", ITEM_COLUMNS, ITEM_ORDER))?;
+    let rows = statement.query_map(params![list_id], ToDoItem::from_row)?;
+    rows.collect()
+}
+
+pub fn with_items(db_connection: &Connection, list: Option<List>) -> Result<Option<Json<ListWithItems>>, ApiError> {
+    let list = match list {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+
+    match fetch_list_items(db_connection, list.id) {
+        Ok(items) => Ok(Some(Json(ListWithItems { list, items }))),
+        Err(_) => Err("Failed to fetch ToDo Items".into()),
+    }
+}
+
+// ?color= only returns lists with that color label
+#[get("/lists?<color>")]
+pub fn fetch_all_lists(color: Option<String>) -> Result<Json<Lists>, ApiError> {
+
+    let color = match color {
+        Some(color) => match colors::normalize_color(&color) {
+            Some(color) => Some(color),
+            None => return Err(ApiError::new(ErrorCode::ValidationFailed, colors::invalid_color_message(&color))),
+        },
+        None => None,
+    };
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(
+        &format!("select {} from lists where $1 is null or color = $1 order by name", LIST_COLUMNS))
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(params![color], List::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<List>>>());
+
+    match results {
+        Ok(lists) => Ok(Json(Lists { lists })),
+        Err(_) => Err("Failed to fetch lists".into()),
+    }
+}
+
+// Inserts a list with a fresh unique slug, inside the caller's transaction
+pub fn insert_list(transaction: &Transaction, name: &str, color: Option<&str>) -> rusqlite::Result<List> {
+    let slug = unique_slug(transaction, name, None)?;
+
+    transaction.execute(
+        "insert into lists (id, name, slug, color) values (null, $1, $2, $3)", params![name, slug, color])?;
+
+    Ok(List {
+        id: transaction.last_insert_rowid(),
+        name: name.to_string(),
+        slug,
+        color: color.map(String::from),
+        template: false,
+    })
+}
+
+// The list called name (or with its slug), inserted when there is none yet. Also
+// says whether it was inserted. For imports that name lists by text
+pub fn find_or_insert_list(transaction: &Transaction, name: &str) -> rusqlite::Result<(List, bool)> {
+    let existing = transaction.query_row(
+        &format!("select {} from lists where name = $1 collate nocase or slug = $2 order by id limit 1", LIST_COLUMNS),
+        params![name, slugify(name)],
+        List::from_row)
+        .optional()?;
+
+    match existing {
+        Some(list) => Ok((list, false)),
+        None => Ok((insert_list(transaction, name, None)?, true)),
+    }
+}
+
+#[post("/lists", format = "json", data = "<list>")]
+pub fn add_list(list: LimitedJson<ListName>) -> Result<Json<List>, ApiError> {
+    create_list(&list.0.name)
+}
+
+#[post("/lists", format = "form", data = "<list>")]
+pub fn add_list_form(list: LenientForm<ListName>, browser: Option<FromBrowser>) -> Result<FormResponse<Json<List>>, ApiError> {
+    forms::respond(browser, create_list(&list.name), |_| Ok("List added".into()))
+}
+
+fn create_list(name: &str) -> Result<Json<List>, ApiError> {
+
+    let mut db_connection = db::connect()?;
+
+    let transaction = match db_connection.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    let list = match insert_list(&transaction, name, None) {
+        Ok(list) => list,
+        Err(_) => return Err("Failed to insert list".into()),
+    };
+
+    match transaction.commit() {
+        Ok(_) => Ok(Json(list)),
+        Err(_) => Err("Failed to insert list".into()),
+    }
+}
+
+#[get("/lists/<id>")]
+pub fn fetch_list_by_id(id: i64) -> Result<Option<Json<ListWithItems>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match fetch_list(&db_connection, id) {
+        Ok(list) => with_items(&db_connection, list),
+        Err(_) => Err("Failed to fetch list".into()),
+    }
+}
+
+#[get("/lists/by-slug/<slug>")]
+pub fn fetch_list_by_slug(slug: String) -> Result<Option<Json<ListWithItems>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let list = db_connection.query_row(
+        &format!("select {} from lists where slug = $1", LIST_COLUMNS), params![slug], List::from_row)
+        .optional();
+
+    match list {
+        Ok(list) => with_items(&db_connection, list),
+        Err(_) => Err("Failed to fetch list".into()),
+    }
+}
+
+// Renaming a list also gives it a new slug, so old by-slug links stop working
+#[put("/lists/<id>", format = "json", data = "<list>")]
+pub fn rename_list(id: i64, list: LimitedJson<ListName>) -> Result<Option<Json<List>>, ApiError> {
+    rename(id, list.0.name)
+}
+
+#[put("/lists/<id>", format = "form", data = "<list>")]
+pub fn rename_list_form(id: i64, list: LenientForm<ListName>, browser: Option<FromBrowser>) -> Result<FormResponse<Option<Json<List>>>, ApiError> {
+    let result = rename(id, list.into_inner().name);
+    forms::respond(browser, result, |list| forms::not_found(list, "List renamed"))
+}
+
+fn rename(id: i64, name: String) -> Result<Option<Json<List>>, ApiError> {
+
+    let mut db_connection = db::connect()?;
+
+    let transaction = match db_connection.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    let slug = match unique_slug(&transaction, &name, Some(id)) {
+        Ok(slug) => slug,
+        Err(_) => return Err("Failed to generate a slug".into()),
+    };
+
+    let updated = match transaction.execute(
+        "update lists set name = $1, slug = $2 where id = $3", params![name, slug, id])
+    {
+        Ok(updated) => updated,
+        Err(_) => return Err("Failed to update list".into()),
+    };
+
+    if transaction.commit().is_err() {
+        return Err("Failed to update list".into());
+    }
+
+    if updated == 0 {
+        return Ok(None);
+    }
+
+    // fetched again so the response has the fields that did not change too
+    match fetch_list(&db_connection, id) {
+        Ok(list) => Ok(list.map(Json)),
+        Err(_) => Err("Failed to fetch list".into()),
+    }
+}
+
+// Deleting a list deletes its items too (on delete cascade)
+#[delete("/lists/<id>")]
+pub fn remove_list(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match db_connection.execute("delete from lists where id = $1", params![id]) {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to delete list".into()),
+    }
+}
+
+// Adds a new item to the list. Returns 404 if the list does not exist
+#[post("/lists/<id>/items", format = "json", data = "<item>")]
+pub fn add_list_item(id: i64, item: LimitedJson<String>) -> Result<Option<Json<StatusMessage>>, ApiError> {
+
+    let item = item.0;
+
+    writer::write(move |db_connection| {
+
+        // immediate for the tenants' writes, which skip the writer (see quotas.rs)
+        let transaction = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        match fetch_list(&transaction, id) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Ok(None),
+            Err(_) => return Err("Failed to fetch list".into()),
+        }
+
+        let text = crypto::encrypt_text(&validation::item_text(&item)?)?;
+        quotas::check_items(&transaction, 1)?;
+
+        // new items go to the end of the list
+        let results = transaction.execute(
+            "insert into todo_list (id, item, list_id, position)
+             values (null, $1, $2, (select coalesce(max(position), 0) + 1 from todo_list where list_id = $2))",
+            params![text, id]);
+
+        match results.and_then(|rows_added| transaction.commit().map(|_| rows_added)) {
+            Ok(rows_added) => Ok(Some(Json(StatusMessage {
+                message: format!("{} rows inserted!", rows_added),
+            }))),
+            Err(_) => Err("Failed to insert ToDo Item".into()),
+        }
+    })?
+}
+
+#[derive(Serialize)]
+pub struct MoveResult {
+    id: i64,
+    // moved, unchanged (already in the list) or not_found
+    result: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct MoveResults {
+    results: Vec<MoveResult>,
+}
+
+// Moves the items with the given ids to the end of the target list, in the order of
+// the ids. All the moves happen in one transaction. Ids that do not exist are
+// reported per item instead of failing the whole request
+#[post("/lists/<target>/move", format = "json", data = "<ids>")]
+pub fn move_items(target: i64, ids: LimitedJson<Vec<i64>>) -> Result<Option<Json<MoveResults>>, ApiError> {
+
+    let ids = ids.0;
+
+    writer::write(move |db_connection| {
+
+        match fetch_list(db_connection, target) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Ok(None),
+            Err(_) => return Err("Failed to fetch list".into()),
+        }
+
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            let current_list = transaction.query_row(
+                "select list_id from todo_list where id = $1", params![id], |row| row.get::<_, Option<i64>>(0))
+                .optional();
+
+            let result = match current_list {
+                Ok(None) => "not_found",
+                Ok(Some(Some(list_id))) if list_id == target => "unchanged",
+                Ok(Some(_)) => {
+                    let moved = transaction.execute(
+                        "update todo_list set list_id = $1,
+                         position = (select coalesce(max(position), 0) + 1 from todo_list where list_id = $1)
+                         where id = $2",
+                        params![target, id]);
+                    if moved.is_err() {
+                        return Err("Failed to move ToDo Items".into());
+                    }
+                    "moved"
+                }
+                Err(_) => return Err("Failed to fetch ToDo Item".into()),
+            };
+
+            results.push(MoveResult { id, result });
+        }
+
+        match transaction.commit() {
+            Ok(_) => Ok(Some(Json(MoveResults { results }))),
+            Err(_) => Err("Failed to move ToDo Items".into()),
+        }
+    })?
+}
+
+// Moves every item of list id to the end of list other (keeping their order) and
+// deletes list id, all in one transaction. Responds with the merged list
+#[post("/lists/<id>/merge-into/<other>")]
+pub fn merge_lists(id: i64, other: i64) -> Result<Option<Json<ListWithItems>>, ApiError> {
+
+    if id == other {
+        return Err(ApiError::new(ErrorCode::BadRequest, "Can not merge a list into itself"));
+    }
+
+    writer::write(move |db_connection| {
+
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        for list_id in [id, other].iter() {
+            match fetch_list(&transaction, *list_id) {
+                Ok(Some(_)) => (),
+                Ok(None) => return Ok(None),
+                Err(_) => return Err("Failed to fetch list".into()),
+            }
+        }
+
+        let last_position = transaction.query_row(
+            "select coalesce(max(position), 0) from todo_list where list_id = $1", params![other], |row| row.get::<_, i64>(0));
+        let last_position = match last_position {
+            Ok(last_position) => last_position,
+            Err(_) => return Err("Failed to fetch list".into()),
+        };
+
+        // archived items are moved too, otherwise they would be deleted with the list
+        let source_items = transaction
+            .prepare("select id from todo_list where list_id = $1 order by position, id")
+            .and_then(|mut statement| {
+                let rows = statement.query_map(params![id], |row| row.get::<_, i64>(0))?;
+                rows.collect::<rusqlite::Result<Vec<i64>>>()
+            });
+        let source_items = match source_items {
+            Ok(source_items) => source_items,
+            Err(_) => return Err("Failed to fetch ToDo Items".into()),
+        };
+
+        for (offset, item_id) in source_items.iter().enumerate() {
+            let moved = transaction.execute(
+                "update todo_list set list_id = $1, position = $2 where id = $3",
+                params![other, last_position + 1 + offset as i64, item_id]);
+            if moved.is_err() {
+                return Err("Failed to move ToDo Items".into());
+            }
+        }
+
+        if transaction.execute("delete from lists where id = $1", params![id]).is_err() {
+            return Err("Failed to delete list".into());
+        }
+
+        if transaction.commit().is_err() {
+            return Err("Failed to merge lists".into());
+        }
+
+        match fetch_list(db_connection, other) {
+            Ok(list) => with_items(db_connection, list),
+            Err(_) => Err("Failed to fetch list".into()),
+        }
+    })?
+}
+
+// Copies the (not archived) items of one list into another, keeping their order and
+// tags. fresh copies start out as open todo items, otherwise done/status are copied
+// too. Client keys and uuids are never copied because they have to stay unique
+fn copy_items(transaction: &Transaction, from: i64, to: i64, open_only: bool, fresh: bool) -> rusqlite::Result<()> {
+    let source_items = {
+        let mut statement = transaction.prepare(
+            "select id from todo_list
+             where list_id = $1 and archived = 0 and ($2 = 0 or done = 0)
+             order by position, id")?;
+        let rows = statement.query_map(params![from, open_only], |row| row.get::<_, i64>(0))?;
+        rows.collect::<rusqlite::Result<Vec<i64>>>()?
+    };
+
+    for source_id in source_items {
+        transaction.execute(
+            "insert into todo_list (item, done, status, pinned, color, position, list_id)
+             select item,
+                    case when $3 then 0 else done end,
+                    case when $3 then 'todo' else status end,
+                    pinned, color, position, $1
+             from todo_list where id = $2",
+            params![to, source_id, fresh])?;
+
+        let copy_id = transaction.last_insert_rowid();
+        transaction.execute(
+            "insert into item_tags (item_id, tag_id) select $1, tag_id from item_tags where item_id = $2",
+            params![copy_id, source_id])?;
+    }
+    Ok(())
+}
+
+// Copies a list and its items (not archived ones) in one transaction, handy for
+// recurring checklists like packing lists. ?open_only=true leaves out completed items.
+// The copies keep text, status, color, pin and order
+#[post("/lists/<id>/duplicate?<open_only>")]
+pub fn duplicate_list(id: i64, open_only: Option<bool>) -> Result<Option<Json<ListWithItems>>, ApiError> {
+
+    writer::write(move |db_connection| {
+
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        let source = match fetch_list(&transaction, id) {
+            Ok(Some(source)) => source,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err("Failed to fetch list".into()),
+        };
+
+        let copy = match insert_list(&transaction, &format!("{} (copy)", source.name), source.color.as_deref()) {
+            Ok(copy) => copy,
+            Err(_) => return Err("Failed to insert list".into()),
+        };
+
+        if copy_items(&transaction, id, copy.id, open_only.unwrap_or(false), false).is_err() {
+            return Err("Failed to copy ToDo Items".into());
+        }
+        quotas::check_items(&transaction, 0)?;
+
+        if transaction.commit().is_err() {
+            return Err("Failed to duplicate list".into());
+        }
+
+        match fetch_list(db_connection, copy.id) {
+            Ok(list) => with_items(db_connection, list),
+            Err(_) => Err("Failed to fetch list".into()),
+        }
+    })?
+}
+
+fn set_template(id: i64, template: bool) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match db_connection.execute("update lists set template = $1 where id = $2", params![template, id]) {
+        Ok(rows_updated) => Ok(Json(StatusMessage {
+            message: format!("{} rows updated", rows_updated),
+        })),
+        Err(_) => Err("Failed to update list".into()),
+    }
+}
+
+#[put("/lists/<id>/template")]
+pub fn mark_template(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_template(id, true)
+}
+
+#[delete("/lists/<id>/template")]
+pub fn unmark_template(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_template(id, false)
+}
+
+// Creates a new list from a template list, with fresh (open) copies of its items in
+// the same order. ?name= names the new list, by default it gets the template's name.
+// Ranked so it does not collide with the POST /lists/<id>/... routes
+#[post("/lists/from-template/<id>?<name>", rank = 1)]
+pub fn instantiate_template(id: i64, name: Option<String>) -> Result<Option<Json<ListWithItems>>, ApiError> {
+
+    writer::write(move |db_connection| {
+
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        let template = match fetch_list(&transaction, id) {
+            Ok(Some(template)) if template.template => template,
+            Ok(_) => return Ok(None),
+            Err(_) => return Err("Failed to fetch list".into()),
+        };
+
+        let name = name.unwrap_or_else(|| template.name.clone());
+        let list = match insert_list(&transaction, &name, template.color.as_deref()) {
+            Ok(list) => list,
+            Err(_) => return Err("Failed to insert list".into()),
+        };
+
+        if copy_items(&transaction, template.id, list.id, false, true).is_err() {
+            return Err("Failed to copy ToDo Items".into());
+        }
+        quotas::check_items(&transaction, 0)?;
+
+        if transaction.commit().is_err() {
+            return Err("Failed to create list from template".into());
+        }
+
+        match fetch_list(db_connection, list.id) {
+            Ok(list) => with_items(db_connection, list),
+            Err(_) => Err("Failed to fetch list".into()),
+        }
+    })?
+}
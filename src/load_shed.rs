@@ -0,0 +1,152 @@
+// Load shedding
+//
+// Counts the requests that are being handled right now. Above
+// shed_low_priority_above, expensive reads that can wait (search, suggestions,
+// the board, smart list results) get 503 with a Retry-After header; above
+// shed_all_above everything does except the admin api and /health. Answering a
+// few requests with "come back in a moment" keeps the rest fast instead of every
+// request timing out once the workers are all busy.
+//
+// Writes all wait for the one database writer (see writer.rs), so a backlog there
+// does not show up as many requests in flight until the workers are stuck on it.
+// With more than shed_writes_above writes queued for the writer, requests that
+// change something (anything but GET and HEAD) are shed as well. 0 turns a
+// threshold off.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Request, Rocket, State};
+use crate::error::{ApiError, ErrorCode};
+use crate::writer;
+
+const OVERLOADED_PATH: &str = "/__overloaded";
+
+// seconds clients are told to wait when load_shed_retry_after is not configured
+const DEFAULT_RETRY_AFTER: u64 = 5;
+
+// shed first when busy
+const LOW_PRIORITY_PREFIXES: [&str; 4] = ["/todo/search", "/todo/suggest", "/board", "/smartlists/"];
+
+// never shed, so the instance can still be inspected and managed while overloaded
+const HIGH_PRIORITY_PREFIXES: [&str; 3] = ["/health", "/admin", "/__"];
+
+enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+fn priority(path: &str) -> Priority {
+    if HIGH_PRIORITY_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+        Priority::High
+    } else if LOW_PRIORITY_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+        Priority::Low
+    } else {
+        Priority::Normal
+    }
+}
+
+pub struct LoadShedder {
+    in_flight: AtomicUsize,
+    shed_low_priority_above: usize,
+    shed_all_above: usize,
+    shed_writes_above: usize,
+    retry_after: u64,
+}
+
+impl LoadShedder {
+    fn should_shed(&self, in_flight: usize, queued_writes: usize, priority: Priority, write: bool) -> bool {
+        let over = |threshold: usize, count: usize| threshold > 0 && count > threshold;
+        let writer_behind = write && over(self.shed_writes_above, queued_writes);
+
+        match priority {
+            Priority::High => false,
+            Priority::Normal => over(self.shed_all_above, in_flight) || writer_behind,
+            Priority::Low => {
+                over(self.shed_all_above, in_flight) || over(self.shed_low_priority_above, in_flight) || writer_behind
+            }
+        }
+    }
+}
+
+// whether this request was counted in in_flight, so the response undoes it
+struct Counted(bool);
+
+fn threshold(rocket: &Rocket, key: &str) -> usize {
+    rocket.config().get_int(key).map(|value| value.max(0) as usize).unwrap_or(0)
+}
+
+pub struct LoadShed;
+
+impl Fairing for LoadShed {
+    fn info(&self) -> Info {
+        Info {
+            name: "Load shedding",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let retry_after = rocket.config().get_int("load_shed_retry_after")
+            .map(|seconds| seconds.max(0) as u64)
+            .unwrap_or(DEFAULT_RETRY_AFTER);
+
+        let shedder = LoadShedder {
+            in_flight: AtomicUsize::new(0),
+            shed_low_priority_above: threshold(&rocket, "shed_low_priority_above"),
+            shed_all_above: threshold(&rocket, "shed_all_above"),
+            shed_writes_above: threshold(&rocket, "shed_writes_above"),
+            retry_after,
+        };
+
+        Ok(rocket.manage(shedder).mount("/", routes![overloaded]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let shed = match request.guard::<State<LoadShedder>>() {
+            rocket::Outcome::Success(shedder) => {
+                // this request included
+                let in_flight = shedder.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                request.local_cache(|| Counted(true));
+                let write = request.method() != Method::Get && request.method() != Method::Head;
+                shedder.should_shed(in_flight, writer::queued(), priority(request.uri().path()), write)
+            }
+            _ => false,
+        };
+
+        if shed {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(OVERLOADED_PATH).expect("valid overloaded path"));
+        }
+    }
+
+    fn on_response(&self, request: &Request, _: &mut Response) {
+        if !request.local_cache(|| Counted(false)).0 {
+            return;
+        }
+        if let rocket::Outcome::Success(shedder) = request.guard::<State<LoadShedder>>() {
+            shedder.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+pub struct Overloaded(u64);
+
+impl<'r> Responder<'r> for Overloaded {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let error = ApiError::new(ErrorCode::Overloaded, "The server is busy, please try again in a moment");
+
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", self.0.to_string())
+            .ok()
+    }
+}
+
+#[get("/__overloaded")]
+pub fn overloaded(shedder: State<LoadShedder>) -> Overloaded {
+    Overloaded(shedder.retry_after)
+}
@@ -0,0 +1,277 @@
+// Lockout after failed logins
+//
+// Every request whose Basic auth credentials or bearer token are rejected counts as
+// a failed login; credentials that could not be checked, with the database or
+// directory server down, do not. Failures are counted per account: the client
+// address (see ip_filter::client_ip) together with the Basic auth username, or the
+// address alone for bearer tokens. With a directory server (see ldap.rs) behind one
+// address there are many users, and one of them mistyping their password should not
+// lock out the others. After login_max_failures failures in a row (within
+// login_failure_window seconds of each other) the account is locked out for
+// login_lockout_seconds, and every further failure doubles that, up to a day. A
+// successful login clears the count. While locked out, every request for the
+// account that needs credentials gets 429 with Retry-After, even with the right
+// ones, so guessing on does not pay off. login_max_failures = 0 turns this off.
+//
+// The counts are kept in the login_failures table of the main database (shared by
+// all tenants), so they survive a restart.
+//
+// Admin api:
+//   GET    /admin/lockouts           - accounts with failed logins
+//   DELETE /admin/lockouts/<address> - forgets the failures of every account at an
+//                                      address, lifting their lockouts
+
+use std::net::IpAddr;
+
+use rocket::response::{self, Responder, Response};
+use rocket::{Request, Rocket};
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::auth::Admin;
+use crate::error::{ApiError, ErrorCode};
+use crate::{clock, db, StatusMessage};
+
+pub const LOCKED_OUT_PATH: &str = "/__locked_out";
+const MAX_LOCKOUT_SECONDS: i64 = 24 * 3600;
+
+pub struct LockoutPolicy {
+    // 0 = off
+    max_failures: i64,
+    lockout_seconds: i64,
+    failure_window: i64,
+}
+
+// Whose logins are counted: the client address and the Basic auth username, empty
+// for bearer tokens
+pub struct Account<'a> {
+    pub address: IpAddr,
+    pub username: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct LoginFailures {
+    address: String,
+    // None for bearer tokens
+    username: Option<String>,
+    failures: i64,
+    last_failure_at: i64,
+    // unix time, None when not locked out
+    locked_until: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Lockouts {
+    lockouts: Vec<LoginFailures>,
+}
+
+// The lockout of this request, for the Retry-After header
+struct LockedUntil(i64);
+
+impl LockoutPolicy {
+    pub fn from_rocket(rocket: &Rocket) -> Result<LockoutPolicy, String> {
+        let policy = LockoutPolicy {
+            max_failures: rocket.config().get_int("login_max_failures").unwrap_or(5),
+            lockout_seconds: rocket.config().get_int("login_lockout_seconds").unwrap_or(60),
+            failure_window: rocket.config().get_int("login_failure_window").unwrap_or(900),
+        };
+        if policy.max_failures < 0 || policy.lockout_seconds < 1 || policy.failure_window < 1 {
+            return Err("login_max_failures can not be negative, login_lockout_seconds and login_failure_window have to be at least 1".into());
+        }
+        Ok(policy)
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_failures > 0
+    }
+
+    // lockout_seconds for the failure that reached max_failures, doubling from there
+    fn lockout_for(&self, failures: i64) -> Option<i64> {
+        if failures < self.max_failures {
+            return None;
+        }
+        let doublings = (failures - self.max_failures).min(20) as u32;
+        Some(self.lockout_seconds.saturating_mul(1 << doublings).min(MAX_LOCKOUT_SECONDS))
+    }
+}
+
+fn locked_until(db_connection: &Connection, address: &str, username: &str) -> rusqlite::Result<Option<i64>> {
+    db_connection.query_row(
+        "select locked_until from login_failures where address = $1 and username = $2",
+        params![address, username], |row| row.get(0))
+        .optional()
+        .map(Option::flatten)
+}
+
+// Returns until when the account is locked out, None when it is not. With the
+// database failing the request is let through, the credentials are still checked
+pub fn check(request: &Request, policy: &LockoutPolicy, account: &Account) -> Option<i64> {
+    if !policy.enabled() {
+        return None;
+    }
+    let address = account.address.to_string();
+    let locked_until = db::connect_main().ok()
+        .and_then(|db_connection| db::retry(|| locked_until(&db_connection, &address, account.username)).ok())
+        .flatten()
+        .filter(|locked_until| *locked_until > clock::now())?;
+    request.local_cache(|| LockedUntil(locked_until));
+    Some(locked_until)
+}
+
+fn failure(db_connection: &Connection, policy: &LockoutPolicy, address: &str, username: &str) -> rusqlite::Result<()> {
+    let now = clock::now();
+    let previous: Option<(i64, i64)> = db_connection.query_row(
+        "select failures, last_failure_at from login_failures where address = $1 and username = $2",
+        params![address, username], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+
+    // failures further apart than the window start a new count
+    let failures = match previous {
+        Some((failures, last_failure_at)) if now - last_failure_at <= policy.failure_window => failures + 1,
+        _ => 1,
+    };
+    let locked_until = policy.lockout_for(failures).map(|seconds| now + seconds);
+
+    db_connection.execute(
+        "insert into login_failures (address, username, failures, last_failure_at, locked_until) values ($1, $2, $3, $4, $5)
+         on conflict(address, username) do update set failures = $3, last_failure_at = $4, locked_until = $5",
+        params![address, username, failures, now, locked_until])?;
+    Ok(())
+}
+
+pub fn record_failure(policy: &LockoutPolicy, account: &Account) {
+    if !policy.enabled() {
+        return;
+    }
+    let address = account.address.to_string();
+    let recorded = db::connect_main()
+        .and_then(|db_connection| db::retry(|| failure(&db_connection, policy, &address, account.username))
+            .map_err(|e| e.to_string()));
+    if let Err(e) = recorded {
+        println!("Failed to record a failed login from {}: {}", address, e);
+    }
+}
+
+// Clears the failures of the account. Only writes when there are any, this runs for
+// every authenticated request
+pub fn record_success(policy: &LockoutPolicy, account: &Account) {
+    if !policy.enabled() {
+        return;
+    }
+    if let Ok(db_connection) = db::connect_main() {
+        let address = account.address.to_string();
+        let failed = db_connection.query_row(
+            "select 1 from login_failures where address = $1 and username = $2",
+            params![address, account.username], |_| Ok(()))
+            .optional();
+        if let Ok(Some(_)) = failed {
+            let _ = db_connection.execute(
+                "delete from login_failures where address = $1 and username = $2", params![address, account.username]);
+        }
+    }
+}
+
+pub struct LockedOut;
+
+impl<'r> Responder<'r> for LockedOut {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let error = ApiError::new(ErrorCode::RateLimited, "Too many failed logins, try again later");
+
+        let retry_after = (request.local_cache(|| LockedUntil(0)).0 - clock::now()).max(1);
+
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", retry_after.to_string())
+            .ok()
+    }
+}
+
+#[get("/__locked_out")]
+pub fn locked_out() -> LockedOut {
+    LockedOut
+}
+
+#[get("/lockouts")]
+pub fn fetch_lockouts(_admin: Admin) -> Result<Json<Lockouts>, ApiError> {
+
+    let db_connection = db::connect_main()?;
+
+    let mut statement = match db_connection.prepare(
+        "select address, username, failures, last_failure_at, locked_until from login_failures order by last_failure_at desc") {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let now = clock::now();
+    let results = statement
+        .query_map(rusqlite::NO_PARAMS, |row| Ok(LoginFailures {
+            address: row.get(0)?,
+            username: Some(row.get::<_, String>(1)?).filter(|username| !username.is_empty()),
+            failures: row.get(2)?,
+            last_failure_at: row.get(3)?,
+            locked_until: row.get::<_, Option<i64>>(4)?.filter(|locked_until| *locked_until > now),
+        }))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<LoginFailures>>>());
+
+    match results {
+        Ok(lockouts) => Ok(Json(Lockouts { lockouts })),
+        Err(_) => Err("Failed to fetch lockouts".into()),
+    }
+}
+
+#[delete("/lockouts/<address>")]
+pub fn remove_lockout(_admin: Admin, address: String) -> Result<Json<StatusMessage>, ApiError> {
+    if address.parse::<IpAddr>().is_err() {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, format!("{} is not an IP address", address)));
+    }
+
+    let db_connection = db::connect_main()?;
+
+    match db_connection.execute("delete from login_failures where address = $1", params![address]) {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to delete lockout".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_failures: i64, lockout_seconds: i64) -> LockoutPolicy {
+        LockoutPolicy { max_failures, lockout_seconds, failure_window: 900 }
+    }
+
+    #[test]
+    fn no_lockout_below_the_threshold() {
+        let policy = policy(5, 60);
+        for failures in 0..5 {
+            assert_eq!(policy.lockout_for(failures), None, "{}", failures);
+        }
+    }
+
+    #[test]
+    fn locks_out_at_the_threshold() {
+        assert_eq!(policy(5, 60).lockout_for(5), Some(60));
+        assert_eq!(policy(1, 30).lockout_for(1), Some(30));
+    }
+
+    #[test]
+    fn every_further_failure_doubles_the_lockout() {
+        let policy = policy(5, 60);
+        assert_eq!(policy.lockout_for(6), Some(120));
+        assert_eq!(policy.lockout_for(7), Some(240));
+        assert_eq!(policy.lockout_for(8), Some(480));
+    }
+
+    #[test]
+    fn the_lockout_is_capped_at_a_day() {
+        let policy = policy(5, 60);
+        assert_eq!(policy.lockout_for(16), Some(MAX_LOCKOUT_SECONDS));
+        assert_eq!(policy.lockout_for(1000), Some(MAX_LOCKOUT_SECONDS));
+        assert_eq!(policy.lockout_for(i64::MAX), Some(MAX_LOCKOUT_SECONDS));
+        assert_eq!(LockoutPolicy { max_failures: 1, lockout_seconds: i64::MAX, failure_window: 1 }.lockout_for(3),
+            Some(MAX_LOCKOUT_SECONDS));
+    }
+}
@@ -0,0 +1,104 @@
+// Read-only (maintenance) mode
+//
+// While the service is read-only, GET requests keep working and every request that
+// would change data gets 503 with a Retry-After header, e.g. while a backup is taken
+// or a migration runs. It can start read-only with read_only = true in Rocket.toml
+// and is switched at runtime with PUT /admin/maintenance.
+// The /admin routes stay usable so the mode can be switched off again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Request, Rocket, State};
+use crate::error::{ApiError, ErrorCode};
+
+const READ_ONLY_PATH: &str = "/__read_only";
+
+// seconds clients are told to wait when maintenance_retry_after is not configured
+const DEFAULT_RETRY_AFTER: u64 = 300;
+
+pub struct MaintenanceMode {
+    read_only: AtomicBool,
+    retry_after: u64,
+}
+
+impl MaintenanceMode {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
+    }
+}
+
+fn is_mutating(method: Method) -> bool {
+    !matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+pub struct Maintenance;
+
+impl Fairing for Maintenance {
+    fn info(&self) -> Info {
+        Info {
+            name: "Read-only mode",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let config = rocket.config();
+        let retry_after = config.get_int("maintenance_retry_after")
+            .map(|seconds| seconds.max(0) as u64)
+            .unwrap_or(DEFAULT_RETRY_AFTER);
+
+        let mode = MaintenanceMode {
+            read_only: AtomicBool::new(config.get_bool("read_only").unwrap_or(false)),
+            retry_after,
+        };
+
+        Ok(rocket.manage(mode).mount("/", routes![read_only]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let rejected = match request.guard::<State<MaintenanceMode>>() {
+            rocket::Outcome::Success(mode) => {
+                let path = request.uri().path();
+                mode.is_read_only()
+                    && is_mutating(request.method())
+                    && !path.starts_with("/admin")
+                    && !path.starts_with("/__")
+            }
+            _ => false,
+        };
+
+        if rejected {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(READ_ONLY_PATH).expect("valid read only path"));
+        }
+    }
+}
+
+pub struct ReadOnly(u64);
+
+impl<'r> Responder<'r> for ReadOnly {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let error = ApiError::new(ErrorCode::ReadOnly, "The service is in read-only mode for maintenance, please try again later");
+
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", self.0.to_string())
+            .ok()
+    }
+}
+
+#[get("/__read_only")]
+pub fn read_only(mode: State<MaintenanceMode>) -> ReadOnly {
+    ReadOnly(mode.retry_after())
+}
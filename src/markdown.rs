@@ -0,0 +1,104 @@
+// Markdown checklist export
+//
+// GET /lists/<id>/export.md writes a list as a GitHub style checklist, ready to be
+// pasted into an issue or a wiki page:
+//
+//     # Groceries
+//
+//     - [ ] Bread
+//
+//     ## dairy
+//
+//     - [ ] Milk (due 2024-05-03)
+//     - [x] Butter
+//
+// ?group_by=tag (the default) puts a heading per tag, the items without tags come
+// first under the list's heading. An item with several tags goes under the first
+// one in alphabetical order, so every item is in the checklist once.
+// ?group_by=status puts a heading per workflow status (see workflow.rs) instead, in
+// the board's order. Archived items are left out, due dates are days in the user's
+// time zone (see timezone.rs).
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::timezone::{TimeZone, UserTimeZone};
+use crate::workflow::ItemStatus;
+use crate::{clock, db, lists, ToDoItem};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum GroupBy {
+    Tag,
+    Status,
+}
+
+impl GroupBy {
+    fn parse(group_by: Option<String>) -> Result<GroupBy, ApiError> {
+        match group_by.as_deref() {
+            None | Some("tag") => Ok(GroupBy::Tag),
+            Some("status") => Ok(GroupBy::Status),
+            Some(_) => Err(ApiError::new(ErrorCode::ValidationFailed, "group_by has to be tag or status")),
+        }
+    }
+}
+
+// the heading an item goes under, None for the top of the list
+fn heading(item: &ToDoItem, group_by: GroupBy) -> Option<String> {
+    match group_by {
+        GroupBy::Tag => item.tags.iter().min().cloned(),
+        GroupBy::Status => Some(item.status.as_str().to_string()),
+    }
+}
+
+fn checklist_line(item: &ToDoItem, timezone: TimeZone) -> String {
+    // an item is one line of the checklist
+    let text = item.item.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let mut line = format!("- [{}] {}", if item.done { "x" } else { " " }, text);
+    if let Some(due_at) = item.due_at {
+        line.push_str(&format!(" (due {})", clock::format_date(timezone.day(due_at))));
+    }
+    line
+}
+
+#[get("/lists/<id>/export.md?<group_by>")]
+pub fn export(id: i64, group_by: Option<String>, timezone: UserTimeZone) -> Result<Option<Content<String>>, ApiError> {
+
+    let group_by = GroupBy::parse(group_by)?;
+    let timezone = timezone.resolve()?;
+    let db_connection = db::connect()?;
+
+    let list = match lists::fetch_list(&db_connection, id) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch list".into()),
+    };
+    let items = match lists::fetch_list_items(&db_connection, id) {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+
+    let mut headings: Vec<Option<String>> = items.iter().map(|item| heading(item, group_by)).collect();
+    match group_by {
+        // None sorts first
+        GroupBy::Tag => headings.sort(),
+        GroupBy::Status => headings.sort_by_key(|heading| {
+            ItemStatus::ALL.iter().position(|status| Some(status.as_str()) == heading.as_deref())
+        }),
+    }
+    headings.dedup();
+
+    let mut text = format!("# {}\n", list.name());
+    for section in headings {
+        if let Some(section) = &section {
+            text.push_str(&format!("\n## {}\n", section));
+        }
+        text.push('\n');
+        for item in items.iter().filter(|item| heading(item, group_by) == section) {
+            text.push_str(&checklist_line(item, timezone));
+            text.push('\n');
+        }
+    }
+
+    Ok(Some(Content(ContentType::new("text", "markdown"), text)))
+}
@@ -0,0 +1,229 @@
+// Usage metering, for billing and capacity planning
+//
+// Every request is counted per UTC day, tenant (see tenants.rs, none outside
+// multi-tenant mode) and api token (see tokens.rs, 0 for Basic auth and requests
+// without credentials), and the size of the database the request used is noted per
+// day and tenant. The numbers are kept in memory and added to the usage_calls and
+// usage_storage tables of the main database at most every FLUSH_INTERVAL (on the
+// request that comes after it), so a crash loses at most that much.
+//
+// GET /admin/usage?period= sums them up for a calendar month (2024-05, the default is
+// the current one) or a single day (2024-05-01):
+//
+//     {"period": "2024-05", "from": "2024-05-01", "to": "2024-05-31",
+//      "calls": [{"tenant": "acme", "token_id": 3, "calls": 1520}],
+//      "storage": [{"tenant": "acme", "bytes": 901120, "max_bytes": 1048576}]}
+//
+// storage has the size at the end of the period (the last day with requests) and
+// the largest size seen in it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Request, Response};
+use rocket_contrib::json::Json;
+use rusqlite::params;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::auth::{self, Admin};
+use crate::error::{ApiError, ErrorCode};
+use crate::{clock, db, tenants};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// the tenant is "" for the main database
+struct Pending {
+    calls: HashMap<(i64, String, i64), i64>,
+    storage: HashMap<(i64, String), i64>,
+    flushed_at: Option<Instant>,
+}
+
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+#[derive(Serialize)]
+pub struct CallUsage {
+    tenant: Option<String>,
+    token_id: Option<i64>,
+    calls: i64,
+}
+
+#[derive(Serialize)]
+pub struct StorageUsage {
+    tenant: Option<String>,
+    bytes: i64,
+    max_bytes: i64,
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    period: String,
+    // first and last day, yyyy-mm-dd
+    from: String,
+    to: String,
+    calls: Vec<CallUsage>,
+    storage: Vec<StorageUsage>,
+}
+
+fn record(tenant: String, token_id: i64, database_bytes: Option<i64>) {
+    let day = clock::now().div_euclid(86_400);
+    let mut pending = PENDING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let pending = pending.get_or_insert_with(|| Pending {
+        calls: HashMap::new(),
+        storage: HashMap::new(),
+        flushed_at: None,
+    });
+
+    *pending.calls.entry((day, tenant.clone(), token_id)).or_insert(0) += 1;
+    if let Some(bytes) = database_bytes {
+        pending.storage.insert((day, tenant), bytes);
+    }
+
+    let due = pending.flushed_at.map_or(true, |flushed_at| flushed_at.elapsed() >= FLUSH_INTERVAL);
+    if due {
+        pending.flushed_at = Some(Instant::now());
+        if let Err(e) = write(pending) {
+            println!("{}", e);
+        }
+    }
+}
+
+// Adds the pending numbers to the tables. They are kept when that fails, and tried
+// again with the next flush
+fn write(pending: &mut Pending) -> Result<(), String> {
+    if pending.calls.is_empty() && pending.storage.is_empty() {
+        return Ok(());
+    }
+    let mut db_connection = db::connect_main()?;
+    let transaction = db_connection.transaction().map_err(|e| e.to_string())?;
+
+    for ((day, tenant, token_id), calls) in &pending.calls {
+        transaction.execute(
+            "insert into usage_calls (day, tenant, token_id, calls) values ($1, $2, $3, $4)
+             on conflict (day, tenant, token_id) do update set calls = calls + excluded.calls",
+            params![day, tenant, token_id, calls])
+            .map_err(|e| format!("Failed to record usage: {}", e))?;
+    }
+    for ((day, tenant), bytes) in &pending.storage {
+        transaction.execute(
+            "insert into usage_storage (day, tenant, bytes, max_bytes) values ($1, $2, $3, $3)
+             on conflict (day, tenant) do update set bytes = excluded.bytes, max_bytes = max(max_bytes, excluded.bytes)",
+            params![day, tenant, bytes])
+            .map_err(|e| format!("Failed to record usage: {}", e))?;
+    }
+
+    transaction.commit().map_err(|e| format!("Failed to record usage: {}", e))?;
+    pending.calls.clear();
+    pending.storage.clear();
+    Ok(())
+}
+
+fn flush() -> Result<(), String> {
+    let mut pending = PENDING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match pending.as_mut() {
+        Some(pending) => {
+            pending.flushed_at = Some(Instant::now());
+            write(pending)
+        }
+        None => Ok(()),
+    }
+}
+
+pub struct Metering;
+
+impl Fairing for Metering {
+    fn info(&self) -> Info {
+        Info {
+            name: "Usage metering",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, _: &mut Response) {
+        let token_id = auth::current_grant(request).and_then(|grant| grant.token_id).unwrap_or(0);
+        let database_bytes = fs::metadata(db::database_file()).ok().map(|metadata| metadata.len() as i64);
+        record(tenants::current_tenant().unwrap_or_default(), token_id, database_bytes);
+    }
+}
+
+// (first day, day after the last, label) of a month (yyyy-mm) or a day (yyyy-mm-dd)
+fn parse_period(period: Option<String>) -> Result<(i64, i64, String), ApiError> {
+    let today = clock::now().div_euclid(86_400);
+    let period = period.unwrap_or_else(|| clock::format_date(today)[..7].to_string());
+
+    if let Some(day) = clock::parse_date(&period) {
+        return Ok((day, day + 1, period));
+    }
+    if period.len() == 7 {
+        if let Some(first) = clock::parse_date(&format!("{}-01", period)) {
+            let year: i64 = period[..4].parse().unwrap_or(0);
+            let month: u32 = period[5..].parse().unwrap_or(1);
+            return Ok((first, first + clock::days_in_month(year, month) as i64, period));
+        }
+    }
+    Err(ApiError::new(ErrorCode::ValidationFailed, "period has to be a month like 2024-05 or a day like 2024-05-01")
+        .with_details(json!({ "parameter": "period", "value": period })))
+}
+
+fn tenant_name(tenant: String) -> Option<String> {
+    if tenant.is_empty() { None } else { Some(tenant) }
+}
+
+#[get("/usage?<period>")]
+pub fn fetch_usage(_admin: Admin, period: Option<String>) -> Result<Json<Usage>, ApiError> {
+    let (from, to, period) = parse_period(period)?;
+
+    // the numbers of the last minute are not in the tables yet
+    flush()?;
+
+    let db_connection = db::connect_main()?;
+
+    let calls = db_connection.prepare(
+        "select tenant, token_id, sum(calls) from usage_calls where day >= $1 and day < $2
+         group by tenant, token_id order by tenant, token_id")
+        .and_then(|mut statement| {
+            statement.query_map(params![from, to], |row| {
+                let token_id: i64 = row.get(1)?;
+                Ok(CallUsage {
+                    tenant: tenant_name(row.get(0)?),
+                    token_id: if token_id == 0 { None } else { Some(token_id) },
+                    calls: row.get(2)?,
+                })
+            })?.collect::<rusqlite::Result<Vec<CallUsage>>>()
+        });
+    let calls = match calls {
+        Ok(calls) => calls,
+        Err(_) => return Err("Failed to fetch usage".into()),
+    };
+
+    let storage = db_connection.prepare(
+        "select tenant,
+                (select bytes from usage_storage latest
+                 where latest.tenant = usage_storage.tenant and latest.day >= $1 and latest.day < $2
+                 order by latest.day desc limit 1),
+                max(max_bytes)
+         from usage_storage where day >= $1 and day < $2
+         group by tenant order by tenant")
+        .and_then(|mut statement| {
+            statement.query_map(params![from, to], |row| Ok(StorageUsage {
+                tenant: tenant_name(row.get(0)?),
+                bytes: row.get(1)?,
+                max_bytes: row.get(2)?,
+            }))?.collect::<rusqlite::Result<Vec<StorageUsage>>>()
+        });
+    let storage = match storage {
+        Ok(storage) => storage,
+        Err(_) => return Err("Failed to fetch usage".into()),
+    };
+
+    Ok(Json(Usage {
+        period,
+        from: clock::format_date(from),
+        to: clock::format_date(to - 1),
+        calls,
+        storage,
+    }))
+}
@@ -0,0 +1,167 @@
+// Per route request metrics
+//
+// For every route the number of requests and the time spent in them, split into
+//   db            time sqlite spent running the request's statements (including
+//                 writes waiting on the write queue, see writer.rs)
+//   serialization time from the last database statement to the finished response,
+//                 which is mostly turning the result into json
+// GET /metrics exports them in the Prometheus text format, together with the WAL
+// checkpoint statistics (see housekeeping.rs).
+//
+// Rocket 0.4 handles a request on one worker thread from start to end, so the
+// database time is collected in a thread local that is reset for every request.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::{Data, Request, Response, Rocket, State};
+
+use crate::housekeeping::Checkpoint;
+
+thread_local! {
+    static DB_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+    static LAST_STATEMENT_END: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// Adds database time to the request running on this thread. Called by the
+// statement profiler in db.rs and by the write queue
+pub fn record_db_time(duration: Duration) {
+    DB_TIME.with(|total| total.set(total.get() + duration));
+    LAST_STATEMENT_END.with(|end| end.set(Some(Instant::now())));
+}
+
+struct CheckpointMetrics {
+    runs: u64,
+    // runs that could not finish because another connection was in the way
+    busy: u64,
+    duration: Duration,
+    checkpointed_frames: u64,
+    // size of the -wal file in pages before the last checkpoint
+    last_log_frames: i64,
+}
+
+static CHECKPOINTS: Mutex<CheckpointMetrics> = Mutex::new(CheckpointMetrics {
+    runs: 0,
+    busy: 0,
+    duration: Duration::from_secs(0),
+    checkpointed_frames: 0,
+    last_log_frames: 0,
+});
+
+pub fn record_checkpoint(checkpoint: &Checkpoint, duration: Duration) {
+    let mut checkpoints = CHECKPOINTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    checkpoints.runs += 1;
+    if checkpoint.busy {
+        checkpoints.busy += 1;
+    }
+    checkpoints.duration += duration;
+    checkpoints.checkpointed_frames += checkpoint.checkpointed_frames.max(0) as u64;
+    checkpoints.last_log_frames = checkpoint.log_frames;
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    requests: u64,
+    total: Duration,
+    db: Duration,
+    serialization: Duration,
+}
+
+// keyed by "GET /todo/<id>", sorted so the export is stable
+pub struct Metrics {
+    routes: Mutex<BTreeMap<String, RouteMetrics>>,
+}
+
+struct RequestStart(Instant);
+
+pub struct RequestMetrics;
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.manage(Metrics { routes: Mutex::new(BTreeMap::new()) }))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        DB_TIME.with(|total| total.set(Duration::from_secs(0)));
+        LAST_STATEMENT_END.with(|end| end.set(None));
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, _: &mut Response) {
+        let now = Instant::now();
+        let started = request.local_cache(|| RequestStart(now)).0;
+        let db = DB_TIME.with(|total| total.get());
+        let serialization = LAST_STATEMENT_END.with(|end| end.get())
+            .map_or(Duration::from_secs(0), |end| now.saturating_duration_since(end));
+
+        let route = match request.route() {
+            Some(route) => format!("{} {}", route.method, route.uri),
+            None => "unmatched".to_string(),
+        };
+
+        if let rocket::Outcome::Success(metrics) = request.guard::<State<Metrics>>() {
+            let mut routes = metrics.routes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let entry = routes.entry(route).or_default();
+            entry.requests += 1;
+            entry.total += now.saturating_duration_since(started);
+            entry.db += db;
+            entry.serialization += serialization;
+        }
+    }
+}
+
+// name, metric type and how to read the value from a route's metrics
+type Series = (&'static str, &'static str, fn(&RouteMetrics) -> f64);
+
+// label values in the Prometheus format escape backslashes and quotes
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[get("/metrics")]
+pub fn metrics(metrics: State<Metrics>) -> Content<String> {
+    let routes = metrics.routes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let mut text = String::new();
+    let series: [Series; 4] = [
+        ("http_requests_total", "counter", |route| route.requests as f64),
+        ("http_request_duration_seconds_sum", "counter", |route| route.total.as_secs_f64()),
+        ("http_request_db_seconds_sum", "counter", |route| route.db.as_secs_f64()),
+        ("http_request_serialization_seconds_sum", "counter", |route| route.serialization.as_secs_f64()),
+    ];
+
+    for (name, kind, value) in series.iter() {
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        for (route, route_metrics) in routes.iter() {
+            let _ = writeln!(text, "{}{{route=\"{}\"}} {}", name, label(route), value(route_metrics));
+        }
+    }
+
+    let checkpoints = CHECKPOINTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let checkpoint_series = [
+        ("sqlite_wal_checkpoints_total", "counter", checkpoints.runs as f64),
+        ("sqlite_wal_checkpoints_busy_total", "counter", checkpoints.busy as f64),
+        ("sqlite_wal_checkpoint_duration_seconds_sum", "counter", checkpoints.duration.as_secs_f64()),
+        ("sqlite_wal_checkpointed_frames_total", "counter", checkpoints.checkpointed_frames as f64),
+        ("sqlite_wal_last_checkpoint_log_frames", "gauge", checkpoints.last_log_frames as f64),
+    ];
+    for (name, kind, value) in checkpoint_series.iter() {
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+
+    Content(ContentType::Plain, text)
+}
@@ -0,0 +1,166 @@
+// MQTT publishing of item changes
+//
+// With mqtt_url set, every change event (see events.rs) is published as its json to
+// <mqtt_topic_prefix>/items/<item id>, e.g. todo/items/12, so home automation setups
+// (Home Assistant, Node-RED ...) can subscribe to todo/items/# and react.
+//
+// Only the little of MQTT 3.1.1 that is needed for that is spoken here: connect
+// (with a clean session, optionally a user name and password) and publish at QoS 0,
+// at most once, without retain. The connection is made on the first event and made
+// again when a publish fails, after which the event is tried once more. Keep alive
+// is off, so an idle connection is not dropped by the broker.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::events::{ChangeEvent, Sink};
+
+const DEFAULT_PORT: u16 = 1883;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct MqttSink {
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
+    topic_prefix: String,
+    client_id: String,
+    stream: Option<TcpStream>,
+}
+
+// the variable length "remaining length" of a packet's fixed header
+fn encode_length(mut length: usize, packet: &mut Vec<u8>) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+// a string or binary field: two bytes of length and the bytes
+fn encode_field(field: &[u8], body: &mut Vec<u8>) {
+    body.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    body.extend_from_slice(field);
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    encode_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+impl MqttSink {
+    // from mqtt://[user[:password]@]host[:port]
+    pub fn from_url(url: &str, topic_prefix: &str) -> Result<MqttSink, String> {
+        let invalid = || format!("Invalid mqtt_url {}, expected mqtt://[user:password@]host[:port]", url);
+
+        let rest = url.strip_prefix("mqtt://").ok_or_else(invalid)?;
+        let (credentials, host) = match rest.rsplit_once('@') {
+            Some((credentials, host)) => (Some(credentials), host),
+            None => (None, rest),
+        };
+        let host = host.trim_end_matches('/');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let address = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+            Some(_) => return Err(invalid()),
+            None => format!("{}:{}", host, DEFAULT_PORT),
+        };
+        let (username, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+            Some(Some((username, password))) => (Some(username.to_string()), Some(password.to_string())),
+            Some(None) => (credentials.map(String::from), None),
+            None => (None, None),
+        };
+
+        Ok(MqttSink {
+            address,
+            username,
+            password,
+            topic_prefix: topic_prefix.trim_end_matches('/').to_string(),
+            client_id: format!("{}-{}", env!("CARGO_PKG_NAME"), uuid::Uuid::new_v4().to_simple()),
+            stream: None,
+        })
+    }
+
+    fn connect(&self) -> Result<TcpStream, String> {
+        let address = self.address.to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("{} has no address", self.address))?;
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+        // clean session, plus the user name and password flags when they are set
+        let mut flags = 0x02;
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+
+        let mut body = Vec::new();
+        encode_field(b"MQTT", &mut body);
+        // protocol level 4 is MQTT 3.1.1, keep alive 0 is off
+        body.extend_from_slice(&[4, flags, 0, 0]);
+        encode_field(self.client_id.as_bytes(), &mut body);
+        for field in self.username.iter().chain(self.password.iter()) {
+            encode_field(field.as_bytes(), &mut body);
+        }
+        stream.write_all(&packet(0x10, &body)).map_err(|e| e.to_string())?;
+
+        // CONNACK: type, length 2, session present, return code
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack).map_err(|e| e.to_string())?;
+        match connack {
+            [0x20, 2, _, 0] => Ok(stream),
+            [0x20, 2, _, 4] | [0x20, 2, _, 5] => Err("the broker refused the user name or password".into()),
+            [0x20, 2, _, code] => Err(format!("the broker refused the connection (code {})", code)),
+            _ => Err("the broker did not answer with CONNACK".into()),
+        }
+    }
+
+    fn send(&mut self, publish: &[u8]) -> Result<(), String> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let sent = match self.stream.as_mut() {
+            Some(stream) => stream.write_all(publish).and_then(|_| stream.flush()),
+            None => return Ok(()),
+        };
+        sent.map_err(|e| {
+            self.stream = None;
+            e.to_string()
+        })
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn publish(&mut self, event: &ChangeEvent) -> Result<(), String> {
+        let topic = format!("{}/items/{}", self.topic_prefix, event.item_id);
+        let payload = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+
+        // QoS 0 has no packet id, the payload is the rest of the packet
+        let mut body = Vec::new();
+        encode_field(topic.as_bytes(), &mut body);
+        body.extend_from_slice(&payload);
+        let publish = packet(0x30, &body);
+
+        // a connection the broker has closed in the meantime only fails on use
+        self.send(&publish).or_else(|_| self.send(&publish))
+    }
+}
@@ -0,0 +1,120 @@
+// In-app notifications inbox
+//
+// Other parts of the app call notify() when something happens that a user should
+// see (a list was shared, an item got a comment), and clients read them from one
+// place: GET /notifications, with an unread count for badges.
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::{clock, db, StatusMessage};
+
+#[derive(Serialize)]
+pub struct Notification {
+    id: i64,
+    // what happened, e.g. "share" or "comment"
+    kind: String,
+    message: String,
+    item_id: Option<i64>,
+    list_id: Option<i64>,
+    created_at: i64,
+    read: bool,
+}
+
+#[derive(Serialize)]
+pub struct Notifications {
+    notifications: Vec<Notification>,
+}
+
+#[derive(Serialize)]
+pub struct UnreadCount {
+    unread: i64,
+}
+
+impl Notification {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Notification> {
+        Ok(Notification {
+            id: row.get(0)?,
+            kind: row.get(1)?,
+            message: row.get(2)?,
+            item_id: row.get(3)?,
+            list_id: row.get(4)?,
+            created_at: row.get(5)?,
+            read: row.get(6)?,
+        })
+    }
+}
+
+pub fn notify(db_connection: &Connection, kind: &str, message: &str, item_id: Option<i64>, list_id: Option<i64>) -> rusqlite::Result<usize> {
+    db_connection.execute(
+        "insert into notifications (id, kind, message, item_id, list_id, created_at, read)
+         values (null, $1, $2, $3, $4, $5, 0)",
+        params![kind, message, item_id, list_id, clock::now()])
+}
+
+// Newest first. ?unread=true only returns the ones not marked as read yet
+#[get("/notifications?<unread>")]
+pub fn fetch_notifications(unread: Option<bool>) -> Result<Json<Notifications>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(
+        "select id, kind, message, item_id, list_id, created_at, read from notifications
+         where $1 = 0 or read = 0
+         order by created_at desc, id desc")
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(params![unread.unwrap_or(false)], Notification::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Notification>>>());
+
+    match results {
+        Ok(notifications) => Ok(Json(Notifications { notifications })),
+        Err(_) => Err("Failed to fetch notifications".into()),
+    }
+}
+
+#[get("/notifications/unread-count")]
+pub fn fetch_unread_count() -> Result<Json<UnreadCount>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let unread = db_connection.query_row(
+        "select count(*) from notifications where read = 0", rusqlite::NO_PARAMS, |row| row.get(0));
+
+    match unread {
+        Ok(unread) => Ok(Json(UnreadCount { unread })),
+        Err(_) => Err("Failed to count notifications".into()),
+    }
+}
+
+#[put("/notifications/<id>/read")]
+pub fn mark_read(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match db_connection.execute("update notifications set read = 1 where id = $1", params![id]) {
+        Ok(rows_updated) => Ok(Json(StatusMessage {
+            message: format!("{} rows updated", rows_updated),
+        })),
+        Err(_) => Err("Failed to update notification".into()),
+    }
+}
+
+#[post("/notifications/read-all")]
+pub fn mark_all_read() -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match db_connection.execute("update notifications set read = 1 where read = 0", rusqlite::NO_PARAMS) {
+        Ok(rows_updated) => Ok(Json(StatusMessage {
+            message: format!("{} rows updated", rows_updated),
+        })),
+        Err(_) => Err("Failed to update notifications".into()),
+    }
+}
@@ -0,0 +1,253 @@
+// Paging of list responses
+//
+// GET /todo returns the whole list unless the request asks for a page with
+// ?page=<n> (from 1) and/or ?per_page=<n> (at most MAX_PER_PAGE, DEFAULT_PER_PAGE
+// when only page is given). A page is answered with the usual body and
+//   - a Link header (RFC 5988) with the first, prev, next and last pages, e.g.
+//       Link: </todo?color=red&page=3&per_page=20>; rel="next", ...
+//     The links keep the other query parameters (the filters) of the request, so a
+//     client can page by following them without knowing the parameters
+//   - X-Total-Count, the number of items on all pages
+//   - meta.page in enveloped responses (see envelope.rs)
+//
+// Tools that already speak ranges can ask for a slice with a Range header instead:
+//
+//     Range: items=0-49
+//
+// is answered with 206 Partial Content, the items 0 to 49 (counted from 0, both
+// included) and Content-Range: items 0-49/<total>. At most MAX_RANGE_ITEMS are sent,
+// Content-Range says which ones. A range that starts after the last item gets 416
+// with Content-Range: items */<total>. A malformed Range header is ignored, as is
+// one on a request that also has ?page / ?per_page. Whole lists are sent with
+// Accept-Ranges: items so clients can tell.
+
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::{Outcome, Request};
+
+use crate::envelope;
+use crate::error::{ApiError, ErrorCode};
+
+pub const DEFAULT_PER_PAGE: i64 = 20;
+pub const MAX_PER_PAGE: i64 = 100;
+pub const MAX_RANGE_ITEMS: i64 = 1000;
+
+const RANGE_UNIT: &str = "items";
+
+#[derive(Clone, Copy, Debug)]
+pub struct PageRequest {
+    pub page: i64,
+    pub per_page: i64,
+}
+
+impl PageRequest {
+    // None when the request does not ask for a page
+    pub fn from_params(page: Option<i64>, per_page: Option<i64>) -> Result<Option<PageRequest>, ApiError> {
+        if page.is_none() && per_page.is_none() {
+            return Ok(None);
+        }
+
+        let page = page.unwrap_or(1);
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE);
+        if page < 1 {
+            return Err(ApiError::new(ErrorCode::ValidationFailed, "page starts at 1"));
+        }
+        if !(1..=MAX_PER_PAGE).contains(&per_page) {
+            return Err(ApiError::new(ErrorCode::ValidationFailed,
+                format!("per_page has to be between 1 and {}", MAX_PER_PAGE)));
+        }
+        Ok(Some(PageRequest { page, per_page }))
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page - 1) * self.per_page
+    }
+}
+
+// Range: items=<first>-<last> (last included, or left out for "to the end")
+#[derive(Clone, Copy, Debug)]
+pub struct ItemRange {
+    pub first: i64,
+    pub last: Option<i64>,
+}
+
+impl ItemRange {
+    fn parse(header: &str) -> Option<ItemRange> {
+        let (unit, range) = header.split_once('=')?;
+        if unit.trim() != RANGE_UNIT {
+            return None;
+        }
+        let (first, last) = range.trim().split_once('-')?;
+        let first: i64 = first.parse().ok()?;
+        let last: Option<i64> = if last.is_empty() { None } else { Some(last.parse().ok()?) };
+        if first < 0 || last.map_or(false, |last| last < first) {
+            return None;
+        }
+        Some(ItemRange { first, last })
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.first
+    }
+
+    pub fn limit(&self) -> i64 {
+        let wanted = self.last.map_or(MAX_RANGE_ITEMS, |last| last - self.first + 1);
+        wanted.min(MAX_RANGE_ITEMS)
+    }
+}
+
+// Request guard for an optional Range header, None when there is none or it is not
+// a valid items range
+pub struct RangeHeader(pub Option<ItemRange>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RangeHeader {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RangeHeader, ()> {
+        Outcome::Success(RangeHeader(request.headers().get_one("Range").and_then(ItemRange::parse)))
+    }
+}
+
+// What part of the list a response has
+#[derive(Clone, Copy)]
+pub enum Slice {
+    // a page and the total number of items
+    Page(PageRequest, i64),
+    // a range, the number of items in the response and the total
+    Range(ItemRange, i64, i64),
+}
+
+// A response that may be a part of a longer list, None for the whole list
+pub struct Paged<R> {
+    pub body: R,
+    pub slice: Option<Slice>,
+}
+
+fn last_page(per_page: i64, total: i64) -> i64 {
+    ((total + per_page - 1) / per_page).max(1)
+}
+
+// the request's path and query with page / per_page replaced
+fn page_link(request: &Request, page: i64, per_page: i64) -> String {
+    let uri = request.uri();
+    let mut params: Vec<&str> = uri.query().unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| {
+            let name = pair.split('=').next().unwrap_or("");
+            name != "page" && name != "per_page"
+        })
+        .collect();
+
+    let paging = format!("page={}&per_page={}", page, per_page);
+    params.push(&paging);
+    format!("{}?{}", uri.path(), params.join("&"))
+}
+
+fn link_header(request: &Request, page: PageRequest, total: i64) -> String {
+    let last = last_page(page.per_page, total);
+    let mut links = vec![(1, "first")];
+    if page.page > 1 {
+        links.push(((page.page - 1).min(last), "prev"));
+    }
+    if page.page < last {
+        links.push((page.page + 1, "next"));
+    }
+    links.push((last, "last"));
+
+    links.iter()
+        .map(|(number, rel)| format!("<{}>; rel=\"{}\"", page_link(request, *number, page.per_page), rel))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Paged<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        if let Some(Slice::Range(_, count, total)) = self.slice {
+            if count == 0 {
+                return Response::build_from(unsatisfiable(total).respond_to(request)?)
+                    .header(Header::new("Content-Range", format!("{} */{}", RANGE_UNIT, total)))
+                    .ok();
+            }
+        }
+
+        let mut response = Response::build_from(self.body.respond_to(request)?);
+
+        match self.slice {
+            Some(Slice::Page(page, total)) => {
+                envelope::PageMeta { page: page.page, per_page: page.per_page, total }.record(request);
+                response
+                    .header(Header::new("Link", link_header(request, page, total)))
+                    .header(Header::new("X-Total-Count", total.to_string()));
+            }
+            Some(Slice::Range(range, count, total)) => {
+                let last = range.first + count - 1;
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new("Content-Range", format!("{} {}-{}/{}", RANGE_UNIT, range.first, last, total)));
+            }
+            None => {
+                response.header(Header::new("Accept-Ranges", RANGE_UNIT));
+            }
+        }
+        response.ok()
+    }
+}
+
+fn unsatisfiable(total: i64) -> ApiError {
+    ApiError::new(ErrorCode::RangeNotSatisfiable, format!("The list has {} items", total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(header: &str) -> Option<(i64, Option<i64>)> {
+        ItemRange::parse(header).map(|range| (range.first, range.last))
+    }
+
+    #[test]
+    fn parses_a_closed_range() {
+        assert_eq!(range("items=0-49"), Some((0, Some(49))));
+        assert_eq!(range(" items = 10-10 "), Some((10, Some(10))));
+        assert_eq!(ItemRange::parse("items=0-49").unwrap().limit(), 50);
+        assert_eq!(ItemRange::parse("items=10-10").unwrap().limit(), 1);
+        assert_eq!(ItemRange::parse("items=10-19").unwrap().offset(), 10);
+    }
+
+    #[test]
+    fn an_open_end_means_to_the_end() {
+        assert_eq!(range("items=100-"), Some((100, None)));
+        assert_eq!(ItemRange::parse("items=100-").unwrap().limit(), MAX_RANGE_ITEMS);
+    }
+
+    #[test]
+    fn rejects_last_before_first() {
+        assert_eq!(range("items=50-49"), None);
+    }
+
+    #[test]
+    fn rejects_other_units() {
+        assert_eq!(range("bytes=0-49"), None);
+        assert_eq!(range("Items=0-49"), None);
+        assert_eq!(range("0-49"), None);
+    }
+
+    #[test]
+    fn rejects_negative_and_malformed_numbers() {
+        assert_eq!(range("items=-5"), None);
+        assert_eq!(range("items=-5-10"), None);
+        assert_eq!(range("items=5--3"), None);
+        assert_eq!(range("items=a-b"), None);
+        assert_eq!(range("items=0-49, 60-69"), None);
+        assert_eq!(range("items=5"), None);
+    }
+
+    #[test]
+    fn caps_the_number_of_items() {
+        assert_eq!(ItemRange::parse("items=0-999").unwrap().limit(), MAX_RANGE_ITEMS);
+        assert_eq!(ItemRange::parse("items=0-5000").unwrap().limit(), MAX_RANGE_ITEMS);
+        assert_eq!(ItemRange::parse("items=20-5000").unwrap().limit(), MAX_RANGE_ITEMS);
+    }
+}
@@ -0,0 +1,51 @@
+// Panics in handlers
+//
+// Rocket does not catch a panicking handler: the worker thread unwinds and the
+// client gets no response at all, just a closed connection. The routes are
+// therefore mounted through catch_panics, which runs each handler under
+// catch_unwind. A panic becomes a normal internal_error json response whose
+// details carry a new incident id:
+//
+//     {"code": "internal_error", ..., "details": {"incident_id": "3e1f..."}}
+//
+// The same id is logged next to the request, so a user quoting it can be matched
+// to the panic message (printed by the panic hook) and the error tracker's report.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use rocket::handler::{self, Handler};
+use rocket::{Data, Request, Route};
+use serde_json::json;
+
+use crate::error::{self, ApiError, ErrorCode};
+
+#[derive(Clone)]
+struct CatchPanic(Box<dyn Handler>);
+
+impl Handler for CatchPanic {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> handler::Outcome<'r> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.handle(request, data))) {
+            Ok(outcome) => outcome,
+            Err(_) => handler::Outcome::from(request, incident(request)),
+        }
+    }
+}
+
+fn incident(request: &Request) -> ApiError {
+    let incident_id = uuid::Uuid::new_v4().to_string();
+    println!("Incident {}: handler panicked during {} {} (request {})",
+        incident_id, request.method(), request.uri().path(), error::request_id(request));
+
+    ApiError::new(ErrorCode::InternalError, format!("Internal server error, incident {}", incident_id))
+        .with_details(json!({ "incident_id": incident_id }))
+}
+
+// The routes with their handlers wrapped, for rocket.mount
+pub fn catch_panics(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(CatchPanic(route.handler));
+            route
+        })
+        .collect()
+}
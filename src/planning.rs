@@ -0,0 +1,300 @@
+// Estimates, burndown and schedule
+//
+// Items can carry an estimate of the work they take, in hours:
+// PUT /todo/<id>/estimate with the hours as a json number (e.g. 1.5), DELETE to
+// clear it.
+//
+// GET /lists/<id>/burndown shows, sprint style, how the estimated work of a list
+// goes down as its items are completed. One point per day (in the user's time zone,
+// see timezone.rs), from the day the first estimated item was added up to today, at
+// most BURNDOWN_DAYS:
+//
+//     {"list_id": 3, "timezone": "+02:00", "total_estimate": 12.5,
+//      "points": [{"date": "2024-05-01", "remaining": 12.5, "completed": 0.0}, ...]}
+//
+// remaining is the estimate of the items that existed and were open at the end of
+// that day, completed the estimate of the items completed that day. Items without an
+// estimate do not count. Completions come from completed_at, so an item that was
+// reopened counts as open until it is completed again.
+//
+// GET /lists/<id>/schedule plans the open items of a list for a Gantt chart, with
+// the critical path method. An item takes its estimate (no estimate = a milestone
+// taking no time) and can start once the items it depends on (see dependencies.rs)
+// are finished, and not before now. Hours are wall clock hours, there are no work
+// days. Every item gets
+//   - earliest_start / earliest_end: when it can be done at the soonest
+//   - latest_start / latest_end: how late it can be done without delaying the items
+//     waiting for it, the end of the whole list or its own due date
+//   - slack_seconds: latest_start - earliest_start, negative when the item can not
+//     make its due date (late is then true)
+// Items with no slack (0 or less) are critical: any delay on them delays the list
+// or misses a due date. critical_path lists them in the order they can start.
+// Done items are finished already and do not hold anything up; dependencies on
+// items outside the list do not move the schedule.
+
+use rocket_contrib::json::Json;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::timezone::UserTimeZone;
+use crate::{clock, db, dependencies, lists, writer, StatusMessage, ToDoItem, ITEM_COLUMNS, ITEM_ORDER};
+
+// more than a year of hours is not an estimate
+pub const MAX_ESTIMATE: f64 = 10_000.0;
+const BURNDOWN_DAYS: i64 = 366;
+
+fn set_estimate(id: i64, estimate: Option<f64>) -> Result<Json<StatusMessage>, ApiError> {
+    if let Some(estimate) = estimate {
+        if !estimate.is_finite() || !(0.0..=MAX_ESTIMATE).contains(&estimate) {
+            return Err(ApiError::new(ErrorCode::ValidationFailed,
+                format!("The estimate has to be between 0 and {} hours", MAX_ESTIMATE)));
+        }
+    }
+
+    writer::write(move |db_connection| {
+
+        let results = db_connection.execute("update todo_list set estimate = $1 where id = $2;", params![estimate, id]);
+
+        match results {
+            Ok(rows_updated) => Ok(Json(StatusMessage {
+                message: format!("{} rows updated", rows_updated),
+            })),
+            Err(_) => Err("Failed to update estimate".into()),
+        }
+    })?
+}
+
+#[put("/todo/<id>/estimate", format = "json", data = "<estimate>")]
+pub fn set_todo_item_estimate(id: i64, estimate: LimitedJson<f64>) -> Result<Json<StatusMessage>, ApiError> {
+    set_estimate(id, Some(estimate.0))
+}
+
+#[delete("/todo/<id>/estimate")]
+pub fn clear_todo_item_estimate(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+    set_estimate(id, None)
+}
+
+#[derive(Serialize)]
+pub struct BurndownPoint {
+    date: String,
+    remaining: f64,
+    completed: f64,
+}
+
+#[derive(Serialize)]
+pub struct Burndown {
+    list_id: i64,
+    timezone: String,
+    total_estimate: f64,
+    points: Vec<BurndownPoint>,
+}
+
+// an estimated item of the list: estimate, created_at, completed_at
+type EstimatedItem = (f64, Option<i64>, Option<i64>);
+
+fn estimated_items(db_connection: &rusqlite::Connection, list_id: i64) -> rusqlite::Result<Vec<EstimatedItem>> {
+    let mut statement = db_connection.prepare(
+        "select estimate, created_at, case when done = 1 then completed_at end
+         from todo_list where list_id = $1 and estimate is not null")?;
+    let rows = statement.query_map(params![list_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    rows.collect()
+}
+
+#[get("/lists/<id>/burndown")]
+pub fn burndown(id: i64, timezone: UserTimeZone) -> Result<Option<Json<Burndown>>, ApiError> {
+
+    let timezone = timezone.resolve()?;
+    let db_connection = db::connect()?;
+
+    match lists::fetch_list(&db_connection, id) {
+        Ok(Some(_)) => (),
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch list".into()),
+    }
+
+    let items = match estimated_items(&db_connection, id) {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+
+    let today = timezone.day(clock::now());
+    // items from before created_at was recorded count from the first day
+    let first = items.iter()
+        .filter_map(|(_, created_at, _)| created_at.map(|created_at| timezone.day(created_at)))
+        .min()
+        .unwrap_or(today)
+        .max(today - BURNDOWN_DAYS + 1);
+
+    let points = (first..=today)
+        .map(|day| {
+            let day_of = |timestamp: Option<i64>| timestamp.map(|timestamp| timezone.day(timestamp));
+            let mut remaining = 0.0;
+            let mut completed = 0.0;
+            for (estimate, created_at, completed_at) in &items {
+                if day_of(*created_at).map_or(false, |created| created > day) {
+                    continue;
+                }
+                match day_of(*completed_at) {
+                    Some(completed_day) if completed_day == day => completed += estimate,
+                    Some(completed_day) if completed_day < day => (),
+                    _ => remaining += estimate,
+                }
+            }
+            BurndownPoint { date: clock::format_date(day), remaining, completed }
+        })
+        .collect();
+
+    Ok(Some(Json(Burndown {
+        list_id: id,
+        timezone: timezone.name(),
+        total_estimate: items.iter().map(|(estimate, _, _)| estimate).sum(),
+        points,
+    })))
+}
+
+#[derive(Serialize)]
+pub struct ScheduledItem {
+    #[serde(flatten)]
+    item: ToDoItem,
+    // the ids of the open items of the list it waits for
+    depends_on: Vec<i64>,
+    // unix timestamps (seconds)
+    earliest_start: i64,
+    earliest_end: i64,
+    latest_start: i64,
+    latest_end: i64,
+    slack_seconds: i64,
+    critical: bool,
+    late: bool,
+}
+
+#[derive(Serialize)]
+pub struct Schedule {
+    list_id: i64,
+    // when the first item can start and the last one is finished
+    start: i64,
+    end: i64,
+    items: Vec<ScheduledItem>,
+    critical_path: Vec<i64>,
+}
+
+fn open_list_items(db_connection: &rusqlite::Connection, list_id: i64) -> rusqlite::Result<Vec<ToDoItem>> {
+    let mut statement = db_connection.prepare(&format!(
+        "select {} from todo_list where list_id = $1 and done = 0 and archived = 0 order by {}",
+        ITEM_COLUMNS, ITEM_ORDER))?;
+    let rows = statement.query_map(params![list_id], ToDoItem::from_row)?;
+    rows.collect()
+}
+
+// The item indexes in an order where every item comes after the ones it depends on,
+// None when the dependencies have a cycle
+fn topological_order(depends_on: &[Vec<usize>]) -> Option<Vec<usize>> {
+    let mut waiting_for: Vec<usize> = depends_on.iter().map(Vec::len).collect();
+    let mut ready: Vec<usize> = (0..depends_on.len()).filter(|i| waiting_for[*i] == 0).rev().collect();
+    let mut order = Vec::with_capacity(depends_on.len());
+
+    while let Some(next) = ready.pop() {
+        order.push(next);
+        // a pair of items has at most one dependency (the primary key)
+        for (i, dependencies) in depends_on.iter().enumerate() {
+            if dependencies.contains(&next) {
+                waiting_for[i] -= 1;
+                if waiting_for[i] == 0 {
+                    ready.push(i);
+                }
+            }
+        }
+    }
+
+    if order.len() == depends_on.len() { Some(order) } else { None }
+}
+
+#[get("/lists/<id>/schedule")]
+pub fn schedule(id: i64) -> Result<Option<Json<Schedule>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match lists::fetch_list(&db_connection, id) {
+        Ok(Some(_)) => (),
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch list".into()),
+    }
+
+    let items = match open_list_items(&db_connection, id) {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+    let pairs = match dependencies::list_dependencies(&db_connection, id) {
+        Ok(pairs) => pairs,
+        Err(_) => return Err("Failed to fetch dependencies".into()),
+    };
+
+    let index_of = |item_id: i64| items.iter().position(|item| item.id == item_id);
+    let mut depends_on: Vec<Vec<usize>> = vec![Vec::new(); items.len()];
+    for (item_id, depends_on_id) in pairs {
+        if let (Some(item), Some(dependency)) = (index_of(item_id), index_of(depends_on_id)) {
+            depends_on[item].push(dependency);
+        }
+    }
+
+    // adding a dependency refuses cycles, so this only happens with a hand edited database
+    let order = match topological_order(&depends_on) {
+        Some(order) => order,
+        None => return Err(ApiError::new(ErrorCode::Conflict, "The dependencies of this list have a cycle")),
+    };
+
+    let now = clock::now();
+    let duration: Vec<i64> = items.iter()
+        .map(|item| (item.estimate.unwrap_or(0.0) * 3600.0).round() as i64)
+        .collect();
+
+    // forward pass: the earliest each item can start and end
+    let mut earliest_start = vec![now; items.len()];
+    let mut earliest_end = vec![now; items.len()];
+    for &i in &order {
+        earliest_start[i] = depends_on[i].iter().map(|&dependency| earliest_end[dependency]).fold(now, i64::max);
+        earliest_end[i] = earliest_start[i] + duration[i];
+    }
+    let end = earliest_end.iter().copied().fold(now, i64::max);
+
+    // backward pass: the latest each item can end without holding up the items
+    // waiting for it, the end of the list or its due date
+    let mut latest_end = vec![end; items.len()];
+    let mut latest_start = vec![end; items.len()];
+    for &i in order.iter().rev() {
+        latest_end[i] = latest_end[i].min(items[i].due_at.unwrap_or(end));
+        latest_start[i] = latest_end[i] - duration[i];
+        for &dependency in &depends_on[i] {
+            latest_end[dependency] = latest_end[dependency].min(latest_start[i]);
+        }
+    }
+
+    let ids: Vec<i64> = items.iter().map(|item| item.id).collect();
+    let critical_path: Vec<i64> = order.iter()
+        .filter(|&&i| latest_start[i] <= earliest_start[i])
+        .map(|&i| ids[i])
+        .collect();
+
+    let scheduled = items.into_iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let slack_seconds = latest_start[i] - earliest_start[i];
+            let late = item.due_at.map_or(false, |due_at| due_at < earliest_end[i]);
+            ScheduledItem {
+                depends_on: depends_on[i].iter().map(|&dependency| ids[dependency]).collect(),
+                item,
+                earliest_start: earliest_start[i],
+                earliest_end: earliest_end[i],
+                latest_start: latest_start[i],
+                latest_end: latest_end[i],
+                slack_seconds,
+                critical: slack_seconds <= 0,
+                late,
+            }
+        })
+        .collect();
+
+    Ok(Some(Json(Schedule { list_id: id, start: now, end, items: scheduled, critical_path })))
+}
@@ -0,0 +1,191 @@
+// Read-only sql console
+//
+// POST /admin/query {"sql": "select ..."} runs one SELECT against the database and
+// returns the rows as json, for looking at the data of a deployment without shell
+// access to data.sqlite. It is off unless admin_query is set in the config, and
+// takes the admin guard like the rest of /admin.
+//
+// Only a single statement starting with SELECT (or WITH) is accepted, and it runs on
+// a connection opened read-only with query_only on (db::connect_read_only), so even
+// a statement that slips past the check can not write. At most MAX_ROWS rows are
+// returned.
+//
+// A select can still take forever (a cross join of big tables, a recursive WITH), so
+// a timer thread interrupts the statement once admin_query_timeout_ms has passed;
+// that is a 503 with the code query_timeout.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+use rusqlite::types::ValueRef;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::Admin;
+use crate::config::AppConfig;
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::db;
+
+const MAX_ROWS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct Query {
+    sql: String,
+}
+
+#[derive(Serialize)]
+pub struct QueryResult {
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+    // there were more than MAX_ROWS rows
+    truncated: bool,
+}
+
+#[derive(Responder)]
+pub enum QueryResponse {
+    #[response(status = 200)]
+    Rows(Json<QueryResult>),
+    // not a single select, or sqlite could not run it
+    #[response(status = 400)]
+    Invalid(ApiError),
+    // ran longer than admin_query_timeout_ms
+    #[response(status = 503)]
+    TimedOut(ApiError),
+}
+
+fn invalid(message: String) -> QueryResponse {
+    QueryResponse::Invalid(ApiError::new(ErrorCode::BadRequest, message))
+}
+
+// invalid, unless sqlite stopped the statement because it ran out of time
+fn failed(error: rusqlite::Error) -> QueryResponse {
+    match error {
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error { code: rusqlite::ErrorCode::OperationInterrupted, .. }, _) => {
+            QueryResponse::TimedOut(ApiError::new(ErrorCode::QueryTimeout, "The query ran longer than admin_query_timeout_ms"))
+        }
+        error => invalid(error.to_string()),
+    }
+}
+
+// The statement without a trailing semicolon, or why it is not accepted
+fn check_statement(sql: &str) -> Result<&str, String> {
+    let sql = sql.trim();
+    let sql = sql.strip_suffix(';').unwrap_or(sql).trim_end();
+
+    // a semicolon in a string literal is turned away too, which is fine here
+    if sql.contains(';') {
+        return Err("Only a single statement can be run".into());
+    }
+
+    let keyword = sql.split_whitespace().next().unwrap_or("").to_lowercase();
+    if keyword != "select" && keyword != "with" {
+        return Err("Only SELECT statements can be run".into());
+    }
+    Ok(sql)
+}
+
+// sqlite values as json, blobs as base64
+fn json_value(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(number) => Value::from(number),
+        ValueRef::Real(number) => Value::from(number),
+        ValueRef::Text(text) => Value::from(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(bytes) => Value::from(base64::encode(bytes)),
+    }
+}
+
+#[post("/query", format = "json", data = "<query>")]
+pub fn run_query(_admin: Admin, app_config: State<AppConfig>, query: LimitedJson<Query>) -> Result<Option<QueryResponse>, ApiError> {
+
+    // switched off, answer as if the route did not exist
+    if !app_config.admin_query {
+        return Ok(None);
+    }
+
+    let sql = match check_statement(&query.0.sql) {
+        Ok(sql) => sql,
+        Err(e) => return Ok(Some(invalid(e))),
+    };
+
+    let db_connection = db::connect_read_only()?;
+
+    // the timer gives up waiting when _finished is dropped, however this returns
+    let (_finished, finished) = mpsc::channel::<()>();
+    let interrupt = db_connection.get_interrupt_handle();
+    let timeout = app_config.admin_query_timeout;
+    thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+            interrupt.interrupt();
+        }
+    });
+
+    let mut statement = match db_connection.prepare(sql) {
+        Ok(statement) => statement,
+        Err(e) => return Ok(Some(invalid(e.to_string()))),
+    };
+
+    let columns: Vec<String> = statement.column_names().iter().map(|name| name.to_string()).collect();
+    let column_count = columns.len();
+
+    let mut rows = match statement.query(rusqlite::NO_PARAMS) {
+        Ok(rows) => rows,
+        Err(e) => return Ok(Some(failed(e))),
+    };
+
+    let mut result = QueryResult { columns, rows: Vec::new(), truncated: false };
+    loop {
+        let row = match rows.next() {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Ok(Some(failed(e))),
+        };
+
+        if result.rows.len() == MAX_ROWS {
+            result.truncated = true;
+            break;
+        }
+
+        let mut values = Vec::with_capacity(column_count);
+        for index in 0..column_count {
+            match row.get_raw_checked(index) {
+                Ok(value) => values.push(json_value(value)),
+                Err(_) => return Err("Failed to read the query results".into()),
+            }
+        }
+        result.rows.push(values);
+    }
+
+    Ok(Some(QueryResponse::Rows(Json(result))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_a_single_select() {
+        assert_eq!(check_statement("select * from todo_list"), Ok("select * from todo_list"));
+        assert_eq!(check_statement("  SELECT 1;  "), Ok("SELECT 1"));
+        assert_eq!(check_statement("with t as (select 1) select * from t"), Ok("with t as (select 1) select * from t"));
+    }
+
+    #[test]
+    fn rejects_other_statements() {
+        for sql in ["delete from todo_list", "update todo_list set done = 1", "insert into todo_list values (1)",
+            "drop table todo_list", "pragma query_only = off", "PRAGMA table_info(todo_list)",
+            "attach database 'other.sqlite' as other", "vacuum", "", "   "] {
+            assert!(check_statement(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn rejects_more_than_one_statement() {
+        assert!(check_statement("select 1; select 2").is_err());
+        assert!(check_statement("select 1; attach database 'x' as x").is_err());
+        assert!(check_statement("select 1;;").is_err());
+    }
+}
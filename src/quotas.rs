@@ -0,0 +1,121 @@
+// Quotas on items and attachments
+//
+// max_items caps the number of items (archived ones included) and
+// max_attachment_bytes the total size of the attachments, 0 = no limit. There are no
+// user accounts, so the limits are per database: in multi-tenant mode (see tenants.rs)
+// every tenant has them to itself, otherwise they are for the whole instance.
+//
+// The paths that create items or attachments check them before writing; imports and
+// list copies, which add many items in one transaction, check after inserting and
+// roll back. Going over a limit is a 403:
+//
+//     {"code": "forbidden", "message": "Quota exceeded: at most 500 items",
+//      "details": {"quota": "items", "limit": 500, "used": 500}}
+//
+// GET /users/me/usage shows where the caller's database stands:
+//
+//     {"items": 120, "max_items": 500, "attachment_bytes": 80211, "max_attachment_bytes": null}
+//
+// The checks run in the same transaction as the write they guard (an immediate one
+// where the check comes first), so two requests at the same moment can not both take
+// the last of a quota.
+
+use std::sync::OnceLock;
+
+use rocket_contrib::json::Json;
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::db;
+use crate::error::{ApiError, ErrorCode};
+
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub max_items: Option<i64>,
+    pub max_attachment_bytes: Option<i64>,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+#[derive(Serialize)]
+pub struct Usage {
+    items: i64,
+    max_items: Option<i64>,
+    attachment_bytes: i64,
+    max_attachment_bytes: Option<i64>,
+}
+
+// From the config, 0 or less is no limit
+pub fn set_limits(max_items: i64, max_attachment_bytes: i64) {
+    let _ = LIMITS.set(Limits {
+        max_items: Some(max_items).filter(|max| *max > 0),
+        max_attachment_bytes: Some(max_attachment_bytes).filter(|max| *max > 0),
+    });
+}
+
+fn limits() -> Limits {
+    LIMITS.get().copied().unwrap_or_default()
+}
+
+// whether max_items is set, adding items has to check it then
+pub fn limits_items() -> bool {
+    limits().max_items.is_some()
+}
+
+fn item_count(db_connection: &Connection) -> Result<i64, ApiError> {
+    db_connection.query_row("select count(*) from todo_list", rusqlite::NO_PARAMS, |row| row.get(0))
+        .map_err(|_| ApiError::from("Failed to count ToDo Items"))
+}
+
+fn attachment_bytes(db_connection: &Connection) -> Result<i64, ApiError> {
+    db_connection.query_row("select coalesce(sum(length(data)), 0) from attachments", rusqlite::NO_PARAMS, |row| row.get(0))
+        .map_err(|_| ApiError::from("Failed to sum up the attachments"))
+}
+
+fn exceeded(quota: &str, message: String, limit: i64, used: i64) -> ApiError {
+    ApiError::new(ErrorCode::Forbidden, message)
+        .with_details(json!({ "quota": quota, "limit": limit, "used": used }))
+}
+
+// Whether adding more items stays within max_items. After inserting (in the same
+// transaction) pass 0
+pub fn check_items(db_connection: &Connection, adding: i64) -> Result<(), ApiError> {
+    let max_items = match limits().max_items {
+        Some(max_items) => max_items,
+        None => return Ok(()),
+    };
+    let used = item_count(db_connection)?;
+    if used + adding > max_items {
+        return Err(exceeded("items", format!("Quota exceeded: at most {} items", max_items), max_items, used));
+    }
+    Ok(())
+}
+
+// Whether an attachment of this size still fits into max_attachment_bytes
+pub fn check_attachment(db_connection: &Connection, bytes: usize) -> Result<(), ApiError> {
+    let max_bytes = match limits().max_attachment_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(()),
+    };
+    let used = attachment_bytes(db_connection)?;
+    if used + bytes as i64 > max_bytes {
+        return Err(exceeded("attachment_bytes",
+            format!("Quota exceeded: at most {} bytes of attachments", max_bytes), max_bytes, used));
+    }
+    Ok(())
+}
+
+#[get("/users/me/usage")]
+pub fn fetch_usage() -> Result<Json<Usage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let limits = limits();
+    Ok(Json(Usage {
+        items: item_count(&db_connection)?,
+        max_items: limits.max_items,
+        attachment_bytes: attachment_bytes(&db_connection)?,
+        max_attachment_bytes: limits.max_attachment_bytes,
+    }))
+}
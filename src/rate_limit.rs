@@ -0,0 +1,150 @@
+// Rate limiting
+//
+// Every api token can make a number of requests per minute: its own rate_limit
+// (PUT /admin/tokens/<id>/rate-limit) or rate_limit_per_minute from the config.
+// Requests without credentials share rate_limit_per_minute per client address.
+// Basic auth (the owner) is not limited, and rate_limit_per_minute = 0 turns the
+// default limit off.
+//
+// Requests are counted in fixed one minute windows in the rate_limit_counters
+// table, so the counts survive a restart. Every limited response carries
+//   X-RateLimit-Limit      requests allowed in the window
+//   X-RateLimit-Remaining  requests left in the window
+//   X-RateLimit-Reset      unix time when the next window starts
+// and a request over the limit is rewritten to GET /__rate_limited (429).
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Request, Rocket, State};
+use rusqlite::{params, Connection};
+
+use crate::error::{ApiError, ErrorCode};
+use crate::{auth, clock, db, ip_filter};
+
+const RATE_LIMITED_PATH: &str = "/__rate_limited";
+const WINDOW_SECONDS: i64 = 60;
+
+pub struct RateLimitSettings {
+    default_per_minute: i64,
+}
+
+// The outcome for this request, kept for the response headers
+#[derive(Clone, Copy)]
+struct Usage {
+    limit: i64,
+    remaining: i64,
+    reset: i64,
+}
+
+struct RequestUsage(Option<Usage>);
+
+// Counts the request for key in the current window and returns the new count
+fn count_request(db_connection: &Connection, key: &str, window_start: i64) -> rusqlite::Result<i64> {
+    db_connection.execute(
+        "delete from rate_limit_counters where window_start < $1", params![window_start])?;
+    db_connection.execute(
+        "insert into rate_limit_counters (key, window_start, count) values ($1, $2, 1)
+         on conflict(key, window_start) do update set count = count + 1",
+        params![key, window_start])?;
+    db_connection.query_row(
+        "select count from rate_limit_counters where key = $1 and window_start = $2",
+        params![key, window_start], |row| row.get(0))
+}
+
+fn usage(request: &Request, settings: &RateLimitSettings) -> Option<Usage> {
+    let (key, limit) = match auth::current_grant(request) {
+        Some(grant) => match grant.token_id {
+            Some(token_id) => {
+                (format!("token:{}", token_id), grant.rate_limit.unwrap_or(settings.default_per_minute))
+            }
+            // Basic auth
+            None => return None,
+        },
+        None => (format!("ip:{}", ip_filter::client_ip(request)?), settings.default_per_minute),
+    };
+
+    if limit <= 0 {
+        return None;
+    }
+
+    let now = clock::now();
+    let window_start = now - now % WINDOW_SECONDS;
+
+    // when the counter can not be written the request is let through rather than
+    // failing everything because of the rate limiter
+    let count = db::connect().ok()
+        .and_then(|db_connection| db::retry(|| count_request(&db_connection, &key, window_start)).ok())?;
+
+    Some(Usage {
+        limit,
+        remaining: limit - count,
+        reset: window_start + WINDOW_SECONDS,
+    })
+}
+
+pub struct RateLimit;
+
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let settings = RateLimitSettings {
+            default_per_minute: rocket.config().get_int("rate_limit_per_minute").unwrap_or(0),
+        };
+
+        Ok(rocket.manage(settings).mount("/", routes![rate_limited]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        // requests that were already turned away are not counted
+        if request.uri().path().starts_with("/__") {
+            return;
+        }
+
+        let usage = match request.guard::<State<RateLimitSettings>>() {
+            rocket::Outcome::Success(settings) => usage(request, &settings),
+            _ => None,
+        };
+        request.local_cache(|| RequestUsage(usage));
+
+        if usage.map_or(false, |usage| usage.remaining < 0) {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(RATE_LIMITED_PATH).expect("valid rate limited path"));
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(usage) = request.local_cache(|| RequestUsage(None)).0 {
+            response.set_raw_header("X-RateLimit-Limit", usage.limit.to_string());
+            response.set_raw_header("X-RateLimit-Remaining", usage.remaining.max(0).to_string());
+            response.set_raw_header("X-RateLimit-Reset", usage.reset.to_string());
+        }
+    }
+}
+
+pub struct RateLimited;
+
+impl<'r> Responder<'r> for RateLimited {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let error = ApiError::new(ErrorCode::RateLimited, "Too many requests, please slow down");
+
+        let retry_after = request.local_cache(|| RequestUsage(None)).0
+            .map_or(WINDOW_SECONDS, |usage| (usage.reset - clock::now()).max(1));
+
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", retry_after.to_string())
+            .ok()
+    }
+}
+
+#[get("/__rate_limited")]
+pub fn rate_limited() -> RateLimited {
+    RateLimited
+}
@@ -0,0 +1,225 @@
+// Error reporting
+//
+// Failures in production should not only end up in stdout. Three kinds of them are
+// reported to an error tracker, with the request they happened in (method, path and
+// request id) when there is one:
+//   - panics, in handlers or anywhere else
+//   - internal_error responses, almost always a query that failed
+//   - sqlite errors that mean the database itself is in trouble (see breaker.rs)
+//
+// Where the reports go is pluggable (the Backend trait). With error_reporting_dsn
+// set in the config they are sent to Sentry, or anything else that takes Sentry's
+// store api (e.g. GlitchTip); without it they are only logged. Reports are sent by
+// a background thread through a bounded queue, so a slow tracker never holds up a
+// request. When the queue is full reports are dropped (and logged).
+
+use std::cell::RefCell;
+use std::panic;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response, Rocket};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{clock, error};
+
+const QUEUE_SIZE: usize = 256;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RequestContext {
+    pub method: String,
+    pub path: String,
+    pub request_id: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Report {
+    // "panic", "internal_error" or "database"
+    pub kind: &'static str,
+    pub message: String,
+    pub request: Option<RequestContext>,
+    pub timestamp: i64,
+}
+
+// Somewhere reports can be sent to
+pub trait Backend: Send {
+    fn send(&self, report: &Report) -> Result<(), String>;
+}
+
+fn describe(report: &Report) -> String {
+    match &report.request {
+        Some(request) => format!("[{}] {} (during {} {}, request {})",
+            report.kind, report.message, request.method, request.path, request.request_id),
+        None => format!("[{}] {}", report.kind, report.message),
+    }
+}
+
+// The default: reports only go to stdout, like the rest of the app's logging
+pub struct LogBackend;
+
+impl Backend for LogBackend {
+    fn send(&self, report: &Report) -> Result<(), String> {
+        println!("{}", describe(report));
+        Ok(())
+    }
+}
+
+// Sends reports to a Sentry project, from a DSN like
+// https://<public key>@sentry.example.com/<project id>
+pub struct SentryBackend {
+    store_url: String,
+    auth_header: String,
+    environment: String,
+}
+
+impl SentryBackend {
+    pub fn from_dsn(dsn: &str, environment: &str) -> Result<SentryBackend, String> {
+        let invalid = || format!("Invalid error_reporting_dsn {}", dsn);
+
+        let (scheme, rest) = dsn.split_once("://").ok_or_else(invalid)?;
+        let (public_key, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        // a secret key (key:secret@) is no longer needed, only the public part is sent
+        let public_key = public_key.split(':').next().unwrap_or("");
+        let (host_and_path, project_id) = rest.rsplit_once('/').ok_or_else(invalid)?;
+        if public_key.is_empty() || project_id.is_empty() || host_and_path.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(SentryBackend {
+            store_url: format!("{}://{}/api/{}/store/", scheme, host_and_path, project_id),
+            auth_header: format!("Sentry sentry_version=7, sentry_client={}/{}, sentry_key={}",
+                env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), public_key),
+            environment: environment.to_string(),
+        })
+    }
+
+    fn event(&self, report: &Report) -> serde_json::Value {
+        let level = if report.kind == "panic" { "fatal" } else { "error" };
+        let mut event = json!({
+            "event_id": uuid::Uuid::new_v4().to_simple().to_string(),
+            "timestamp": report.timestamp,
+            "platform": "other",
+            "level": level,
+            "logger": report.kind,
+            "message": report.message,
+            "environment": self.environment,
+            "tags": { "kind": report.kind },
+        });
+        if let Some(request) = &report.request {
+            event["request"] = json!({ "method": request.method, "url": request.path });
+            event["tags"]["request_id"] = json!(request.request_id);
+        }
+        event
+    }
+}
+
+impl Backend for SentryBackend {
+    fn send(&self, report: &Report) -> Result<(), String> {
+        ureq::post(&self.store_url)
+            .set("X-Sentry-Auth", &self.auth_header)
+            .send_json(self.event(report))
+            .map(|_| ())
+            .map_err(|e| format!("Failed to send an error report: {}", e))
+    }
+}
+
+static QUEUE: OnceLock<SyncSender<Report>> = OnceLock::new();
+
+thread_local! {
+    // the request this thread is working on, for reports made without one at hand
+    // (panics, sqlite's error log)
+    static CURRENT_REQUEST: RefCell<Option<RequestContext>> = const { RefCell::new(None) };
+}
+
+fn run(backend: Box<dyn Backend>, receiver: Receiver<Report>) {
+    for report in receiver {
+        if let Err(e) = backend.send(&report) {
+            println!("{}: {}", e, describe(&report));
+        }
+    }
+}
+
+pub fn start(backend: Box<dyn Backend>) -> Result<(), String> {
+    let (sender, receiver) = mpsc::sync_channel::<Report>(QUEUE_SIZE);
+    if QUEUE.set(sender).is_err() {
+        return Err("Error reporting is already running".into());
+    }
+    thread::spawn(move || run(backend, receiver));
+    Ok(())
+}
+
+// Reports a failure, with the request the current thread is handling
+pub fn capture(kind: &'static str, message: String) {
+    let request = CURRENT_REQUEST.with(|current| current.borrow().clone());
+    let report = Report { kind, message, request, timestamp: clock::now() };
+
+    match QUEUE.get() {
+        Some(queue) => {
+            if queue.try_send(report.clone()).is_err() {
+                println!("Error report queue is full, dropped: {}", describe(&report));
+            }
+        }
+        None => println!("{}", describe(&report)),
+    }
+}
+
+// Reports panics after the default hook has printed them
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        capture("panic", info.to_string());
+    }));
+}
+
+pub struct ErrorReporting;
+
+impl Fairing for ErrorReporting {
+    fn info(&self) -> Info {
+        Info {
+            name: "Error reporting",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let dsn = rocket.config().get_str("error_reporting_dsn").unwrap_or("").to_string();
+        let environment = rocket.config().environment.to_string();
+
+        let backend: Box<dyn Backend> = if dsn.is_empty() {
+            Box::new(LogBackend)
+        } else {
+            match SentryBackend::from_dsn(&dsn, &environment) {
+                Ok(backend) => Box::new(backend),
+                Err(e) => {
+                    println!("{}", e);
+                    return Err(rocket);
+                }
+            }
+        };
+
+        if let Err(e) = start(backend) {
+            println!("{}", e);
+            return Err(rocket);
+        }
+        install_panic_hook();
+        Ok(rocket)
+    }
+
+    // Rocket handles a request on one thread from the fairings to the response
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let context = RequestContext {
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            request_id: error::request_id(request),
+        };
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = Some(context));
+    }
+
+    fn on_response(&self, _: &Request, _: &mut Response) {
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = None);
+    }
+}
@@ -0,0 +1,146 @@
+// Time reports
+//
+// GET /reports/time?from=&to=&group_by=tag|list|day adds up the time entries (see
+// timers.rs) between from and to (RFC 3339, like the date filters; all time and
+// now when left out), e.g. for an invoice or a weekly review:
+//
+//     {"from": 1714521600, "to": 1715126400, "group_by": "tag", "total_seconds": 36000,
+//      "groups": [{"key": "client-a", "seconds": 28800, "entries": 6}, ...]}
+//
+// Entries that only partly fall between from and to count with that part, a running
+// timer up to now. Groups come largest first:
+//   - tag: per tag of the entry's item, key null for items without tags. An item
+//     with two tags counts in both, so the groups can add up to more than the total
+//   - list: per list of the item (key is the list name), null for items in no list
+//   - day: per day the entry started (yyyy-mm-dd, in the user's time zone, see
+//     timezone.rs), oldest first. Entries started before from count on from's day
+//
+// group_by is required.
+
+use rocket_contrib::json::Json;
+use rusqlite::params;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::timezone::UserTimeZone;
+use crate::{clock, db};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Tag,
+    List,
+    Day,
+}
+
+impl GroupBy {
+    fn parse(group_by: &str) -> Result<GroupBy, ApiError> {
+        match group_by {
+            "tag" => Ok(GroupBy::Tag),
+            "list" => Ok(GroupBy::List),
+            "day" => Ok(GroupBy::Day),
+            _ => Err(ApiError::new(ErrorCode::ValidationFailed, "group_by has to be tag, list or day")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TimeGroup {
+    key: Option<String>,
+    seconds: i64,
+    entries: i64,
+}
+
+#[derive(Serialize)]
+pub struct TimeReport {
+    // unix timestamps (seconds)
+    from: i64,
+    to: i64,
+    group_by: GroupBy,
+    total_seconds: i64,
+    groups: Vec<TimeGroup>,
+}
+
+// the seconds of an entry between ?1 (from) and ?2 (to), ?3 is now. The parameters
+// are numbered explicitly since the queries use them in different orders
+const CLIPPED_SECONDS: &str = "max(0, min(coalesce(time_entries.stopped_at, ?3), ?2) - max(time_entries.started_at, ?1))";
+const OVERLAPS: &str = "time_entries.started_at < ?2 and coalesce(time_entries.stopped_at, ?3) > ?1";
+
+fn timestamp(name: &str, value: Option<String>, default: i64) -> Result<i64, ApiError> {
+    match value {
+        Some(value) => clock::parse_rfc3339(&value).ok_or_else(|| {
+            ApiError::new(ErrorCode::ValidationFailed,
+                format!("{} has to be an RFC 3339 date-time like 2024-05-01T12:00:00Z", name))
+                .with_details(json!({ "parameter": name, "value": value }))
+        }),
+        None => Ok(default),
+    }
+}
+
+fn group_query(group_by: GroupBy, offset: i64) -> String {
+    // offset is a number from timezone.rs, not text from the request
+    let (key, joins, order) = match group_by {
+        GroupBy::Tag => ("tags.name",
+            "left join item_tags on item_tags.item_id = time_entries.item_id
+             left join tags on tags.id = item_tags.tag_id",
+            "seconds desc, group_key"),
+        GroupBy::List => ("lists.name",
+            "left join todo_list on todo_list.id = time_entries.item_id
+             left join lists on lists.id = todo_list.list_id",
+            "seconds desc, group_key"),
+        GroupBy::Day => ("(max(time_entries.started_at, ?1) + {offset}) / 86400", "", "group_key"),
+    };
+    let key = key.replace("{offset}", &offset.to_string());
+
+    format!("select {key} as group_key, sum({seconds}) as seconds, count(*) from time_entries {joins}
+             where {overlaps} group by group_key order by {order}",
+        key = key, seconds = CLIPPED_SECONDS, joins = joins, overlaps = OVERLAPS, order = order)
+}
+
+#[get("/reports/time?<from>&<to>&<group_by>")]
+pub fn time_report(from: Option<String>, to: Option<String>, group_by: Option<String>, timezone: UserTimeZone) -> Result<Json<TimeReport>, ApiError> {
+
+    let group_by = match group_by {
+        Some(group_by) => GroupBy::parse(&group_by)?,
+        None => return Err(ApiError::new(ErrorCode::ValidationFailed, "group_by has to be tag, list or day")),
+    };
+    let timezone = timezone.resolve()?;
+    let now = clock::now();
+    let from = timestamp("from", from, 0)?;
+    let to = timestamp("to", to, now)?;
+    if from >= to {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "from has to be earlier than to"));
+    }
+
+    let db_connection = db::connect()?;
+
+    let total_seconds = db_connection.query_row(
+        &format!("select coalesce(sum({}), 0) from time_entries where {}", CLIPPED_SECONDS, OVERLAPS),
+        params![from, to, now],
+        |row| row.get(0));
+    let total_seconds = match total_seconds {
+        Ok(total_seconds) => total_seconds,
+        Err(_) => return Err("Failed to fetch time entries".into()),
+    };
+
+    let mut statement = match db_connection.prepare(&group_query(group_by, timezone.offset())) {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let groups = statement
+        .query_map(params![from, to, now], |row| {
+            let key = match group_by {
+                GroupBy::Day => row.get::<_, Option<i64>>(0)?.map(clock::format_date),
+                _ => row.get(0)?,
+            };
+            Ok(TimeGroup { key, seconds: row.get(1)?, entries: row.get(2)? })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<TimeGroup>>>());
+
+    match groups {
+        Ok(groups) => Ok(Json(TimeReport { from, to, group_by, total_seconds, groups })),
+        Err(_) => Err("Failed to fetch time entries".into()),
+    }
+}
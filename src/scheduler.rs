@@ -0,0 +1,208 @@
+// Background jobs on a cron-like schedule
+//
+// A schedule has the five fields of a crontab line, in UTC:
+//
+//     minute hour day-of-month month day-of-week
+//     30     3    *            *     *           every day at 03:30
+//     0      */6  *            *     *           every six hours
+//     0      4    *            *     0,6         weekends at 04:00
+//
+// Each field is *, a number, a range (1-5), a list (1,15) or any of them with a
+// step (*/15, 0-30/10). Like cron, when both day-of-month and day-of-week are
+// restricted a day matching either one counts. Sunday is 0.
+
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::{self, UtcTime};
+
+struct Field {
+    allowed: Vec<bool>,
+    // the field was *, used for the day-of-month / day-of-week rule
+    any: bool,
+}
+
+pub struct Schedule {
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+}
+
+fn parse_number(text: &str, min: u32, max: u32) -> Result<u32, String> {
+    match text.parse::<u32>() {
+        Ok(number) if number >= min && number <= max => Ok(number),
+        _ => Err(format!("{} is not a number from {} to {}", text, min, max)),
+    }
+}
+
+fn parse_field(text: &str, min: u32, max: u32) -> Result<Field, String> {
+    let mut allowed = vec![false; max as usize + 1];
+
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_number(step, 1, max.max(1))?),
+            None => (part, 1),
+        };
+
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((first, last)) = range.split_once('-') {
+            (parse_number(first, min, max)?, parse_number(last, min, max)?)
+        } else {
+            let first = parse_number(range, min, max)?;
+            // "5/10" means from 5 to the end in steps of 10
+            (first, if part.contains('/') { max } else { first })
+        };
+
+        let mut value = first;
+        while value <= last {
+            allowed[value as usize] = true;
+            value += step;
+        }
+    }
+
+    // like cron, */2 counts as unrestricted for the day rule too
+    Ok(Field { allowed, any: text.starts_with('*') })
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid schedule \"{}\", expected 5 fields like \"30 3 * * *\"", text));
+        }
+
+        let invalid = |e: String| format!("Invalid schedule \"{}\": {}", text, e);
+
+        // 7 is sunday as well
+        let mut weekday = parse_field(fields[4], 0, 7).map_err(invalid)?;
+        weekday.allowed[0] = weekday.allowed[0] || weekday.allowed[7];
+
+        Ok(Schedule {
+            minute: parse_field(fields[0], 0, 59).map_err(invalid)?,
+            hour: parse_field(fields[1], 0, 23).map_err(invalid)?,
+            day: parse_field(fields[2], 1, 31).map_err(invalid)?,
+            month: parse_field(fields[3], 1, 12).map_err(invalid)?,
+            weekday,
+        })
+    }
+}
+
+impl Schedule {
+    pub fn matches(&self, time: &UtcTime) -> bool {
+        let day = self.day.allowed[time.day as usize];
+        let weekday = self.weekday.allowed[time.weekday as usize];
+        let day_matches = match (self.day.any, self.weekday.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minute.allowed[time.minute as usize]
+            && self.hour.allowed[time.hour as usize]
+            && self.month.allowed[time.month as usize]
+            && day_matches
+    }
+}
+
+// Runs job on its own thread whenever the schedule matches, checked once a minute
+pub fn spawn(name: &'static str, schedule: Schedule, job: fn() -> Result<String, String>) {
+    thread::spawn(move || loop {
+        // wake up just after the start of the next minute
+        let now = clock::now();
+        thread::sleep(Duration::from_secs((60 - now.rem_euclid(60)) as u64));
+
+        if schedule.matches(&clock::utc(clock::now())) {
+            match job() {
+                Ok(message) => println!("{}: {}", name, message),
+                Err(e) => println!("{} failed: {}", name, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(field: &Field) -> Vec<u32> {
+        (0..field.allowed.len() as u32).filter(|value| field.allowed[*value as usize]).collect()
+    }
+
+    fn time(month: u32, day: u32, hour: u32, minute: u32, weekday: u32) -> UtcTime {
+        UtcTime { month, day, hour, minute, weekday }
+    }
+
+    #[test]
+    fn parses_steps() {
+        assert_eq!(allowed(&parse_field("*/15", 0, 59).unwrap()), vec![0, 15, 30, 45]);
+        assert_eq!(allowed(&parse_field("0-30/10", 0, 59).unwrap()), vec![0, 10, 20, 30]);
+        assert_eq!(allowed(&parse_field("5/20", 0, 59).unwrap()), vec![5, 25, 45]);
+        assert_eq!(allowed(&parse_field("*/6", 0, 23).unwrap()), vec![0, 6, 12, 18]);
+    }
+
+    #[test]
+    fn parses_ranges_and_lists() {
+        assert_eq!(allowed(&parse_field("1-5", 0, 7).unwrap()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(allowed(&parse_field("1,15", 1, 31).unwrap()), vec![1, 15]);
+        assert_eq!(allowed(&parse_field("1-3,10,20-40/10", 0, 59).unwrap()), vec![1, 2, 3, 10, 20, 30, 40]);
+        assert_eq!(allowed(&parse_field("*", 1, 12).unwrap()), (1..=12).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn star_fields_are_unrestricted() {
+        assert!(parse_field("*", 1, 31).unwrap().any);
+        assert!(parse_field("*/2", 1, 31).unwrap().any);
+        assert!(!parse_field("1-31", 1, 31).unwrap().any);
+        assert!(!parse_field("1,*", 1, 31).unwrap().any);
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        for (text, min, max) in [("60", 0, 59), ("0", 1, 31), ("13", 1, 12), ("8", 0, 7), ("1-32", 1, 31),
+            ("*/0", 0, 59), ("*/61", 0, 59), ("-1", 0, 59), ("x", 0, 59), ("", 0, 59), ("1,", 0, 59)] {
+            assert!(parse_field(text, min, max).is_err(), "{}", text);
+        }
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("* 24 * * *".parse::<Schedule>().is_err());
+        assert!("* * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn weekday_7_is_sunday() {
+        let schedule: Schedule = "0 4 * * 7".parse().unwrap();
+        assert!(schedule.matches(&time(1, 4, 4, 0, 0)));
+        assert!(!schedule.matches(&time(1, 5, 4, 0, 1)));
+
+        let schedule: Schedule = "0 4 * * 5-7".parse().unwrap();
+        assert!(schedule.matches(&time(1, 4, 4, 0, 0)));
+        assert!(schedule.matches(&time(1, 9, 4, 0, 5)));
+        assert!(!schedule.matches(&time(1, 5, 4, 0, 1)));
+    }
+
+    #[test]
+    fn matches_the_time() {
+        let schedule: Schedule = "30 3 * * *".parse().unwrap();
+        assert!(schedule.matches(&time(6, 15, 3, 30, 2)));
+        assert!(!schedule.matches(&time(6, 15, 3, 31, 2)));
+        assert!(!schedule.matches(&time(6, 15, 4, 30, 2)));
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        let schedule: Schedule = "0 0 1 * 1".parse().unwrap();
+        assert!(schedule.matches(&time(3, 1, 0, 0, 4)));
+        assert!(schedule.matches(&time(3, 9, 0, 0, 1)));
+        assert!(!schedule.matches(&time(3, 10, 0, 0, 2)));
+
+        // a step from * leaves the day of the month unrestricted, both have to match
+        let schedule: Schedule = "0 0 */2 * 1".parse().unwrap();
+        assert!(schedule.matches(&time(3, 9, 0, 0, 1)));
+        assert!(!schedule.matches(&time(3, 10, 0, 0, 1)));
+        assert!(!schedule.matches(&time(3, 11, 0, 0, 2)));
+    }
+}
@@ -0,0 +1,309 @@
+// Searching todo items
+//
+// suggest: prefix based type-ahead for UIs adding new items.
+// search: fuzzy full text matching that tolerates typos ("groseries" still finds
+// "groceries"). Every result carries a score between 0 and 1 for ranking.
+
+use rocket_contrib::json::Json;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::date_filters::DateFilters;
+use crate::error::ApiError;
+use crate::features::Features;
+use crate::{crypto, db, ToDoItem, ITEM_COLUMNS};
+
+const DEFAULT_SUGGESTIONS: u32 = 10;
+const MAX_SUGGESTIONS: u32 = 50;
+
+#[derive(Serialize)]
+pub struct Suggestions {
+    suggestions: Vec<String>,
+}
+
+// % and _ are wildcards in LIKE, so they are escaped to match literally
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// With item encryption sqlite only sees ciphertext, so the texts are decrypted and
+// matched here instead. Same result as the query in suggest, just without the index
+fn suggest_decrypted(db_connection: &rusqlite::Connection, q: &str, limit: u32) -> Result<Vec<String>, String> {
+    let mut statement = match db_connection.prepare("select item from todo_list") {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let texts = statement
+        .query_map(rusqlite::NO_PARAMS, |row| crypto::decrypt_column(row.get(0)?, 0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>());
+    let texts = match texts {
+        Ok(texts) => texts,
+        Err(_) => return Err("Failed to fetch suggestions".into()),
+    };
+
+    // count the texts starting with q, grouping them ignoring case
+    let prefix = q.to_lowercase();
+    let mut counts: Vec<(String, String, usize)> = Vec::new();
+    for text in texts {
+        let lower = text.to_lowercase();
+        if !lower.starts_with(&prefix) {
+            continue;
+        }
+        match counts.iter_mut().find(|(key, _, _)| *key == lower) {
+            Some((_, _, count)) => *count += 1,
+            None => counts.push((lower, text, 1)),
+        }
+    }
+
+    counts.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    Ok(counts.into_iter().take(limit as usize).map(|(_, text, _)| text).collect())
+}
+
+// Returns up to `limit` distinct item texts that start with q (ignoring case), the
+// ones used most often first. The prefix match uses the todo_list_item_nocase index
+#[get("/todo/suggest?<q>&<limit>")]
+pub fn suggest(q: String, limit: Option<u32>) -> Result<Json<Suggestions>, ApiError> {
+
+    let limit = limit.unwrap_or(DEFAULT_SUGGESTIONS).min(MAX_SUGGESTIONS);
+    if q.trim().is_empty() {
+        return Ok(Json(Suggestions { suggestions: Vec::new() }));
+    }
+
+    let db_connection = db::connect()?;
+
+    if crypto::enabled() {
+        return Ok(Json(Suggestions { suggestions: suggest_decrypted(&db_connection, &q, limit)? }));
+    }
+
+    let mut statement = match db_connection.prepare(
+        "select item from todo_list
+         where item like $1 escape '\\'
+         group by item collate nocase
+         order by count(*) desc, item collate nocase
+         limit $2")
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let pattern = format!("{}%", escape_like(&q));
+    let results = statement
+        .query_map(params![pattern, limit], |row| row.get(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>());
+
+    match results {
+        Ok(suggestions) => Ok(Json(Suggestions { suggestions })),
+        Err(_) => Err("Failed to fetch suggestions".into()),
+    }
+}
+
+const DEFAULT_SEARCH_RESULTS: u32 = 20;
+const MAX_SEARCH_RESULTS: u32 = 100;
+
+// items scoring below this are not considered a match
+pub const MIN_SCORE: f64 = 0.6;
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    item: ToDoItem,
+    score: f64,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    results: Vec<SearchResult>,
+}
+
+// Number of single character insertions, deletions or substitutions needed to turn
+// a into b (Levenshtein distance). Only keeps one row of the table in memory
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+// 1.0 for identical words, going down to 0.0 the more edits are needed
+fn word_similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(&a, &b) as f64 / longest as f64
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// How well the item text matches the query. Each query word is matched against the
+// closest word in the text (so word order does not matter) and the item scores the
+// average of those. Query words that are a prefix of a text word count as exact
+// matches, so searching while typing works
+pub fn score(query: &str, text: &str) -> f64 {
+    let query_words = words(query);
+    let text_words = words(text);
+    if query_words.is_empty() || text_words.is_empty() {
+        return 0.0;
+    }
+
+    let total: f64 = query_words.iter()
+        .map(|query_word| {
+            text_words.iter()
+                .map(|text_word| {
+                    if text_word.starts_with(query_word.as_str()) {
+                        1.0
+                    } else {
+                        word_similarity(query_word, text_word)
+                    }
+                })
+                .fold(0.0, f64::max)
+        })
+        .sum();
+
+    total / query_words.len() as f64
+}
+
+// Fuzzy search over all items, best matches first
+// Behind the search-tags feature flag the tag names are matched as well.
+// The date range filters of GET /todo narrow down the items searched, see date_filters.rs
+#[get("/todo/search?<q>&<limit>&<created_after>&<created_before>&<due_after>&<due_before>")]
+pub fn search(q: String, limit: Option<u32>, created_after: Option<String>, created_before: Option<String>,
+    due_after: Option<String>, due_before: Option<String>, features: Features) -> Result<Json<SearchResults>, ApiError> {
+
+    let limit = limit.unwrap_or(DEFAULT_SEARCH_RESULTS).min(MAX_SEARCH_RESULTS) as usize;
+    let dates = DateFilters::from_params(created_after, created_before, due_after, due_before)?;
+
+    let db_connection = db::connect()?;
+
+    // edit distance can not be computed by sqlite, so the scoring happens here
+    let mut statement = match db_connection.prepare(
+        &format!("select {} from todo_list where {}", ITEM_COLUMNS, DateFilters::sql(1)))
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let [created_after, created_before, due_after, due_before] = dates.params();
+    let items = statement
+        .query_map(params![created_after, created_before, due_after, due_before], ToDoItem::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<ToDoItem>>>());
+
+    let items = match items {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+
+    let search_tags = features.enabled("search-tags");
+
+    let mut results: Vec<SearchResult> = items.into_iter()
+        .map(|item| {
+            let text_score = score(&q, &item.item);
+            let tag_score = if search_tags {
+                item.tags.iter().map(|tag| score(&q, tag)).fold(0.0, f64::max)
+            } else {
+                0.0
+            };
+            SearchResult { score: text_score.max(tag_score), item }
+        })
+        .filter(|result| result.score >= MIN_SCORE)
+        .collect();
+
+    // scores are never NaN so partial_cmp always has an answer
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    results.truncate(limit);
+
+    Ok(Json(SearchResults { results }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        edit_distance(&a, &b)
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("milk", "milk"), 0);
+        assert_eq!(distance("", "milk"), 4);
+        assert_eq!(distance("milk", ""), 4);
+        assert_eq!(distance("milk", "silk"), 1);
+        assert_eq!(distance("milk", "mik"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("flaw", "lawn"), 2);
+    }
+
+    #[test]
+    fn edit_distance_counts_characters_not_bytes() {
+        assert_eq!(distance("café", "cafe"), 1);
+        assert_eq!(distance("über", "uber"), 1);
+    }
+
+    #[test]
+    fn word_similarity_goes_from_one_to_zero() {
+        assert_eq!(word_similarity("milk", "milk"), 1.0);
+        assert_eq!(word_similarity("", ""), 1.0);
+        assert_eq!(word_similarity("abc", "xyz"), 0.0);
+        assert_eq!(word_similarity("milk", "silk"), 0.75);
+    }
+
+    #[test]
+    fn score_ignores_case_punctuation_and_word_order() {
+        assert_eq!(score("Buy MILK", "milk, buy"), 1.0);
+        assert_eq!(score("milk buy", "Buy milk!"), 1.0);
+    }
+
+    #[test]
+    fn score_counts_prefixes_as_matches() {
+        assert_eq!(score("mil", "buy milk"), 1.0);
+        assert_eq!(score("bu mi", "buy milk"), 1.0);
+    }
+
+    #[test]
+    fn score_tolerates_typos() {
+        let typo = score("mulk", "buy milk");
+        assert!(typo >= MIN_SCORE, "{}", typo);
+        assert!(typo < 1.0);
+    }
+
+    #[test]
+    fn score_averages_over_the_query_words() {
+        // "milk" matches, "xyzw" has nothing in common with either word
+        assert_eq!(score("milk xyzw", "buy milk"), 0.5);
+        assert!(score("bread", "buy milk") < MIN_SCORE);
+    }
+
+    #[test]
+    fn score_is_zero_without_words() {
+        assert_eq!(score("", "buy milk"), 0.0);
+        assert_eq!(score("milk", ""), 0.0);
+        assert_eq!(score("...", "buy milk"), 0.0);
+    }
+}
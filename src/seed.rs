@@ -0,0 +1,227 @@
+// Seed data
+//
+// Loads a fixed set of lists, items and tags into an empty database, for demos and
+// for test environments that should always start from the same data. Either at
+// startup with `--seed <file.json>`, with `todo seed <file.json>` or through
+// POST /admin/seed. `todo export` writes the current data in the same format. The
+// file looks like
+//
+//     {
+//         "lists": [
+//             {"name": "Groceries", "color": "green", "items": [
+//                 {"item": "Milk", "tags": ["dairy"]},
+//                 {"item": "Bread", "done": true}
+//             ]}
+//         ],
+//         "items": [{"item": "Call the plumber", "pinned": true}]
+//     }
+//
+// where "items" at the top are items without a list. A database that already has
+// any lists, items or tags is left alone.
+
+use std::fs;
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, Row, Transaction};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Admin;
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::{colors, crypto, db, lists, tags, validation};
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// Fields left at their default are left out of an export, to keep the file short
+#[derive(Serialize, Deserialize)]
+pub struct SeedItem {
+    item: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    done: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pinned: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    archived: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SeedList {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(default)]
+    items: Vec<SeedItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Seed {
+    #[serde(default)]
+    lists: Vec<SeedList>,
+    #[serde(default)]
+    items: Vec<SeedItem>,
+}
+
+#[derive(Serialize)]
+pub struct SeedResults {
+    lists: usize,
+    items: usize,
+}
+
+// What POST /admin/seed responds with
+#[derive(Responder)]
+pub enum SeedResponse {
+    #[response(status = 201)]
+    Seeded(Json<SeedResults>),
+    #[response(status = 409)]
+    NotEmpty(ApiError),
+}
+
+fn checked_color(color: &Option<String>) -> Result<Option<String>, String> {
+    match color {
+        Some(color) => colors::normalize_color(color)
+            .map(Some)
+            .ok_or_else(|| colors::invalid_color_message(color)),
+        None => Ok(None),
+    }
+}
+
+fn is_empty(transaction: &Transaction) -> rusqlite::Result<bool> {
+    transaction.query_row(
+        "select not exists (select 1 from todo_list)
+            and not exists (select 1 from lists)
+            and not exists (select 1 from tags)",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0))
+}
+
+fn insert_item(transaction: &Transaction, item: &SeedItem, list_id: Option<i64>, position: i64) -> Result<(), String> {
+    let text = crypto::encrypt_text(&validation::item_text(&item.item)?)?;
+    let color = checked_color(&item.color)?;
+    let status = if item.done { "done" } else { "todo" };
+
+    transaction.execute(
+        "insert into todo_list (id, item, done, status, pinned, archived, color, list_id, position)
+         values (null, $1, $2, $3, $4, $5, $6, $7, $8)",
+        params![text, item.done, status, item.pinned, item.archived, color, list_id, list_id.map(|_| position)])
+        .map_err(|_| String::from("Failed to insert ToDo Item"))?;
+
+    let item_id = transaction.last_insert_rowid();
+    for name in item.tags.iter().map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        tags::tag_item(transaction, item_id, name).map_err(|_| String::from("Failed to tag item"))?;
+    }
+    Ok(())
+}
+
+// Writes the seed in one transaction. None when the database is not empty
+pub fn load(seed: &Seed) -> Result<Option<SeedResults>, ApiError> {
+
+    let mut db_connection = db::connect()?;
+
+    let transaction = match db_connection.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    match is_empty(&transaction) {
+        Ok(true) => (),
+        Ok(false) => return Ok(None),
+        Err(_) => return Err("Failed to count the existing data".into()),
+    }
+
+    let mut results = SeedResults { lists: 0, items: 0 };
+
+    for seed_list in seed.lists.iter() {
+        let color = checked_color(&seed_list.color)?;
+        let list = lists::insert_list(&transaction, &seed_list.name, color.as_deref())
+            .map_err(|_| String::from("Failed to insert list"))?;
+        results.lists += 1;
+
+        for (index, item) in seed_list.items.iter().enumerate() {
+            insert_item(&transaction, item, Some(list.id()), index as i64 + 1)?;
+            results.items += 1;
+        }
+    }
+
+    for item in seed.items.iter() {
+        insert_item(&transaction, item, None, 0)?;
+        results.items += 1;
+    }
+
+    match transaction.commit() {
+        Ok(_) => Ok(Some(results)),
+        Err(_) => Err("Failed to write the seed data".into()),
+    }
+}
+
+// Used by --seed at startup
+pub fn load_file(path: &str) -> Result<Option<SeedResults>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let seed: Seed = serde_json::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+    Ok(load(&seed)?)
+}
+
+const EXPORT_ITEM_SELECT: &str = "
+    select item, done, pinned, archived, color,
+           (select group_concat(name, char(31)) from tags join item_tags on tags.id = item_tags.tag_id
+            where item_tags.item_id = todo_list.id)
+    from todo_list";
+
+fn export_item(row: &Row) -> rusqlite::Result<SeedItem> {
+    Ok(SeedItem {
+        item: crypto::decrypt_column(row.get(0)?, 0)?,
+        done: row.get(1)?,
+        pinned: row.get(2)?,
+        archived: row.get(3)?,
+        color: row.get(4)?,
+        tags: tags::split_tags(row.get(5)?),
+    })
+}
+
+fn export_items(db_connection: &Connection, list_id: Option<i64>) -> rusqlite::Result<Vec<SeedItem>> {
+    let mut statement = db_connection.prepare(&format!(
+        "{} where list_id is $1 order by position, id", EXPORT_ITEM_SELECT))?;
+    let rows = statement.query_map(params![list_id], export_item)?;
+    rows.collect()
+}
+
+fn export_data(db_connection: &Connection) -> rusqlite::Result<Seed> {
+    let list_rows: Vec<(i64, String, Option<String>)> = {
+        let mut statement = db_connection.prepare("select id, name, color from lists order by id")?;
+        let rows = statement.query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut lists = Vec::new();
+    for (id, name, color) in list_rows {
+        lists.push(SeedList { name, color, items: export_items(db_connection, Some(id))? });
+    }
+
+    Ok(Seed { lists, items: export_items(db_connection, None)? })
+}
+
+// Everything in the database in the seed format, used by `todo export`
+pub fn export() -> Result<Seed, String> {
+    let db_connection = db::connect()?;
+    export_data(&db_connection).map_err(|_| String::from("Failed to export the data"))
+}
+
+impl SeedResults {
+    pub fn summary(&self) -> String {
+        format!("{} lists and {} items", self.lists, self.items)
+    }
+}
+
+#[post("/seed", format = "json", data = "<seed>")]
+pub fn seed(_admin: Admin, seed: LimitedJson<Seed>) -> Result<SeedResponse, ApiError> {
+    match load(&seed.0)? {
+        Some(results) => Ok(SeedResponse::Seeded(Json(results))),
+        None => Ok(SeedResponse::NotEmpty(ApiError::new(ErrorCode::Conflict,
+            "The database already has data, seed data is only loaded into an empty one"))),
+    }
+}
@@ -0,0 +1,139 @@
+// Public read-only share links for lists
+//
+// POST /lists/<id>/share creates a random token and anyone with the link
+// /shared/<token> can see the list and its items, e.g. to send a shopping list to
+// someone without an account. The token is the only thing protecting the list so it
+// is a random uuid (122 random bits) rather than anything derived from the list.
+//
+// Links can be created with an expiry (?expires_in=<seconds>) and revoked with
+// DELETE /lists/<id>/share/<token>. Expired and revoked links answer 410 Gone so
+// the person holding the link knows it existed but is no longer valid.
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request};
+use rocket_contrib::json::Json;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::lists::{self, ListWithItems};
+use crate::{clock, db, notifications, StatusMessage};
+
+#[derive(Serialize)]
+pub struct Share {
+    token: String,
+    list_id: i64,
+    url: String,
+    // unix timestamp (seconds), None for links that never expire
+    expires_at: Option<i64>,
+}
+
+// Request guard for /shared/<token>. Succeeds with the shared list id when the token
+// is valid, fails with 410 when it expired or was revoked and forwards (404) when
+// the token does not exist at all
+pub struct ActiveShare {
+    list_id: i64,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ActiveShare {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ActiveShare, String> {
+        // the token is the second path segment of /shared/<token>
+        let token = match request.get_param::<String>(1) {
+            Some(Ok(token)) => token,
+            _ => return Outcome::Forward(()),
+        };
+
+        let db_connection = match db::connect() {
+            Ok(connection) => connection,
+            Err(e) => return Outcome::Failure((Status::InternalServerError, e)),
+        };
+
+        let share = db_connection.query_row(
+            "select list_id, expires_at, revoked from list_shares where token = $1",
+            params![token],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?, row.get::<_, bool>(2)?)))
+            .optional();
+
+        match share {
+            Ok(Some((_, _, true))) => Outcome::Failure((Status::Gone, "Share link was revoked".into())),
+            Ok(Some((_, Some(expires_at), _))) if expires_at <= clock::now() => {
+                Outcome::Failure((Status::Gone, "Share link has expired".into()))
+            }
+            Ok(Some((list_id, _, _))) => Outcome::Success(ActiveShare { list_id }),
+            Ok(None) => Outcome::Forward(()),
+            Err(_) => Outcome::Failure((Status::InternalServerError, "Failed to fetch share link".into())),
+        }
+    }
+}
+
+#[post("/lists/<id>/share?<expires_in>")]
+pub fn share_list(id: i64, expires_in: Option<u32>) -> Result<Option<Json<Share>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let list = match lists::fetch_list(&db_connection, id) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch list".into()),
+    };
+
+    let token = uuid::Uuid::new_v4().to_simple().to_string();
+    let expires_at = expires_in.map(|seconds| clock::now() + i64::from(seconds));
+
+    let results = db_connection.execute(
+        "insert into list_shares (token, list_id, expires_at) values ($1, $2, $3)", params![token, id, expires_at]);
+
+    if results.is_err() {
+        return Err("Failed to create share link".into());
+    }
+
+    // the inbox is informational, the link works even if this fails
+    let _ = notifications::notify(
+        &db_connection, "share", &format!("A share link was created for {}", list.name()), None, Some(id));
+
+    Ok(Some(Json(Share {
+        url: format!("/shared/{}", token),
+        token,
+        list_id: id,
+        expires_at,
+    })))
+}
+
+// Revoked links are kept (not deleted) so they keep answering 410 instead of 404
+#[delete("/lists/<id>/share/<token>")]
+pub fn revoke_share(id: i64, token: String) -> Result<Option<Json<StatusMessage>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let results = db_connection.execute(
+        "update list_shares set revoked = 1 where token = $1 and list_id = $2", params![token, id]);
+
+    match results {
+        Ok(0) => Ok(None),
+        Ok(rows_revoked) => Ok(Some(Json(StatusMessage {
+            message: format!("{} share links revoked", rows_revoked),
+        }))),
+        Err(_) => Err("Failed to revoke share link".into()),
+    }
+}
+
+// No authentication on purpose - knowing a valid token is enough.
+// The token itself is checked (and looked up) by the ActiveShare guard
+#[get("/shared/<_token>")]
+pub fn fetch_shared_list(_token: String, share: ActiveShare) -> Result<Option<Json<ListWithItems>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match lists::fetch_list(&db_connection, share.list_id) {
+        Ok(list) => lists::with_items(&db_connection, list),
+        Err(_) => Err("Failed to fetch list".into()),
+    }
+}
+
+#[catch(410)]
+pub fn gone() -> ApiError {
+    ApiError::new(ErrorCode::Gone, "This share link has expired or was revoked")
+}
@@ -0,0 +1,259 @@
+// Smart lists - saved searches
+//
+// A smart list is a named filter definition that is stored on the server and run
+// with GET /smartlists/<id>/items, so every client sees the same "Open groceries"
+// or "Done this week" without rebuilding the filter itself.
+// The filter is stored as json text so new filter fields can be added without
+// changing the table.
+//
+// Like GET /todo, smart lists leave archived items out. due_after / due_before are
+// RFC 3339 date-times, checked the same way as the query parameters of GET /todo
+// (see date_filters.rs).
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::date_filters::DateFilters;
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::{colors, db, search, StatusMessage, ToDoItem, ITEM_COLUMNS, ITEM_ORDER};
+
+// Every field is optional, a missing field does not filter anything
+#[derive(Serialize, Deserialize, Default)]
+pub struct ItemFilter {
+    // fuzzy text query, ranked the same way as GET /todo/search
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default)]
+    done: Option<bool>,
+    #[serde(default)]
+    list_id: Option<i64>,
+    // normalized color label, see colors.rs
+    #[serde(default)]
+    color: Option<String>,
+    // only items with this tag
+    #[serde(default)]
+    tag: Option<String>,
+    // only items due in this window, both ends exclusive
+    #[serde(default)]
+    due_after: Option<String>,
+    #[serde(default)]
+    due_before: Option<String>,
+}
+
+impl ItemFilter {
+    fn dates(&self) -> Result<DateFilters, ApiError> {
+        DateFilters::from_params(None, None, self.due_after.clone(), self.due_before.clone())
+    }
+
+    // Normalizes the color and checks the due window before the filter is saved
+    fn checked(mut self) -> Result<ItemFilter, ApiError> {
+        self.color = match self.color {
+            Some(color) => match colors::normalize_color(&color) {
+                Some(color) => Some(color),
+                None => return Err(ApiError::new(ErrorCode::ValidationFailed, colors::invalid_color_message(&color))),
+            },
+            None => None,
+        };
+        self.dates()?;
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct SmartList {
+    id: i64,
+    name: String,
+    filter: ItemFilter,
+}
+
+#[derive(Serialize)]
+pub struct SmartLists {
+    smartlists: Vec<SmartList>,
+}
+
+// body of POST /smartlists and PUT /smartlists/<id>
+#[derive(Deserialize)]
+pub struct SmartListDefinition {
+    name: String,
+    #[serde(default)]
+    filter: ItemFilter,
+}
+
+#[derive(Serialize)]
+pub struct SmartListItems {
+    items: Vec<ToDoItem>,
+}
+
+impl SmartList {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<SmartList> {
+        let filter: String = row.get(2)?;
+        Ok(SmartList {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            // a filter that can not be parsed (e.g. written by a newer version) is
+            // treated as no filter rather than failing the whole request
+            filter: serde_json::from_str(&filter).unwrap_or_default(),
+        })
+    }
+}
+
+fn fetch_smartlist(db_connection: &Connection, id: i64) -> rusqlite::Result<Option<SmartList>> {
+    db_connection.query_row(
+        "select id, name, filter from smartlists where id = $1", params![id], SmartList::from_row)
+        .optional()
+}
+
+// Runs the filter. done, list_id, color, tag and the due window are handled by sqlite, the
+// text query is scored in Rust and decides the order (best match first)
+pub fn run_filter(db_connection: &Connection, filter: &ItemFilter) -> rusqlite::Result<Vec<ToDoItem>> {
+    let mut statement = db_connection.prepare(&format!(
+        "select {} from todo_list
+         where archived = 0
+         and ($1 is null or done = $1)
+         and ($2 is null or list_id = $2)
+         and ($3 is null or color = $3)
+         and ($4 is null or exists (
+             select 1 from item_tags join tags on tags.id = item_tags.tag_id
+             where item_tags.item_id = todo_list.id and tags.name = $4))
+         and {}
+         order by {}",
+        ITEM_COLUMNS, DateFilters::sql(5), ITEM_ORDER))?;
+
+    // checked when saved; one that no longer parses does not filter, like a filter
+    // that can not be read at all (see SmartList::from_row)
+    let [created_after, created_before, due_after, due_before] = filter.dates().unwrap_or_default().params();
+    let items = statement
+        .query_map(params![filter.done, filter.list_id, filter.color, filter.tag,
+            created_after, created_before, due_after, due_before], ToDoItem::from_row)?
+        .collect::<rusqlite::Result<Vec<ToDoItem>>>()?;
+
+    let query = match &filter.query {
+        Some(query) if !query.trim().is_empty() => query,
+        _ => return Ok(items),
+    };
+
+    let mut scored: Vec<(f64, ToDoItem)> = items.into_iter()
+        .map(|item| (search::score(query, &item.item), item))
+        .filter(|(score, _)| *score >= search::MIN_SCORE)
+        .collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    Ok(scored.into_iter().map(|(_, item)| item).collect())
+}
+
+#[get("/smartlists")]
+pub fn fetch_all_smartlists() -> Result<Json<SmartLists>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare("select id, name, filter from smartlists order by name") {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(rusqlite::NO_PARAMS, SmartList::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<SmartList>>>());
+
+    match results {
+        Ok(smartlists) => Ok(Json(SmartLists { smartlists })),
+        Err(_) => Err("Failed to fetch smart lists".into()),
+    }
+}
+
+#[post("/smartlists", format = "json", data = "<definition>")]
+pub fn add_smartlist(definition: LimitedJson<SmartListDefinition>) -> Result<Json<SmartList>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut definition = definition.0;
+    definition.filter = definition.filter.checked()?;
+
+    let filter = match serde_json::to_string(&definition.filter) {
+        Ok(filter) => filter,
+        Err(_) => return Err("Failed to save the filter".into()),
+    };
+
+    let results = db_connection.execute(
+        "insert into smartlists (id, name, filter) values (null, $1, $2)", params![definition.name, filter]);
+
+    match results {
+        Ok(_) => Ok(Json(SmartList {
+            id: db_connection.last_insert_rowid(),
+            name: definition.name,
+            filter: definition.filter,
+        })),
+        Err(_) => Err("Failed to insert smart list".into()),
+    }
+}
+
+#[get("/smartlists/<id>")]
+pub fn fetch_smartlist_by_id(id: i64) -> Result<Option<Json<SmartList>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match fetch_smartlist(&db_connection, id) {
+        Ok(smartlist) => Ok(smartlist.map(Json)),
+        Err(_) => Err("Failed to fetch smart list".into()),
+    }
+}
+
+#[put("/smartlists/<id>", format = "json", data = "<definition>")]
+pub fn update_smartlist(id: i64, definition: LimitedJson<SmartListDefinition>) -> Result<Option<Json<SmartList>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut definition = definition.0;
+    definition.filter = definition.filter.checked()?;
+
+    let filter = match serde_json::to_string(&definition.filter) {
+        Ok(filter) => filter,
+        Err(_) => return Err("Failed to save the filter".into()),
+    };
+
+    let results = db_connection.execute(
+        "update smartlists set name = $1, filter = $2 where id = $3", params![definition.name, filter, id]);
+
+    match results {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(Json(SmartList {
+            id,
+            name: definition.name,
+            filter: definition.filter,
+        }))),
+        Err(_) => Err("Failed to update smart list".into()),
+    }
+}
+
+#[delete("/smartlists/<id>")]
+pub fn remove_smartlist(id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match db_connection.execute("delete from smartlists where id = $1", params![id]) {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to delete smart list".into()),
+    }
+}
+
+// Runs the stored filter and returns the matching items
+#[get("/smartlists/<id>/items")]
+pub fn fetch_smartlist_items(id: i64) -> Result<Option<Json<SmartListItems>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let smartlist = match fetch_smartlist(&db_connection, id) {
+        Ok(Some(smartlist)) => smartlist,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch smart list".into()),
+    };
+
+    match run_filter(&db_connection, &smartlist.filter) {
+        Ok(items) => Ok(Some(Json(SmartListItems { items }))),
+        Err(_) => Err("Failed to fetch ToDo Items".into()),
+    }
+}
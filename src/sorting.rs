@@ -0,0 +1,127 @@
+// Sorting of item lists
+//
+// ?sort=done:asc,status:desc sorts GET /todo by several fields, each in its own
+// direction (asc when left out). Only the fields in SORT_FIELDS can be used, they
+// are mapped to their columns here, so nothing from the request ends up in the sql
+// as it was sent. Pinned items still come first and id breaks ties, like in the
+// default order (ITEM_ORDER).
+
+use crate::error::{ApiError, ErrorCode};
+
+// name in ?sort= and the column it sorts by
+const SORT_FIELDS: [(&str, &str); 8] = [
+    ("id", "id"),
+    ("done", "done"),
+    ("status", "status"),
+    ("color", "color"),
+    ("position", "position"),
+    ("pinned", "pinned"),
+    ("created_at", "created_at"),
+    ("due_at", "due_at"),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Direction {
+    Asc,
+    Desc,
+}
+
+impl Direction {
+    fn sql(self) -> &'static str {
+        match self {
+            Direction::Asc => "asc",
+            Direction::Desc => "desc",
+        }
+    }
+}
+
+fn invalid(message: String) -> ApiError {
+    ApiError::new(ErrorCode::ValidationFailed, message)
+}
+
+fn column(field: &str) -> Result<&'static str, ApiError> {
+    SORT_FIELDS.iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, column)| *column)
+        .ok_or_else(|| {
+            let names: Vec<&str> = SORT_FIELDS.iter().map(|(name, _)| *name).collect();
+            invalid(format!("Can not sort by {}, use one of {}", field, names.join(", ")))
+        })
+}
+
+// The order by clause for ?sort=, e.g. "pinned desc, done asc, status desc, id"
+pub fn order_by(sort: &str) -> Result<String, ApiError> {
+    let mut keys: Vec<(&str, Direction)> = Vec::new();
+
+    for part in sort.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (field, direction) = match part.split_once(':') {
+            Some((field, direction)) => (field.trim(), direction.trim()),
+            None => (part, "asc"),
+        };
+        let direction = match direction.to_lowercase().as_str() {
+            "asc" => Direction::Asc,
+            "desc" => Direction::Desc,
+            _ => return Err(invalid(format!("Sort direction has to be asc or desc, not {}", direction))),
+        };
+
+        let column = column(field)?;
+        if keys.iter().any(|(existing, _)| *existing == column) {
+            return Err(invalid(format!("{} is sorted by more than once", field)));
+        }
+        keys.push((column, direction));
+    }
+
+    let mut terms = Vec::new();
+    if !keys.iter().any(|(column, _)| *column == "pinned") {
+        terms.push(String::from("pinned desc"));
+    }
+    terms.extend(keys.iter().map(|(column, direction)| format!("{} {}", column, direction.sql())));
+    if !keys.iter().any(|(column, _)| *column == "id") {
+        terms.push(String::from("id"));
+    }
+    Ok(terms.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_pinned_first_and_id_last() {
+        assert_eq!(order_by("").unwrap(), "pinned desc, id");
+        assert_eq!(order_by("done").unwrap(), "pinned desc, done asc, id");
+        assert_eq!(order_by("done:asc,status:desc").unwrap(), "pinned desc, done asc, status desc, id");
+    }
+
+    #[test]
+    fn pinned_and_id_can_be_sorted_explicitly() {
+        assert_eq!(order_by("pinned:asc").unwrap(), "pinned asc, id");
+        assert_eq!(order_by("id:desc").unwrap(), "pinned desc, id desc");
+        assert_eq!(order_by("id:desc,pinned").unwrap(), "id desc, pinned asc");
+    }
+
+    #[test]
+    fn ignores_case_and_spaces_in_directions() {
+        assert_eq!(order_by(" due_at : DESC , ").unwrap(), "pinned desc, due_at desc, id");
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        for sort in ["item", "ID", "id;drop table todo_list", "done,1=1", "created_at desc"] {
+            assert_eq!(order_by(sort).unwrap_err().code, ErrorCode::ValidationFailed, "{}", sort);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_directions() {
+        for sort in ["done:up", "done:", "done:asc:desc", "done:desc;"] {
+            assert_eq!(order_by(sort).unwrap_err().code, ErrorCode::ValidationFailed, "{}", sort);
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        assert!(order_by("done,done:desc").is_err());
+        assert!(order_by("status:asc,color,status").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+// Hosting for a bundled single page frontend
+//
+// With spa_dir set in the config, the files in that directory (the build output of
+// the frontend, e.g. frontend/dist) are served under /app. Any path under /app that
+// is not a file there gets index.html instead, so the frontend's own router can
+// handle deep links like /app/lists/3 after a reload.
+//
+// The files themselves are public (see auth::PUBLIC_PREFIXES); the frontend sends
+// its credentials with its api calls like any other client.
+
+use std::path::{Path, PathBuf};
+
+use rocket::fairing::AdHoc;
+use rocket::response::NamedFile;
+use rocket::State;
+
+pub struct SpaDir(PathBuf);
+
+fn index_html(dir: &Path) -> Option<NamedFile> {
+    NamedFile::open(dir.join("index.html")).ok()
+}
+
+#[get("/app")]
+pub fn app_index(dir: State<SpaDir>) -> Option<NamedFile> {
+    index_html(&dir.0)
+}
+
+// PathBuf as a route segment already turns away "..", so this stays inside spa_dir
+#[get("/app/<path..>")]
+pub fn app_file(path: PathBuf, dir: State<SpaDir>) -> Option<NamedFile> {
+    let file = dir.0.join(path);
+    if file.is_file() {
+        return NamedFile::open(file).ok();
+    }
+    index_html(&dir.0)
+}
+
+// Mounts /app when spa_dir is configured
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Frontend hosting", |rocket| {
+        let dir = match rocket.config().get_string("spa_dir") {
+            Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir),
+            _ => return Ok(rocket),
+        };
+
+        if !dir.join("index.html").is_file() {
+            println!("spa_dir {} has no index.html", dir.display());
+            return Err(rocket);
+        }
+
+        Ok(rocket.manage(SpaDir(dir)).mount("/", routes![app_index, app_file]))
+    })
+}
@@ -0,0 +1,92 @@
+// Completion streaks
+//
+// Every time an item is marked done a row goes into completions (by a trigger, see
+// db.rs, so it does not matter which route did it). Reopening an item does not take
+// the completion back: the work was done that day. Items are not owned by anyone in
+// this app, so the counts are those of the whole database, i.e. of its one user.
+//
+// GET /stats/streaks counts them per day in the user's time zone (see timezone.rs):
+//
+//     {"timezone": "+02:00", "current_streak": 4, "longest_streak": 12,
+//      "heatmap_start": "2023-05-02", "heatmap": [0, 3, 1, ...]}
+//
+// A streak is a run of days with at least one completion. The current one ends today,
+// or yesterday while nothing was completed today yet, so it does not drop to 0 in
+// the morning. heatmap has the completions of HEATMAP_DAYS days, oldest first,
+// ending with today.
+
+use rocket_contrib::json::Json;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::timezone::UserTimeZone;
+use crate::{clock, db};
+
+const HEATMAP_DAYS: i64 = 365;
+
+#[derive(Serialize)]
+pub struct Streaks {
+    timezone: String,
+    current_streak: i64,
+    longest_streak: i64,
+    heatmap_start: String,
+    heatmap: Vec<i64>,
+}
+
+// (day, completions) for every day with completions, oldest first
+fn completions_per_day(offset: i64) -> Result<Vec<(i64, i64)>, ApiError> {
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(
+        "select (completed_at + $1) / 86400 as day, count(*) from completions group by day order by day")
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let days = statement
+        .query_map(params![offset], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<(i64, i64)>>>());
+
+    match days {
+        Ok(days) => Ok(days),
+        Err(_) => Err("Failed to fetch completions".into()),
+    }
+}
+
+#[get("/stats/streaks")]
+pub fn streaks(timezone: UserTimeZone) -> Result<Json<Streaks>, ApiError> {
+    let timezone = timezone.resolve()?;
+    let today = timezone.day(clock::now());
+    let days = completions_per_day(timezone.offset())?;
+
+    let mut longest_streak = 0;
+    let mut run = 0;
+    let mut previous = None;
+    for (day, _) in &days {
+        run = if previous == Some(day - 1) { run + 1 } else { 1 };
+        longest_streak = longest_streak.max(run);
+        previous = Some(*day);
+    }
+
+    // run is the streak ending on the last day with completions
+    let current_streak = match previous {
+        Some(last) if last >= today - 1 => run,
+        _ => 0,
+    };
+
+    let first = today - HEATMAP_DAYS + 1;
+    let mut heatmap = vec![0; HEATMAP_DAYS as usize];
+    for (day, count) in days.iter().filter(|(day, _)| (first..=today).contains(day)) {
+        heatmap[(day - first) as usize] = *count;
+    }
+
+    Ok(Json(Streaks {
+        timezone: timezone.name(),
+        current_streak,
+        longest_streak,
+        heatmap_start: clock::format_date(first),
+        heatmap,
+    }))
+}
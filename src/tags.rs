@@ -0,0 +1,213 @@
+// Tags on todo items
+//
+// A tag is a name (unique, ignoring case) that can be put on any number of items.
+// Items carry the names of their tags (see the tags column in ITEM_COLUMNS).
+// Tags can be renamed, and merged into another tag, which moves all the items of
+// the first tag over and removes it.
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::{db, StatusMessage};
+
+// Separator used by group_concat when the tag names of an item are selected together
+// with the item. It is the ascii unit separator so tag names can contain commas
+pub const TAG_SEPARATOR: char = '\u{1f}';
+
+#[derive(Serialize)]
+pub struct Tag {
+    id: i64,
+    name: String,
+    // number of items with this tag
+    items: i64,
+}
+
+#[derive(Serialize)]
+pub struct Tags {
+    tags: Vec<Tag>,
+}
+
+// body of PUT /tags/<id>
+#[derive(Deserialize)]
+pub struct TagName {
+    name: String,
+}
+
+const TAG_SELECT: &str = "select id, name, (select count(*) from item_tags where tag_id = tags.id) from tags";
+
+impl Tag {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Tag> {
+        Ok(Tag {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            items: row.get(2)?,
+        })
+    }
+}
+
+// What PUT /tags/<id> responds with
+#[derive(Responder)]
+pub enum RenameResponse {
+    #[response(status = 200)]
+    Renamed(Json<Tag>),
+    // another tag already has the new name. Merge the tags instead
+    #[response(status = 409)]
+    Conflict(ApiError),
+}
+
+// Splits the group_concat'ed tag names read together with an item
+pub fn split_tags(tags: Option<String>) -> Vec<String> {
+    match tags {
+        Some(tags) => tags.split(TAG_SEPARATOR).map(String::from).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn clean_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "Tag name can not be empty"));
+    }
+    Ok(name.to_string())
+}
+
+fn fetch_tag(db_connection: &Connection, id: i64) -> rusqlite::Result<Option<Tag>> {
+    db_connection.query_row(&format!("{} where id = $1", TAG_SELECT), params![id], Tag::from_row).optional()
+}
+
+// Puts the tag with this name on an item, creating the tag when it does not exist yet
+pub fn tag_item(db_connection: &Connection, item_id: i64, name: &str) -> rusqlite::Result<()> {
+    db_connection.execute("insert or ignore into tags (id, name) values (null, $1)", params![name])?;
+    db_connection.execute(
+        "insert or ignore into item_tags (item_id, tag_id) select $1, id from tags where name = $2",
+        params![item_id, name])?;
+    Ok(())
+}
+
+#[get("/tags")]
+pub fn fetch_all_tags() -> Result<Json<Tags>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(&format!("{} order by name", TAG_SELECT)) {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(rusqlite::NO_PARAMS, Tag::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Tag>>>());
+
+    match results {
+        Ok(tags) => Ok(Json(Tags { tags })),
+        Err(_) => Err("Failed to fetch tags".into()),
+    }
+}
+
+// The body is the tag name as a json string
+#[post("/todo/<id>/tags", format = "json", data = "<name>")]
+pub fn add_item_tag(id: i64, name: LimitedJson<String>) -> Result<Json<StatusMessage>, ApiError> {
+
+    let name = clean_name(&name.0)?;
+    let db_connection = db::connect()?;
+
+    match tag_item(&db_connection, id, &name) {
+        Ok(_) => Ok(Json(StatusMessage {
+            message: format!("Tagged with {}", name),
+        })),
+        Err(_) => Err("Failed to tag ToDo Item".into()),
+    }
+}
+
+#[delete("/todo/<id>/tags/<tag_id>")]
+pub fn remove_item_tag(id: i64, tag_id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let results = db_connection.execute(
+        "delete from item_tags where item_id = $1 and tag_id = $2", params![id, tag_id]);
+
+    match results {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to untag ToDo Item".into()),
+    }
+}
+
+// Renames a tag. Names are unique ignoring case, so renaming to a name another tag
+// already has is a 409 - use merge-into for that
+#[put("/tags/<id>", format = "json", data = "<tag>")]
+pub fn rename_tag(id: i64, tag: LimitedJson<TagName>) -> Result<Option<RenameResponse>, ApiError> {
+
+    let name = clean_name(&tag.0.name)?;
+    let db_connection = db::connect()?;
+
+    let taken = db_connection.query_row(
+        "select 1 from tags where name = $1 and id != $2", params![name, id], |_| Ok(()))
+        .optional();
+    match taken {
+        Ok(Some(_)) => return Ok(Some(RenameResponse::Conflict(ApiError::new(ErrorCode::Conflict,
+            format!("A tag named {} already exists", name))))),
+        Ok(None) => (),
+        Err(_) => return Err("Failed to fetch tags".into()),
+    }
+
+    if db_connection.execute("update tags set name = $1 where id = $2", params![name, id]).is_err() {
+        return Err("Failed to rename tag".into());
+    }
+
+    match fetch_tag(&db_connection, id) {
+        Ok(tag) => Ok(tag.map(|tag| RenameResponse::Renamed(Json(tag)))),
+        Err(_) => Err("Failed to fetch tag".into()),
+    }
+}
+
+// Moves every item of tag id over to tag other and deletes tag id, in one
+// transaction. Items that already had both tags end up with just other
+#[post("/tags/<id>/merge-into/<other>")]
+pub fn merge_tags(id: i64, other: i64) -> Result<Option<Json<Tag>>, ApiError> {
+
+    if id == other {
+        return Err(ApiError::new(ErrorCode::BadRequest, "Can not merge a tag into itself"));
+    }
+
+    let mut db_connection = db::connect()?;
+
+    let transaction = match db_connection.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return Err("Failed to start a transaction".into()),
+    };
+
+    for tag_id in [id, other].iter() {
+        match fetch_tag(&transaction, *tag_id) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Ok(None),
+            Err(_) => return Err("Failed to fetch tag".into()),
+        }
+    }
+
+    let remapped = transaction.execute(
+        "insert or ignore into item_tags (item_id, tag_id) select item_id, $1 from item_tags where tag_id = $2",
+        params![other, id]);
+    if remapped.is_err() {
+        return Err("Failed to merge tags".into());
+    }
+
+    // deleting the tag also deletes its old item_tags rows (on delete cascade)
+    if transaction.execute("delete from tags where id = $1", params![id]).is_err() {
+        return Err("Failed to delete tag".into());
+    }
+
+    if transaction.commit().is_err() {
+        return Err("Failed to merge tags".into());
+    }
+
+    match fetch_tag(&db_connection, other) {
+        Ok(tag) => Ok(tag.map(Json)),
+        Err(_) => Err("Failed to fetch tag".into()),
+    }
+}
@@ -0,0 +1,484 @@
+// Multi-tenant mode
+//
+// With multi_tenant = true every tenant gets a database of its own, a file in
+// tenant_dir named after the tenant (tenants/acme.sqlite), so the data of two tenants
+// can never end up in one query. A request says which tenant it is for with an
+// X-Tenant header, or with the subdomain of tenant_domain it was sent to (with
+// tenant_domain = "todo.example.com", acme.todo.example.com is tenant acme); the
+// header wins.
+//
+// Tenants have to be in the registry, the tenants table of the main database. The
+// ones listed in the tenants setting are added to it at startup. Tenant names are 1
+// to 63 lowercase letters, digits and dashes, not starting with a dash.
+//
+// A fairing resolves the tenant before routing and remembers it for the thread
+// handling the request (Rocket 0.4 handles a request on one thread, see also
+// reporting.rs); db::connect then opens the tenant's file instead of the main one,
+// so every handler, guard and fairing that reads the database stays within the
+// tenant without knowing about it. Requests for no tenant, an unknown or a
+// suspended one never get that far: they are rewritten to a route answering 400,
+// 404 or 403. Only /health, /metrics and /admin work without a tenant, on the main
+// database.
+//
+// A tenant's database is opened lazily: the first request for it (after a start)
+// creates the file and brings its schema up to date. Connections are opened per
+// request like in single tenant mode; there is no connection pool, for tenants or
+// the main database, so there is none per tenant either. Item writes of tenants go
+// to their file directly instead of through the write queue (see writer.rs), which
+// owns a connection to the main database. Item change events (see events.rs) only
+// cover the main database. The scheduled vacuum and checkpoints (see
+// housekeeping.rs) go through the main database and every tenant's that has been
+// created.
+//
+// API tokens are per database too. A tenant's tokens (issued with POST /admin/tokens
+// and its X-Tenant header, by an admin of the main database) only work for its
+// requests, and never with the admin scope: the admin api checks tokens against the
+// main database only (see auth.rs).
+//
+// Admin api (404 while multi_tenant is off):
+//   GET    /admin/tenants                  - the registry, with each database's size
+//   POST   /admin/tenants                  - {"name": "acme"}, adds a tenant and creates its database
+//   PUT    /admin/tenants/<name>/suspended - {"suspended": true}, 403 for its requests until false
+//   DELETE /admin/tenants/<name>           - removes a tenant. Its database is first copied to
+//                                            <backup_dir>/tenants/<name>-<unix time>.sqlite
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::{Data, Request, Rocket, State};
+use rocket_contrib::json::Json;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Admin;
+use crate::config::AppConfig;
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::{clock, db};
+
+const DEFAULT_TENANT_DIR: &str = "tenants";
+const TENANT_HEADER: &str = "X-Tenant";
+const TENANT_REQUIRED_PATH: &str = "/__tenant/required";
+const TENANT_UNKNOWN_PATH: &str = "/__tenant/unknown";
+const TENANT_SUSPENDED_PATH: &str = "/__tenant/suspended";
+
+// paths that work without a tenant, on the main database
+const MAIN_PATHS: [&str; 3] = ["/health", "/metrics", "/admin"];
+
+#[derive(Clone)]
+pub struct TenantConfig {
+    dir: PathBuf,
+    // the domain whose subdomains are tenants, if any
+    domain: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Tenant {
+    name: String,
+    created_at: i64,
+    suspended: bool,
+    // size of the database file in bytes, 0 before its first use
+    database_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+// body of POST /admin/tenants
+#[derive(Deserialize)]
+pub struct NewTenant {
+    name: String,
+}
+
+// body of PUT /admin/tenants/<name>/suspended
+#[derive(Deserialize)]
+pub struct Suspension {
+    suspended: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeletedTenant {
+    name: String,
+    // the copy of its database
+    archive: String,
+}
+
+thread_local! {
+    // the name and database file of the tenant this thread is handling a request for
+    static CURRENT: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+// tenants whose database has been set up since the start
+static PROVISIONED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+// the config, for the scheduled jobs that have no request state. Set at startup in
+// multi-tenant mode only
+static CONFIG: OnceLock<TenantConfig> = OnceLock::new();
+
+pub fn current_tenant() -> Option<String> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(name, _)| name.clone()))
+}
+
+pub fn current_database() -> Option<String> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(_, file)| file.clone()))
+}
+
+fn set_current(tenant: Option<(String, String)>) {
+    CURRENT.with(|current| *current.borrow_mut() = tenant);
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && name.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+}
+
+impl TenantConfig {
+    fn from_rocket(rocket: &Rocket) -> TenantConfig {
+        let dir = rocket.config().get_str("tenant_dir").unwrap_or(DEFAULT_TENANT_DIR);
+        let domain = rocket.config().get_str("tenant_domain").unwrap_or("").trim().trim_start_matches('.').to_lowercase();
+        TenantConfig {
+            dir: PathBuf::from(dir),
+            domain: if domain.is_empty() { None } else { Some(domain) },
+        }
+    }
+
+    pub fn database_file(&self, name: &str) -> String {
+        self.dir.join(format!("{}.sqlite", name)).to_string_lossy().into_owned()
+    }
+
+    // from the X-Tenant header, or the subdomain of tenant_domain the request was sent to
+    fn tenant_of(&self, request: &Request) -> Option<String> {
+        if let Some(tenant) = request.headers().get_one(TENANT_HEADER) {
+            return Some(tenant.trim().to_lowercase());
+        }
+        let domain = self.domain.as_ref()?;
+        let host = request.headers().get_one("Host")?.to_lowercase();
+        let host = host.split(':').next().unwrap_or("");
+        host.strip_suffix(domain.as_str())
+            .and_then(|subdomain| subdomain.strip_suffix('.'))
+            .filter(|subdomain| !subdomain.is_empty() && !subdomain.contains('.'))
+            .map(String::from)
+    }
+}
+
+// Whether the tenant is in the registry, and suspended. None when it is not
+fn lookup(name: &str) -> Result<Option<bool>, String> {
+    db::connect_main()?
+        .query_row("select suspended from tenants where name = $1", params![name], |row| row.get(0))
+        .optional()
+        .map_err(|_| String::from("Failed to read the tenant registry"))
+}
+
+fn register(name: &str) -> Result<(), String> {
+    db::connect_main()?
+        .execute("insert or ignore into tenants (name, created_at) values ($1, $2)", params![name, clock::now()])
+        .map(|_| ())
+        .map_err(|_| String::from("Failed to add the tenant"))
+}
+
+// Creates the tenant's database, or brings its schema up to date, once per start.
+// The tenant's database is current on this thread while it runs
+pub fn provision(config: &TenantConfig, name: &str) -> Result<(), String> {
+    let mut provisioned = PROVISIONED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if provisioned.as_ref().map_or(false, |provisioned| provisioned.contains(name)) {
+        return Ok(());
+    }
+
+    let previous = CURRENT.with(|current| current.borrow().clone());
+    set_current(Some((name.to_string(), config.database_file(name))));
+    let prepared = db::prepare_schema(true).and_then(|_| {
+        // events are only delivered from the main database, an outbox here would
+        // only grow
+        db::connect()?.execute_batch("
+            drop trigger if exists outbox_item_created;
+            drop trigger if exists outbox_item_updated;
+            drop trigger if exists outbox_item_completed;
+            drop trigger if exists outbox_item_deleted;")
+            .map_err(|e| e.to_string())
+    });
+    set_current(previous);
+
+    prepared.map_err(|e| format!("Failed to set up the database of tenant {}: {}", name, e))?;
+    provisioned.get_or_insert_with(HashSet::new).insert(name.to_string());
+    Ok(())
+}
+
+// The database files of the registered tenants that have been created, for the
+// scheduled housekeeping. Empty while multi-tenant mode is off
+pub fn databases() -> Result<Vec<String>, String> {
+    let config = match CONFIG.get() {
+        Some(config) => config,
+        None => return Ok(Vec::new()),
+    };
+
+    let db_connection = db::connect_main()?;
+    let names = db_connection.prepare("select name from tenants order by name")
+        .and_then(|mut statement| {
+            statement.query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()
+        })
+        .map_err(|_| String::from("Failed to read the tenant registry"))?;
+
+    Ok(names.iter()
+        .map(|name| config.database_file(name))
+        // not used yet, opening it would create an empty file
+        .filter(|file| fs::metadata(file).is_ok())
+        .collect())
+}
+
+// Forgets that the tenant's database was set up, after it was deleted
+fn forget(name: &str) {
+    let mut provisioned = PROVISIONED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(provisioned) = provisioned.as_mut() {
+        provisioned.remove(name);
+    }
+}
+
+pub struct Tenancy;
+
+impl Fairing for Tenancy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Multi-tenant mode",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        if !rocket.config().get_bool("multi_tenant").unwrap_or(false) {
+            return Ok(rocket);
+        }
+        let config = TenantConfig::from_rocket(&rocket);
+        if let Err(e) = fs::create_dir_all(&config.dir) {
+            println!("Failed to create tenant_dir {}: {}", config.dir.display(), e);
+            return Err(rocket);
+        }
+
+        let names: Vec<String> = rocket.config().get_slice("tenants")
+            .map(|values| values.iter().filter_map(|value| value.as_str()).map(String::from).collect())
+            .unwrap_or_default();
+        for name in names {
+            if !is_valid_name(&name) {
+                println!("Invalid tenant name {}, use lowercase letters, digits and dashes", name);
+                return Err(rocket);
+            }
+            if let Err(e) = register(&name) {
+                println!("{}", e);
+                return Err(rocket);
+            }
+        }
+
+        let _ = CONFIG.set(config.clone());
+        Ok(rocket.manage(config).mount("/", routes![tenant_required, tenant_unknown, tenant_suspended]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        // a thread handles one request after the other, forget the last one's tenant
+        set_current(None);
+
+        // already turned away by an earlier fairing (load shedding, the ip filter),
+        // do not look the tenant up for it
+        if request.uri().path().starts_with("/__") {
+            return;
+        }
+
+        let config = match request.guard::<State<TenantConfig>>() {
+            rocket::Outcome::Success(config) => config,
+            // not in multi-tenant mode
+            _ => return,
+        };
+
+        let rejected = match config.tenant_of(request) {
+            None => {
+                let path = request.uri().path();
+                let main = MAIN_PATHS.iter().any(|main| path == *main || path.starts_with(&format!("{}/", main)));
+                if main { None } else { Some(TENANT_REQUIRED_PATH) }
+            }
+            Some(name) if !is_valid_name(&name) => Some(TENANT_UNKNOWN_PATH),
+            Some(name) => match lookup(&name) {
+                Ok(None) => Some(TENANT_UNKNOWN_PATH),
+                Ok(Some(true)) => Some(TENANT_SUSPENDED_PATH),
+                Ok(Some(false)) => match provision(&config, &name) {
+                    Ok(_) => {
+                        let file = config.database_file(&name);
+                        set_current(Some((name, file)));
+                        None
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        Some(TENANT_UNKNOWN_PATH)
+                    }
+                },
+                Err(e) => {
+                    println!("{}", e);
+                    Some(TENANT_UNKNOWN_PATH)
+                }
+            },
+        };
+
+        if let Some(path) = rejected {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(path).expect("valid tenant path"));
+        }
+    }
+}
+
+#[get("/__tenant/required")]
+pub fn tenant_required() -> ApiError {
+    ApiError::new(ErrorCode::BadRequest, "Say which tenant the request is for with an X-Tenant header")
+}
+
+#[get("/__tenant/unknown")]
+pub fn tenant_unknown() -> ApiError {
+    ApiError::new(ErrorCode::NotFound, "Unknown tenant")
+}
+
+#[get("/__tenant/suspended")]
+pub fn tenant_suspended() -> ApiError {
+    ApiError::new(ErrorCode::Forbidden, "This tenant is suspended")
+}
+
+// The config, or a 404 while multi-tenant mode is off
+fn tenant_config(config: Option<State<TenantConfig>>) -> Result<State<TenantConfig>, ApiError> {
+    config.ok_or_else(|| ApiError::new(ErrorCode::NotFound, "Multi-tenant mode is off"))
+}
+
+fn fetch_tenant(config: &TenantConfig, name: &str) -> Result<Option<Tenant>, ApiError> {
+    let found: Option<(i64, bool)> = db::connect_main()?
+        .query_row("select created_at, suspended from tenants where name = $1", params![name],
+            |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()
+        .map_err(|_| ApiError::from("Failed to fetch tenant"))?;
+
+    Ok(found.map(|(created_at, suspended)| Tenant {
+        name: name.to_string(),
+        created_at,
+        suspended,
+        database_size: fs::metadata(config.database_file(name)).map(|metadata| metadata.len()).unwrap_or(0),
+    }))
+}
+
+#[get("/tenants")]
+pub fn fetch_all_tenants(_admin: Admin, config: Option<State<TenantConfig>>) -> Result<Json<Tenants>, ApiError> {
+    let config = tenant_config(config)?;
+
+    let db_connection = db::connect_main()?;
+
+    let mut statement = match db_connection.prepare("select name, created_at, suspended from tenants order by name") {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(rusqlite::NO_PARAMS, |row| {
+            let name: String = row.get(0)?;
+            Ok(Tenant {
+                database_size: fs::metadata(config.database_file(&name)).map(|metadata| metadata.len()).unwrap_or(0),
+                name,
+                created_at: row.get(1)?,
+                suspended: row.get(2)?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Tenant>>>());
+
+    match results {
+        Ok(tenants) => Ok(Json(Tenants { tenants })),
+        Err(_) => Err("Failed to fetch tenants".into()),
+    }
+}
+
+#[post("/tenants", format = "json", data = "<new_tenant>")]
+pub fn add_tenant(_admin: Admin, config: Option<State<TenantConfig>>, new_tenant: LimitedJson<NewTenant>) -> Result<Json<Tenant>, ApiError> {
+    let config = tenant_config(config)?;
+
+    let name = new_tenant.0.name.trim().to_string();
+    if !is_valid_name(&name) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed,
+            "A tenant name is 1 to 63 lowercase letters, digits and dashes, not starting with a dash"));
+    }
+
+    let db_connection = db::connect_main()?;
+    let results = db_connection.execute(
+        "insert or ignore into tenants (name, created_at) values ($1, $2)", params![name, clock::now()]);
+    match results {
+        Ok(0) => return Err(ApiError::new(ErrorCode::Conflict, format!("Tenant {} already exists", name))),
+        Ok(_) => (),
+        Err(_) => return Err("Failed to add the tenant".into()),
+    }
+
+    // the database is set up now rather than on the first request, so a broken
+    // tenant_dir shows up here
+    if let Err(e) = provision(&config, &name) {
+        let _ = db_connection.execute("delete from tenants where name = $1", params![name]);
+        return Err(e.into());
+    }
+
+    fetch_tenant(&config, &name)?
+        .map(Json)
+        .ok_or_else(|| ApiError::from("Failed to add the tenant"))
+}
+
+#[put("/tenants/<name>/suspended", format = "json", data = "<suspension>")]
+pub fn set_suspended(_admin: Admin, config: Option<State<TenantConfig>>, name: String, suspension: LimitedJson<Suspension>) -> Result<Option<Json<Tenant>>, ApiError> {
+    let config = tenant_config(config)?;
+
+    let db_connection = db::connect_main()?;
+
+    match db_connection.execute("update tenants set suspended = $1 where name = $2", params![suspension.0.suspended, name]) {
+        Ok(0) => Ok(None),
+        Ok(_) => fetch_tenant(&config, &name).map(|tenant| tenant.map(Json)),
+        Err(_) => Err("Failed to update the tenant".into()),
+    }
+}
+
+// Removes the tenant from the registry first, so it gets no more requests, then
+// archives its database and deletes the file
+#[delete("/tenants/<name>")]
+pub fn remove_tenant(_admin: Admin, config: Option<State<TenantConfig>>, app_config: State<AppConfig>, name: String) -> Result<Option<Json<DeletedTenant>>, ApiError> {
+    let config = tenant_config(config)?;
+
+    if fetch_tenant(&config, &name)?.is_none() {
+        return Ok(None);
+    }
+
+    let archive_dir = PathBuf::from(&app_config.backup_dir).join("tenants");
+    if fs::create_dir_all(&archive_dir).is_err() {
+        return Err("Failed to create the archive directory".into());
+    }
+    let archive = archive_dir.join(format!("{}-{}.sqlite", name, clock::now())).to_string_lossy().into_owned();
+
+    let db_connection = db::connect_main()?;
+    if db_connection.execute("delete from tenants where name = $1", params![name]).is_err() {
+        return Err("Failed to delete the tenant".into());
+    }
+    forget(&name);
+
+    // a tenant that never had a request has no database to archive
+    let file = config.database_file(&name);
+    if fs::metadata(&file).is_ok() {
+        let archived = db::connect_to(&file).and_then(|tenant_connection| {
+            tenant_connection.execute("vacuum into $1", params![archive]).map_err(|e| e.to_string())
+        });
+        if let Err(e) = archived {
+            // back in the registry, nothing is lost
+            let _ = db_connection.execute(
+                "insert or ignore into tenants (name, created_at) values ($1, $2)", params![name, clock::now()]);
+            return Err(format!("Failed to archive the database of tenant {}: {}", name, e).into());
+        }
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", file, suffix));
+        }
+    }
+
+    Ok(Some(Json(DeletedTenant { name, archive })))
+}
@@ -0,0 +1,98 @@
+// Time tracking
+//
+// POST /todo/<id>/timer/start starts a timer on an item, POST /todo/<id>/timer/stop
+// stops it. Each start / stop pair is kept as a time entry (time_entries), an item
+// can have one running timer at a time. Items report the time tracked on them in
+// seconds (tracked_seconds in ITEM_COLUMNS), a running timer counted up to now.
+// Time entries are deleted with their item.
+
+use rocket_contrib::json::Json;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::{clock, db};
+
+#[derive(Serialize)]
+pub struct TimeEntry {
+    id: i64,
+    item_id: i64,
+    // unix timestamps (seconds), stopped_at is None while the timer runs
+    started_at: i64,
+    stopped_at: Option<i64>,
+}
+
+fn item_exists(db_connection: &rusqlite::Connection, id: i64) -> Result<bool, ApiError> {
+    let exists = db_connection.query_row(
+        "select 1 from todo_list where id = $1", params![id], |_| Ok(()))
+        .optional();
+    match exists {
+        Ok(exists) => Ok(exists.is_some()),
+        Err(_) => Err("Failed to fetch ToDo Item".into()),
+    }
+}
+
+// 404 when the item does not exist, 409 when its timer is already running
+#[post("/todo/<id>/timer/start")]
+pub fn start_timer(id: i64) -> Result<Option<Json<TimeEntry>>, ApiError> {
+
+    let db_connection = db::connect()?;
+    if !item_exists(&db_connection, id)? {
+        return Ok(None);
+    }
+
+    // the time_entries_running index allows one entry without stopped_at per item
+    let started_at = clock::now();
+    let results = db_connection.execute(
+        "insert into time_entries (id, item_id, started_at) values (null, $1, $2)", params![id, started_at]);
+
+    match results {
+        Ok(_) => Ok(Some(Json(TimeEntry {
+            id: db_connection.last_insert_rowid(),
+            item_id: id,
+            started_at,
+            stopped_at: None,
+        }))),
+        Err(rusqlite::Error::SqliteFailure(error, _)) if error.code == rusqlite::ErrorCode::ConstraintViolation =>
+            Err(ApiError::new(ErrorCode::Conflict, "The timer of this item is already running")),
+        Err(_) => Err("Failed to start the timer".into()),
+    }
+}
+
+// 404 when the item does not exist, 409 when its timer is not running
+#[post("/todo/<id>/timer/stop")]
+pub fn stop_timer(id: i64) -> Result<Option<Json<TimeEntry>>, ApiError> {
+
+    let db_connection = db::connect()?;
+    if !item_exists(&db_connection, id)? {
+        return Ok(None);
+    }
+
+    let running = db_connection.query_row(
+        "select id, started_at from time_entries where item_id = $1 and stopped_at is null",
+        params![id],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))
+        .optional();
+
+    let (entry_id, started_at) = match running {
+        Ok(Some(running)) => running,
+        Ok(None) => return Err(not_running()),
+        Err(_) => return Err("Failed to fetch the timer".into()),
+    };
+
+    // a clock that went back would make the entry negative, it is cut to 0 instead
+    let stopped_at = clock::now().max(started_at);
+    // a stop at the same time may have come first
+    let results = db_connection.execute(
+        "update time_entries set stopped_at = $1 where id = $2 and stopped_at is null", params![stopped_at, entry_id]);
+
+    match results {
+        Ok(0) => Err(not_running()),
+        Ok(_) => Ok(Some(Json(TimeEntry { id: entry_id, item_id: id, started_at, stopped_at: Some(stopped_at) }))),
+        Err(_) => Err("Failed to stop the timer".into()),
+    }
+}
+
+fn not_running() -> ApiError {
+    ApiError::new(ErrorCode::Conflict, "The timer of this item is not running")
+}
@@ -0,0 +1,128 @@
+// The user's time zone
+//
+// Timestamps are stored in UTC, but "today", "overdue since yesterday" and the like
+// depend on where the user is. Clients say so with a header:
+//
+//     Time-Zone: +02:00
+//
+// Requests without one use timezone in the config (UTC unless set). Zones are
+// fixed offsets from UTC: "+02:00", "-05:30", "+0530", "+02", or "UTC" / "Z". Named
+// zones (Europe/Berlin) would need the tz database, so a client that knows its zone
+// sends the current offset instead. An unreadable header is answered with 422 by the
+// routes that need it, rather than silently using the default.
+
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, Rocket, State};
+use serde_json::json;
+
+use crate::config::AppConfig;
+use crate::error::{ApiError, ErrorCode};
+
+const HEADER: &str = "Time-Zone";
+const SECONDS_PER_DAY: i64 = 86_400;
+
+// offsets beyond this do not exist anywhere (the largest in use is +14:00)
+const MAX_OFFSET_HOURS: i64 = 18;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimeZone {
+    // seconds east of UTC
+    offset: i64,
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone { offset: 0 };
+
+    pub fn parse(text: &str) -> Option<TimeZone> {
+        let text = text.trim();
+        if text.eq_ignore_ascii_case("utc") || text.eq_ignore_ascii_case("z") {
+            return Some(TimeZone::UTC);
+        }
+
+        let sign = match text.chars().next()? {
+            '+' => 1,
+            '-' => -1,
+            _ => return None,
+        };
+        let digits: String = text[1..].chars().filter(|c| *c != ':').collect();
+        if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        let (hours, minutes) = match digits.len() {
+            2 => (digits.parse::<i64>().ok()?, 0),
+            4 => (digits[..2].parse::<i64>().ok()?, digits[2..].parse::<i64>().ok()?),
+            _ => return None,
+        };
+        if hours > MAX_OFFSET_HOURS || minutes > 59 {
+            return None;
+        }
+        Some(TimeZone { offset: sign * (hours * 3600 + minutes * 60) })
+    }
+
+    // seconds east of UTC
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    // as +hh:mm
+    pub fn name(&self) -> String {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+        format!("{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+    }
+
+    // the local calendar day of a timestamp, counted in days since 1970-01-01
+    pub fn day(&self, timestamp: i64) -> i64 {
+        (timestamp + self.offset).div_euclid(SECONDS_PER_DAY)
+    }
+
+    // the timestamp of local midnight at the start of a day
+    pub fn start_of_day(&self, day: i64) -> i64 {
+        day * SECONDS_PER_DAY - self.offset
+    }
+}
+
+// timezone in the config, UTC when not set
+pub fn from_rocket(rocket: &Rocket) -> Result<TimeZone, String> {
+    match rocket.config().get_string("timezone") {
+        Ok(zone) if zone.trim().is_empty() => Ok(TimeZone::UTC),
+        Ok(zone) => TimeZone::parse(&zone)
+            .ok_or_else(|| format!("timezone has to be an offset like +02:00 or UTC, not {}", zone)),
+        Err(_) => Ok(TimeZone::UTC),
+    }
+}
+
+// Request guard for the time zone of the request, see resolve
+pub struct UserTimeZone {
+    header: Option<String>,
+    default: TimeZone,
+}
+
+impl UserTimeZone {
+    // the zone of the Time-Zone header, or the configured one without it
+    pub fn resolve(self) -> Result<TimeZone, ApiError> {
+        match self.header {
+            Some(header) => TimeZone::parse(&header).ok_or_else(|| {
+                ApiError::new(ErrorCode::ValidationFailed,
+                    format!("The {} header has to be an offset like +02:00 or UTC", HEADER))
+                    .with_details(json!({ "header": HEADER, "value": header }))
+            }),
+            None => Ok(self.default),
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserTimeZone {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<UserTimeZone, ()> {
+        let default = match request.guard::<State<AppConfig>>() {
+            Outcome::Success(app_config) => app_config.timezone,
+            _ => TimeZone::UTC,
+        };
+        Outcome::Success(UserTimeZone {
+            header: request.headers().get_one(HEADER).map(String::from),
+            default,
+        })
+    }
+}
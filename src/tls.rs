@@ -0,0 +1,94 @@
+// TLS configuration
+//
+// Rocket serves https when it is built with its tls feature and given a certificate
+// chain and private key. The paths are read from the app config like every other
+// setting:
+//
+//     [global]
+//     tls_certs = "/etc/todo/fullchain.pem"
+//     tls_key = "/etc/todo/privkey.pem"
+//     # check the files for changes every 60 seconds and restart to pick them up
+//     tls_reload_interval = 60
+//
+// Rocket 0.4 can not swap certificates in a running server, so "reloading" means
+// the process exits with RELOAD_EXIT_CODE when the files change and the process
+// supervisor (systemd, docker, ...) starts it again with the new certificate.
+
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rocket::Rocket;
+
+pub const RELOAD_EXIT_CODE: i32 = 3;
+
+struct TlsPaths {
+    certs: String,
+    key: String,
+}
+
+fn tls_paths(rocket: &Rocket) -> Option<TlsPaths> {
+    let config = rocket.config();
+    match (config.get_string("tls_certs"), config.get_string("tls_key")) {
+        (Ok(certs), Ok(key)) => Some(TlsPaths { certs, key }),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "tls")]
+fn enable_tls(rocket: Rocket, paths: &TlsPaths) -> Result<Rocket, String> {
+    // the TLS settings of a Rocket can not be changed after ignite, so a new one is
+    // built from a copy of its config
+    let mut config = rocket.config().clone();
+    match config.set_tls(&paths.certs, &paths.key) {
+        Ok(_) => Ok(rocket::custom(config)),
+        Err(e) => Err(format!("Invalid TLS configuration: {}", e)),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn enable_tls(_rocket: Rocket, _paths: &TlsPaths) -> Result<Rocket, String> {
+    Err("tls_certs/tls_key are set but this build does not have the tls feature".into())
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(PathBuf::from(path)).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Polls the certificate files and exits the process when one of them changes
+fn watch_for_changes(paths: TlsPaths, interval: Duration) {
+    thread::spawn(move || {
+        let initial = (modified(&paths.certs), modified(&paths.key));
+        loop {
+            thread::sleep(interval);
+            if (modified(&paths.certs), modified(&paths.key)) != initial {
+                println!("TLS certificate changed on disk, exiting so it can be reloaded");
+                std::process::exit(RELOAD_EXIT_CODE);
+            }
+        }
+    });
+}
+
+// Has to be called on a fresh rocket, before anything is attached or mounted,
+// because enabling TLS replaces the rocket. Panics on a broken TLS configuration
+// so the server never silently falls back to plain http
+pub fn configure(rocket: Rocket) -> Rocket {
+    let paths = match tls_paths(&rocket) {
+        Some(paths) => paths,
+        None => return rocket,
+    };
+
+    let reload_interval = rocket.config().get_int("tls_reload_interval").ok().filter(|seconds| *seconds > 0);
+
+    let rocket = match enable_tls(rocket, &paths) {
+        Ok(rocket) => rocket,
+        Err(e) => panic!("{}", e),
+    };
+
+    if let Some(seconds) = reload_interval {
+        watch_for_changes(paths, Duration::from_secs(seconds as u64));
+    }
+
+    rocket
+}
@@ -0,0 +1,335 @@
+// todo.txt import and export
+//
+// GET /todo/export.txt writes the items that are not archived in the todo.txt
+// format (http://todotxt.org), one per line, and POST /todo/import.txt adds the
+// items of such a file (the plain text body). Lines like
+//
+//     (A) 2024-05-01 Call the plumber +house @phone due:2024-05-03
+//     x 2024-05-02 2024-05-01 Buy paint +house
+//
+// map to the crate's fields as
+//   - x: done, with the completion date that follows it (completed_at)
+//   - (A): a priority. The items have none, so any priority pins the item, and
+//     pinned items are exported as (A)
+//   - the first date (the second one for done items): created_at
+//   - +project: the list, found by name or slug and created when there is none.
+//     Exported as the list's slug. Only the first +project of a line is a list,
+//     further ones stay in the text
+//   - @context: a tag (spaces in tag names are exported as -)
+//   - due:yyyy-mm-dd: due_at, the end of that day
+// Everything else is the item text. Dates are days in the user's time zone (see
+// timezone.rs).
+//
+// The import runs in one transaction. Lines that can not be imported (an empty or
+// too long text, see validation.rs) are skipped and reported with their line number;
+// blank lines are ignored.
+
+use std::collections::HashMap;
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket_contrib::json::Json;
+use rusqlite::Transaction;
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::imports::{self, NewItem};
+use crate::limits::LimitedText;
+use crate::timezone::{TimeZone, UserTimeZone};
+use crate::{clock, db, lists, quotas, validation, writer, ToDoItem, ITEM_COLUMNS, ITEM_ORDER};
+
+// what a line says, dates as days since 1970-01-01
+#[derive(Default, Debug)]
+struct TodoTxtLine {
+    text: String,
+    done: bool,
+    pinned: bool,
+    created: Option<i64>,
+    completed: Option<i64>,
+    due: Option<i64>,
+    project: Option<String>,
+    contexts: Vec<String>,
+}
+
+// (A) to (Z)
+fn is_priority(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    bytes.len() == 3 && bytes[0] == b'(' && bytes[1].is_ascii_uppercase() && bytes[2] == b')'
+}
+
+fn parse_line(line: &str) -> TodoTxtLine {
+    let mut parsed = TodoTxtLine::default();
+    let mut words = line.split_whitespace().peekable();
+
+    if words.peek() == Some(&"x") {
+        words.next();
+        parsed.done = true;
+    }
+    if words.peek().map_or(false, |word| is_priority(word)) {
+        words.next();
+        parsed.pinned = true;
+    }
+    if let Some(day) = words.peek().and_then(|word| clock::parse_date(word)) {
+        words.next();
+        if parsed.done {
+            parsed.completed = Some(day);
+            parsed.created = words.peek().and_then(|word| clock::parse_date(word));
+            if parsed.created.is_some() {
+                words.next();
+            }
+        } else {
+            parsed.created = Some(day);
+        }
+    }
+
+    let mut text = Vec::new();
+    for word in words {
+        if let Some(project) = word.strip_prefix('+').filter(|project| !project.is_empty()) {
+            if parsed.project.is_none() {
+                parsed.project = Some(project.to_string());
+                continue;
+            }
+        }
+        if let Some(context) = word.strip_prefix('@').filter(|context| !context.is_empty()) {
+            parsed.contexts.push(context.to_string());
+            continue;
+        }
+        if let Some(day) = word.strip_prefix("due:").and_then(clock::parse_date) {
+            parsed.due = Some(day);
+            continue;
+        }
+        text.push(word);
+    }
+    parsed.text = text.join(" ");
+    parsed
+}
+
+#[derive(Serialize)]
+pub struct SkippedLine {
+    line: usize,
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct ImportResults {
+    imported: usize,
+    lists_created: usize,
+    skipped: Vec<SkippedLine>,
+}
+
+// text is the validated text of the line
+fn insert_line(transaction: &Transaction, parsed: &TodoTxtLine, text: &str, timezone: TimeZone, results: &mut ImportResults) -> Result<(), ApiError> {
+    let list_id = match &parsed.project {
+        Some(project) => {
+            let (list, created) = lists::find_or_insert_list(transaction, project)
+                .map_err(|_| ApiError::from("Failed to insert list"))?;
+            if created {
+                results.lists_created += 1;
+            }
+            Some(list.id())
+        }
+        None => None,
+    };
+
+    imports::insert_item(transaction, &NewItem {
+        text,
+        done: parsed.done,
+        pinned: parsed.pinned,
+        list_id,
+        created_at: parsed.created.map(|day| timezone.start_of_day(day)),
+        completed_at: parsed.completed.map(|day| timezone.start_of_day(day)),
+        // due at the end of the day, so the item is not overdue during it
+        due_at: parsed.due.map(|day| timezone.start_of_day(day + 1) - 1),
+        tags: &parsed.contexts,
+    })?;
+    Ok(())
+}
+
+#[post("/todo/import.txt", data = "<body>")]
+pub fn import(body: LimitedText, timezone: UserTimeZone) -> Result<Json<ImportResults>, ApiError> {
+
+    let timezone = timezone.resolve()?;
+    let body = body.0;
+
+    writer::write(move |db_connection| {
+
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        let mut results = ImportResults { imported: 0, lists_created: 0, skipped: Vec::new() };
+
+        for (index, line) in body.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = parse_line(line);
+            // a line that can not be stored is skipped, the database failing fails all
+            let text = match validation::item_text(&parsed.text) {
+                Ok(text) => text,
+                Err(e) => {
+                    results.skipped.push(SkippedLine { line: index + 1, message: e.message });
+                    continue;
+                }
+            };
+            insert_line(&transaction, &parsed, &text, timezone, &mut results)?;
+            results.imported += 1;
+        }
+        quotas::check_items(&transaction, 0)?;
+
+        match transaction.commit() {
+            Ok(_) => Ok(Json(results)),
+            Err(_) => Err("Failed to import the items".into()),
+        }
+    })?
+}
+
+fn export_line(item: &ToDoItem, slugs: &HashMap<i64, String>, timezone: TimeZone) -> String {
+    let date = |timestamp: i64| clock::format_date(timezone.day(timestamp));
+    let mut words: Vec<String> = Vec::new();
+
+    if item.done {
+        words.push(String::from("x"));
+        // a creation date can only follow a completion date
+        if let Some(completed_at) = item.completed_at {
+            words.push(date(completed_at));
+            words.extend(item.created_at.map(date));
+        }
+    } else {
+        if item.pinned {
+            words.push(String::from("(A)"));
+        }
+        words.extend(item.created_at.map(date));
+    }
+
+    words.push(item.item.clone());
+    if let Some(slug) = item.list_id.and_then(|list_id| slugs.get(&list_id)) {
+        words.push(format!("+{}", slug));
+    }
+    for tag in &item.tags {
+        words.push(format!("@{}", tag.split_whitespace().collect::<Vec<&str>>().join("-")));
+    }
+    if let Some(due_at) = item.due_at {
+        words.push(format!("due:{}", date(due_at)));
+    }
+    words.join(" ")
+}
+
+// open items first, each group in the usual order
+#[get("/todo/export.txt")]
+pub fn export(timezone: UserTimeZone) -> Result<Content<String>, ApiError> {
+
+    let timezone = timezone.resolve()?;
+    let db_connection = db::connect()?;
+
+    let slugs = db_connection.prepare("select id, slug from lists")
+        .and_then(|mut statement| {
+            let rows = statement.query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<HashMap<i64, String>>>()
+        });
+    let slugs = match slugs {
+        Ok(slugs) => slugs,
+        Err(_) => return Err("Failed to fetch lists".into()),
+    };
+
+    let items = db_connection.prepare(
+        &format!("select {} from todo_list where archived = 0 order by done, {}", ITEM_COLUMNS, ITEM_ORDER))
+        .and_then(|mut statement| {
+            let rows = statement.query_map(rusqlite::NO_PARAMS, ToDoItem::from_row)?;
+            rows.collect::<rusqlite::Result<Vec<ToDoItem>>>()
+        });
+    let items = match items {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+
+    let mut text: String = items.iter()
+        .map(|item| export_line(item, &slugs, timezone))
+        .collect::<Vec<String>>()
+        .join("\n");
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    Ok(Content(ContentType::Plain, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(text: &str) -> Option<i64> {
+        clock::parse_date(text)
+    }
+
+    #[test]
+    fn parses_plain_text() {
+        let parsed = parse_line("  Call the   plumber ");
+        assert_eq!(parsed.text, "Call the plumber");
+        assert!(!parsed.done && !parsed.pinned);
+        assert_eq!((parsed.created, parsed.completed, parsed.due), (None, None, None));
+        assert_eq!(parsed.project, None);
+        assert!(parsed.contexts.is_empty());
+    }
+
+    #[test]
+    fn parses_a_done_line_with_both_dates() {
+        let parsed = parse_line("x 2024-05-02 2024-05-01 Buy paint");
+        assert!(parsed.done);
+        assert_eq!(parsed.completed, day("2024-05-02"));
+        assert_eq!(parsed.created, day("2024-05-01"));
+        assert_eq!(parsed.text, "Buy paint");
+
+        // a single date after x is when it was done
+        let parsed = parse_line("x 2024-05-02 Buy paint");
+        assert_eq!((parsed.completed, parsed.created), (day("2024-05-02"), None));
+
+        // x only counts at the start
+        let parsed = parse_line("Buy x paint");
+        assert!(!parsed.done);
+        assert_eq!(parsed.text, "Buy x paint");
+    }
+
+    #[test]
+    fn a_priority_pins_the_item() {
+        let parsed = parse_line("(A) 2024-05-01 Call the plumber");
+        assert!(parsed.pinned);
+        assert_eq!(parsed.created, day("2024-05-01"));
+        assert_eq!(parsed.text, "Call the plumber");
+
+        assert!(parse_line("x (Z) Call the plumber").pinned);
+        assert!(!parse_line("(a) Call the plumber").pinned);
+        assert!(!parse_line("Call (A) the plumber").pinned);
+    }
+
+    #[test]
+    fn the_first_project_is_the_list() {
+        let parsed = parse_line("Buy paint +house +garden");
+        assert_eq!(parsed.project.as_deref(), Some("house"));
+        assert_eq!(parsed.text, "Buy paint +garden");
+
+        let parsed = parse_line("1 + 1");
+        assert_eq!(parsed.project, None);
+        assert_eq!(parsed.text, "1 + 1");
+    }
+
+    #[test]
+    fn contexts_are_tags() {
+        let parsed = parse_line("Call the plumber @phone @home");
+        assert_eq!(parsed.contexts, vec!["phone", "home"]);
+        assert_eq!(parsed.text, "Call the plumber");
+        assert_eq!(parse_line("meet @ noon").text, "meet @ noon");
+    }
+
+    #[test]
+    fn parses_the_due_date() {
+        let parsed = parse_line("(A) 2024-05-01 Call the plumber +house @phone due:2024-05-03");
+        assert_eq!(parsed.due, day("2024-05-03"));
+        assert_eq!(parsed.text, "Call the plumber");
+
+        // not a date, stays in the text
+        let parsed = parse_line("Call the plumber due:friday");
+        assert_eq!(parsed.due, None);
+        assert_eq!(parsed.text, "Call the plumber due:friday");
+    }
+}
@@ -0,0 +1,245 @@
+// API tokens with scopes
+//
+// POST /admin/tokens issues a random bearer token with a list of scopes:
+//   read  - GET requests
+//   write - everything that changes data (POST, PUT, DELETE)
+//   admin - the /admin routes (including managing tokens), and anything a read or
+//           write token can do
+// Clients send it as "Authorization: Bearer <token>". Which scope a request needs
+// is decided in one place, auth::required_scope, so routes do not check it themselves.
+//
+// Only a sha256 hash of the token is stored; the token itself is shown once, in the
+// response that created it.
+
+use rand::RngCore;
+use rocket_contrib::json::Json;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::Admin;
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::{clock, db, StatusMessage};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Read, Scope::Write, Scope::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Scope> {
+        Scope::ALL.iter().copied().find(|candidate| candidate.as_str() == scope)
+    }
+}
+
+// Scopes are stored as a comma separated list, e.g. "read,write"
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes.iter().map(|scope| scope.as_str()).collect::<Vec<&str>>().join(",")
+}
+
+fn split_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split(',').filter_map(|scope| Scope::parse(scope.trim())).collect()
+}
+
+// The scopes a request was authenticated with
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub scopes: Vec<Scope>,
+    // the api token used, None for Basic auth
+    pub token_id: Option<i64>,
+    // requests per minute allowed for the token, None = rate_limit_per_minute
+    pub rate_limit: Option<i64>,
+}
+
+impl Grant {
+    pub fn full() -> Grant {
+        Grant { scopes: vec![Scope::Admin], token_id: None, rate_limit: None }
+    }
+
+    pub fn allows(&self, required: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&required)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiToken {
+    id: i64,
+    name: String,
+    scopes: Vec<Scope>,
+    // requests per minute, None = the configured rate_limit_per_minute
+    rate_limit: Option<i64>,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiTokens {
+    tokens: Vec<ApiToken>,
+}
+
+// body of POST /admin/tokens
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    rate_limit: Option<i64>,
+}
+
+// body of PUT /admin/tokens/<id>/rate-limit, null goes back to the default
+#[derive(Deserialize)]
+pub struct RateLimitTier {
+    rate_limit: Option<i64>,
+}
+
+// Only returned once, when the token is created
+#[derive(Serialize)]
+pub struct IssuedToken {
+    #[serde(flatten)]
+    details: ApiToken,
+    token: String,
+}
+
+impl ApiToken {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiToken> {
+        let scopes: String = row.get(2)?;
+        Ok(ApiToken {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            scopes: split_scopes(&scopes),
+            rate_limit: row.get(3)?,
+            created_at: row.get(4)?,
+            last_used_at: row.get(5)?,
+        })
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Looks up a bearer token and returns its scopes, None when the token is unknown
+pub fn authenticate(db_connection: &Connection, token: &str) -> rusqlite::Result<Option<Grant>> {
+    let found: Option<(i64, String, Option<i64>)> = db_connection.query_row(
+        "select id, scopes, rate_limit from api_tokens where token_hash = $1", params![hash_token(token)],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .optional()?;
+
+    let (id, scopes, rate_limit) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    db_connection.execute("update api_tokens set last_used_at = $1 where id = $2", params![clock::now(), id])?;
+
+    Ok(Some(Grant { scopes: split_scopes(&scopes), token_id: Some(id), rate_limit }))
+}
+
+#[get("/tokens")]
+pub fn fetch_all_tokens(_admin: Admin) -> Result<Json<ApiTokens>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(
+        "select id, name, scopes, rate_limit, created_at, last_used_at from api_tokens order by id") {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(rusqlite::NO_PARAMS, ApiToken::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<ApiToken>>>());
+
+    match results {
+        Ok(tokens) => Ok(Json(ApiTokens { tokens })),
+        Err(_) => Err("Failed to fetch tokens".into()),
+    }
+}
+
+#[post("/tokens", format = "json", data = "<new_token>")]
+pub fn add_token(_admin: Admin, new_token: LimitedJson<NewToken>) -> Result<Json<IssuedToken>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let new_token = new_token.0;
+    if new_token.scopes.is_empty() {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "A token needs at least one scope"));
+    }
+    if new_token.rate_limit.map_or(false, |rate_limit| rate_limit < 1) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "rate_limit has to be at least 1 request per minute"));
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+
+    let created_at = clock::now();
+    let results = db_connection.execute(
+        "insert into api_tokens (id, name, token_hash, scopes, rate_limit, created_at) values (null, $1, $2, $3, $4, $5)",
+        params![new_token.name, hash_token(&token), join_scopes(&new_token.scopes), new_token.rate_limit, created_at]);
+
+    match results {
+        Ok(_) => Ok(Json(IssuedToken {
+            details: ApiToken {
+                id: db_connection.last_insert_rowid(),
+                name: new_token.name,
+                scopes: new_token.scopes,
+                rate_limit: new_token.rate_limit,
+                created_at,
+                last_used_at: None,
+            },
+            token,
+        })),
+        Err(_) => Err("Failed to create token".into()),
+    }
+}
+
+// Moves a token to another rate limit tier
+#[put("/tokens/<id>/rate-limit", format = "json", data = "<tier>")]
+pub fn set_token_rate_limit(_admin: Admin, id: i64, tier: LimitedJson<RateLimitTier>) -> Result<Option<Json<StatusMessage>>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let rate_limit = tier.0.rate_limit;
+    if rate_limit.map_or(false, |rate_limit| rate_limit < 1) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "rate_limit has to be at least 1 request per minute"));
+    }
+
+    match db_connection.execute("update api_tokens set rate_limit = $1 where id = $2", params![rate_limit, id]) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(Json(StatusMessage {
+            message: match rate_limit {
+                Some(rate_limit) => format!("Token limited to {} requests per minute", rate_limit),
+                None => "Token uses the default rate limit".into(),
+            },
+        }))),
+        Err(_) => Err("Failed to update token".into()),
+    }
+}
+
+#[delete("/tokens/<id>")]
+pub fn remove_token(_admin: Admin, id: i64) -> Result<Json<StatusMessage>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    match db_connection.execute("delete from api_tokens where id = $1", params![id]) {
+        Ok(rows_deleted) => Ok(Json(StatusMessage {
+            message: format!("{} rows deleted", rows_deleted),
+        })),
+        Err(_) => Err("Failed to delete token".into()),
+    }
+}
@@ -0,0 +1,177 @@
+// Server-rendered web ui
+//
+// /ui shows the main list as a plain html page (templates/ui/index.html.tera) with
+// forms to add, complete, reopen and delete items, so the app can be used from a
+// browser without a separate frontend. The forms post to the routes below, which
+// do the same as their json counterparts and then redirect back to /ui with a flash
+// message (post/redirect/get, so reloading the page does not repeat the action).
+//
+// Html forms can only GET and POST, so every action is a POST. Auth works like for
+// the rest of the api: with basic_auth configured the browser asks for the password.
+// Every form starts with the page's CSRF token (see csrf.rs).
+//
+// GET /lists/<id>/print renders a list for paper (templates/ui/print.html.tera): a
+// box to tick per item, its due date (a day in the user's time zone, see
+// timezone.rs), tags and comments as notes. Open items come first, archived items
+// are left out.
+
+use std::collections::HashMap;
+
+use rocket::request::{FlashMessage, LenientForm};
+use rocket::response::{Flash, Redirect};
+use rocket::State;
+use rocket_contrib::templates::Template;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::config::AppConfig;
+use crate::csrf::CsrfToken;
+use crate::error::ApiError;
+use crate::i18n::{self, Language};
+use crate::timezone::UserTimeZone;
+use crate::workflow::ItemStatus;
+use crate::{clock, crypto, db, lists, validation, ToDoItem};
+
+const UI_PATH: &str = "/ui";
+
+// lenient, the csrf_token field is checked by the CSRF fairing and ignored here
+#[derive(FromForm)]
+pub struct NewItem {
+    item: String,
+}
+
+#[derive(Serialize)]
+struct FlashContext {
+    // "success" or "error", used as a css class
+    kind: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct IndexContext {
+    csrf_token: String,
+    items: Vec<ToDoItem>,
+    flash: Option<FlashContext>,
+}
+
+fn back(result: Result<(), ApiError>, success: &str) -> Flash<Redirect> {
+    match result {
+        Ok(_) => Flash::success(Redirect::to(UI_PATH), success),
+        Err(e) => Flash::error(Redirect::to(UI_PATH), e.message),
+    }
+}
+
+#[get("/ui")]
+pub fn index(flash: Option<FlashMessage>, csrf: CsrfToken, language: Language) -> Result<Template, ApiError> {
+    let list = crate::fetch_todo_list(&crate::ItemQuery::all())?;
+
+    let context = IndexContext {
+        csrf_token: csrf.0,
+        items: list.items,
+        flash: flash.map(|flash| FlashContext {
+            kind: flash.name().to_string(),
+            message: i18n::translate(language.0, flash.msg()),
+        }),
+    };
+    Ok(Template::render("ui/index", &context))
+}
+
+#[post("/ui/items", data = "<form>")]
+pub fn add_item(form: LenientForm<NewItem>, app_config: State<AppConfig>) -> Flash<Redirect> {
+    let result = validation::item_text(&form.into_inner().item).and_then(|text| {
+        let text = crypto::encrypt_text(&text)?;
+        crate::insert_new_item(text, crate::generated_uuid(&app_config))?;
+        Ok(())
+    });
+    back(result, "Item added")
+}
+
+#[post("/ui/items/<id>/done")]
+pub fn complete_item(id: i64) -> Flash<Redirect> {
+    back(crate::force_todo_item_status(id, ItemStatus::Done).map(|_| ()), "Item completed")
+}
+
+#[post("/ui/items/<id>/reopen")]
+pub fn reopen_item(id: i64) -> Flash<Redirect> {
+    back(crate::force_todo_item_status(id, ItemStatus::Todo).map(|_| ()), "Item reopened")
+}
+
+#[post("/ui/items/<id>/delete")]
+pub fn remove_item(id: i64) -> Flash<Redirect> {
+    back(crate::remove_todo_item(id).map(|_| ()), "Item deleted")
+}
+
+#[derive(Serialize)]
+struct PrintItem {
+    #[serde(flatten)]
+    item: ToDoItem,
+    // yyyy-mm-dd
+    due: Option<String>,
+    // the comments of the item, oldest first
+    notes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PrintContext {
+    name: String,
+    printed: String,
+    open: usize,
+    done: usize,
+    items: Vec<PrintItem>,
+}
+
+// the comments of the items of a list, by item id
+fn list_notes(db_connection: &rusqlite::Connection, list_id: i64) -> rusqlite::Result<HashMap<i64, Vec<String>>> {
+    let mut statement = db_connection.prepare(
+        "select comments.item_id, comments.body from comments
+         join todo_list on todo_list.id = comments.item_id
+         where todo_list.list_id = $1 order by comments.created_at, comments.id")?;
+    let rows = statement.query_map(params![list_id], |row| Ok((row.get::<_, i64>(0)?, crypto::decrypt_column(row.get(1)?, 1)?)))?;
+
+    let mut notes: HashMap<i64, Vec<String>> = HashMap::new();
+    for row in rows {
+        let (item_id, body) = row?;
+        notes.entry(item_id).or_default().push(body);
+    }
+    Ok(notes)
+}
+
+#[get("/lists/<id>/print")]
+pub fn print_list(id: i64, timezone: UserTimeZone) -> Result<Option<Template>, ApiError> {
+
+    let timezone = timezone.resolve()?;
+    let db_connection = db::connect()?;
+
+    let list = match lists::fetch_list(&db_connection, id) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err("Failed to fetch list".into()),
+    };
+    let mut items = match lists::fetch_list_items(&db_connection, id) {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+    let mut notes = match list_notes(&db_connection, id) {
+        Ok(notes) => notes,
+        Err(_) => return Err("Failed to fetch comments".into()),
+    };
+
+    // stable, so each half keeps the list's order
+    items.sort_by_key(|item| item.done);
+    let done = items.iter().filter(|item| item.done).count();
+
+    let context = PrintContext {
+        name: list.name().to_string(),
+        printed: clock::format_date(timezone.day(clock::now())),
+        open: items.len() - done,
+        done,
+        items: items.into_iter()
+            .map(|item| PrintItem {
+                due: item.due_at.map(|due_at| clock::format_date(timezone.day(due_at))),
+                notes: notes.remove(&item.id).unwrap_or_default(),
+                item,
+            })
+            .collect(),
+    };
+    Ok(Some(Template::render("ui/print", &context)))
+}
@@ -0,0 +1,180 @@
+// Item text validation
+//
+// Every path that creates or changes the text of an item (the json and form routes,
+// list items, /ui, seed data) runs it through item_text first, so what is stored
+// does not depend on how the client typed or encoded it:
+//   - the text is normalized to NFC, so "é" typed as e + combining accent and the
+//     precomposed "é" are stored (and deduplicated, searched) the same
+//   - runs of whitespace, line breaks included, become a single space and the ends
+//     are trimmed
+//   - other control characters are removed
+//   - the result may not be empty, nor longer than MAX_ITEM_LENGTH graphemes
+//     (characters as the user sees them, so an emoji with skin tone counts once)
+//   - nor start with the prefix of encrypted values (see crypto.rs), which would
+//     make it unreadable. Comments are checked for that too, see comment_body
+//
+// Between the clean up and the checks the text goes through the validator chain:
+// Validators registered when the rocket is built (the Validators fairing), which
+// can reject the text or change it, e.g. for company policy checks. Each gets the
+// output of the one before. One comes with the app: BlockedWords, which rejects
+// items containing a word of blocked_words in the config.
+
+use std::sync::{Mutex, OnceLock};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::Rocket;
+use serde_json::json;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::crypto;
+use crate::error::{ApiError, ErrorCode};
+
+pub const MAX_ITEM_LENGTH: usize = 500;
+
+// A step of the chain: the text to store instead, or an error to answer with
+pub trait Validator: Send + Sync {
+    fn validate(&self, text: String) -> Result<String, ApiError>;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(String) -> Result<String, ApiError> + Send + Sync,
+{
+    fn validate(&self, text: String) -> Result<String, ApiError> {
+        self(text)
+    }
+}
+
+// set once, when the Validators fairing is attached
+static CHAIN: OnceLock<Vec<Box<dyn Validator>>> = OnceLock::new();
+
+fn clean(text: &str) -> String {
+    let normalized: String = text.nfc().collect();
+    let collapsed = normalized.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let cleaned: String = collapsed.chars().filter(|c| !c.is_control()).collect();
+    cleaned.trim().to_string()
+}
+
+// The cleaned up text, or why it can not be stored
+pub fn item_text(text: &str) -> Result<String, ApiError> {
+    let mut text = clean(text);
+    for validator in CHAIN.get().into_iter().flatten() {
+        // a validator may hand back untidy text, it is cleaned again
+        text = clean(&validator.validate(text)?);
+    }
+
+    if text.is_empty() {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "The item can not be empty"));
+    }
+
+    let length = text.graphemes(true).count();
+    if length > MAX_ITEM_LENGTH {
+        return Err(ApiError::new(ErrorCode::ValidationFailed,
+            format!("The item can be at most {} characters long", MAX_ITEM_LENGTH))
+            .with_details(json!({ "max_length": MAX_ITEM_LENGTH, "length": length })));
+    }
+
+    if text.starts_with(crypto::PREFIX) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, format!("The item can not start with {}", crypto::PREFIX)));
+    }
+
+    Ok(text)
+}
+
+// The trimmed comment, or why it can not be stored
+pub fn comment_body(body: &str) -> Result<String, ApiError> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, "Comment can not be empty"));
+    }
+    if body.starts_with(crypto::PREFIX) {
+        return Err(ApiError::new(ErrorCode::ValidationFailed, format!("Comment can not start with {}", crypto::PREFIX)));
+    }
+    Ok(body.to_string())
+}
+
+// Rejects items that contain one of the words (whole words, ignoring case)
+pub struct BlockedWords {
+    words: Vec<String>,
+}
+
+impl BlockedWords {
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(words: I) -> BlockedWords {
+        BlockedWords {
+            words: words.into_iter().map(|word| word.as_ref().trim().to_lowercase()).filter(|word| !word.is_empty()).collect(),
+        }
+    }
+
+    // blocked_words in the config, None when there are none
+    fn from_rocket(rocket: &Rocket) -> Result<Option<BlockedWords>, String> {
+        let values = match rocket.config().get_slice("blocked_words") {
+            Ok(values) => values,
+            Err(_) => return Ok(None),
+        };
+
+        let words = values.iter()
+            .map(|value| value.as_str().ok_or_else(|| String::from("blocked_words must be a list of strings")))
+            .collect::<Result<Vec<&str>, String>>()?;
+        let blocked = BlockedWords::new(words);
+        Ok(if blocked.words.is_empty() { None } else { Some(blocked) })
+    }
+}
+
+impl Validator for BlockedWords {
+    fn validate(&self, text: String) -> Result<String, ApiError> {
+        let lowercase = text.to_lowercase();
+        let blocked = lowercase.unicode_words().any(|word| self.words.iter().any(|blocked| blocked == word));
+        if blocked {
+            return Err(ApiError::new(ErrorCode::ValidationFailed, "The item contains a blocked word"));
+        }
+        Ok(text)
+    }
+}
+
+// Fairing that installs the validator chain: BlockedWords when blocked_words is
+// configured, then the ones added with `with`, in that order
+#[derive(Default)]
+pub struct Validators {
+    // taken out when the fairing is attached
+    validators: Mutex<Vec<Box<dyn Validator>>>,
+}
+
+impl Validators {
+    pub fn new() -> Validators {
+        Validators::default()
+    }
+
+    pub fn with<V: Validator + 'static>(self, validator: V) -> Validators {
+        self.validators.lock().expect("validators lock poisoned").push(Box::new(validator));
+        self
+    }
+}
+
+impl Fairing for Validators {
+    fn info(&self) -> Info {
+        Info {
+            name: "Item validators",
+            kind: Kind::Attach,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let mut chain: Vec<Box<dyn Validator>> = Vec::new();
+        match BlockedWords::from_rocket(&rocket) {
+            Ok(Some(blocked)) => chain.push(Box::new(blocked)),
+            Ok(None) => (),
+            Err(e) => {
+                println!("{}", e);
+                return Err(rocket);
+            }
+        }
+        chain.append(&mut self.validators.lock().expect("validators lock poisoned"));
+
+        if CHAIN.set(chain).is_err() {
+            println!("The item validators can only be set up once");
+            return Err(rocket);
+        }
+        Ok(rocket)
+    }
+}
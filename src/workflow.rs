@@ -0,0 +1,165 @@
+// Kanban style status workflow
+//
+// Every item has a status: todo -> in-progress -> done, with blocked on the side.
+// Only the moves listed in ItemStatus::can_move_to are allowed, so e.g. a blocked
+// item has to be unblocked before it can be finished.
+// The older done flag is kept in step with the status (done <=> status is done) so
+// PUT/DELETE /todo/<id>/done keep working as shortcuts.
+
+use rocket_contrib::json::Json;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiError, ErrorCode};
+use crate::limits::LimitedJson;
+use crate::{db, writer, StatusMessage, ToDoItem, ITEM_COLUMNS, ITEM_ORDER};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemStatus {
+    Todo,
+    InProgress,
+    Blocked,
+    Done,
+}
+
+impl ItemStatus {
+    // the order of the columns on the board
+    pub const ALL: [ItemStatus; 4] = [ItemStatus::Todo, ItemStatus::InProgress, ItemStatus::Blocked, ItemStatus::Done];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ItemStatus::Todo => "todo",
+            ItemStatus::InProgress => "in-progress",
+            ItemStatus::Blocked => "blocked",
+            ItemStatus::Done => "done",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<ItemStatus> {
+        ItemStatus::ALL.iter().copied().find(|candidate| candidate.as_str() == status)
+    }
+
+    pub fn can_move_to(self, next: ItemStatus) -> bool {
+        use ItemStatus::*;
+
+        match (self, next) {
+            (current, next) if current == next => true,
+            (Todo, InProgress) | (Todo, Blocked) | (Todo, Done) => true,
+            (InProgress, Todo) | (InProgress, Blocked) | (InProgress, Done) => true,
+            (Blocked, Todo) | (Blocked, InProgress) => true,
+            // reopening a finished item puts it back at the start
+            (Done, Todo) => true,
+            _ => false,
+        }
+    }
+}
+
+// stored as text in the status column
+impl ToSql for ItemStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ItemStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str().and_then(|status| ItemStatus::parse(status).ok_or(FromSqlError::InvalidType))
+    }
+}
+
+// Writes the status and the matching done flag without checking the transition
+pub fn write_status(db_connection: &Connection, id: i64, status: ItemStatus) -> rusqlite::Result<usize> {
+    db_connection.execute(
+        "update todo_list set status = $1, done = $2 where id = $3",
+        params![status, status == ItemStatus::Done, id])
+}
+
+// Moves an item to another status. The body is the status as a json string, e.g.
+// "in-progress". Moves that are not allowed by the workflow are rejected
+#[put("/todo/<id>/status", format = "json", data = "<status>")]
+pub fn set_todo_item_status(id: i64, status: LimitedJson<ItemStatus>) -> Result<Option<Json<StatusMessage>>, ApiError> {
+
+    let next = status.0;
+    writer::write(move |db_connection| {
+
+        // read and write in one transaction so the check is against the status we change
+        let transaction = match db_connection.transaction() {
+            Ok(transaction) => transaction,
+            Err(_) => return Err("Failed to start a transaction".into()),
+        };
+
+        let current = transaction.query_row(
+            "select status from todo_list where id = $1", params![id], |row| row.get::<_, ItemStatus>(0))
+            .optional();
+
+        let current = match current {
+            Ok(Some(current)) => current,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err("Failed to fetch ToDo Item".into()),
+        };
+
+        if !current.can_move_to(next) {
+            return Err(ApiError::new(ErrorCode::Conflict, format!("Can not move an item from {} to {}", current.as_str(), next.as_str())));
+        }
+
+        if write_status(&transaction, id, next).is_err() || transaction.commit().is_err() {
+            return Err("Failed to update ToDo Item".into());
+        }
+
+        Ok(Some(Json(StatusMessage {
+            message: format!("Moved from {} to {}", current.as_str(), next.as_str()),
+        })))
+    })?
+}
+
+#[derive(Serialize)]
+pub struct BoardColumn {
+    status: ItemStatus,
+    items: Vec<ToDoItem>,
+}
+
+#[derive(Serialize)]
+pub struct Board {
+    columns: Vec<BoardColumn>,
+}
+
+// All (not archived) items grouped by status, one column per status in workflow
+// order. ?list_id= limits the board to one list
+#[get("/board?<list_id>")]
+pub fn fetch_board(list_id: Option<i64>) -> Result<Json<Board>, ApiError> {
+
+    let db_connection = db::connect()?;
+
+    let mut statement = match db_connection.prepare(&format!(
+        "select {} from todo_list
+         where archived = 0 and ($1 is null or list_id = $1)
+         order by {}",
+        ITEM_COLUMNS, ITEM_ORDER))
+    {
+        Ok(statement) => statement,
+        Err(_) => return Err("Failed to prepare a query".into()),
+    };
+
+    let results = statement
+        .query_map(params![list_id], ToDoItem::from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<ToDoItem>>>());
+
+    let items = match results {
+        Ok(items) => items,
+        Err(_) => return Err("Failed to fetch ToDo Items".into()),
+    };
+
+    let mut columns: Vec<BoardColumn> = ItemStatus::ALL.iter()
+        .map(|status| BoardColumn { status: *status, items: Vec::new() })
+        .collect();
+
+    for item in items {
+        if let Some(column) = columns.iter_mut().find(|column| column.status == item.status) {
+            column.items.push(item);
+        }
+    }
+
+    Ok(Json(Board { columns }))
+}
@@ -0,0 +1,216 @@
+// Write queue
+//
+// SQLite lets one connection write at a time; with every request opening its own
+// connection, concurrent writes wait on each other's locks and can fail with
+// "database is locked". Item writes (adding, changing, moving, archiving, deleting
+// and importing items) are instead sent to one writer thread that owns a connection
+// and runs them one after another. The queue is bounded
+// (write_queue_size), so when it is full a request waits for room instead of piling
+// up more work, and each request gets its result back on its own reply channel.
+// queued() tells load shedding (see load_shed.rs) how far behind the writer is.
+//
+// Batching (opt-in, write_batch_ms > 0): a plain new item (insert_item) is not
+// written right away. The writer waits up to write_batch_ms for more of them and
+// then writes them all with one multi-row insert in one transaction. With many
+// clients adding items at once this saves a transaction (and a disk sync) per item.
+//
+// The writer's connection is to the main database: requests for a tenant (see
+// tenants.rs) write to the tenant's database directly.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::AdHoc;
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection};
+
+use crate::{db, metrics, tenants};
+
+const DEFAULT_QUEUE_SIZE: usize = 64;
+
+// two parameters per row, well below sqlite's limit of 999 per statement
+const MAX_BATCH_ROWS: usize = 400;
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+// a new item waiting to be written in the next batch
+struct PendingInsert {
+    text: String,
+    uuid: Option<String>,
+    reply: SyncSender<Result<usize, String>>,
+}
+
+enum Message {
+    Job(Job),
+    Insert(PendingInsert),
+}
+
+static QUEUE: OnceLock<SyncSender<Message>> = OnceLock::new();
+
+// writes sent to the writer that have not been answered yet, waiting for room
+// included
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+// The number of writes waiting for (or being run by) the writer
+pub fn queued() -> usize {
+    QUEUED.load(Ordering::SeqCst)
+}
+
+fn run_job(db_connection: &mut Connection, job: Job) {
+    // a panicking job only fails its own request (its reply channel is dropped),
+    // the writer keeps going
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| job(db_connection)));
+}
+
+fn insert_rows(db_connection: &mut Connection, batch: &[PendingInsert]) -> rusqlite::Result<()> {
+    let transaction = db_connection.transaction()?;
+
+    let rows = vec!["(null, ?, ?)"; batch.len()].join(", ");
+    let values: Vec<&dyn ToSql> = batch.iter()
+        .flat_map(|insert| vec![&insert.text as &dyn ToSql, &insert.uuid as &dyn ToSql])
+        .collect();
+    transaction.execute(&format!("insert into todo_list (id, item, uuid) values {}", rows), values)?;
+
+    transaction.commit()
+}
+
+fn flush(db_connection: &mut Connection, batch: Vec<PendingInsert>) {
+    let result = match insert_rows(db_connection, &batch) {
+        Ok(_) => Ok(1),
+        Err(_) => Err(String::from("Failed to insert ToDo Item")),
+    };
+    for insert in batch {
+        let _ = insert.reply.send(result.clone());
+    }
+}
+
+// The writer thread. Returns when the queue is dropped
+fn run(mut db_connection: Connection, receiver: Receiver<Message>, batch_window: Option<Duration>) {
+    // a message that arrived while a batch was being collected
+    let mut next: Option<Message> = None;
+
+    loop {
+        let message = match next.take() {
+            Some(message) => message,
+            None => match receiver.recv() {
+                Ok(message) => message,
+                Err(_) => return,
+            },
+        };
+
+        let first = match message {
+            Message::Job(job) => {
+                run_job(&mut db_connection, job);
+                continue;
+            }
+            Message::Insert(insert) => insert,
+        };
+
+        let mut batch = vec![first];
+        if let Some(window) = batch_window {
+            let deadline = Instant::now() + window;
+            while batch.len() < MAX_BATCH_ROWS {
+                match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(Message::Insert(insert)) => batch.push(insert),
+                    // anything else runs after the batch, keeping the order of writes
+                    Ok(message) => {
+                        next = Some(message);
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        }
+        flush(&mut db_connection, batch);
+    }
+}
+
+fn start(capacity: usize, batch_window: Option<Duration>) -> Result<(), String> {
+    let db_connection = db::connect()?;
+    let (sender, receiver) = mpsc::sync_channel::<Message>(capacity);
+
+    thread::spawn(move || run(db_connection, receiver, batch_window));
+
+    QUEUE.set(sender).map_err(|_| String::from("The write queue was already started"))
+}
+
+// Sends a message to the writer and waits for the answer on `result`
+fn send<T>(queue: &SyncSender<Message>, message: Message, result: Receiver<T>) -> Result<T, String> {
+    // the time until the writer is done, waiting in the queue included, is database
+    // time of this request
+    let started = Instant::now();
+    QUEUED.fetch_add(1, Ordering::SeqCst);
+    let result = queue.send(message)
+        .map_err(|_| String::from("The database writer has stopped"))
+        .and_then(|_| result.recv().map_err(|_| String::from("Failed to write to the database")));
+    QUEUED.fetch_sub(1, Ordering::SeqCst);
+    metrics::record_db_time(started.elapsed());
+    result
+}
+
+// The queue, when the write is for the main database
+fn queue() -> Option<&'static SyncSender<Message>> {
+    QUEUE.get().filter(|_| tenants::current_database().is_none())
+}
+
+// Runs job on the writer thread and waits for its result
+pub fn write<T, F>(job: F) -> Result<T, String>
+where
+    F: FnOnce(&mut Connection) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let queue = match queue() {
+        Some(queue) => queue,
+        // queue not started or a tenant's write, write directly
+        None => return Ok(job(&mut db::connect()?)),
+    };
+
+    let (reply, result) = mpsc::sync_channel(1);
+    let job: Job = Box::new(move |db_connection| {
+        let _ = reply.send(job(db_connection));
+    });
+
+    send(queue, Message::Job(job), result)
+}
+
+// Inserts a new item (text already encrypted if needed), in a batch when batching
+// is on. Returns the number of rows inserted
+pub fn insert_item(text: String, uuid: Option<String>) -> Result<usize, String> {
+    let queue = match queue() {
+        Some(queue) => queue,
+        None => {
+            let db_connection = db::connect()?;
+            return db_connection
+                .execute("insert into todo_list (id, item, uuid) values (null, $1, $2)", params![text, uuid])
+                .map_err(|_| String::from("Failed to insert ToDo Item"));
+        }
+    };
+
+    let (reply, result) = mpsc::sync_channel(1);
+    send(queue, Message::Insert(PendingInsert { text, uuid, reply }), result)?
+}
+
+// Starts the writer thread. Attach after the config fairing, the writer's connection
+// needs the database key
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Write queue", |rocket| {
+        let capacity = rocket.config().get_int("write_queue_size")
+            .map(|size| size.max(1) as usize)
+            .unwrap_or(DEFAULT_QUEUE_SIZE);
+        let batch_window = rocket.config().get_int("write_batch_ms").ok()
+            .filter(|milliseconds| *milliseconds > 0)
+            .map(|milliseconds| Duration::from_millis(milliseconds as u64));
+
+        match start(capacity, batch_window) {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                println!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}